@@ -114,6 +114,8 @@ pub fn main() -> Result<()> {
         tool: ToolType::Thin,
         engine_type: EngineType::Sync,
         use_metadata_snap: false,
+        faults: None,
+        fault_log: None,
     };
 
     let report = mk_report(false);
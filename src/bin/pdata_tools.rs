@@ -26,6 +26,7 @@ fn register_commands<'a>() -> Vec<Box<dyn Command<'a>>> {
         Box::new(thin_delta::ThinDeltaCommand),
         Box::new(thin_dump::ThinDumpCommand),
         Box::new(thin_ls::ThinLsCommand),
+        Box::new(thin_merge::ThinMergeCommand),
         Box::new(thin_metadata_pack::ThinMetadataPackCommand),
         Box::new(thin_metadata_size::ThinMetadataSizeCommand),
         Box::new(thin_metadata_unpack::ThinMetadataUnpackCommand),
@@ -12,11 +12,13 @@ fn get_basename(path: &OsStr) -> &Path {
 fn register_commands<'a>() -> Vec<Box<dyn Command<'a>>> {
     vec![
         Box::new(era_generate_metadata::EraGenerateMetadataCommand),
+        Box::new(era_generate_damage::EraGenerateDamageCommand),
         Box::new(cache_generate_metadata::CacheGenerateMetadataCommand),
         Box::new(cache_generate_damage::CacheGenerateDamageCommand),
         Box::new(thin_explore::ThinExploreCommand),
         Box::new(thin_generate_metadata::ThinGenerateMetadataCommand),
         Box::new(thin_generate_damage::ThinGenerateDamageCommand),
+        Box::new(thin_journal_check::ThinJournalCheckCommand),
         Box::new(thin_stat::ThinStatCommand),
     ]
 }
@@ -11,6 +11,7 @@ use crate::io_engine::*;
 use crate::pdata::array::{self, ArrayBlock, ArrayError};
 use crate::pdata::array_walker::*;
 use crate::pdata::bitset::*;
+use crate::pdata::issues::IssueLog;
 use crate::pdata::space_map::checker::*;
 use crate::pdata::space_map::common::*;
 use crate::pdata::space_map::*;
@@ -276,7 +277,23 @@ fn check_superblock(sb: &Superblock) -> anyhow::Result<()> {
 
 pub fn check(opts: CacheCheckOptions) -> anyhow::Result<()> {
     let ctx = mk_context(&opts)?;
+    check_(&ctx, &opts)
+}
+
+// Checks the metadata held by the given engine, rather than opening
+// the device named in the options.
+pub fn check_with_engine(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    opts: &CacheCheckOptions,
+) -> anyhow::Result<()> {
+    let ctx = Context {
+        report: opts.report.clone(),
+        engine,
+    };
+    check_(&ctx, opts)
+}
 
+fn check_(ctx: &Context, opts: &CacheCheckOptions) -> anyhow::Result<()> {
     let engine = &ctx.engine;
     let metadata_sm = core_sm(engine.get_nr_blocks(), u8::MAX as u32);
     inc_superblock(&metadata_sm)?;
@@ -376,6 +393,7 @@ pub fn check(opts: CacheCheckOptions) -> anyhow::Result<()> {
     let metadata_leaks = check_metadata_space_map(
         engine.clone(),
         ctx.report.clone(),
+        &IssueLog::new(),
         root,
         metadata_sm.clone(),
         opts.ignore_non_fatal,
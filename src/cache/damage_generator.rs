@@ -1,26 +1,118 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
 
 use crate::cache::superblock::*;
 use crate::commands::engine::*;
 use crate::devtools::damage_generator::*;
+use crate::io_engine::IoEngine;
 use crate::pdata::space_map::common::*;
 use crate::pdata::unpack::unpack;
 
 //------------------------------------------
 
+pub struct SuperblockOverrides {
+    pub version: Option<u32>,
+    pub mapping_root: Option<u64>,
+    pub hint_root: Option<u64>,
+    pub dirty_root: Option<u64>,
+    pub discard_root: Option<u64>,
+    pub policy_hint_size: Option<u32>,
+    pub cache_blocks: Option<u32>,
+}
+
+// The version is written as given, even if it isn't supported, so
+// the checker's handling of unknown versions can be tested.
+pub fn override_superblock(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    opts: &SuperblockOverrides,
+) -> Result<()> {
+    let mut sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    if let Some(v) = opts.version {
+        sb.version = v;
+    }
+    if let Some(v) = opts.mapping_root {
+        sb.mapping_root = v;
+    }
+    if let Some(v) = opts.hint_root {
+        sb.hint_root = v;
+    }
+    if let Some(v) = opts.dirty_root {
+        sb.dirty_root = Some(v);
+    }
+    if let Some(v) = opts.discard_root {
+        sb.discard_root = v;
+    }
+    if let Some(v) = opts.policy_hint_size {
+        sb.policy_hint_size = v;
+    }
+    if let Some(v) = opts.cache_blocks {
+        sb.cache_blocks = v;
+    }
+    write_superblock(engine.as_ref(), SUPERBLOCK_LOCATION, &sb)
+}
+
+//------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheArray {
+    Mapping,
+    Hint,
+    Dirty,
+    Discard,
+}
+
+impl FromStr for CacheArray {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mapping" => Ok(CacheArray::Mapping),
+            "hint" => Ok(CacheArray::Hint),
+            "dirty" => Ok(CacheArray::Dirty),
+            "discard" => Ok(CacheArray::Discard),
+            _ => Err(anyhow!("unknown array '{}'", s)),
+        }
+    }
+}
+
+fn array_root(sb: &Superblock, array: CacheArray) -> Result<u64> {
+    let (root, name) = match array {
+        CacheArray::Mapping => (sb.mapping_root, "mapping array"),
+        CacheArray::Hint => (sb.hint_root, "hint array"),
+        CacheArray::Dirty => (sb.dirty_root.unwrap_or(0), "dirty bitset"),
+        CacheArray::Discard => (sb.discard_root, "discard bitset"),
+    };
+    if root == 0 {
+        return Err(anyhow!("the metadata has no {}", name));
+    }
+    Ok(root)
+}
+
+//------------------------------------------
+
 pub enum DamageOp {
     CreateMetadataLeaks {
         nr_blocks: usize,
         expected_rc: u32,
         actual_rc: u32,
     },
+    OverrideSuperblock(SuperblockOverrides),
+    DamageArray {
+        array: CacheArray,
+        index: usize,
+        damage: BlockDamage,
+    },
 }
 
 pub struct CacheDamageOpts<'a> {
     pub engine_opts: EngineOptions,
     pub op: DamageOp,
     pub output: &'a Path,
+    pub seed: u64,
 }
 
 pub fn damage_metadata(opts: CacheDamageOpts) -> Result<()> {
@@ -29,13 +121,23 @@ pub fn damage_metadata(opts: CacheDamageOpts) -> Result<()> {
         .build()?;
     let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
     let sm_root = unpack::<SMRoot>(&sb.metadata_sm_root)?;
+    let mut rng = StdRng::seed_from_u64(opts.seed);
 
     match opts.op {
         DamageOp::CreateMetadataLeaks {
             nr_blocks,
             expected_rc,
             actual_rc,
-        } => create_metadata_leaks(engine, sm_root, nr_blocks, expected_rc, actual_rc),
+        } => create_metadata_leaks(engine, sm_root, nr_blocks, expected_rc, actual_rc, &mut rng),
+        DamageOp::OverrideSuperblock(opts) => override_superblock(engine, &opts),
+        DamageOp::DamageArray {
+            array,
+            index,
+            damage,
+        } => {
+            let root = array_root(&sb, array)?;
+            damage_array_block(engine, root, index, damage, &mut rng)
+        }
     }
 }
 
@@ -1,3 +1,4 @@
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::{value_parser, Arg, ArgAction, ArgGroup};
 use std::path::Path;
 use std::process;
@@ -5,6 +6,7 @@ use std::process;
 use crate::cache::damage_generator::*;
 use crate::commands::engine::*;
 use crate::commands::utils::*;
+use crate::devtools::damage_generator::BlockDamage;
 use crate::version::*;
 
 //------------------------------------------
@@ -26,6 +28,25 @@ impl CacheGenerateDamageCommand {
                     .action(ArgAction::SetTrue)
                     .requires_all(["EXPECTED", "ACTUAL", "NR_BLOCKS"]),
             )
+            .arg(
+                Arg::new("OVERRIDE")
+                    .help("Override superblock fields")
+                    .long("override")
+                    .action(ArgAction::SetTrue)
+                    .requires("overrides"),
+            )
+            .arg(
+                Arg::new("DAMAGE_ARRAY")
+                    .help("Damage a block of an array or bitset")
+                    .long("damage-array")
+                    .value_name("DAMAGE")
+                    .value_parser(
+                        PossibleValuesParser::new(["checksum", "zero", "scramble"])
+                            .map(|s| s.parse::<BlockDamage>().unwrap()),
+                    )
+                    .hide_possible_values(true)
+                    .requires("ARRAY"),
+            )
             // options
             .arg(
                 Arg::new("EXPECTED")
@@ -48,6 +69,82 @@ impl CacheGenerateDamageCommand {
                     .value_name("NUM")
                     .value_parser(value_parser!(usize)),
             )
+            .arg(
+                Arg::new("SUPERBLOCK_VERSION")
+                    .help("Specify the metadata version")
+                    .long("superblock-version")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                Arg::new("MAPPING_ROOT")
+                    .help("Specify the mapping array root")
+                    .long("mapping-root")
+                    .value_name("BLOCKNR")
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                Arg::new("HINT_ROOT")
+                    .help("Specify the hint array root")
+                    .long("hint-root")
+                    .value_name("BLOCKNR")
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                Arg::new("DIRTY_ROOT")
+                    .help("Specify the dirty bitset root")
+                    .long("dirty-root")
+                    .value_name("BLOCKNR")
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                Arg::new("DISCARD_ROOT")
+                    .help("Specify the discard bitset root")
+                    .long("discard-root")
+                    .value_name("BLOCKNR")
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                Arg::new("POLICY_HINT_SIZE")
+                    .help("Specify the policy hint size")
+                    .long("policy-hint-size")
+                    .value_name("BYTES")
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                Arg::new("CACHE_BLOCKS")
+                    .help("Specify the number of cache blocks")
+                    .long("cache-blocks")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                Arg::new("ARRAY")
+                    .help("Specify the array to damage")
+                    .long("array")
+                    .value_name("TYPE")
+                    .value_parser(
+                        PossibleValuesParser::new(["mapping", "hint", "dirty", "discard"])
+                            .map(|s| s.parse::<CacheArray>().unwrap()),
+                    )
+                    .hide_possible_values(true),
+            )
+            .arg(
+                Arg::new("ARRAY_BLOCK")
+                    .help("Specify the index of the array block to damage")
+                    .long("array-block")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(usize))
+                    .default_value("0"),
+            )
+            .arg(
+                Arg::new("SEED")
+                    .help("Specify the seed of the random generator")
+                    .long("seed")
+                    .value_name("SEED")
+                    .value_parser(value_parser!(u64))
+                    .default_value("0"),
+            )
             .arg(
                 Arg::new("OUTPUT")
                     .help("Specify the output device")
@@ -58,8 +155,21 @@ impl CacheGenerateDamageCommand {
             )
             .group(
                 ArgGroup::new("commands")
-                    .args(["CREATE_METADATA_LEAKS"])
+                    .args(["CREATE_METADATA_LEAKS", "OVERRIDE", "DAMAGE_ARRAY"])
                     .required(true),
+            )
+            .group(
+                ArgGroup::new("overrides")
+                    .args([
+                        "SUPERBLOCK_VERSION",
+                        "MAPPING_ROOT",
+                        "HINT_ROOT",
+                        "DIRTY_ROOT",
+                        "DISCARD_ROOT",
+                        "POLICY_HINT_SIZE",
+                        "CACHE_BLOCKS",
+                    ])
+                    .multiple(true),
             );
         engine_args(version_args(cmd))
     }
@@ -87,6 +197,20 @@ impl<'a> Command<'a> for CacheGenerateDamageCommand {
                 expected_rc: *matches.get_one::<u32>("EXPECTED").unwrap(),
                 actual_rc: *matches.get_one::<u32>("ACTUAL").unwrap(),
             },
+            "OVERRIDE" => DamageOp::OverrideSuperblock(SuperblockOverrides {
+                version: matches.get_one::<u32>("SUPERBLOCK_VERSION").copied(),
+                mapping_root: matches.get_one::<u64>("MAPPING_ROOT").copied(),
+                hint_root: matches.get_one::<u64>("HINT_ROOT").copied(),
+                dirty_root: matches.get_one::<u64>("DIRTY_ROOT").copied(),
+                discard_root: matches.get_one::<u64>("DISCARD_ROOT").copied(),
+                policy_hint_size: matches.get_one::<u32>("POLICY_HINT_SIZE").copied(),
+                cache_blocks: matches.get_one::<u32>("CACHE_BLOCKS").copied(),
+            }),
+            "DAMAGE_ARRAY" => DamageOp::DamageArray {
+                array: *matches.get_one::<CacheArray>("ARRAY").unwrap(),
+                index: *matches.get_one::<usize>("ARRAY_BLOCK").unwrap(),
+                damage: *matches.get_one::<BlockDamage>("DAMAGE_ARRAY").unwrap(),
+            },
             _ => {
                 eprintln!("unknown option");
                 process::exit(1);
@@ -97,6 +221,7 @@ impl<'a> Command<'a> for CacheGenerateDamageCommand {
             engine_opts: engine_opts.unwrap(),
             op,
             output: Path::new(matches.get_one::<String>("OUTPUT").unwrap()),
+            seed: *matches.get_one::<u64>("SEED").unwrap(),
         };
 
         to_exit_code(&report, damage_metadata(opts))
@@ -1,10 +1,12 @@
 use anyhow::{anyhow, Result};
 use clap::ArgMatches;
 use roaring::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use crate::io_engine::fault::FaultSchedule;
 use crate::io_engine::*;
+use crate::pack::toplevel::is_pack_file;
 use crate::pdata::space_map::allocated_blocks::*;
 use crate::pdata::space_map::common::*;
 use crate::pdata::unpack::*;
@@ -33,6 +35,10 @@ pub struct EngineOptions {
     pub tool: ToolType,
     pub engine_type: EngineType,
     pub use_metadata_snap: bool,
+
+    // Faults are only injected by the devtools builds
+    pub faults: Option<FaultSchedule>,
+    pub fault_log: Option<PathBuf>,
 }
 
 //------------------------------------------
@@ -41,12 +47,56 @@ pub struct EngineOptions {
 pub fn engine_args(cmd: clap::Command) -> clap::Command {
     use clap::Arg;
 
-    cmd.arg(
+    let cmd = cmd.arg(
         Arg::new("IO_ENGINE")
             .help("Select an io engine to use")
             .long("io-engine")
             .value_name("IO_ENGINE")
             .hide(true),
+    );
+
+    #[cfg(feature = "devtools")]
+    let cmd = fault_args(cmd);
+
+    cmd
+}
+
+// Fault injection is for testing the tools, so the flags are hidden
+#[cfg(feature = "devtools")]
+fn fault_args(cmd: clap::Command) -> clap::Command {
+    use clap::{value_parser, Arg};
+
+    cmd.arg(
+        Arg::new("FAULT_SEED")
+            .help("Inject faults at random, following the seed")
+            .long("fault-seed")
+            .value_name("SEED")
+            .value_parser(value_parser!(u64))
+            .hide(true),
+    )
+    .arg(
+        Arg::new("FAULT_RATE")
+            .help("Specify the probability of faulting a block")
+            .long("fault-rate")
+            .value_name("RATE")
+            .value_parser(value_parser!(f64))
+            .default_value("0.01")
+            .hide(true),
+    )
+    .arg(
+        Arg::new("FAULT_RULES")
+            .help("Inject faults following the rules in a file")
+            .long("fault-rules")
+            .value_name("FILE")
+            .conflicts_with("FAULT_SEED")
+            .hide(true),
+    )
+    .arg(
+        Arg::new("FAULT_LOG")
+            .help("Record the faults injected in a file")
+            .long("fault-log")
+            .value_name("FILE")
+            .hide(true),
     )
 }
 
@@ -89,6 +139,35 @@ fn metadata_snap_flag(matches: &ArgMatches) -> bool {
     )
 }
 
+#[cfg(feature = "devtools")]
+fn parse_faults(matches: &ArgMatches) -> Result<Option<FaultSchedule>> {
+    if let Some(seed) = matches.get_one::<u64>("FAULT_SEED") {
+        let rate = *matches.get_one::<f64>("FAULT_RATE").unwrap();
+        return FaultSchedule::seeded(*seed, rate).map(Some);
+    }
+
+    if let Some(path) = matches.get_one::<String>("FAULT_RULES") {
+        return FaultSchedule::from_rules_file(path).map(Some);
+    }
+
+    Ok(None)
+}
+
+#[cfg(not(feature = "devtools"))]
+fn parse_faults(_matches: &ArgMatches) -> Result<Option<FaultSchedule>> {
+    Ok(None)
+}
+
+#[cfg(feature = "devtools")]
+fn parse_fault_log(matches: &ArgMatches) -> Option<PathBuf> {
+    matches.get_one::<String>("FAULT_LOG").map(PathBuf::from)
+}
+
+#[cfg(not(feature = "devtools"))]
+fn parse_fault_log(_matches: &ArgMatches) -> Option<PathBuf> {
+    None
+}
+
 pub fn parse_engine_opts(tool: ToolType, matches: &ArgMatches) -> Result<EngineOptions> {
     let engine_type = parse_type(matches)?;
     let use_metadata_snap =
@@ -98,6 +177,8 @@ pub fn parse_engine_opts(tool: ToolType, matches: &ArgMatches) -> Result<EngineO
         tool,
         engine_type,
         use_metadata_snap,
+        faults: parse_faults(matches)?,
+        fault_log: parse_fault_log(matches),
     })
 }
 
@@ -189,15 +270,39 @@ impl<'a, P: AsRef<Path>> EngineBuilder<'a, P> {
     }
 
     pub fn build(self) -> Result<Arc<dyn IoEngine + Send + Sync>> {
+        let engine = self.build_()?;
+
+        if let Some(faults) = &self.opts.faults {
+            let mut engine = FaultIoEngine::new(engine, faults.clone());
+            if let Some(log) = &self.opts.fault_log {
+                engine = engine.log_to(log)?;
+            }
+            return Ok(Arc::new(engine));
+        }
+
+        Ok(engine)
+    }
+
+    fn build_(&self) -> Result<Arc<dyn IoEngine + Send + Sync>> {
+        // Packed metadata is read in place, whichever engine was asked for
+        if matches!(is_pack_file(self.path.as_ref()), Ok(true)) {
+            if self.write {
+                return Err(anyhow!(
+                    "Can't write to a pack file, run thin_metadata_unpack on it first"
+                ));
+            }
+            return Ok(Arc::new(PackIoEngine::new(self.path.as_ref())?));
+        }
+
         let engine: Arc<dyn IoEngine + Send + Sync> = match self.opts.engine_type {
             #[cfg(feature = "io_uring")]
             EngineType::Async => Arc::new(AsyncIoEngine::new_with(
-                self.path,
+                self.path.as_ref(),
                 self.write,
                 self.exclusive,
             )?),
             EngineType::Sync => Arc::new(SyncIoEngine::new_with(
-                self.path,
+                self.path.as_ref(),
                 self.write,
                 self.exclusive,
             )?),
@@ -212,7 +317,11 @@ impl<'a, P: AsRef<Path>> EngineBuilder<'a, P> {
                     }
                 };
 
-                Arc::new(SpindleIoEngine::new(self.path, valid_blocks, self.write)?)
+                Arc::new(SpindleIoEngine::new(
+                    self.path.as_ref(),
+                    valid_blocks,
+                    self.write,
+                )?)
             }
         };
         Ok(engine)
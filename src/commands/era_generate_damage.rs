@@ -0,0 +1,190 @@
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{value_parser, Arg, ArgAction, ArgGroup};
+use std::path::Path;
+use std::process;
+
+use crate::commands::engine::*;
+use crate::commands::utils::*;
+use crate::devtools::damage_generator::BlockDamage;
+use crate::era::damage_generator::*;
+use crate::version::*;
+
+//------------------------------------------
+use crate::commands::Command;
+
+pub struct EraGenerateDamageCommand;
+
+fn block_damage_parser() -> impl TypedValueParser<Value = BlockDamage> {
+    PossibleValuesParser::new(["checksum", "zero", "scramble"])
+        .map(|s| s.parse::<BlockDamage>().unwrap())
+}
+
+impl EraGenerateDamageCommand {
+    fn cli(&self) -> clap::Command {
+        let cmd = clap::Command::new(self.name())
+            .next_display_order(None)
+            .version(crate::tools_version!())
+            .disable_version_flag(true)
+            .about("A tool for creating damages in era metadata.")
+            .arg(
+                Arg::new("OVERRIDE")
+                    .help("Override superblock fields")
+                    .long("override")
+                    .action(ArgAction::SetTrue)
+                    .requires("overrides"),
+            )
+            .arg(
+                Arg::new("DAMAGE_WRITESET")
+                    .help("Damage a block of a writeset bitset")
+                    .long("damage-writeset")
+                    .value_name("DAMAGE")
+                    .value_parser(block_damage_parser())
+                    .hide_possible_values(true),
+            )
+            .arg(
+                Arg::new("DAMAGE_ERA_ARRAY")
+                    .help("Damage a block of the era array")
+                    .long("damage-era-array")
+                    .value_name("DAMAGE")
+                    .value_parser(block_damage_parser())
+                    .hide_possible_values(true),
+            )
+            // options
+            .arg(
+                Arg::new("SUPERBLOCK_VERSION")
+                    .help("Specify the metadata version")
+                    .long("superblock-version")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                Arg::new("CURRENT_ERA")
+                    .help("Specify the current era")
+                    .long("current-era")
+                    .value_name("ERA")
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                Arg::new("CURRENT_WRITESET_ROOT")
+                    .help("Specify the root of the current writeset")
+                    .long("current-writeset-root")
+                    .value_name("BLOCKNR")
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                Arg::new("WRITESET_TREE_ROOT")
+                    .help("Specify the writeset tree root")
+                    .long("writeset-tree-root")
+                    .value_name("BLOCKNR")
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                Arg::new("ERA_ARRAY_ROOT")
+                    .help("Specify the era array root")
+                    .long("era-array-root")
+                    .value_name("BLOCKNR")
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                Arg::new("ERA")
+                    .help("Specify the era of the writeset to damage")
+                    .long("era")
+                    .value_name("ERA")
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                Arg::new("ARRAY_BLOCK")
+                    .help("Specify the index of the array block to damage")
+                    .long("array-block")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(usize))
+                    .default_value("0"),
+            )
+            .arg(
+                Arg::new("SEED")
+                    .help("Specify the seed of the random generator")
+                    .long("seed")
+                    .value_name("SEED")
+                    .value_parser(value_parser!(u64))
+                    .default_value("0"),
+            )
+            .arg(
+                Arg::new("OUTPUT")
+                    .help("Specify the output device")
+                    .short('o')
+                    .long("output")
+                    .value_name("FILE")
+                    .required(true),
+            )
+            .group(
+                ArgGroup::new("commands")
+                    .args(["OVERRIDE", "DAMAGE_WRITESET", "DAMAGE_ERA_ARRAY"])
+                    .required(true),
+            )
+            .group(
+                ArgGroup::new("overrides")
+                    .args([
+                        "SUPERBLOCK_VERSION",
+                        "CURRENT_ERA",
+                        "CURRENT_WRITESET_ROOT",
+                        "WRITESET_TREE_ROOT",
+                        "ERA_ARRAY_ROOT",
+                    ])
+                    .multiple(true),
+            );
+        engine_args(version_args(cmd))
+    }
+}
+
+impl<'a> Command<'a> for EraGenerateDamageCommand {
+    fn name(&self) -> &'a str {
+        "era_generate_damage"
+    }
+
+    fn run(&self, args: &mut dyn Iterator<Item = std::ffi::OsString>) -> exitcode::ExitCode {
+        let matches = self.cli().get_matches_from(args);
+        display_version(&matches);
+
+        let report = mk_report(false);
+
+        let engine_opts = parse_engine_opts(ToolType::Era, &matches);
+        if engine_opts.is_err() {
+            return to_exit_code(&report, engine_opts);
+        }
+
+        let index = *matches.get_one::<usize>("ARRAY_BLOCK").unwrap();
+        let op = match matches.get_one::<clap::Id>("commands").unwrap().as_str() {
+            "OVERRIDE" => DamageOp::OverrideSuperblock(SuperblockOverrides {
+                version: matches.get_one::<u32>("SUPERBLOCK_VERSION").copied(),
+                current_era: matches.get_one::<u32>("CURRENT_ERA").copied(),
+                current_writeset_root: matches.get_one::<u64>("CURRENT_WRITESET_ROOT").copied(),
+                writeset_tree_root: matches.get_one::<u64>("WRITESET_TREE_ROOT").copied(),
+                era_array_root: matches.get_one::<u64>("ERA_ARRAY_ROOT").copied(),
+            }),
+            "DAMAGE_WRITESET" => DamageOp::DamageWriteset {
+                era: matches.get_one::<u32>("ERA").copied(),
+                index,
+                damage: *matches.get_one::<BlockDamage>("DAMAGE_WRITESET").unwrap(),
+            },
+            "DAMAGE_ERA_ARRAY" => DamageOp::DamageEraArray {
+                index,
+                damage: *matches.get_one::<BlockDamage>("DAMAGE_ERA_ARRAY").unwrap(),
+            },
+            _ => {
+                eprintln!("unknown option");
+                process::exit(1);
+            }
+        };
+
+        let opts = EraDamageOpts {
+            engine_opts: engine_opts.unwrap(),
+            op,
+            output: Path::new(matches.get_one::<String>("OUTPUT").unwrap()),
+            seed: *matches.get_one::<u64>("SEED").unwrap(),
+        };
+
+        to_exit_code(&report, damage_metadata(opts))
+    }
+}
+
+//------------------------------------------
@@ -14,6 +14,7 @@ pub mod thin_check;
 pub mod thin_delta;
 pub mod thin_dump;
 pub mod thin_ls;
+pub mod thin_merge;
 pub mod thin_metadata_pack;
 pub mod thin_metadata_size;
 pub mod thin_metadata_unpack;
@@ -29,6 +30,8 @@ pub mod cache_generate_damage;
 #[cfg(feature = "devtools")]
 pub mod cache_generate_metadata;
 #[cfg(feature = "devtools")]
+pub mod era_generate_damage;
+#[cfg(feature = "devtools")]
 pub mod era_generate_metadata;
 #[cfg(feature = "devtools")]
 pub mod thin_explore;
@@ -37,6 +40,8 @@ pub mod thin_generate_damage;
 #[cfg(feature = "devtools")]
 pub mod thin_generate_metadata;
 #[cfg(feature = "devtools")]
+pub mod thin_journal_check;
+#[cfg(feature = "devtools")]
 pub mod thin_stat;
 
 pub trait Command<'a> {
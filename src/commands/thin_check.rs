@@ -1,3 +1,4 @@
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::{value_parser, Arg, ArgAction};
 use std::path::Path;
 
@@ -5,7 +6,7 @@ use crate::commands::engine::*;
 use crate::commands::utils::*;
 use crate::commands::Command;
 use crate::report::{parse_log_level, verbose_args};
-use crate::thin::check::{check, ThinCheckOptions};
+use crate::thin::check::{check, CheckFormat, ThinCheckOptions};
 use crate::version::*;
 
 pub struct ThinCheckCommand;
@@ -78,6 +79,19 @@ impl ThinCheckCommand {
                     .action(ArgAction::SetTrue),
             )
             // options
+            .arg(
+                Arg::new("FORMAT")
+                    .help("Choose the report format, text or json")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .value_parser(
+                        PossibleValuesParser::new(["text", "json"])
+                            .map(|s| s.parse::<CheckFormat>().unwrap()),
+                    )
+                    .hide_possible_values(true)
+                    .default_value("text")
+                    .hide_default_value(true),
+            )
             .arg(
                 Arg::new("OVERRIDE_MAPPING_ROOT")
                     .help("Specify a mapping root to use")
@@ -114,7 +128,9 @@ impl<'a> Command<'a> for ThinCheckCommand {
 
         let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
 
-        let report = mk_report(matches.get_flag("QUIET"));
+        // The json report is the only output on stdout
+        let format = *matches.get_one::<CheckFormat>("FORMAT").unwrap();
+        let report = mk_report(matches.get_flag("QUIET") || format == CheckFormat::Json);
         let log_level = match parse_log_level(&matches) {
             Ok(level) => level,
             Err(e) => return to_exit_code::<()>(&report, Err(anyhow::Error::msg(e))),
@@ -144,6 +160,7 @@ impl<'a> Command<'a> for ThinCheckCommand {
             clear_needs_check: matches.get_flag("CLEAR_NEEDS_CHECK"),
             override_mapping_root: matches.get_one::<u64>("OVERRIDE_MAPPING_ROOT").cloned(),
             override_details_root: matches.get_one::<u64>("OVERRIDE_DETAILS_ROOT").cloned(),
+            format,
             report: report.clone(),
         };
 
@@ -1,14 +1,17 @@
 extern crate clap;
 
 use anyhow::anyhow;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::{value_parser, Arg, ArgAction, ArgGroup};
 use std::path::Path;
 
 use crate::commands::engine::*;
 use crate::commands::utils::*;
 use crate::commands::Command;
+use crate::io_engine::SECTOR_SHIFT;
 use crate::thin::delta::*;
 use crate::thin::delta_visitor::Snap;
+use crate::units::StorageSize;
 use crate::version::*;
 
 //------------------------------------------
@@ -36,6 +39,29 @@ impl ThinDeltaCommand {
                     .action(ArgAction::SetTrue),
             )
             // options
+            .arg(
+                Arg::new("FORMAT")
+                    .help("Choose the output format")
+                    .short('f')
+                    .long("format")
+                    .value_name("TYPE")
+                    .value_parser(
+                        PossibleValuesParser::new(["xml", "binary", "qemu_bitmap"])
+                            .map(|s| s.parse::<OutputFormat>().unwrap()),
+                    )
+                    .hide_possible_values(true)
+                    .default_value("xml")
+                    .hide_default_value(true),
+            )
+            .arg(
+                Arg::new("CHAIN")
+                    .help("Diff each consecutive pair in a comma separated list of thin volumes")
+                    .long("chain")
+                    .value_name("DEV_IDS")
+                    .value_parser(value_parser!(u64))
+                    .value_delimiter(',')
+                    .conflicts_with_all(["SNAP1", "SNAP2"]),
+            )
             .arg(
                 Arg::new("ROOT1")
                     .help("The root block for the first thin volume to diff")
@@ -66,6 +92,13 @@ impl ThinDeltaCommand {
                     .value_parser(value_parser!(u64))
                     .visible_alias("snap2"),
             )
+            .arg(
+                Arg::new("THIN_SIZE")
+                    .help("Specify the size of the thin volumes, for the qemu_bitmap format")
+                    .long("thin-size")
+                    .value_name("SIZE[bskmgtp]")
+                    .value_parser(value_parser!(StorageSize)),
+            )
             // arguments
             .arg(
                 Arg::new("INPUT")
@@ -103,34 +136,47 @@ impl<'a> Command<'a> for ThinDeltaCommand {
             return to_exit_code::<()>(&report, Err(e));
         }
 
-        let snap1 = match matches
-            .get_one::<clap::Id>("SNAP1")
-            .unwrap_or(&clap::Id::default())
-            .as_str()
-        {
-            "THIN1" => Snap::DeviceId(*matches.get_one::<u64>("THIN1").unwrap()),
-            "ROOT1" => Snap::RootBlock(*matches.get_one::<u64>("ROOT1").unwrap()),
-            _ => {
-                return to_exit_code::<()>(
-                    &report,
-                    Err(anyhow!("--thin1 or --root1 not specified")),
-                )
-            }
-        };
-
-        let snap2 = match matches
-            .get_one::<clap::Id>("SNAP2")
-            .unwrap_or(&clap::Id::default())
-            .as_str()
-        {
-            "THIN2" => Snap::DeviceId(*matches.get_one::<u64>("THIN2").unwrap()),
-            "ROOT2" => Snap::RootBlock(*matches.get_one::<u64>("ROOT2").unwrap()),
-            _ => {
+        let snaps = if let Some(chain) = matches.get_many::<u64>("CHAIN") {
+            let snaps: Vec<Snap> = chain.map(|id| Snap::DeviceId(*id)).collect();
+            if snaps.len() < 2 {
                 return to_exit_code::<()>(
                     &report,
-                    Err(anyhow!("--thin2 or --root2 not specified")),
-                )
+                    Err(anyhow!("--chain requires at least two thin devices")),
+                );
             }
+            snaps
+        } else {
+            let snap1 = match matches
+                .get_one::<clap::Id>("SNAP1")
+                .unwrap_or(&clap::Id::default())
+                .as_str()
+            {
+                "THIN1" => Snap::DeviceId(*matches.get_one::<u64>("THIN1").unwrap()),
+                "ROOT1" => Snap::RootBlock(*matches.get_one::<u64>("ROOT1").unwrap()),
+                _ => {
+                    return to_exit_code::<()>(
+                        &report,
+                        Err(anyhow!("--thin1 or --root1 not specified")),
+                    )
+                }
+            };
+
+            let snap2 = match matches
+                .get_one::<clap::Id>("SNAP2")
+                .unwrap_or(&clap::Id::default())
+                .as_str()
+            {
+                "THIN2" => Snap::DeviceId(*matches.get_one::<u64>("THIN2").unwrap()),
+                "ROOT2" => Snap::RootBlock(*matches.get_one::<u64>("ROOT2").unwrap()),
+                _ => {
+                    return to_exit_code::<()>(
+                        &report,
+                        Err(anyhow!("--thin2 or --root2 not specified")),
+                    )
+                }
+            };
+
+            vec![snap1, snap2]
         };
 
         let engine_opts = parse_engine_opts(ToolType::Thin, &matches);
@@ -142,9 +188,12 @@ impl<'a> Command<'a> for ThinDeltaCommand {
             input: input_file,
             engine_opts: engine_opts.unwrap(),
             report: report.clone(),
-            snap1,
-            snap2,
+            snaps,
             verbose: matches.get_flag("VERBOSE"),
+            format: matches.get_one::<OutputFormat>("FORMAT").unwrap().clone(),
+            thin_size: matches
+                .get_one::<StorageSize>("THIN_SIZE")
+                .map(|s| s.size_bytes().div_ceil(1 << SECTOR_SHIFT)),
         };
 
         to_exit_code(&report, delta(opts))
@@ -65,7 +65,7 @@ impl ThinDumpCommand {
                     .long("format")
                     .value_name("TYPE")
                     .value_parser(
-                        PossibleValuesParser::new(["xml", "human_readable"])
+                        PossibleValuesParser::new(["xml", "human_readable", "json", "json_lines"])
                             .map(|s| s.parse::<OutputFormat>().unwrap()),
                     )
                     .hide_possible_values(true)
@@ -1,3 +1,4 @@
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::{value_parser, Arg, ArgAction, ArgGroup};
 use std::path::Path;
 use std::process;
@@ -33,6 +34,54 @@ impl ThinGenerateDamageCommand {
                     .action(ArgAction::SetTrue)
                     .requires("overrides"),
             )
+            .arg(
+                Arg::new("CORRUPT_CHECKSUM")
+                    .help("Corrupt the checksum of a btree node")
+                    .long("corrupt-checksum")
+                    .action(ArgAction::SetTrue)
+                    .requires("BLOCK"),
+            )
+            .arg(
+                Arg::new("ZERO_NODE")
+                    .help("Zero an internal node of a device's mapping tree")
+                    .long("zero-node")
+                    .action(ArgAction::SetTrue)
+                    .requires("DEV_ID"),
+            )
+            .arg(
+                Arg::new("SCRAMBLE_NODE")
+                    .help("Scramble an internal node of a device's mapping tree")
+                    .long("scramble-node")
+                    .action(ArgAction::SetTrue)
+                    .requires("DEV_ID"),
+            )
+            .arg(
+                Arg::new("BREAK_DETAILS_TREE")
+                    .help("Zero the root of the device details tree")
+                    .long("break-details-tree")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("CREATE_CYCLE")
+                    .help("Point a node of a device's mapping tree back at its root")
+                    .long("create-cycle")
+                    .action(ArgAction::SetTrue)
+                    .requires("DEV_ID"),
+            )
+            .arg(
+                Arg::new("CROSS_LINK")
+                    .help("Make a device share the mapping tree of another")
+                    .long("cross-link")
+                    .action(ArgAction::SetTrue)
+                    .requires_all(["DEV_ID", "OTHER_DEV_ID"]),
+            )
+            .arg(
+                Arg::new("DROP_BITMAP")
+                    .help("Zero a bitmap of a space map")
+                    .long("drop-bitmap")
+                    .action(ArgAction::SetTrue)
+                    .requires("SPACE_MAP"),
+            )
             // options
             .arg(
                 Arg::new("EXPECTED")
@@ -76,6 +125,54 @@ impl ThinGenerateDamageCommand {
                     .value_name("BLOCKNR")
                     .value_parser(value_parser!(u64)),
             )
+            .arg(
+                Arg::new("BLOCK")
+                    .help("Specify the block to damage")
+                    .long("block")
+                    .value_name("BLOCKNR")
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                Arg::new("DEV_ID")
+                    .help("Specify the thin device to damage")
+                    .long("dev-id")
+                    .value_name("THIN_ID")
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                Arg::new("OTHER_DEV_ID")
+                    .help("Specify the thin device to cross link to")
+                    .long("other-dev-id")
+                    .value_name("THIN_ID")
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                Arg::new("SPACE_MAP")
+                    .help("Specify the space map to damage")
+                    .long("space-map")
+                    .value_name("TYPE")
+                    .value_parser(
+                        PossibleValuesParser::new(["data", "metadata"])
+                            .map(|s| s.parse::<SpaceMapType>().unwrap()),
+                    )
+                    .hide_possible_values(true),
+            )
+            .arg(
+                Arg::new("BITMAP_INDEX")
+                    .help("Specify the index of the bitmap to drop")
+                    .long("bitmap-index")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(usize))
+                    .default_value("0"),
+            )
+            .arg(
+                Arg::new("SEED")
+                    .help("Specify the seed of the random generator")
+                    .long("seed")
+                    .value_name("SEED")
+                    .value_parser(value_parser!(u64))
+                    .default_value("0"),
+            )
             .arg(
                 Arg::new("OUTPUT")
                     .help("Specify the output device")
@@ -86,7 +183,17 @@ impl ThinGenerateDamageCommand {
             )
             .group(
                 ArgGroup::new("commands")
-                    .args(["CREATE_METADATA_LEAKS", "OVERRIDE"])
+                    .args([
+                        "CREATE_METADATA_LEAKS",
+                        "OVERRIDE",
+                        "CORRUPT_CHECKSUM",
+                        "ZERO_NODE",
+                        "SCRAMBLE_NODE",
+                        "BREAK_DETAILS_TREE",
+                        "CREATE_CYCLE",
+                        "CROSS_LINK",
+                        "DROP_BITMAP",
+                    ])
                     .required(true),
             )
             .group(
@@ -125,6 +232,27 @@ impl<'a> Command<'a> for ThinGenerateDamageCommand {
                 details_root: matches.get_one::<u64>("DETAILS_ROOT").cloned(),
                 metadata_snapshot: matches.get_one::<u64>("METADATA_SNAPSHOT").cloned(),
             }),
+            "CORRUPT_CHECKSUM" => DamageOp::CorruptNodeChecksum {
+                block: *matches.get_one::<u64>("BLOCK").unwrap(),
+            },
+            "ZERO_NODE" => DamageOp::ZeroInternalNode {
+                dev_id: *matches.get_one::<u64>("DEV_ID").unwrap(),
+            },
+            "SCRAMBLE_NODE" => DamageOp::ScrambleInternalNode {
+                dev_id: *matches.get_one::<u64>("DEV_ID").unwrap(),
+            },
+            "BREAK_DETAILS_TREE" => DamageOp::BreakDetailsTree,
+            "CREATE_CYCLE" => DamageOp::CreateCycle {
+                dev_id: *matches.get_one::<u64>("DEV_ID").unwrap(),
+            },
+            "CROSS_LINK" => DamageOp::CrossLink {
+                dev_id: *matches.get_one::<u64>("DEV_ID").unwrap(),
+                other_dev_id: *matches.get_one::<u64>("OTHER_DEV_ID").unwrap(),
+            },
+            "DROP_BITMAP" => DamageOp::DropBitmap {
+                sm: *matches.get_one::<SpaceMapType>("SPACE_MAP").unwrap(),
+                index: *matches.get_one::<usize>("BITMAP_INDEX").unwrap(),
+            },
             _ => {
                 eprintln!("unknown option");
                 process::exit(1);
@@ -135,6 +263,7 @@ impl<'a> Command<'a> for ThinGenerateDamageCommand {
             engine_opts: engine_opts.unwrap(),
             op,
             output: Path::new(matches.get_one::<String>("OUTPUT").unwrap()),
+            seed: *matches.get_one::<u64>("SEED").unwrap(),
         };
 
         to_exit_code(&report, damage_metadata(opts))
@@ -45,6 +45,14 @@ impl ThinGenerateMetadataCommand {
                     .value_parser(value_parser!(u32))
                     .default_value("128"),
             )
+            .arg(
+                Arg::new("FRAGMENTATION")
+                    .help("Specify the chance of a run being placed at random on the data device")
+                    .long("fragmentation")
+                    .value_name("PERCENT")
+                    .value_parser(value_parser!(u8).range(0..=100))
+                    .default_value("0"),
+            )
             .arg(
                 Arg::new("NR_DATA_BLOCKS")
                     .help("Specify the number of data blocks")
@@ -53,6 +61,22 @@ impl ThinGenerateMetadataCommand {
                     .value_parser(value_parser!(u64))
                     .default_value("10240"),
             )
+            .arg(
+                Arg::new("NR_SNAPSHOTS")
+                    .help("Specify the length of the snapshot chain of each thin")
+                    .long("nr-snapshots")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u32))
+                    .default_value("0"),
+            )
+            .arg(
+                Arg::new("NR_THINS")
+                    .help("Specify the number of thin devices")
+                    .long("nr-thins")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u32))
+                    .default_value("0"),
+            )
             .arg(
                 Arg::new("OUTPUT")
                     .help("Specify the output device")
@@ -61,6 +85,44 @@ impl ThinGenerateMetadataCommand {
                     .value_name("FILE")
                     .required(true),
             )
+            .arg(
+                Arg::new("PROVISIONED")
+                    .help("Specify the percentage of provisioned blocks in each thin")
+                    .long("provisioned")
+                    .value_name("PERCENT")
+                    .value_parser(value_parser!(u8).range(0..=100))
+                    .default_value("50"),
+            )
+            .arg(
+                Arg::new("RUN_LENGTH")
+                    .help("Specify the average length of mapped runs")
+                    .long("run-length")
+                    .value_name("BLOCKS")
+                    .value_parser(value_parser!(u64).range(1..))
+                    .default_value("16"),
+            )
+            .arg(
+                Arg::new("SEED")
+                    .help("Specify the seed of the random generator")
+                    .long("seed")
+                    .value_name("NUM")
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                Arg::new("SHARED")
+                    .help("Specify the percentage of mappings shared between consecutive snapshots")
+                    .long("shared")
+                    .value_name("PERCENT")
+                    .value_parser(value_parser!(u8).range(0..=100))
+                    .default_value("90"),
+            )
+            .arg(
+                Arg::new("THIN_SIZE")
+                    .help("Specify the virtual size of each thin, defaults to the pool size")
+                    .long("thin-size")
+                    .value_name("BLOCKS")
+                    .value_parser(value_parser!(u64)),
+            )
             .group(
                 ArgGroup::new("commands")
                     .args(["FORMAT", "SET_NEEDS_CHECK"])
@@ -90,6 +152,14 @@ impl<'a> Command<'a> for ThinGenerateMetadataCommand {
             "FORMAT" => MetadataOp::Format(ThinFormatOpts {
                 data_block_size: *matches.get_one::<u32>("DATA_BLOCK_SIZE").unwrap(),
                 nr_data_blocks: *matches.get_one::<u64>("NR_DATA_BLOCKS").unwrap(),
+                nr_thins: *matches.get_one::<u32>("NR_THINS").unwrap(),
+                nr_snapshots: *matches.get_one::<u32>("NR_SNAPSHOTS").unwrap(),
+                thin_size: matches.get_one::<u64>("THIN_SIZE").cloned(),
+                percent_provisioned: *matches.get_one::<u8>("PROVISIONED").unwrap(),
+                percent_shared: *matches.get_one::<u8>("SHARED").unwrap(),
+                run_length: *matches.get_one::<u64>("RUN_LENGTH").unwrap(),
+                percent_fragmented: *matches.get_one::<u8>("FRAGMENTATION").unwrap(),
+                seed: matches.get_one::<u64>("SEED").cloned(),
             }),
             "SET_NEEDS_CHECK" => MetadataOp::SetNeedsCheck(
                 *matches.get_one::<bool>("SET_NEEDS_CHECK").unwrap_or(&true),
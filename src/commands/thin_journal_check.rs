@@ -0,0 +1,97 @@
+use clap::{Arg, ArgAction};
+use std::path::Path;
+
+use crate::commands::engine::*;
+use crate::commands::utils::*;
+use crate::journal::check::*;
+use crate::version::*;
+
+//------------------------------------------
+use crate::commands::Command;
+
+pub struct ThinJournalCheckCommand;
+
+impl ThinJournalCheckCommand {
+    fn cli(&self) -> clap::Command {
+        let cmd = clap::Command::new(self.name())
+            .next_display_order(None)
+            .version(crate::tools_version!())
+            .disable_version_flag(true)
+            .about("Replays a block manager journal, checking the metadata is crash-consistent after every transaction")
+            // flags
+            .arg(
+                Arg::new("CACHE")
+                    .help("Check the metadata as cache metadata")
+                    .long("cache")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("QUIET")
+                    .help("Suppress output messages, return only exit code.")
+                    .short('q')
+                    .long("quiet")
+                    .action(ArgAction::SetTrue),
+            )
+            // options
+            .arg(
+                Arg::new("JOURNAL")
+                    .help("Specify the journal to replay")
+                    .short('j')
+                    .long("journal")
+                    .value_name("FILE")
+                    .required(true),
+            )
+            // arguments
+            .arg(
+                Arg::new("INPUT")
+                    .help("Specify the metadata as it was when the journal was opened")
+                    .required(true)
+                    .index(1),
+            );
+
+        engine_args(version_args(cmd))
+    }
+}
+
+impl<'a> Command<'a> for ThinJournalCheckCommand {
+    fn name(&self) -> &'a str {
+        "thin_journal_check"
+    }
+
+    fn run(&self, args: &mut dyn Iterator<Item = std::ffi::OsString>) -> exitcode::ExitCode {
+        let matches = self.cli().get_matches_from(args);
+        display_version(&matches);
+
+        let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
+        let journal_file = Path::new(matches.get_one::<String>("JOURNAL").unwrap());
+        let report = mk_report(matches.get_flag("QUIET"));
+
+        if let Err(e) = check_input_file(input_file)
+            .and_then(check_file_not_tiny)
+            .and_then(|_| check_input_file(journal_file))
+        {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+
+        let tool = if matches.get_flag("CACHE") {
+            ToolType::Cache
+        } else {
+            ToolType::Thin
+        };
+        let engine_opts = parse_engine_opts(tool, &matches);
+        if engine_opts.is_err() {
+            return to_exit_code(&report, engine_opts.map(|_| ()));
+        }
+
+        let opts = JournalCheckOptions {
+            input: input_file,
+            journal: journal_file,
+            engine_opts: engine_opts.unwrap(),
+            report: report.clone(),
+        };
+
+        to_exit_code(&report, check(opts))
+    }
+}
+
+//------------------------------------------
@@ -1,5 +1,6 @@
 extern crate clap;
 
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::{value_parser, Arg, ArgAction};
 use std::path::Path;
 
@@ -33,6 +34,22 @@ impl ThinLsCommand {
                     .action(ArgAction::SetTrue),
             )
             // options
+            .arg(
+                Arg::new("DEV_IDS")
+                    .help("Only list the devices in a comma separated list of device ids")
+                    .long("dev-id")
+                    .value_delimiter(',')
+                    .value_name("DEV_IDS")
+                    .value_parser(value_parser!(u64)),
+            )
+            .arg(
+                Arg::new("FILTER")
+                    .help("Filter the devices by an expression, e.g., EXCLUSIVE_BYTES>10G")
+                    .long("filter")
+                    .value_name("EXPR")
+                    .value_parser(value_parser!(Filter))
+                    .action(ArgAction::Append),
+            )
             .arg(
                 Arg::new("FORMAT")
                     .help("Give a comma separated list of fields to be output")
@@ -42,6 +59,28 @@ impl ThinLsCommand {
                     .value_name("FIELDS")
                     .value_parser(value_parser!(OutputField)),
             )
+            .arg(
+                Arg::new("OUTPUT_MODE")
+                    .help("Choose the output mode")
+                    .long("output-mode")
+                    .value_name("MODE")
+                    .value_parser(
+                        PossibleValuesParser::new(["table", "json", "csv"])
+                            .map(|s| s.parse::<OutputMode>().unwrap()),
+                    )
+                    .hide_possible_values(true)
+                    .default_value("table")
+                    .hide_default_value(true),
+            )
+            .arg(
+                Arg::new("SORT")
+                    .help("Sort by a comma separated list of fields, descending if prefixed by '-'")
+                    .long("sort")
+                    .value_delimiter(',')
+                    .value_name("FIELDS")
+                    .value_parser(value_parser!(SortKey))
+                    .allow_hyphen_values(true),
+            )
             // arguments
             .arg(
                 Arg::new("INPUT")
@@ -92,6 +131,16 @@ impl<'a> Command<'a> for ThinLsCommand {
             engine_opts: engine_opts.unwrap(),
             fields,
             no_headers: matches.get_flag("NO_HEADERS"),
+            sort_keys: matches
+                .get_many::<SortKey>("SORT")
+                .map_or_else(Vec::new, |keys| keys.cloned().collect()),
+            filters: matches
+                .get_many::<Filter>("FILTER")
+                .map_or_else(Vec::new, |filters| filters.cloned().collect()),
+            dev_ids: matches
+                .get_many::<u64>("DEV_IDS")
+                .map(|ids| ids.cloned().collect()),
+            mode: *matches.get_one::<OutputMode>("OUTPUT_MODE").unwrap(),
             report: report.clone(),
         };
 
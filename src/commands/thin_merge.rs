@@ -0,0 +1,116 @@
+extern crate clap;
+
+use clap::{value_parser, Arg, ArgAction};
+use std::path::Path;
+
+use crate::commands::engine::*;
+use crate::commands::utils::*;
+use crate::commands::Command;
+use crate::report::{parse_log_level, verbose_args};
+use crate::thin::merge::{merge, ThinMergeOptions};
+use crate::version::*;
+
+pub struct ThinMergeCommand;
+
+impl ThinMergeCommand {
+    fn cli(&self) -> clap::Command {
+        let cmd = clap::Command::new(self.name())
+            .next_display_order(None)
+            .version(crate::tools_version!())
+            .disable_version_flag(true)
+            .about("Merge a thin snapshot into its origin, and write the metadata to different device or file")
+            .arg(
+                Arg::new("OVERLAY")
+                    .help("Keep the origin's mappings where the snapshot has none")
+                    .long("overlay")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("QUIET")
+                    .help("Suppress output messages, return only exit code.")
+                    .short('q')
+                    .long("quiet")
+                    .action(ArgAction::SetTrue),
+            )
+            // options
+            .arg(
+                Arg::new("INPUT")
+                    .help("Specify the input device")
+                    .short('i')
+                    .long("input")
+                    .value_name("FILE")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("ORIGIN")
+                    .help("Specify the device id of the origin")
+                    .long("origin")
+                    .value_name("DEV_ID")
+                    .value_parser(value_parser!(u64))
+                    .required(true),
+            )
+            .arg(
+                Arg::new("OUTPUT")
+                    .help("Specify the output device")
+                    .short('o')
+                    .long("output")
+                    .value_name("FILE")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("SNAPSHOT")
+                    .help("Specify the device id of the snapshot to merge")
+                    .long("snapshot")
+                    .value_name("DEV_ID")
+                    .value_parser(value_parser!(u64))
+                    .required(true),
+            );
+
+        verbose_args(engine_args(version_args(cmd)))
+    }
+}
+
+impl<'a> Command<'a> for ThinMergeCommand {
+    fn name(&self) -> &'a str {
+        "thin_merge"
+    }
+
+    fn run(&self, args: &mut dyn Iterator<Item = std::ffi::OsString>) -> exitcode::ExitCode {
+        let matches = self.cli().get_matches_from(args);
+        display_version(&matches);
+
+        let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
+        let output_file = Path::new(matches.get_one::<String>("OUTPUT").unwrap());
+
+        let report = mk_report(matches.get_flag("QUIET"));
+        let log_level = match parse_log_level(&matches) {
+            Ok(level) => level,
+            Err(e) => return to_exit_code::<()>(&report, Err(anyhow::Error::msg(e))),
+        };
+        report.set_level(log_level);
+
+        if let Err(e) = check_input_file(input_file)
+            .and_then(check_file_not_tiny)
+            .and_then(|_| check_output_file(output_file))
+        {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+
+        let engine_opts = parse_engine_opts(ToolType::Thin, &matches);
+        if engine_opts.is_err() {
+            return to_exit_code(&report, engine_opts);
+        }
+
+        let opts = ThinMergeOptions {
+            input: input_file,
+            output: output_file,
+            engine_opts: engine_opts.unwrap(),
+            report: report.clone(),
+            origin: *matches.get_one::<u64>("ORIGIN").unwrap(),
+            snapshot: *matches.get_one::<u64>("SNAPSHOT").unwrap(),
+            overlay: matches.get_flag("OVERLAY"),
+        };
+
+        to_exit_code(&report, merge(opts))
+    }
+}
@@ -1,10 +1,12 @@
 extern crate clap;
 
-use clap::{Arg, ArgAction};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{value_parser, Arg, ArgAction};
 use std::path::Path;
 
 use crate::commands::utils::*;
 use crate::commands::Command;
+use crate::pack::toplevel::{Compression, PackOptions};
 use crate::report::*;
 use crate::version::*;
 
@@ -18,12 +20,33 @@ impl ThinMetadataPackCommand {
             .disable_version_flag(true)
             .about("Produces a compressed file of thin metadata.  Only packs metadata blocks that are actually used.")
             // flags
+            .arg(Arg::new("ANONYMISE")
+                .help("Scrub the uuid from the superblocks")
+                .long("anonymise")
+                .action(ArgAction::SetTrue))
             .arg(Arg::new("FORCE")
                 .help("Force overwrite the output file")
                 .short('f')
                 .long("force")
                 .action(ArgAction::SetTrue))
             // options
+            .arg(Arg::new("COMPRESSION")
+                .help("Choose the compression of the packed blocks")
+                .short('z')
+                .long("compression")
+                .value_name("TYPE")
+                .value_parser(
+                    PossibleValuesParser::new(["zlib", "zstd"])
+                        .map(|s| s.parse::<Compression>().unwrap()),
+                )
+                .hide_possible_values(true)
+                .default_value("zlib"))
+            .arg(Arg::new("DEV_ID")
+                .help("Pack only the blocks used by the specified thin device")
+                .long("dev-id")
+                .action(ArgAction::Append)
+                .value_name("THIN_ID")
+                .value_parser(value_parser!(u64)))
             .arg(Arg::new("INPUT")
                 .help("Specify thinp metadata binary device/file")
                 .required(true)
@@ -52,6 +75,13 @@ impl<'a> Command<'a> for ThinMetadataPackCommand {
 
         let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
         let output_file = Path::new(matches.get_one::<String>("OUTPUT").unwrap());
+        let opts = PackOptions {
+            compression: *matches.get_one::<Compression>("COMPRESSION").unwrap(),
+            anonymise: matches.get_flag("ANONYMISE"),
+            selected_devs: matches
+                .get_many::<u64>("DEV_ID")
+                .map(|devs| devs.copied().collect()),
+        };
 
         let report = mk_simple_report();
 
@@ -71,7 +101,7 @@ impl<'a> Command<'a> for ThinMetadataPackCommand {
         let report = std::sync::Arc::new(report);
         to_exit_code(
             &report,
-            crate::pack::toplevel::pack(input_file, output_file),
+            crate::pack::toplevel::pack(input_file, output_file, &opts),
         )
     }
 }
@@ -8,7 +8,7 @@ use crate::commands::utils::*;
 use crate::commands::Command;
 use crate::report::{parse_log_level, verbose_args};
 use crate::thin::metadata_repair::SuperblockOverrides;
-use crate::thin::repair::{repair, ThinRepairOptions};
+use crate::thin::repair::{dry_run, repair, ThinRepairDryRunOptions, ThinRepairOptions};
 use crate::version::*;
 
 pub struct ThinRepairCommand;
@@ -20,6 +20,7 @@ impl ThinRepairCommand {
             .version(crate::tools_version!())
             .disable_version_flag(true)
             .about("Repair thin-provisioning metadata, and write it to different device or file")
+            .override_usage("thin_repair [OPTIONS] --input <FILE> <--output <FILE> | --dry-run>")
             .arg(
                 Arg::new("QUIET")
                     .help("Suppress output messages, return only exit code.")
@@ -27,6 +28,18 @@ impl ThinRepairCommand {
                     .long("quiet")
                     .action(ArgAction::SetTrue),
             )
+            .arg(
+                Arg::new("ROLLBACK")
+                    .help("Roll back to the roots of an earlier transaction")
+                    .long("rollback")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("DRY_RUN")
+                    .help("Report what would be recovered without writing the output")
+                    .long("dry-run")
+                    .action(ArgAction::SetTrue),
+            )
             // options
             .arg(
                 Arg::new("DATA_BLOCK_SIZE")
@@ -43,6 +56,13 @@ impl ThinRepairCommand {
                     .value_name("FILE")
                     .required(true),
             )
+            .arg(
+                Arg::new("LOST_FOUND")
+                    .help("Recover orphaned mappings into a new device, listed in a file")
+                    .long("lost-found")
+                    .value_name("FILE")
+                    .conflicts_with("DRY_RUN"),
+            )
             .arg(
                 Arg::new("NR_DATA_BLOCKS")
                     .help("Override the number of data blocks if needed")
@@ -56,11 +76,11 @@ impl ThinRepairCommand {
                     .short('o')
                     .long("output")
                     .value_name("FILE")
-                    .required(true),
+                    .required_unless_present("DRY_RUN"),
             )
             .arg(
                 Arg::new("TRANSACTION_ID")
-                    .help("Override the transaction id if needed")
+                    .help("Override the transaction id if needed, or pick one to roll back to")
                     .long("transaction-id")
                     .value_name("NUM")
                     .value_parser(value_parser!(u64)),
@@ -82,7 +102,6 @@ impl<'a> Command<'a> for ThinRepairCommand {
         display_version(&matches);
 
         let input_file = Path::new(matches.get_one::<String>("INPUT").unwrap());
-        let output_file = Path::new(matches.get_one::<String>("OUTPUT").unwrap());
 
         let report = mk_report(matches.get_flag("QUIET"));
         let log_level = match parse_log_level(&matches) {
@@ -91,10 +110,7 @@ impl<'a> Command<'a> for ThinRepairCommand {
         };
         report.set_level(log_level);
 
-        if let Err(e) = check_input_file(input_file)
-            .and_then(check_file_not_tiny)
-            .and_then(|_| check_output_file(output_file))
-        {
+        if let Err(e) = check_input_file(input_file).and_then(check_file_not_tiny) {
             return to_exit_code::<()>(&report, Err(e));
         }
 
@@ -103,16 +119,36 @@ impl<'a> Command<'a> for ThinRepairCommand {
             return to_exit_code(&report, engine_opts);
         }
 
+        let overrides = SuperblockOverrides {
+            transaction_id: matches.get_one::<u64>("TRANSACTION_ID").cloned(),
+            data_block_size: matches.get_one::<u32>("DATA_BLOCK_SIZE").cloned(),
+            nr_data_blocks: matches.get_one::<u64>("NR_DATA_BLOCKS").cloned(),
+        };
+
+        if matches.get_flag("DRY_RUN") {
+            let opts = ThinRepairDryRunOptions {
+                input: input_file,
+                engine_opts: engine_opts.unwrap(),
+                report: report.clone(),
+                overrides,
+                rollback: matches.get_flag("ROLLBACK"),
+            };
+            return to_exit_code(&report, dry_run(opts));
+        }
+
+        let output_file = Path::new(matches.get_one::<String>("OUTPUT").unwrap());
+        if let Err(e) = check_output_file(output_file) {
+            return to_exit_code::<()>(&report, Err(e));
+        }
+
         let opts = ThinRepairOptions {
             input: input_file,
             output: output_file,
             engine_opts: engine_opts.unwrap(),
             report: report.clone(),
-            overrides: SuperblockOverrides {
-                transaction_id: matches.get_one::<u64>("TRANSACTION_ID").cloned(),
-                data_block_size: matches.get_one::<u32>("DATA_BLOCK_SIZE").cloned(),
-                nr_data_blocks: matches.get_one::<u64>("NR_DATA_BLOCKS").cloned(),
-            },
+            overrides,
+            rollback: matches.get_flag("ROLLBACK"),
+            lost_found: matches.get_one::<String>("LOST_FOUND").map(Path::new),
         };
 
         to_exit_code(&report, repair(opts))
@@ -1,5 +1,6 @@
 extern crate clap;
 
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::{value_parser, Arg, ArgAction};
 use std::path::Path;
 
@@ -8,7 +9,7 @@ use crate::commands::utils::*;
 use crate::commands::Command;
 use crate::report::{parse_log_level, verbose_args};
 use crate::thin::metadata_repair::SuperblockOverrides;
-use crate::thin::restore::{restore, ThinRestoreOptions};
+use crate::thin::restore::{restore, InputFormat, ThinRestoreOptions};
 use crate::version::*;
 
 pub struct ThinRestoreCommand;
@@ -35,6 +36,20 @@ impl ThinRestoreCommand {
                     .value_name("SECTORS")
                     .value_parser(value_parser!(u32)),
             )
+            .arg(
+                Arg::new("FORMAT")
+                    .help("Choose the input format")
+                    .short('f')
+                    .long("format")
+                    .value_name("TYPE")
+                    .value_parser(
+                        PossibleValuesParser::new(["xml", "json", "json_lines"])
+                            .map(|s| s.parse::<InputFormat>().unwrap()),
+                    )
+                    .hide_possible_values(true)
+                    .default_value("xml")
+                    .hide_default_value(true),
+            )
             .arg(
                 Arg::new("INPUT")
                     .help("Specify the input xml")
@@ -107,6 +122,7 @@ impl<'a> Command<'a> for ThinRestoreCommand {
                 data_block_size: matches.get_one::<u32>("DATA_BLOCK_SIZE").cloned(),
                 nr_data_blocks: matches.get_one::<u64>("NR_DATA_BLOCKS").cloned(),
             },
+            format: matches.get_one::<InputFormat>("FORMAT").unwrap().clone(),
         };
 
         to_exit_code(&report, restore(opts))
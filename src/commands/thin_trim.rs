@@ -6,7 +6,7 @@ use std::path::Path;
 use crate::commands::engine::*;
 use crate::commands::utils::*;
 use crate::report::{parse_log_level, verbose_args};
-use crate::thin::check::{check, ThinCheckOptions};
+use crate::thin::check::{check, CheckFormat, ThinCheckOptions};
 use crate::thin::trim::{trim, ThinTrimOptions};
 use crate::version::*;
 
@@ -89,6 +89,7 @@ impl<'a> Command<'a> for ThinTrimCommand {
             clear_needs_check: false,
             override_mapping_root: None,
             override_details_root: None,
+            format: CheckFormat::Text,
             report: report.clone(),
         };
 
@@ -1,16 +1,19 @@
 use anyhow::{anyhow, Result};
 use rand::prelude::SliceRandom;
+use rand::rngs::StdRng;
+use rand::Rng;
 use std::io::Cursor;
-
+use std::str::FromStr;
 use std::sync::Arc;
 
 use crate::checksum;
 
-use crate::io_engine::IoEngine;
+use crate::io_engine::{Block, IoEngine};
+use crate::pdata::array::ArrayBlockHeader;
 use crate::pdata::btree_walker::btree_to_map;
 use crate::pdata::space_map::common::*;
 use crate::pdata::space_map::metadata::*;
-use crate::pdata::unpack::{unpack, Pack};
+use crate::pdata::unpack::{unpack, Pack, Unpack};
 
 //------------------------------------------
 
@@ -118,6 +121,7 @@ pub fn create_metadata_leaks(
     nr_leaks: usize,
     expected_rc: u32,
     actual_rc: u32,
+    rng: &mut StdRng,
 ) -> Result<()> {
     let mut blocks = find_blocks_of_rc(engine.clone(), sm_root.clone(), expected_rc)?;
     if blocks.len() < nr_leaks {
@@ -127,7 +131,7 @@ pub fn create_metadata_leaks(
         ));
     }
 
-    blocks.shuffle(&mut rand::thread_rng());
+    blocks.shuffle(rng);
     blocks.truncate(nr_leaks);
     blocks.sort_unstable();
 
@@ -153,3 +157,98 @@ pub fn create_metadata_leaks(
 }
 
 //------------------------------------------
+
+pub fn zero_block(engine: &dyn IoEngine, loc: u64) -> Result<()> {
+    engine.write(&Block::zeroed(loc))?;
+    Ok(())
+}
+
+// Inverts the checksum, leaving the rest of the block intact.  All the
+// metadata blocks begin with their checksum.
+pub fn corrupt_checksum(engine: &dyn IoEngine, loc: u64) -> Result<()> {
+    let b = engine.read(loc)?;
+    for byte in &mut b.get_data()[0..4] {
+        *byte ^= 0xff;
+    }
+    engine.write(&b)?;
+    Ok(())
+}
+
+// Fills the block with random bytes from the given offset, then rewrites
+// the checksum, so the damage can only be spotted by looking inside.
+pub fn scramble_block(
+    engine: &dyn IoEngine,
+    loc: u64,
+    offset: usize,
+    kind: checksum::BT,
+    rng: &mut StdRng,
+) -> Result<()> {
+    let b = engine.read(loc)?;
+    rng.fill(&mut b.get_data()[offset..]);
+    checksum::write_checksum(b.get_data(), kind)?;
+    engine.write(&b)?;
+    Ok(())
+}
+
+//------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockDamage {
+    Checksum,
+    Zero,
+    Scramble,
+}
+
+impl FromStr for BlockDamage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "checksum" => Ok(BlockDamage::Checksum),
+            "zero" => Ok(BlockDamage::Zero),
+            "scramble" => Ok(BlockDamage::Scramble),
+            _ => Err(anyhow!("unknown block damage '{}'", s)),
+        }
+    }
+}
+
+// Returns the array blocks of an array, in index order
+fn array_blocks(engine: Arc<dyn IoEngine + Send + Sync>, root: u64) -> Result<Vec<u64>> {
+    let mut path = Vec::new();
+    let ablocks = btree_to_map::<u64>(&mut path, engine, false, root)?;
+    Ok(ablocks.into_values().collect())
+}
+
+// Damages the index'th block of an array.  Bitsets are stored as arrays,
+// so this works for them too, though scrambling the bits of a bitset
+// leaves it valid.
+pub fn damage_array_block(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    root: u64,
+    index: usize,
+    damage: BlockDamage,
+    rng: &mut StdRng,
+) -> Result<()> {
+    let ablocks = array_blocks(engine.clone(), root)?;
+    let loc = *ablocks.get(index).ok_or_else(|| {
+        anyhow!(
+            "array block {} out of range, the array has {} blocks",
+            index,
+            ablocks.len()
+        )
+    })?;
+
+    match damage {
+        BlockDamage::Checksum => corrupt_checksum(engine.as_ref(), loc),
+        BlockDamage::Zero => zero_block(engine.as_ref(), loc),
+        BlockDamage::Scramble => scramble_block(
+            engine.as_ref(),
+            loc,
+            ArrayBlockHeader::disk_size() as usize,
+            checksum::BT::ARRAY,
+            rng,
+        ),
+    }
+}
+
+//------------------------------------------
@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Result};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::commands::engine::*;
+use crate::devtools::damage_generator::*;
+use crate::era::superblock::*;
+use crate::era::writeset::Writeset;
+use crate::io_engine::IoEngine;
+use crate::pdata::btree_walker::btree_to_map;
+
+//------------------------------------------
+
+pub struct SuperblockOverrides {
+    pub version: Option<u32>,
+    pub current_era: Option<u32>,
+    pub current_writeset_root: Option<u64>,
+    pub writeset_tree_root: Option<u64>,
+    pub era_array_root: Option<u64>,
+}
+
+pub fn override_superblock(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    opts: &SuperblockOverrides,
+) -> Result<()> {
+    let mut sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    if let Some(v) = opts.version {
+        sb.version = v;
+    }
+    if let Some(v) = opts.current_era {
+        sb.current_era = v;
+    }
+    if let Some(v) = opts.current_writeset_root {
+        sb.current_writeset.root = v;
+    }
+    if let Some(v) = opts.writeset_tree_root {
+        sb.writeset_tree_root = v;
+    }
+    if let Some(v) = opts.era_array_root {
+        sb.era_array_root = v;
+    }
+    write_superblock(engine.as_ref(), SUPERBLOCK_LOCATION, &sb)
+}
+
+//------------------------------------------
+
+// Returns the root of the writeset bitset of the given era, or of the
+// oldest archived writeset if no era is given.
+fn writeset_root(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &Superblock,
+    era: Option<u32>,
+) -> Result<u64> {
+    let mut path = Vec::new();
+    let writesets = btree_to_map::<Writeset>(&mut path, engine, false, sb.writeset_tree_root)?;
+
+    match era {
+        Some(era) => match writesets.get(&(era as u64)) {
+            Some(ws) => Ok(ws.root),
+            None if era == sb.current_era && sb.current_writeset.root != 0 => {
+                Ok(sb.current_writeset.root)
+            }
+            None => Err(anyhow!("no writeset for era {}", era)),
+        },
+        None => writesets
+            .values()
+            .next()
+            .map(|ws| ws.root)
+            .ok_or_else(|| anyhow!("no archived writesets")),
+    }
+}
+
+//------------------------------------------
+
+pub enum DamageOp {
+    OverrideSuperblock(SuperblockOverrides),
+    DamageWriteset {
+        era: Option<u32>,
+        index: usize,
+        damage: BlockDamage,
+    },
+    DamageEraArray {
+        index: usize,
+        damage: BlockDamage,
+    },
+}
+
+pub struct EraDamageOpts<'a> {
+    pub engine_opts: EngineOptions,
+    pub op: DamageOp,
+    pub output: &'a Path,
+    pub seed: u64,
+}
+
+pub fn damage_metadata(opts: EraDamageOpts) -> Result<()> {
+    let engine = EngineBuilder::new(opts.output, &opts.engine_opts)
+        .write(true)
+        .build()?;
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let mut rng = StdRng::seed_from_u64(opts.seed);
+
+    match opts.op {
+        DamageOp::OverrideSuperblock(opts) => override_superblock(engine, &opts),
+        DamageOp::DamageWriteset { era, index, damage } => {
+            let root = writeset_root(engine.clone(), &sb, era)?;
+            damage_array_block(engine, root, index, damage, &mut rng)
+        }
+        DamageOp::DamageEraArray { index, damage } => {
+            damage_array_block(engine, sb.era_array_root, index, damage, &mut rng)
+        }
+    }
+}
+
+//------------------------------------------
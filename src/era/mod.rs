@@ -8,5 +8,7 @@ pub mod superblock;
 pub mod writeset;
 pub mod xml;
 
+#[cfg(feature = "devtools")]
+pub mod damage_generator;
 #[cfg(feature = "devtools")]
 pub mod metadata_generator;
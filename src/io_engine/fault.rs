@@ -0,0 +1,439 @@
+use anyhow::{anyhow, Result};
+use rand::prelude::*;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::io_engine::*;
+
+//------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Op {
+    Read,
+    Write,
+}
+
+impl Op {
+    // Keeps the random choices for the reads and the writes of a block apart
+    fn salt(&self) -> u64 {
+        match self {
+            Op::Read => 0,
+            Op::Write => 0x5555_5555_5555_5555,
+        }
+    }
+}
+
+impl FromStr for Op {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read" => Ok(Op::Read),
+            "write" => Ok(Op::Write),
+            _ => Err(anyhow!("unknown op '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::Read => write!(f, "read"),
+            Op::Write => write!(f, "write"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    // The io fails with EIO
+    Eio,
+
+    // Only the first half of the block reaches the disk, then the write
+    // fails with EIO
+    TornWrite,
+
+    // A single bit of the data read is flipped
+    BitFlip,
+
+    // The checksum of the data read is corrupted, leaving the rest of the
+    // block intact.  All the metadata blocks begin with their checksum.
+    BadChecksum,
+}
+
+impl Fault {
+    fn applies_to(&self, op: Op) -> bool {
+        match self {
+            Fault::Eio => true,
+            Fault::TornWrite => op == Op::Write,
+            Fault::BitFlip | Fault::BadChecksum => op == Op::Read,
+        }
+    }
+}
+
+impl FromStr for Fault {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "eio" => Ok(Fault::Eio),
+            "torn" => Ok(Fault::TornWrite),
+            "bitflip" => Ok(Fault::BitFlip),
+            "checksum" => Ok(Fault::BadChecksum),
+            _ => Err(anyhow!("unknown fault '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::Eio => write!(f, "eio"),
+            Fault::TornWrite => write!(f, "torn"),
+            Fault::BitFlip => write!(f, "bitflip"),
+            Fault::BadChecksum => write!(f, "checksum"),
+        }
+    }
+}
+
+//------------------------------------------
+
+#[derive(Clone, Debug)]
+pub struct FaultRule {
+    pub op: Op,
+    pub blocks: Range<u64>,
+    pub fault: Fault,
+}
+
+fn parse_blocks(s: &str) -> Result<Range<u64>> {
+    if s == "*" {
+        return Ok(0..u64::MAX);
+    }
+
+    let r = if let Some((begin, end)) = s.split_once("..") {
+        begin.parse::<u64>()?..end.parse::<u64>()?
+    } else {
+        let b = s.parse::<u64>()?;
+        b..b + 1
+    };
+
+    if r.is_empty() {
+        return Err(anyhow!("empty block range '{}'", s));
+    }
+    Ok(r)
+}
+
+// Each line of the rule file holds an op, the blocks and the fault, eg.
+//
+//     read   0       eio
+//     read   10..20  bitflip
+//     write  *       torn
+//
+// The first rule matching an io is used.
+fn parse_rule(line: &str) -> Result<FaultRule> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 3 {
+        return Err(anyhow!("expected '<op> <blocks> <fault>'"));
+    }
+
+    let op = fields[0].parse::<Op>()?;
+    let blocks = parse_blocks(fields[1])?;
+    let fault = fields[2].parse::<Fault>()?;
+    if !fault.applies_to(op) {
+        return Err(anyhow!("can't inject {} into a {}", fault, op));
+    }
+
+    Ok(FaultRule { op, blocks, fault })
+}
+
+#[derive(Clone, Debug)]
+pub enum FaultSchedule {
+    // Faults blocks at random with the given probability.  The choice
+    // depends on the seed and the block alone, so it doesn't vary with
+    // the order the threads of a tool issue their io.
+    Seeded { seed: u64, rate: f64 },
+
+    Rules(Vec<FaultRule>),
+}
+
+impl FaultSchedule {
+    pub fn seeded(seed: u64, rate: f64) -> Result<Self> {
+        if !(rate > 0.0 && rate <= 1.0) {
+            return Err(anyhow!("fault rate must be within (0, 1]"));
+        }
+        Ok(FaultSchedule::Seeded { seed, rate })
+    }
+
+    pub fn from_rules(text: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            rules.push(parse_rule(line).map_err(|e| anyhow!("fault rule {}: {}", n + 1, e))?);
+        }
+        Ok(FaultSchedule::Rules(rules))
+    }
+
+    pub fn from_rules_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let text = std::fs::read_to_string(path.as_ref()).map_err(|e| {
+            anyhow!(
+                "unable to read fault rules '{}': {}",
+                path.as_ref().display(),
+                e
+            )
+        })?;
+        Self::from_rules(&text)
+    }
+
+    // The rules are fixed, so any randomness they need is taken from a
+    // seed of zero.
+    fn seed(&self) -> u64 {
+        match self {
+            FaultSchedule::Seeded { seed, .. } => *seed,
+            FaultSchedule::Rules(_) => 0,
+        }
+    }
+
+    fn rng_for(seed: u64, salt: u64, loc: u64) -> StdRng {
+        StdRng::seed_from_u64(seed ^ loc.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ salt)
+    }
+
+    fn fault_for(&self, op: Op, loc: u64) -> Option<Fault> {
+        match self {
+            FaultSchedule::Seeded { seed, rate } => {
+                let mut rng = Self::rng_for(*seed, op.salt(), loc);
+                if rng.gen::<f64>() >= *rate {
+                    return None;
+                }
+                let faults = match op {
+                    Op::Read => &[Fault::Eio, Fault::BitFlip, Fault::BadChecksum][..],
+                    Op::Write => &[Fault::Eio, Fault::TornWrite][..],
+                };
+                faults.choose(&mut rng).copied()
+            }
+            FaultSchedule::Rules(rules) => rules
+                .iter()
+                .find(|r| r.op == op && r.blocks.contains(&loc))
+                .map(|r| r.fault),
+        }
+    }
+}
+
+//------------------------------------------
+
+fn eio() -> io::Error {
+    io::Error::from_raw_os_error(libc::EIO)
+}
+
+// Keeps the bit flipped apart from the choice of the fault
+const BIT_FLIP_SALT: u64 = 0xaaaa_aaaa_aaaa_aaaa;
+
+/// Wraps an engine, injecting faults into the io as the schedule
+/// dictates.  Each fault is recorded in the fault log, if there is one,
+/// the first time it's injected, so tests can tell whether a tool ran
+/// into one.
+pub struct FaultIoEngine {
+    inner: Arc<dyn IoEngine + Send + Sync>,
+    schedule: FaultSchedule,
+    injected: Mutex<BTreeSet<(Op, u64)>>,
+    log: Option<File>,
+}
+
+impl FaultIoEngine {
+    pub fn new(inner: Arc<dyn IoEngine + Send + Sync>, schedule: FaultSchedule) -> Self {
+        FaultIoEngine {
+            inner,
+            schedule,
+            injected: Mutex::new(BTreeSet::new()),
+            log: None,
+        }
+    }
+
+    /// Appends the faults injected to the given file, one per line in the
+    /// format of the rule file.  Several engines may share the file, as each
+    /// fault is written in one go.
+    pub fn log_to<P: AsRef<Path>>(self, path: P) -> Result<Self> {
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.as_ref())
+            .map_err(|e| {
+                anyhow!(
+                    "unable to open fault log '{}': {}",
+                    path.as_ref().display(),
+                    e
+                )
+            })?;
+        Ok(FaultIoEngine {
+            log: Some(log),
+            ..self
+        })
+    }
+
+    fn get_fault(&self, op: Op, loc: u64) -> Option<Fault> {
+        let fault = self.schedule.fault_for(op, loc)?;
+        if self.injected.lock().unwrap().insert((op, loc)) {
+            if let Some(mut log) = self.log.as_ref() {
+                let line = format!("{} {} {}\n", op, loc, fault);
+                // The fault itself is what the tool has to cope with
+                let _ = log.write_all(line.as_bytes());
+            }
+        }
+        Some(fault)
+    }
+
+    fn corrupt(&self, b: &Block, fault: Fault) {
+        let data = b.get_data();
+        match fault {
+            Fault::BitFlip => {
+                let mut rng = FaultSchedule::rng_for(self.schedule.seed(), BIT_FLIP_SALT, b.loc);
+                let bit = rng.gen_range(0..BLOCK_SIZE * 8);
+                data[bit / 8] ^= 1 << (bit % 8);
+            }
+            Fault::BadChecksum => {
+                for byte in &mut data[0..4] {
+                    *byte ^= 0xff;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_(&self, loc: u64, r: io::Result<Block>) -> io::Result<Block> {
+        match self.get_fault(Op::Read, loc) {
+            Some(Fault::Eio) => Err(eio()),
+            Some(fault) => {
+                let b = r?;
+                self.corrupt(&b, fault);
+                Ok(b)
+            }
+            None => r,
+        }
+    }
+
+    fn tear(&self, b: &Block) -> io::Result<()> {
+        let torn = self
+            .inner
+            .read(b.loc)
+            .unwrap_or_else(|_| Block::zeroed(b.loc));
+        torn.get_data()[..BLOCK_SIZE / 2].copy_from_slice(&b.get_data()[..BLOCK_SIZE / 2]);
+        self.inner.write(&torn)
+    }
+}
+
+impl IoEngine for FaultIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.inner.get_nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        self.inner.get_batch_size()
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        self.inner.suggest_nr_threads()
+    }
+
+    fn read(&self, loc: u64) -> io::Result<Block> {
+        self.read_(loc, self.inner.read(loc))
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> io::Result<Vec<io::Result<Block>>> {
+        let rs = self.inner.read_many(blocks)?;
+        Ok(blocks
+            .iter()
+            .zip(rs)
+            .map(|(loc, r)| self.read_(*loc, r))
+            .collect())
+    }
+
+    fn write(&self, b: &Block) -> io::Result<()> {
+        match self.get_fault(Op::Write, b.loc) {
+            Some(Fault::TornWrite) => {
+                self.tear(b)?;
+                Err(eio())
+            }
+            Some(_) => Err(eio()),
+            None => self.inner.write(b),
+        }
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> io::Result<Vec<io::Result<()>>> {
+        Ok(blocks.iter().map(|b| self.write(b)).collect())
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rules() {
+        let text =
+            "# op  blocks  fault\nread 0 eio\n\nwrite 5..9 torn # tear them\nread * checksum\n";
+        let schedule = FaultSchedule::from_rules(text).unwrap();
+        assert_eq!(schedule.fault_for(Op::Read, 0), Some(Fault::Eio));
+        assert_eq!(schedule.fault_for(Op::Write, 0), None);
+        assert_eq!(schedule.fault_for(Op::Write, 8), Some(Fault::TornWrite));
+        assert_eq!(schedule.fault_for(Op::Write, 9), None);
+        assert_eq!(schedule.fault_for(Op::Read, 100), Some(Fault::BadChecksum));
+    }
+
+    #[test]
+    fn reject_bad_rules() {
+        for text in [
+            "read 0",
+            "read 0 torn",
+            "write 1 bitflip",
+            "read 5..5 eio",
+            "seek 0 eio",
+        ] {
+            assert!(FaultSchedule::from_rules(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn seeded_schedule_is_repeatable() {
+        let schedule = FaultSchedule::seeded(42, 0.1).unwrap();
+        let faults: Vec<Option<Fault>> =
+            (0..1000).map(|b| schedule.fault_for(Op::Read, b)).collect();
+        let again: Vec<Option<Fault>> =
+            (0..1000).map(|b| schedule.fault_for(Op::Read, b)).collect();
+        assert_eq!(faults, again);
+
+        let nr_faults = faults.iter().filter(|f| f.is_some()).count();
+        assert!(nr_faults > 50 && nr_faults < 150);
+        assert!(faults.iter().flatten().all(|f| f.applies_to(Op::Read)));
+    }
+
+    #[test]
+    fn bit_flips_follow_the_seed() {
+        use crate::io_engine::core::CoreIoEngine;
+
+        let flipped_bit = |seed| {
+            let schedule = FaultSchedule::seeded(seed, 1.0).unwrap();
+            let engine = FaultIoEngine::new(Arc::new(CoreIoEngine::new(1)), schedule);
+            let b = Block::zeroed(0);
+            engine.corrupt(&b, Fault::BitFlip);
+            let data = b.get_data();
+            let i = data.iter().position(|byte| *byte != 0).unwrap();
+            i * 8 + data[i].trailing_zeros() as usize
+        };
+
+        let bits: BTreeSet<usize> = (0..8).map(flipped_bit).collect();
+        assert!(bits.len() > 1);
+        assert_eq!(flipped_bit(3), flipped_bit(3));
+    }
+}
+
+//------------------------------------------
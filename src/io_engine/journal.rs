@@ -0,0 +1,122 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Result};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::io_engine::*;
+use crate::journal::format::*;
+
+//------------------------------------------
+
+// Thin, cache and era metadata all keep the superblock in block zero
+const SUPERBLOCK_LOCATION: u64 = 0;
+
+/// Records the blocks read and written through the inner engine in the
+/// format of the kernel's journalling block manager, so the transactions
+/// of the tools can be checked with thin_journal_check.  Each write is
+/// journalled as a write lock and unlock of the block, and writing the
+/// superblock is taken as the commit.
+pub struct JournalIoEngine {
+    inner: Arc<dyn IoEngine + Send + Sync>,
+    journal: Mutex<JournalWriter<BufWriter<File>>>,
+}
+
+impl JournalIoEngine {
+    pub fn new<P: AsRef<Path>>(inner: Arc<dyn IoEngine + Send + Sync>, journal: P) -> Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(journal)?;
+        let mut journal = JournalWriter::new(BufWriter::new(file));
+        journal.write_entry(&Entry::OpenJournal {
+            nr_blocks: inner.get_nr_blocks(),
+        })?;
+
+        Ok(JournalIoEngine {
+            inner,
+            journal: Mutex::new(journal),
+        })
+    }
+}
+
+impl Drop for JournalIoEngine {
+    fn drop(&mut self) {
+        let mut journal = self.journal.lock().unwrap();
+        let _ = journal.write_entry(&Entry::CloseJournal);
+        let _ = journal.flush();
+    }
+}
+
+impl IoEngine for JournalIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.inner.get_nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        self.inner.get_batch_size()
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        self.inner.suggest_nr_threads()
+    }
+
+    fn read(&self, loc: u64) -> Result<Block> {
+        let r = self.inner.read(loc);
+
+        let mut journal = self.journal.lock().unwrap();
+        journal.write_entry(&Entry::ReadLock { loc, ok: r.is_ok() })?;
+        if r.is_ok() {
+            journal.write_entry(&Entry::Unlock {
+                loc,
+                deltas: Vec::new(),
+            })?;
+        }
+        r
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
+        Ok(blocks.iter().map(|b| self.read(*b)).collect())
+    }
+
+    // Only the writes that reach the inner engine are journalled.
+    fn write(&self, b: &Block) -> Result<()> {
+        // Hold the journal across the write, so concurrent writes to the
+        // same block are journalled against the right contents.
+        let mut journal = self.journal.lock().unwrap();
+
+        let (lock, old) = match self.inner.read(b.loc) {
+            Ok(old) => (
+                Entry::WriteLock {
+                    loc: b.loc,
+                    ok: true,
+                },
+                old,
+            ),
+            Err(_) => (
+                Entry::ZeroLock {
+                    loc: b.loc,
+                    ok: true,
+                },
+                Block::zeroed(b.loc),
+            ),
+        };
+        self.inner.write(b)?;
+
+        let deltas = compute_deltas(old.get_data(), b.get_data());
+        journal.write_entry(&lock)?;
+        if b.loc == SUPERBLOCK_LOCATION {
+            journal.write_entry(&Entry::FlushAndUnlock { loc: b.loc, deltas })?;
+            journal.flush()?;
+        } else {
+            journal.write_entry(&Entry::Unlock { loc: b.loc, deltas })?;
+        }
+        Ok(())
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>> {
+        Ok(blocks.iter().map(|b| self.write(b)).collect())
+    }
+}
+
+//------------------------------------------
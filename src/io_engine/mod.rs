@@ -1,11 +1,19 @@
 pub mod base;
 pub mod buffer;
+pub mod fault;
 pub mod gaps;
+pub mod journal;
+pub mod overlay;
+pub mod pack;
 pub mod spindle;
 pub mod sync;
 pub mod utils;
 
 pub use crate::io_engine::base::*;
+pub use crate::io_engine::fault::FaultIoEngine;
+pub use crate::io_engine::journal::JournalIoEngine;
+pub use crate::io_engine::overlay::OverlayIoEngine;
+pub use crate::io_engine::pack::PackIoEngine;
 pub use crate::io_engine::spindle::SpindleIoEngine;
 pub use crate::io_engine::sync::SyncIoEngine;
 
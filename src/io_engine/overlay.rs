@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+use std::io::{self, Result};
+use std::sync::{Arc, Mutex};
+
+use crate::io_engine::*;
+
+//------------------------------------------
+
+/// Keeps the blocks written in memory, leaving the underlying metadata
+/// untouched.  Reads of blocks that haven't been written fall through to
+/// the inner engine.
+pub struct OverlayIoEngine {
+    inner: Arc<dyn IoEngine + Send + Sync>,
+    blocks: Mutex<BTreeMap<u64, Vec<u8>>>,
+}
+
+impl OverlayIoEngine {
+    pub fn new(inner: Arc<dyn IoEngine + Send + Sync>) -> Self {
+        OverlayIoEngine {
+            inner,
+            blocks: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the blocks written, keyed by their location.
+    pub fn into_blocks(self) -> BTreeMap<u64, Vec<u8>> {
+        self.blocks.into_inner().unwrap()
+    }
+}
+
+impl IoEngine for OverlayIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.inner.get_nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        self.inner.get_batch_size()
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        self.inner.suggest_nr_threads()
+    }
+
+    fn read(&self, loc: u64) -> Result<Block> {
+        if let Some(data) = self.blocks.lock().unwrap().get(&loc) {
+            let b = Block::new(loc);
+            b.get_data().copy_from_slice(data);
+            return Ok(b);
+        }
+        self.inner.read(loc)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
+        Ok(blocks.iter().map(|b| self.read(*b)).collect())
+    }
+
+    fn write(&self, b: &Block) -> Result<()> {
+        if b.loc >= self.get_nr_blocks() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write beyond the end of the metadata",
+            ));
+        }
+        self.blocks
+            .lock()
+            .unwrap()
+            .insert(b.loc, b.get_data().to_vec());
+        Ok(())
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>> {
+        Ok(blocks.iter().map(|b| self.write(b)).collect())
+    }
+}
+
+//------------------------------------------
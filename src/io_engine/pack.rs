@@ -0,0 +1,133 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Result};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::io_engine::*;
+use crate::pack::toplevel::PackReader;
+
+//------------------------------------------
+
+// Decoded chunks hold up to 1024 blocks each
+const CACHE_CHUNKS: usize = 16;
+
+type Chunk = Arc<BTreeMap<u64, Vec<u8>>>;
+
+struct ChunkCache {
+    chunks: HashMap<u64, Chunk>,
+    lru: VecDeque<u64>,
+}
+
+impl ChunkCache {
+    fn new() -> Self {
+        ChunkCache {
+            chunks: HashMap::new(),
+            lru: VecDeque::new(),
+        }
+    }
+
+    fn get(&mut self, offset: u64) -> Option<Chunk> {
+        let chunk = self.chunks.get(&offset)?.clone();
+        if let Some(i) = self.lru.iter().position(|o| *o == offset) {
+            self.lru.remove(i);
+        }
+        self.lru.push_back(offset);
+        Some(chunk)
+    }
+
+    fn insert(&mut self, offset: u64, chunk: Chunk) {
+        if self.chunks.insert(offset, chunk).is_some() {
+            return;
+        }
+        self.lru.push_back(offset);
+        if self.lru.len() > CACHE_CHUNKS {
+            let old = self.lru.pop_front().unwrap();
+            self.chunks.remove(&old);
+        }
+    }
+}
+
+/// A read only engine that reads the blocks straight out of a pack file,
+/// so the metadata doesn't need unpacking first.  Recently used chunks are
+/// kept decoded, since the tools tend to read neighbouring blocks together.
+pub struct PackIoEngine {
+    reader: PackReader,
+    cache: Mutex<ChunkCache>,
+}
+
+impl PackIoEngine {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let reader = PackReader::open(path.as_ref())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        Ok(PackIoEngine {
+            reader,
+            cache: Mutex::new(ChunkCache::new()),
+        })
+    }
+
+    fn bad_write() -> io::Error {
+        io::Error::new(io::ErrorKind::PermissionDenied, "pack files are read only")
+    }
+
+    fn get_chunk(&self, offset: u64) -> Result<Chunk> {
+        if let Some(chunk) = self.cache.lock().unwrap().get(offset) {
+            return Ok(chunk);
+        }
+
+        // Decode without holding the lock, so other threads can carry
+        // on with the chunks already cached.
+        let blocks = self
+            .reader
+            .read_chunk(offset)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let chunk = Arc::new(blocks.into_iter().collect::<BTreeMap<_, _>>());
+        self.cache.lock().unwrap().insert(offset, chunk.clone());
+        Ok(chunk)
+    }
+}
+
+impl IoEngine for PackIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.reader.nr_blocks()
+    }
+
+    fn get_batch_size(&self) -> usize {
+        32
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        std::cmp::min(8, num_cpus::get())
+    }
+
+    fn read(&self, loc: u64) -> Result<Block> {
+        if loc >= self.reader.nr_blocks() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read beyond the end of the pack file",
+            ));
+        }
+
+        let b = Block::zeroed(loc);
+        if let Some(offset) = self.reader.chunk_of(loc) {
+            if let Some(data) = self.get_chunk(offset)?.get(&loc) {
+                b.get_data().copy_from_slice(data);
+            }
+        }
+        Ok(b)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> Result<Vec<Result<Block>>> {
+        Ok(blocks.iter().map(|b| self.read(*b)).collect())
+    }
+
+    fn write(&self, _b: &Block) -> Result<()> {
+        Err(Self::bad_write())
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> Result<Vec<Result<()>>> {
+        Ok(blocks.iter().map(|_| Err(Self::bad_write())).collect())
+    }
+}
+
+//------------------------------------------
@@ -0,0 +1,149 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::cache::check::{check_with_engine, CacheCheckOptions};
+use crate::commands::engine::*;
+use crate::io_engine::*;
+use crate::journal::format::*;
+use crate::report::*;
+use crate::thin::check::check_with_maps;
+
+//------------------------------------------
+
+pub struct JournalCheckOptions<'a> {
+    pub input: &'a Path,
+    pub journal: &'a Path,
+    pub engine_opts: EngineOptions,
+    pub report: Arc<Report>,
+}
+
+fn check_metadata(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    opts: &JournalCheckOptions,
+) -> Result<()> {
+    let report = Arc::new(mk_quiet_report());
+    match opts.engine_opts.tool {
+        ToolType::Thin => check_with_maps(engine, report).map(|_| ()),
+        ToolType::Cache => {
+            let cache_opts = CacheCheckOptions {
+                dev: opts.input,
+                engine_opts: opts.engine_opts.clone(),
+                sb_only: false,
+                skip_mappings: false,
+                skip_hints: false,
+                skip_discards: false,
+                ignore_non_fatal: false,
+                auto_repair: false,
+                clear_needs_check: false,
+                report,
+            };
+            check_with_engine(engine, &cache_opts)
+        }
+        _ => Err(anyhow!("unsupported metadata type")),
+    }
+}
+
+fn apply(engine: &OverlayIoEngine, loc: u64, deltas: &[Delta]) -> Result<()> {
+    let b = engine.read(loc)?;
+    apply_deltas(b.get_data(), deltas);
+    engine.write(&b)?;
+    Ok(())
+}
+
+/// Replays the journal onto the metadata, checking it is consistent at
+/// every commit.  A crash can leave any of the writes since the last
+/// commit on disk, so the metadata is checked just before each commit too,
+/// with all of the writes applied, but still under the old superblock.
+/// The metadata is assumed to be unformatted until the first transaction
+/// that checks out.
+pub fn check(opts: JournalCheckOptions) -> Result<()> {
+    let base = EngineBuilder::new(opts.input, &opts.engine_opts)
+        .exclusive(false)
+        .build()?;
+    let engine = Arc::new(OverlayIoEngine::new(base));
+
+    let mut journal = JournalReader::new(BufReader::new(File::open(opts.journal)?));
+    match journal.read_entry()? {
+        Some(Entry::OpenJournal { nr_blocks }) => {
+            if nr_blocks != engine.get_nr_blocks() {
+                return Err(anyhow!(
+                    "journal is for metadata of {} blocks, but the metadata has {} blocks",
+                    nr_blocks,
+                    engine.get_nr_blocks()
+                ));
+            }
+        }
+        None => return Err(anyhow!("journal is unformatted")),
+        Some(_) => return Err(anyhow!("journal doesn't begin with an open message")),
+    }
+
+    let mut consistent = check_metadata(engine.clone(), &opts).is_ok();
+    let mut dirty = false;
+    let mut transaction = 0;
+
+    while let Some(entry) = journal.read_entry()? {
+        match entry {
+            Entry::ZeroLock { loc, ok: true } => {
+                engine.write(&Block::zeroed(loc))?;
+                dirty = true;
+            }
+            Entry::Unlock { loc, deltas } if !deltas.is_empty() => {
+                apply(&engine, loc, &deltas)?;
+                dirty = true;
+            }
+            Entry::FlushAndUnlock { loc, deltas } => {
+                transaction += 1;
+                if consistent && dirty {
+                    check_metadata(engine.clone(), &opts).map_err(|e| {
+                        anyhow!(
+                            "transaction {} is not crash-consistent, its writes damage the previous transaction: {}",
+                            transaction,
+                            e
+                        )
+                    })?;
+                }
+
+                apply(&engine, loc, &deltas)?;
+                match check_metadata(engine.clone(), &opts) {
+                    Ok(()) => {
+                        opts.report
+                            .to_stdout(&format!("transaction {} is consistent", transaction));
+                        consistent = true;
+                    }
+                    Err(e) if consistent => {
+                        return Err(anyhow!(
+                            "transaction {} is not crash-consistent, the committed metadata is damaged: {}",
+                            transaction,
+                            e
+                        ));
+                    }
+                    Err(_) => {
+                        opts.report.to_stdout(&format!(
+                            "transaction {} leaves the metadata unformatted",
+                            transaction
+                        ));
+                    }
+                }
+                dirty = false;
+            }
+            Entry::CloseJournal => break,
+            Entry::OpenJournal { .. } => {
+                return Err(anyhow!("unexpected open message in the journal"));
+            }
+            _ => {}
+        }
+    }
+
+    if !consistent {
+        return Err(anyhow!("no consistent transaction found"));
+    }
+    opts.report
+        .to_stdout(&format!("{} transactions checked", transaction));
+
+    Ok(())
+}
+
+//------------------------------------------
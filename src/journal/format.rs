@@ -0,0 +1,356 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Result, Write};
+
+use crate::io_engine::BLOCK_SIZE;
+
+//------------------------------------------
+
+// The journal is the byte stream written by the journalling block manager
+// in the kernel (see doc/bm-journal.patch).  Each message is a single byte
+// holding the message type and a success bit, followed by its arguments.
+// Unlocking a block records the 32 byte chunks of it that changed while
+// it was write locked.
+
+pub const DELTA_SIZE: usize = 32;
+pub const NR_DELTAS: usize = BLOCK_SIZE / DELTA_SIZE;
+const END_OF_DELTAS: u16 = 0xffff;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MsgType {
+    OpenJournal,
+    CloseJournal,
+    ReadLock,
+    WriteLock,
+    ZeroLock,
+    TryReadLock,
+    Unlock,
+    Verify,
+    Prepare,
+    Flush,
+    FlushAndUnlock,
+    Prefetch,
+    SetReadOnly,
+    SetReadWrite,
+}
+
+impl MsgType {
+    fn from_u8(v: u8) -> Option<MsgType> {
+        use MsgType::*;
+        let t = match v {
+            0 => OpenJournal,
+            1 => CloseJournal,
+            2 => ReadLock,
+            3 => WriteLock,
+            4 => ZeroLock,
+            5 => TryReadLock,
+            6 => Unlock,
+            7 => Verify,
+            8 => Prepare,
+            9 => Flush,
+            10 => FlushAndUnlock,
+            11 => Prefetch,
+            12 => SetReadOnly,
+            13 => SetReadWrite,
+            _ => return None,
+        };
+        Some(t)
+    }
+}
+
+/// A changed chunk of a block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delta {
+    pub index: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Entry {
+    OpenJournal { nr_blocks: u64 },
+    CloseJournal,
+    ReadLock { loc: u64, ok: bool },
+    WriteLock { loc: u64, ok: bool },
+    ZeroLock { loc: u64, ok: bool },
+    TryReadLock { loc: u64, ok: bool },
+    Unlock { loc: u64, deltas: Vec<Delta> },
+    Verify { loc: u64, ok: bool },
+    Flush { ok: bool },
+
+    // Writes the superblock, committing the transaction
+    FlushAndUnlock { loc: u64, deltas: Vec<Delta> },
+    SetReadOnly,
+    SetReadWrite,
+}
+
+//------------------------------------------
+
+/// Lists the chunks that differ between two versions of a block.
+pub fn compute_deltas(old: &[u8], new: &[u8]) -> Vec<Delta> {
+    old.chunks(DELTA_SIZE)
+        .zip(new.chunks(DELTA_SIZE))
+        .enumerate()
+        .filter(|(_, (o, n))| o != n)
+        .map(|(i, (_, n))| Delta {
+            index: i as u16,
+            data: n.to_vec(),
+        })
+        .collect()
+}
+
+pub fn apply_deltas(data: &mut [u8], deltas: &[Delta]) {
+    for d in deltas {
+        let begin = d.index as usize * DELTA_SIZE;
+        data[begin..begin + DELTA_SIZE].copy_from_slice(&d.data);
+    }
+}
+
+//------------------------------------------
+
+fn bad_journal(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+pub struct JournalReader<R: Read> {
+    r: R,
+}
+
+impl<R: Read> JournalReader<R> {
+    pub fn new(r: R) -> Self {
+        JournalReader { r }
+    }
+
+    fn read_deltas(&mut self) -> Result<Vec<Delta>> {
+        let mut deltas = Vec::new();
+        loop {
+            let index = self.r.read_u16::<LittleEndian>()?;
+            if index == END_OF_DELTAS {
+                break;
+            }
+            if index as usize >= NR_DELTAS {
+                return Err(bad_journal(&format!("delta index {} out of range", index)));
+            }
+            let mut data = vec![0; DELTA_SIZE];
+            self.r.read_exact(&mut data)?;
+            deltas.push(Delta { index, data });
+        }
+        Ok(deltas)
+    }
+
+    fn read_entry_(&mut self, t: MsgType, ok: bool) -> Result<Entry> {
+        use MsgType::*;
+        let e = match t {
+            OpenJournal => Entry::OpenJournal {
+                nr_blocks: self.r.read_u64::<LittleEndian>()?,
+            },
+            CloseJournal => Entry::CloseJournal,
+            ReadLock => Entry::ReadLock {
+                loc: self.r.read_u64::<LittleEndian>()?,
+                ok,
+            },
+            WriteLock => Entry::WriteLock {
+                loc: self.r.read_u64::<LittleEndian>()?,
+                ok,
+            },
+            ZeroLock => Entry::ZeroLock {
+                loc: self.r.read_u64::<LittleEndian>()?,
+                ok,
+            },
+            TryReadLock => Entry::TryReadLock {
+                loc: self.r.read_u64::<LittleEndian>()?,
+                ok,
+            },
+            Unlock => {
+                let loc = self.r.read_u64::<LittleEndian>()?;
+                Entry::Unlock {
+                    loc,
+                    deltas: self.read_deltas()?,
+                }
+            }
+            Verify => Entry::Verify {
+                loc: self.r.read_u64::<LittleEndian>()?,
+                ok,
+            },
+            Flush => Entry::Flush { ok },
+            FlushAndUnlock => {
+                let loc = self.r.read_u64::<LittleEndian>()?;
+                Entry::FlushAndUnlock {
+                    loc,
+                    deltas: self.read_deltas()?,
+                }
+            }
+            SetReadOnly => Entry::SetReadOnly,
+            SetReadWrite => Entry::SetReadWrite,
+            Prepare | Prefetch => {
+                return Err(bad_journal(&format!("unexpected message {:?}", t)));
+            }
+        };
+        Ok(e)
+    }
+
+    /// Returns None at the end of the journal.  The journal device is
+    /// zeroed before use, so a zero byte marks the end of the messages.
+    pub fn read_entry(&mut self) -> Result<Option<Entry>> {
+        let mut b = [0u8; 1];
+        if self.r.read(&mut b)? == 0 || b[0] == 0 {
+            return Ok(None);
+        }
+
+        let t = MsgType::from_u8(b[0] >> 1)
+            .ok_or_else(|| bad_journal(&format!("unknown message type {}", b[0] >> 1)))?;
+        let ok = (b[0] & 1) != 0;
+        self.read_entry_(t, ok).map(Some).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                bad_journal("journal truncated")
+            } else {
+                e
+            }
+        })
+    }
+}
+
+//------------------------------------------
+
+pub struct JournalWriter<W: Write> {
+    w: W,
+}
+
+impl<W: Write> JournalWriter<W> {
+    pub fn new(w: W) -> Self {
+        JournalWriter { w }
+    }
+
+    fn write_msg(&mut self, t: MsgType, ok: bool) -> Result<()> {
+        self.w.write_u8(((t as u8) << 1) | ok as u8)
+    }
+
+    fn write_deltas(&mut self, deltas: &[Delta]) -> Result<()> {
+        for d in deltas {
+            self.w.write_u16::<LittleEndian>(d.index)?;
+            self.w.write_all(&d.data)?;
+        }
+        self.w.write_u16::<LittleEndian>(END_OF_DELTAS)
+    }
+
+    pub fn write_entry(&mut self, e: &Entry) -> Result<()> {
+        use Entry::*;
+        match e {
+            OpenJournal { nr_blocks } => {
+                self.write_msg(MsgType::OpenJournal, true)?;
+                self.w.write_u64::<LittleEndian>(*nr_blocks)
+            }
+            CloseJournal => self.write_msg(MsgType::CloseJournal, true),
+            ReadLock { loc, ok } => {
+                self.write_msg(MsgType::ReadLock, *ok)?;
+                self.w.write_u64::<LittleEndian>(*loc)
+            }
+            WriteLock { loc, ok } => {
+                self.write_msg(MsgType::WriteLock, *ok)?;
+                self.w.write_u64::<LittleEndian>(*loc)
+            }
+            ZeroLock { loc, ok } => {
+                self.write_msg(MsgType::ZeroLock, *ok)?;
+                self.w.write_u64::<LittleEndian>(*loc)
+            }
+            TryReadLock { loc, ok } => {
+                self.write_msg(MsgType::TryReadLock, *ok)?;
+                self.w.write_u64::<LittleEndian>(*loc)
+            }
+            Unlock { loc, deltas } => {
+                self.write_msg(MsgType::Unlock, true)?;
+                self.w.write_u64::<LittleEndian>(*loc)?;
+                self.write_deltas(deltas)
+            }
+            Verify { loc, ok } => {
+                self.write_msg(MsgType::Verify, *ok)?;
+                self.w.write_u64::<LittleEndian>(*loc)
+            }
+            Flush { ok } => self.write_msg(MsgType::Flush, *ok),
+            FlushAndUnlock { loc, deltas } => {
+                self.write_msg(MsgType::FlushAndUnlock, true)?;
+                self.w.write_u64::<LittleEndian>(*loc)?;
+                self.write_deltas(deltas)
+            }
+            SetReadOnly => self.write_msg(MsgType::SetReadOnly, true),
+            SetReadWrite => self.write_msg(MsgType::SetReadWrite, true),
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.w.flush()
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deltas_cover_changed_chunks() {
+        let old = vec![0u8; BLOCK_SIZE];
+        let mut new = old.clone();
+        new[5] = 1;
+        new[DELTA_SIZE * 3 + 31] = 2;
+
+        let deltas = compute_deltas(&old, &new);
+        assert_eq!(
+            deltas.iter().map(|d| d.index).collect::<Vec<u16>>(),
+            vec![0, 3]
+        );
+
+        let mut data = old.clone();
+        apply_deltas(&mut data, &deltas);
+        assert_eq!(data, new);
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let entries = vec![
+            Entry::OpenJournal { nr_blocks: 1024 },
+            Entry::ReadLock { loc: 7, ok: true },
+            Entry::WriteLock { loc: 8, ok: false },
+            Entry::ZeroLock { loc: 9, ok: true },
+            Entry::Unlock {
+                loc: 9,
+                deltas: vec![Delta {
+                    index: 127,
+                    data: vec![0xaa; DELTA_SIZE],
+                }],
+            },
+            Entry::Flush { ok: true },
+            Entry::FlushAndUnlock {
+                loc: 0,
+                deltas: vec![],
+            },
+            Entry::CloseJournal,
+        ];
+
+        let mut buf = Vec::new();
+        let mut w = JournalWriter::new(&mut buf);
+        for e in &entries {
+            w.write_entry(e).unwrap();
+        }
+
+        let mut r = JournalReader::new(&buf[..]);
+        let mut actual = Vec::new();
+        while let Some(e) = r.read_entry().unwrap() {
+            actual.push(e);
+        }
+        assert_eq!(actual, entries);
+    }
+
+    #[test]
+    fn truncated_journal_fails() {
+        let mut buf = Vec::new();
+        let mut w = JournalWriter::new(&mut buf);
+        w.write_entry(&Entry::ReadLock { loc: 1, ok: true })
+            .unwrap();
+        buf.truncate(4);
+
+        let mut r = JournalReader::new(&buf[..]);
+        assert!(r.read_entry().is_err());
+    }
+}
+
+//------------------------------------------
@@ -0,0 +1,4 @@
+pub mod format;
+
+#[cfg(feature = "devtools")]
+pub mod check;
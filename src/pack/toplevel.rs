@@ -1,16 +1,17 @@
 use anyhow::{anyhow, Context, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
-use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder};
 
 use std::os::unix::fs::{FileExt, OpenOptionsExt};
 use std::{
-    fs::OpenOptions,
+    fs::{File, OpenOptions},
     io,
     io::prelude::*,
     io::Write,
     ops::DerefMut,
     path::Path,
+    str::FromStr,
     sync::{Arc, Mutex},
     thread::spawn,
 };
@@ -20,11 +21,22 @@ use std::sync::mpsc::{sync_channel, Receiver};
 
 use crate::checksum::*;
 use crate::file_utils;
+use crate::io_engine::SyncIoEngine;
 use crate::pack::node_encode::*;
+use crate::thin::subset::*;
 
 const BLOCK_SIZE: u64 = 4096;
 const MAGIC: u64 = 0xa537a0aa6309ef77;
-const PACK_VERSION: u64 = 3;
+const PACK_VERSION: u64 = 4;
+const LEGACY_PACK_VERSION: u64 = 3;
+const HEADER_SIZE: u64 = 40;
+const LEGACY_HEADER_SIZE: u64 = 32;
+const CHUNK_BLOCKS: u64 = 1024;
+const INDEX_MAGIC: u64 = 0x7f1c84ae2b0d36c5;
+const INDEX_ENTRY_SIZE: u64 = 24;
+const FOOTER_SIZE: u64 = 24;
+const UUID_OFFSET: usize = 16;
+const UUID_SIZE: usize = 16;
 
 fn shuffle<T>(v: &mut Vec<T>) {
     let mut rng = rand::thread_rng();
@@ -62,8 +74,39 @@ fn mk_chunk_vecs(nr_blocks: u64, nr_jobs: u64) -> Vec<Vec<(u64, u64)>> {
     vs
 }
 
-pub fn pack(input_file: &Path, output_file: &Path) -> Result<()> {
+pub struct PackOptions {
+    pub compression: Compression,
+
+    // Scrubs the uuid from the superblocks
+    pub anonymise: bool,
+
+    // Packs only the blocks needed by these thin devices
+    pub selected_devs: Option<Vec<u64>>,
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        PackOptions {
+            compression: Compression::Zlib,
+            anonymise: false,
+            selected_devs: None,
+        }
+    }
+}
+
+pub fn pack(input_file: &Path, output_file: &Path, opts: &PackOptions) -> Result<()> {
     let nr_blocks = get_nr_blocks(input_file)?;
+    let compression = opts.compression;
+    let anonymise = opts.anonymise;
+
+    let subset = match &opts.selected_devs {
+        Some(devs) => {
+            let engine = Arc::new(SyncIoEngine::new_with(input_file, false, false)?);
+            let subset = build_subset(engine, devs).context("unable to select devices")?;
+            Some(Arc::new(subset))
+        }
+        None => None,
+    };
     let nr_jobs = std::cmp::max(1, std::cmp::min(num_cpus::get() as u64, nr_blocks / 128));
     let chunk_vecs = mk_chunk_vecs(nr_blocks, nr_jobs);
 
@@ -73,40 +116,69 @@ pub fn pack(input_file: &Path, output_file: &Path) -> Result<()> {
         .custom_flags(libc::O_EXCL)
         .open(input_file)?;
 
-    let output = OpenOptions::new()
+    let mut output = OpenOptions::new()
         .read(false)
         .write(true)
         .create(true)
         .truncate(true)
         .open(output_file)?;
 
-    write_header(&output, nr_blocks).context("unable to write pack file header")?;
+    write_header(&mut output, nr_blocks, compression)
+        .context("unable to write pack file header")?;
 
     let sync_input = Arc::new(Mutex::new(input));
-    let sync_output = Arc::new(Mutex::new(output));
+    let sync_output = Arc::new(Mutex::new(PackWriter::new(output, HEADER_SIZE)));
 
     let mut threads = Vec::new();
     for job in 0..nr_jobs {
         let sync_input = Arc::clone(&sync_input);
         let sync_output = Arc::clone(&sync_output);
         let chunks = chunk_vecs[job as usize].clone();
-        threads.push(spawn(move || crunch(sync_input, sync_output, chunks)));
+        let subset = subset.clone();
+        threads.push(spawn(move || {
+            crunch(
+                sync_input,
+                sync_output,
+                chunks,
+                compression,
+                anonymise,
+                subset,
+            )
+        }));
     }
 
     for t in threads {
         t.join().unwrap()?;
     }
+
+    let output = Arc::try_unwrap(sync_output)
+        .map_err(|_| anyhow!("pack file writer still in use"))?
+        .into_inner()
+        .unwrap();
+    output.finish().context("unable to write pack file index")?;
     Ok(())
 }
 
-fn crunch<R, W>(input: Arc<Mutex<R>>, output: Arc<Mutex<W>>, ranges: Vec<(u64, u64)>) -> Result<()>
+// A chunk stays open across the ranges of a job, so it can hold
+// blocks from several ranges.  Each range gets its own index entry,
+// and since the ranges of the jobs are disjoint the entries in the
+// index don't overlap.
+fn crunch<R, W>(
+    input: Arc<Mutex<R>>,
+    output: Arc<Mutex<PackWriter<W>>>,
+    ranges: Vec<(u64, u64)>,
+    compression: Compression,
+    anonymise: bool,
+    subset: Option<Arc<Subset>>,
+) -> Result<()>
 where
     R: Read + Seek + FileExt,
     W: Write,
 {
-    let mut written = 0u64;
-    let mut z = ZlibEncoder::new(Vec::new(), Compression::default());
+    let mut chunk = ChunkBuilder::new();
     for (lo, hi) in ranges {
+        chunk.start_range();
+
         // We read multiple blocks at once to reduce contention
         // on input.
         let mut input = input.lock().unwrap();
@@ -115,36 +187,234 @@ where
 
         for b in lo..hi {
             let block_start = ((b - lo) * BLOCK_SIZE) as usize;
-            let data = &big_data[block_start..(block_start + BLOCK_SIZE as usize)];
-            let kind = metadata_block_type(data);
-            if kind != BT::UNKNOWN {
-                z.write_u64::<LittleEndian>(b)?;
-                pack_block(&mut z, kind, data)?;
-
-                written += 1;
-                if written == 1024 {
-                    let compressed = z.reset(Vec::new())?;
-
-                    let mut output = output.lock().unwrap();
-                    output.write_u64::<LittleEndian>(compressed.len() as u64)?;
-                    output.write_all(&compressed)?;
-                    written = 0;
+            let mut data = &big_data[block_start..(block_start + BLOCK_SIZE as usize)];
+            if let Some(subset) = &subset {
+                if !subset.contains(b) {
+                    continue;
                 }
+                if let Some(rewritten) = subset.get_rewritten(b) {
+                    data = rewritten;
+                }
+            }
+
+            let kind = metadata_block_type(data);
+            if kind == BT::UNKNOWN {
+                continue;
+            }
+
+            if anonymise && is_superblock(&kind) {
+                chunk.push(b, kind, &scrub_superblock(data)?)?;
+            } else {
+                chunk.push(b, kind, data)?;
+            }
+
+            if chunk.nr_blocks == CHUNK_BLOCKS {
+                chunk.flush(&output, compression)?;
             }
         }
     }
 
-    if written > 0 {
-        let compressed = z.finish()?;
-        let mut output = output.lock().unwrap();
-        output.write_u64::<LittleEndian>(compressed.len() as u64)?;
-        output.write_all(&compressed)?;
+    chunk.flush(&output, compression)?;
+    Ok(())
+}
+
+fn is_superblock(kind: &BT) -> bool {
+    matches!(
+        kind,
+        BT::THIN_SUPERBLOCK | BT::CACHE_SUPERBLOCK | BT::ERA_SUPERBLOCK
+    )
+}
+
+// The thin, cache and era superblocks all hold the uuid at the same
+// offset.
+fn scrub_superblock(data: &[u8]) -> Result<Vec<u8>> {
+    let kind = metadata_block_type(data);
+    let mut data = data.to_vec();
+    data[UUID_OFFSET..UUID_OFFSET + UUID_SIZE].fill(0);
+    write_checksum(&mut data, kind)?;
+    Ok(data)
+}
+
+//------------------------------------------
+
+/// The compression applied to the chunks of a pack file.  Version 3
+/// files are always zlib compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Zlib,
+    Zstd,
+}
+
+impl Compression {
+    fn to_u64(self) -> u64 {
+        match self {
+            Compression::Zlib => 0,
+            Compression::Zstd => 1,
+        }
+    }
+
+    fn from_u64(n: u64) -> io::Result<Self> {
+        match n {
+            0 => Ok(Compression::Zlib),
+            1 => Ok(Compression::Zstd),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown pack file compression ({})", n),
+            )),
+        }
     }
+}
 
-    Ok(())
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zlib" => Ok(Compression::Zlib),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(anyhow!("unknown compression")),
+        }
+    }
+}
+
+fn compress(compression: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    match compression {
+        Compression::Zlib => {
+            let mut z = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            z.write_all(data)?;
+            z.finish()
+        }
+        Compression::Zstd => zstd::encode_all(data, 0),
+    }
+}
+
+fn decompress(compression: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    match compression {
+        Compression::Zlib => {
+            ZlibDecoder::new(data).read_to_end(&mut buf)?;
+        }
+        Compression::Zstd => {
+            buf = zstd::decode_all(data)?;
+        }
+    }
+    Ok(buf)
+}
+
+/// Locates a chunk in the pack file, covering the blocks from `begin`
+/// up to, but excluding, `end`.  Blocks in this range that aren't in
+/// the chunk weren't packed.
+#[derive(Clone, Copy, Debug)]
+struct IndexEntry {
+    begin: u64,
+    end: u64,
+    offset: u64,
+}
+
+// The runs are the parts of the input ranges covered by the chunk,
+// each becomes an index entry.
+struct ChunkBuilder {
+    data: Vec<u8>,
+    nr_blocks: u64,
+    runs: Vec<(u64, u64)>,
+    new_run: bool,
+}
+
+impl ChunkBuilder {
+    fn new() -> Self {
+        ChunkBuilder {
+            data: Vec::new(),
+            nr_blocks: 0,
+            runs: Vec::new(),
+            new_run: true,
+        }
+    }
+
+    fn start_range(&mut self) {
+        self.new_run = true;
+    }
+
+    fn push(&mut self, b: u64, kind: BT, data: &[u8]) -> Result<()> {
+        match self.runs.last_mut() {
+            Some((_, end)) if !self.new_run => *end = b + 1,
+            _ => self.runs.push((b, b + 1)),
+        }
+        self.new_run = false;
+        self.nr_blocks += 1;
+        self.data.write_u64::<LittleEndian>(b)?;
+        pack_block(&mut self.data, kind, data)
+    }
+
+    fn flush<W: Write>(
+        &mut self,
+        output: &Mutex<PackWriter<W>>,
+        compression: Compression,
+    ) -> Result<()> {
+        if self.nr_blocks == 0 {
+            return Ok(());
+        }
+
+        let compressed = compress(compression, &self.data)?;
+        output
+            .lock()
+            .unwrap()
+            .write_chunk(&self.runs, &compressed)?;
+
+        self.data.clear();
+        self.nr_blocks = 0;
+        self.runs.clear();
+        Ok(())
+    }
+}
+
+struct PackWriter<W> {
+    w: W,
+    offset: u64,
+    index: Vec<IndexEntry>,
+}
+
+impl<W: Write> PackWriter<W> {
+    fn new(w: W, offset: u64) -> Self {
+        PackWriter {
+            w,
+            offset,
+            index: Vec::new(),
+        }
+    }
+
+    fn write_chunk(&mut self, runs: &[(u64, u64)], compressed: &[u8]) -> io::Result<()> {
+        for &(begin, end) in runs {
+            self.index.push(IndexEntry {
+                begin,
+                end,
+                offset: self.offset,
+            });
+        }
+        self.w.write_u64::<LittleEndian>(compressed.len() as u64)?;
+        self.w.write_all(compressed)?;
+        self.offset += 8 + compressed.len() as u64;
+        Ok(())
+    }
+
+    // The index follows the last chunk, and is located by a fixed size
+    // footer at the end of the file.
+    fn finish(mut self) -> io::Result<()> {
+        self.index.sort_by_key(|e| e.begin);
+        for e in &self.index {
+            self.w.write_u64::<LittleEndian>(e.begin)?;
+            self.w.write_u64::<LittleEndian>(e.end)?;
+            self.w.write_u64::<LittleEndian>(e.offset)?;
+        }
+        self.w.write_u64::<LittleEndian>(self.offset)?;
+        self.w.write_u64::<LittleEndian>(self.index.len() as u64)?;
+        self.w.write_u64::<LittleEndian>(INDEX_MAGIC)?;
+        self.w.flush()
+    }
 }
 
-fn write_header<W>(mut w: W, nr_blocks: u64) -> io::Result<()>
+//------------------------------------------
+
+fn write_header<W>(mut w: W, nr_blocks: u64, compression: Compression) -> io::Result<()>
 where
     W: byteorder::WriteBytesExt,
 {
@@ -152,11 +422,18 @@ where
     w.write_u64::<LittleEndian>(PACK_VERSION)?;
     w.write_u64::<LittleEndian>(4096)?;
     w.write_u64::<LittleEndian>(nr_blocks)?;
+    w.write_u64::<LittleEndian>(compression.to_u64())?;
 
     Ok(())
 }
 
-fn read_header<R>(mut r: R) -> io::Result<u64>
+struct Header {
+    version: u64,
+    nr_blocks: u64,
+    compression: Compression,
+}
+
+fn read_header<R>(mut r: R) -> io::Result<Header>
 where
     R: byteorder::ReadBytesExt,
 {
@@ -169,10 +446,10 @@ where
     }
 
     let version = r.read_u64::<LittleEndian>()?;
-    if version != PACK_VERSION {
+    if version != PACK_VERSION && version != LEGACY_PACK_VERSION {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
-            format!("unsupported pack file version ({}).", PACK_VERSION),
+            format!("unsupported pack file version ({}).", version),
         ));
     }
 
@@ -184,7 +461,137 @@ where
         ));
     }
 
-    r.read_u64::<LittleEndian>()
+    let nr_blocks = r.read_u64::<LittleEndian>()?;
+    let compression = if version == LEGACY_PACK_VERSION {
+        Compression::Zlib
+    } else {
+        Compression::from_u64(r.read_u64::<LittleEndian>()?)?
+    };
+
+    Ok(Header {
+        version,
+        nr_blocks,
+        compression,
+    })
+}
+
+fn read_index(input: &File) -> io::Result<Vec<IndexEntry>> {
+    let bad_index = || io::Error::new(io::ErrorKind::InvalidData, "bad pack file index");
+
+    let file_len = input.metadata()?.len();
+    if file_len < HEADER_SIZE + FOOTER_SIZE {
+        return Err(bad_index());
+    }
+
+    let mut footer = vec![0; FOOTER_SIZE as usize];
+    input.read_exact_at(&mut footer, file_len - FOOTER_SIZE)?;
+    let mut r = &footer[0..];
+    let index_offset = r.read_u64::<LittleEndian>()?;
+    let nr_entries = r.read_u64::<LittleEndian>()?;
+    let magic = r.read_u64::<LittleEndian>()?;
+    if magic != INDEX_MAGIC
+        || index_offset < HEADER_SIZE
+        || nr_entries.checked_mul(INDEX_ENTRY_SIZE) != Some(file_len - FOOTER_SIZE - index_offset)
+    {
+        return Err(bad_index());
+    }
+
+    let mut buf = vec![0; (nr_entries * INDEX_ENTRY_SIZE) as usize];
+    input.read_exact_at(&mut buf, index_offset)?;
+    let mut r = &buf[0..];
+    let mut index = Vec::with_capacity(nr_entries as usize);
+    for _ in 0..nr_entries {
+        let begin = r.read_u64::<LittleEndian>()?;
+        let end = r.read_u64::<LittleEndian>()?;
+        let offset = r.read_u64::<LittleEndian>()?;
+        if begin >= end || offset >= index_offset {
+            return Err(bad_index());
+        }
+        index.push(IndexEntry { begin, end, offset });
+    }
+
+    Ok(index)
+}
+
+// Version 3 files have no index, so we build one by decoding every
+// chunk.  Each run of contiguous blocks within a chunk gets an entry.
+fn scan_chunks(input: &File, compression: Compression) -> io::Result<Vec<IndexEntry>> {
+    let file_len = input.metadata()?.len();
+    let mut index = Vec::new();
+    let mut offset = LEGACY_HEADER_SIZE;
+    while offset < file_len {
+        let bytes = read_chunk_at(input, offset)?;
+        let mut blocks: Vec<u64> = decode_chunk(compression, &bytes)?
+            .iter()
+            .map(|(b, _)| *b)
+            .collect();
+        blocks.sort_unstable();
+
+        let mut run: Option<(u64, u64)> = None;
+        for b in blocks {
+            run = match run {
+                Some((begin, end)) if end == b => Some((begin, b + 1)),
+                Some((begin, end)) => {
+                    index.push(IndexEntry { begin, end, offset });
+                    Some((b, b + 1))
+                }
+                None => Some((b, b + 1)),
+            };
+        }
+        if let Some((begin, end)) = run {
+            index.push(IndexEntry { begin, end, offset });
+        }
+
+        offset += 8 + bytes.len() as u64;
+    }
+
+    index.sort_by_key(|e| e.begin);
+    Ok(index)
+}
+
+// The length comes from the pack file, so it's checked against the
+// size of the file before the buffer is allocated.
+fn check_chunk_len(input: &File, offset: u64, len: u64) -> io::Result<()> {
+    let file_len = input.metadata()?.len();
+    match offset.checked_add(8).and_then(|o| o.checked_add(len)) {
+        Some(end) if end <= file_len => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "chunk at offset {} runs past the end of the pack file",
+                offset
+            ),
+        )),
+    }
+}
+
+fn read_chunk_at(input: &File, offset: u64) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 8];
+    input.read_exact_at(&mut len, offset)?;
+    let len = u64::from_le_bytes(len);
+    check_chunk_len(input, offset, len)?;
+
+    let mut bytes = vec![0; len as usize];
+    input.read_exact_at(&mut bytes, offset + 8)?;
+    Ok(bytes)
+}
+
+fn decode_chunk(compression: Compression, bytes: &[u8]) -> io::Result<Vec<(u64, Vec<u8>)>> {
+    let data = decompress(compression, bytes)?;
+    let mut r = &data[0..];
+    let mut blocks = Vec::new();
+    while !r.is_empty() {
+        let b = r.read_u64::<LittleEndian>()?;
+        let block = crate::pack::vm::unpack(&mut r, BLOCK_SIZE as usize)?;
+        if metadata_block_type(&block[0..]) == BT::UNKNOWN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unpacked block {} isn't metadata", b),
+            ));
+        }
+        blocks.push((b, block));
+    }
+    Ok(blocks)
 }
 
 fn get_nr_blocks(path: &Path) -> io::Result<u64> {
@@ -236,23 +643,20 @@ where
     Ok(())
 }
 
-fn decode_worker<W>(rx: Receiver<Vec<u8>>, w: Arc<Mutex<W>>) -> io::Result<()>
+fn decode_worker<W>(
+    rx: Receiver<Vec<u8>>,
+    w: Arc<Mutex<W>>,
+    compression: Compression,
+) -> io::Result<()>
 where
     W: Write + Seek + FileExt,
 {
     let mut blocks = Vec::new();
 
     while let Ok(bytes) = rx.recv() {
-        let mut z = ZlibDecoder::new(&bytes[0..]);
-
-        while let Ok(b) = z.read_u64::<LittleEndian>() {
-            let block = crate::pack::vm::unpack(&mut z, BLOCK_SIZE as usize).unwrap();
-            assert!(metadata_block_type(&block[0..]) != BT::UNKNOWN);
-            blocks.push((b, block));
-
-            if blocks.len() >= 32 {
-                write_blocks(&w, &mut blocks)?;
-            }
+        blocks.extend(decode_chunk(compression, &bytes)?);
+        if blocks.len() >= 32 {
+            write_blocks(&w, &mut blocks)?;
         }
     }
 
@@ -260,13 +664,38 @@ where
     Ok(())
 }
 
+// With an index each worker can read its own chunks, so the input is
+// no longer read sequentially.
+fn indexed_worker<W>(
+    input: Arc<File>,
+    offsets: Vec<u64>,
+    w: Arc<Mutex<W>>,
+    compression: Compression,
+) -> io::Result<()>
+where
+    W: Write + Seek + FileExt,
+{
+    for offset in offsets {
+        let bytes = read_chunk_at(&input, offset)?;
+        let mut blocks = decode_chunk(compression, &bytes)?;
+        write_blocks(&w, &mut blocks)?;
+    }
+    Ok(())
+}
+
+/// Checks for the pack file magic at the start of a file.
+pub fn is_pack_file(path: &Path) -> io::Result<bool> {
+    let mut file = OpenOptions::new().read(true).write(false).open(path)?;
+    Ok(file.read_u64::<LittleEndian>()? == MAGIC)
+}
+
 pub fn unpack(input_file: &Path, output_file: &Path) -> Result<()> {
     let mut input = OpenOptions::new()
         .read(true)
         .write(false)
         .open(input_file)?;
 
-    let nr_blocks = read_header(&input)?;
+    let header = read_header(&input)?;
 
     let mut output = OpenOptions::new()
         .read(false)
@@ -276,13 +705,42 @@ pub fn unpack(input_file: &Path, output_file: &Path) -> Result<()> {
         .open(output_file)?;
 
     // zero the last block to size the file
-    write_zero_block(&mut output, nr_blocks - 1)?;
+    write_zero_block(&mut output, header.nr_blocks - 1)?;
 
     // Run until we hit the end
     let output = Arc::new(Mutex::new(output));
+    let compression = header.compression;
+    let nr_jobs = num_cpus::get();
+
+    if header.version != LEGACY_PACK_VERSION {
+        // A chunk may have several index entries, but is only
+        // decoded once.
+        let mut offsets: Vec<u64> = read_index(&input)?.iter().map(|e| e.offset).collect();
+        offsets.sort_unstable();
+        offsets.dedup();
+
+        let mut offset_vecs = vec![Vec::new(); nr_jobs];
+        for (i, offset) in offsets.into_iter().enumerate() {
+            offset_vecs[i % nr_jobs].push(offset);
+        }
+
+        let input = Arc::new(input);
+        let mut threads = Vec::new();
+        for offsets in offset_vecs {
+            let input = Arc::clone(&input);
+            let output = Arc::clone(&output);
+            threads.push(spawn(move || {
+                indexed_worker(input, offsets, output, compression)
+            }));
+        }
+
+        for t in threads {
+            t.join().unwrap()?;
+        }
+        return Ok(());
+    }
 
     // kick off the workers
-    let nr_jobs = num_cpus::get();
     let mut senders = Vec::new();
     let mut threads = Vec::new();
 
@@ -290,12 +748,14 @@ pub fn unpack(input_file: &Path, output_file: &Path) -> Result<()> {
         let (tx, rx) = sync_channel(1);
         let output = Arc::clone(&output);
         senders.push(tx);
-        threads.push(spawn(move || decode_worker(rx, output)));
+        threads.push(spawn(move || decode_worker(rx, output, compression)));
     }
 
     // Read z compressed chunk, and hand to worker thread.
     let mut next_worker = 0;
     while let Ok(len) = input.read_u64::<LittleEndian>() {
+        let offset = input.stream_position()? - 8;
+        check_chunk_len(&input, offset, len)?;
         let mut bytes = vec![0; len as usize];
         input.read_exact(&mut bytes)?;
         senders[next_worker].send(bytes).unwrap();
@@ -311,3 +771,79 @@ pub fn unpack(input_file: &Path, output_file: &Path) -> Result<()> {
     }
     Ok(())
 }
+
+//------------------------------------------
+
+/// Random access to the blocks of a pack file.  Version 3 files have
+/// no index, so opening one decodes the whole file once to build it.
+pub struct PackReader {
+    input: File,
+    nr_blocks: u64,
+    compression: Compression,
+    index: Vec<IndexEntry>,
+}
+
+impl PackReader {
+    pub fn open(path: &Path) -> Result<PackReader> {
+        let input = OpenOptions::new().read(true).write(false).open(path)?;
+        let header = read_header(&input)?;
+        let index = if header.version == LEGACY_PACK_VERSION {
+            scan_chunks(&input, header.compression)?
+        } else {
+            read_index(&input)?
+        };
+
+        Ok(PackReader {
+            input,
+            nr_blocks: header.nr_blocks,
+            compression: header.compression,
+            index,
+        })
+    }
+
+    /// The number of blocks in the unpacked metadata.
+    pub fn nr_blocks(&self) -> u64 {
+        self.nr_blocks
+    }
+
+    /// Identifies the chunk that would hold a block, if any.  The chunk
+    /// is only a candidate; the block may still be missing from it.
+    pub fn chunk_of(&self, b: u64) -> Option<u64> {
+        let i = self.index.partition_point(|e| e.begin <= b);
+        if i > 0 && b < self.index[i - 1].end {
+            Some(self.index[i - 1].offset)
+        } else {
+            None
+        }
+    }
+
+    /// Reads and decodes all the blocks of a chunk.
+    pub fn read_chunk(&self, chunk: u64) -> Result<Vec<(u64, Vec<u8>)>> {
+        let bytes = read_chunk_at(&self.input, chunk)?;
+        Ok(decode_chunk(self.compression, &bytes)?)
+    }
+
+    /// Reads a single block.  Blocks that weren't packed read back as
+    /// zeroes, as they would from an unpacked file.
+    pub fn read_block(&self, b: u64) -> Result<Vec<u8>> {
+        if b >= self.nr_blocks {
+            return Err(anyhow!(
+                "block {} is beyond the end of the metadata ({} blocks)",
+                b,
+                self.nr_blocks
+            ));
+        }
+
+        if let Some(chunk) = self.chunk_of(b) {
+            for (loc, block) in self.read_chunk(chunk)? {
+                if loc == b {
+                    return Ok(block);
+                }
+            }
+        }
+
+        Ok(vec![0; BLOCK_SIZE as usize])
+    }
+}
+
+//------------------------------------------
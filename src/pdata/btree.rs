@@ -17,7 +17,7 @@ pub use btree_error::*;
 
 //------------------------------------------
 
-const NODE_HEADER_SIZE: usize = 32;
+pub const NODE_HEADER_SIZE: usize = 32;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct NodeHeader {
@@ -211,6 +211,22 @@ pub struct NodeSummary {
     shared: bool,
 }
 
+impl NodeSummary {
+    /// Describes a pre-built node so it can be passed to push_nodes().
+    pub fn new(block: u64, key: u64, nr_entries: usize, shared: bool) -> NodeSummary {
+        NodeSummary {
+            block,
+            key,
+            nr_entries,
+            shared,
+        }
+    }
+
+    pub fn nr_entries(&self) -> usize {
+        self.nr_entries
+    }
+}
+
 impl<V: Pack + Unpack + Clone> NodeBuilder<V> {
     /// Create a new NodeBuilder
     pub fn new(nio: Box<dyn NodeIO<V>>, value_rc: Box<dyn RefCounter<V>>, shared: bool) -> Self {
@@ -354,6 +354,12 @@ pub enum BTreeError {
 
     ContextError(String),
 
+    // #[error("key out of range: {0}")]
+    KeyOutOfRange(u64),
+
+    // #[error("shared node key out of range: {0}")]
+    SharedKeyOutOfRange(u64),
+
     // #[error("keys: {0:?}")]
     KeyContext(KeyRange, Box<BTreeError>),
 
@@ -370,6 +376,10 @@ impl fmt::Display for BTreeError {
             BTreeError::NodeError(e) => write!(f, "node error: {}", e),
             BTreeError::ValueError(msg) => write!(f, "value error: {}", msg),
             BTreeError::ContextError(msg) => write!(f, "context error: {}", msg),
+            BTreeError::KeyOutOfRange(k) => write!(f, "key {} out of range", k),
+            BTreeError::SharedKeyOutOfRange(k) => {
+                write!(f, "key {} of shared node out of range", k)
+            }
             BTreeError::KeyContext(kr, be) => write!(f, "{}, effecting keys {}", be, kr),
             BTreeError::Aggregate(errs) => {
                 for e in errs {
@@ -400,6 +410,14 @@ pub fn context_err(path: &[u64], msg: &str) -> BTreeError {
     )
 }
 
+pub fn key_range_err(path: &[u64], kr: &KeyRange, k: u64) -> BTreeError {
+    BTreeError::Path(path.to_vec(), Box::new(BTreeError::KeyOutOfRange(k))).keys_context(kr)
+}
+
+pub fn shared_key_range_err(path: &[u64], kr: &KeyRange, k: u64) -> BTreeError {
+    BTreeError::Path(path.to_vec(), Box::new(BTreeError::SharedKeyOutOfRange(k))).keys_context(kr)
+}
+
 pub fn value_err(msg: String) -> BTreeError {
     BTreeError::ValueError(msg)
 }
@@ -94,18 +94,29 @@ impl<'a> LeafWalker<'a> {
             .read_many(&blocks[0..])
             .map_err(|_e| io_err(path))?;
 
+        // Carries on with the siblings of a broken node, so the visitor
+        // still sees every leaf that can be reached.
+        let mut errs = Vec::new();
         for (i, rb) in rblocks.into_iter().enumerate() {
             match rb {
                 Err(_) => {
-                    return Err(io_err(path).keys_context(&filtered_krs[i]));
+                    errs.push(io_err(path).keys_context(&filtered_krs[i]));
                 }
                 Ok(b) => {
-                    self.walk_node(depth - 1, path, visitor, &filtered_krs[i], &b, false)?;
+                    if let Err(e) =
+                        self.walk_node(depth - 1, path, visitor, &filtered_krs[i], &b, false)
+                    {
+                        errs.push(e.keys_context(&filtered_krs[i]));
+                    }
                 }
             }
         }
 
-        Ok(())
+        match errs.len() {
+            0 => Ok(()),
+            1 => Err(errs.pop().unwrap()),
+            _ => Err(aggregate_error(errs)),
+        }
     }
 
     fn walk_node_<LV, V>(
@@ -186,9 +197,19 @@ impl<'a> LeafWalker<'a> {
         let node = unpack_node::<V>(path, b.get_data(), self.ignore_non_fatal, is_root)?;
 
         match node {
+            // A broken child needn't fail the walk if any of its siblings
+            // can tell the depth.
             Internal { values, .. } => {
-                let n = self.get_depth::<V>(path, values[0], false)?;
-                Ok(n + 1)
+                let mut first_err = None;
+                for v in values {
+                    match self.get_depth::<V>(path, v, false) {
+                        Ok(n) => return Ok(n + 1),
+                        Err(e) => {
+                            first_err.get_or_insert(e);
+                        }
+                    }
+                }
+                Err(first_err.unwrap_or_else(|| node_err(path, NodeError::NumEntriesTooSmall)))
             }
             Leaf { .. } => Ok(0),
         }
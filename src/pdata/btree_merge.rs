@@ -4,12 +4,16 @@ use std::sync::{Arc, Mutex};
 use crate::io_engine::*;
 use crate::pdata::btree;
 use crate::pdata::btree::*;
+use crate::pdata::btree_builder::{self, *};
 use crate::pdata::btree_error::*;
 use crate::pdata::btree_walker::*;
 use crate::pdata::space_map::*;
 use crate::pdata::unpack::*;
 use crate::write_batcher::*;
 
+#[cfg(test)]
+mod tests;
+
 //------------------------------------------
 
 // The subtrees will often consist of a single under populated leaf node.  Given this
@@ -18,7 +22,6 @@ use crate::write_batcher::*;
 // ii) Merge leaf nodes where they can be packed more efficiently (non destructively to original subtrees).
 // iii) Build higher levels from scratch.  There are very few of these internal nodes compared to leaves anyway.
 
-#[allow(dead_code)]
 struct NodeSummary {
     block: u64,
     nr_entries: usize,
@@ -26,9 +29,7 @@ struct NodeSummary {
     key_high: u64, // inclusive
 }
 
-#[allow(dead_code)]
 struct LVInner {
-    last_key: Option<u64>,
     leaves: Vec<NodeSummary>,
 }
 
@@ -39,10 +40,7 @@ struct LeafVisitor {
 impl LeafVisitor {
     fn new() -> LeafVisitor {
         LeafVisitor {
-            inner: Mutex::new(LVInner {
-                last_key: None,
-                leaves: Vec::new(),
-            }),
+            inner: Mutex::new(LVInner { leaves: Vec::new() }),
         }
     }
 }
@@ -85,8 +83,13 @@ impl<V: Unpack> NodeVisitor<V> for LeafVisitor {
         Ok(())
     }
 
-    fn visit_again(&self, _path: &[u64], _b: u64) -> btree::Result<()> {
-        Ok(())
+    // A node that is reachable from more than one root implies the
+    // subtrees overlap.
+    fn visit_again(&self, path: &[u64], _b: u64) -> btree::Result<()> {
+        Err(context_err(
+            path,
+            "unable to merge btrees: sub trees overlap",
+        ))
     }
 
     fn end_walk(&self) -> btree::Result<()> {
@@ -98,7 +101,8 @@ pub type AEngine = Arc<dyn IoEngine + Send + Sync>;
 
 fn collect_leaves<V: Unpack>(engine: AEngine, roots: &[u64]) -> Result<Vec<NodeSummary>> {
     let lv = LeafVisitor::new();
-    let walker = BTreeWalker::new(engine, false);
+    // Under populated nodes are tolerated, they'll get repacked.
+    let walker = BTreeWalker::new(engine, true);
 
     let mut path = Vec::new();
     for root in roots {
@@ -110,28 +114,89 @@ fn collect_leaves<V: Unpack>(engine: AEngine, roots: &[u64]) -> Result<Vec<NodeS
 
 //------------------------------------------
 
-#[allow(clippy::extra_unused_type_parameters)]
-fn optimise_leaves<V: Unpack + Pack>(
-    _batcher: &mut WriteBatcher,
+// Identifies runs of adjacent, partially filled leaves that could be
+// packed into fewer nodes.  Returns a flag per leaf indicating whether
+// its values should be copied into new nodes, rather than the leaf
+// being shared.
+fn plan_repacking(lvs: &[NodeSummary], max_entries: usize) -> Vec<bool> {
+    let mut unpack = vec![false; lvs.len()];
+
+    let mut begin = 0;
+    while begin < lvs.len() {
+        if lvs[begin].nr_entries >= max_entries {
+            begin += 1;
+            continue;
+        }
+
+        let mut end = begin;
+        let mut nr_entries = 0;
+        while end < lvs.len() && lvs[end].nr_entries < max_entries {
+            nr_entries += lvs[end].nr_entries;
+            end += 1;
+        }
+
+        let nr_nodes = nr_entries.div_ceil(max_entries);
+        if nr_nodes < end - begin {
+            for u in unpack.iter_mut().take(end).skip(begin) {
+                *u = true;
+            }
+        }
+
+        begin = end;
+    }
+
+    unpack
+}
+
+// Full leaves are shared with the original subtrees.  Partially filled
+// leaves are either shared, or have their values copied into newly
+// packed nodes.  The NodeBuilder takes care of rebalancing any under
+// populated leaves that remain.
+fn optimise_leaves<V: Unpack + Pack + Clone>(
+    batcher: &mut WriteBatcher,
+    value_rc: Box<dyn RefCounter<V>>,
     lvs: Vec<NodeSummary>,
-) -> Result<Vec<NodeSummary>> {
-    // FIXME: implement
-    Ok(lvs)
+) -> Result<Vec<btree_builder::NodeSummary>> {
+    let unpack = plan_repacking(&lvs, calc_max_entries::<V>());
+
+    let nio = LeafIO {};
+    let mut builder = NodeBuilder::new(Box::new(LeafIO {}), value_rc, false);
+    for (l, unpack) in lvs.iter().zip(unpack) {
+        if unpack {
+            let (keys, values) = NodeIO::<V>::read(&nio, batcher, l.block)?;
+            for (k, v) in keys.into_iter().zip(values) {
+                builder.push_value(batcher, k, v)?;
+            }
+        } else {
+            let n = btree_builder::NodeSummary::new(l.block, l.key_low, l.nr_entries, true);
+            builder.push_nodes(batcher, &[n])?;
+        }
+    }
+
+    builder.complete(batcher)
 }
 
 //------------------------------------------
 
-pub fn merge<V: Unpack + Pack>(
+/// Merges several btrees, with disjoint and ordered key ranges, into a
+/// single new tree.  The original trees are left intact; any leaves
+/// they share with the new tree have their ref counts incremented in
+/// the space map, as do values copied into new leaves via 'value_rc'.
+/// Returns the root of the new tree.
+pub fn merge<V: Unpack + Pack + Clone>(
     engine: AEngine,
     sm: Arc<Mutex<dyn SpaceMap>>,
+    value_rc: Box<dyn RefCounter<V>>,
     roots: &[u64],
 ) -> Result<u64> {
     let lvs = collect_leaves::<V>(engine.clone(), roots)?;
 
     let mut batcher = WriteBatcher::new(engine, sm, 256);
-    let _lvs = optimise_leaves::<V>(&mut batcher, lvs)?;
+    let leaves = optimise_leaves::<V>(&mut batcher, value_rc, lvs)?;
+    let root = build_btree(&mut batcher, leaves)?;
+    batcher.flush()?;
 
-    todo!();
+    Ok(root)
 }
 
 //------------------------------------------
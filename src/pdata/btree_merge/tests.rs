@@ -0,0 +1,135 @@
+use super::*;
+
+use std::collections::BTreeMap;
+
+use crate::io_engine::core::CoreIoEngine;
+use crate::pdata::btree_builder::test_utils::*;
+
+//------------------------------------------
+
+struct MergeTests {
+    engine: AEngine,
+    sm: Arc<Mutex<dyn SpaceMap>>,
+}
+
+impl MergeTests {
+    fn new(nr_blocks: u64) -> MergeTests {
+        let engine = Arc::new(CoreIoEngine::new(nr_blocks));
+        let sm = Arc::new(Mutex::new(CoreSpaceMap::<u32>::new(nr_blocks)));
+        MergeTests { engine, sm }
+    }
+
+    fn build_tree(&self, mappings: &[(u64, u64)]) -> u64 {
+        let mut w = WriteBatcher::new(self.engine.clone(), self.sm.clone(), 16);
+        let layout = build_btree_from_mappings(&mut w, mappings);
+        layout.root().block
+    }
+
+    fn merge(&self, roots: &[u64]) -> Result<u64> {
+        merge::<u64>(
+            self.engine.clone(),
+            self.sm.clone(),
+            Box::new(NoopRC {}),
+            roots,
+        )
+    }
+
+    fn verify_mappings(&self, root: u64, mappings: &[(u64, u64)]) {
+        let actual =
+            btree_to_map::<u64>(&mut Vec::new(), self.engine.clone(), false, root).unwrap();
+        let expected = mappings.iter().cloned().collect::<BTreeMap<u64, u64>>();
+        assert_eq!(actual, expected);
+    }
+
+    fn nr_leaves(&self, root: u64) -> usize {
+        collect_leaves::<u64>(self.engine.clone(), &[root])
+            .unwrap()
+            .len()
+    }
+}
+
+fn mk_mappings(begin: u64, end: u64) -> Vec<(u64, u64)> {
+    (begin..end).map(|k| (k, k * 2)).collect()
+}
+
+//------------------------------------------
+
+#[test]
+fn merge_disjoint_trees() {
+    let t = MergeTests::new(1024);
+    let max_entries = calc_max_entries::<u64>() as u64;
+
+    let m1 = mk_mappings(0, max_entries * 5);
+    let m2 = mk_mappings(max_entries * 10, max_entries * 13 + 7);
+    let roots = [t.build_tree(&m1), t.build_tree(&m2)];
+
+    let root = t.merge(&roots).unwrap();
+
+    let mut all = m1;
+    all.extend(m2);
+    t.verify_mappings(root, &all);
+
+    // the original trees are untouched
+    t.verify_mappings(roots[0], &all[..max_entries as usize * 5]);
+}
+
+#[test]
+fn merge_packs_fragmented_leaves() {
+    let t = MergeTests::new(1024);
+    let max_entries = calc_max_entries::<u64>() as u64;
+
+    // Lots of single leaf trees, each a third full.
+    let mut roots = Vec::new();
+    let mut all = Vec::new();
+    for i in 0..30 {
+        let m = mk_mappings(i * max_entries, i * max_entries + max_entries / 3);
+        roots.push(t.build_tree(&m));
+        all.extend(m);
+    }
+
+    let root = t.merge(&roots).unwrap();
+    t.verify_mappings(root, &all);
+    assert_eq!(t.nr_leaves(root), 10);
+}
+
+#[test]
+fn merge_shares_full_leaves() {
+    let t = MergeTests::new(1024);
+    let max_entries = calc_max_entries::<u64>() as u64;
+
+    let m = mk_mappings(0, max_entries * 4);
+    let orig = t.build_tree(&m);
+    let orig_leaves = collect_leaves::<u64>(t.engine.clone(), &[orig]).unwrap();
+
+    let root = t.merge(&[orig]).unwrap();
+    t.verify_mappings(root, &m);
+
+    let leaves = collect_leaves::<u64>(t.engine.clone(), &[root]).unwrap();
+    assert_eq!(leaves.len(), orig_leaves.len());
+
+    let sm = t.sm.lock().unwrap();
+    for (l, o) in leaves.iter().zip(orig_leaves.iter()) {
+        assert_eq!(l.block, o.block);
+        assert_eq!(sm.get(l.block).unwrap(), 2);
+    }
+}
+
+#[test]
+fn merge_out_of_order_trees_fails() {
+    let t = MergeTests::new(1024);
+
+    let r1 = t.build_tree(&mk_mappings(100, 200));
+    let r2 = t.build_tree(&mk_mappings(0, 100));
+    assert!(t.merge(&[r1, r2]).is_err());
+}
+
+#[test]
+fn merge_overlapping_trees_fails() {
+    let t = MergeTests::new(1024);
+
+    let r1 = t.build_tree(&mk_mappings(0, 200));
+    let r2 = t.build_tree(&mk_mappings(150, 300));
+    assert!(t.merge(&[r1, r2]).is_err());
+}
+
+//------------------------------------------
@@ -31,11 +31,36 @@ pub trait NodeVisitor<V: Unpack> {
     fn end_walk(&self) -> Result<()>;
 }
 
+// The lowest and highest keys in a subtree
+type KeySpan = (u64, u64);
+
+fn check_key_span(path: &[u64], kr: &KeyRange, span: &KeySpan, shared: bool) -> Result<()> {
+    let bad_key = if kr.start.is_some_and(|start| span.0 < start) {
+        Some(span.0)
+    } else if kr.end.is_some_and(|end| span.1 >= end) {
+        Some(span.1)
+    } else {
+        None
+    };
+
+    match bad_key {
+        Some(k) if shared => Err(shared_key_range_err(path, kr, k)),
+        Some(k) => Err(key_range_err(path, kr, k)),
+        None => Ok(()),
+    }
+}
+
 #[derive(Clone)]
 pub struct BTreeWalker {
     engine: Arc<dyn IoEngine + Send + Sync>,
     sm: Arc<Mutex<dyn SpaceMap + Send + Sync>>,
     fails: Arc<Mutex<BTreeMap<u64, BTreeError>>>,
+
+    // Key spans of the shared subtrees, for checking them against the key
+    // ranges of the other paths reaching them.  A span is only worked out
+    // when a node is first reached again, so the unshared nodes, which are
+    // the bulk of most trees, don't take up any memory.
+    spans: Arc<Mutex<BTreeMap<u64, KeySpan>>>,
     ignore_non_fatal: bool,
 }
 
@@ -46,6 +71,7 @@ impl BTreeWalker {
             engine,
             sm: Arc::new(Mutex::new(RestrictedSpaceMap::new(nr_blocks))),
             fails: Arc::new(Mutex::new(BTreeMap::new())),
+            spans: Arc::new(Mutex::new(BTreeMap::new())),
             ignore_non_fatal,
         };
         r
@@ -65,6 +91,7 @@ impl BTreeWalker {
             engine,
             sm,
             fails: Arc::new(Mutex::new(BTreeMap::new())),
+            spans: Arc::new(Mutex::new(BTreeMap::new())),
             ignore_non_fatal,
         })
     }
@@ -80,6 +107,103 @@ impl BTreeWalker {
         fails.insert(b, err);
     }
 
+    // Follows the first or the last children down to a leaf, returning the
+    // lowest or the highest key of the subtree.
+    fn edge_key<V: Unpack>(&self, path: &[u64], b: u64, first: bool) -> Result<Option<u64>> {
+        let mut loc = b;
+        let mut seen = Vec::new();
+        loop {
+            if seen.contains(&loc) {
+                return Err(context_err(path, "cycle in the tree"));
+            }
+            seen.push(loc);
+
+            let blk = self.engine.read(loc).map_err(|_| io_err(path))?;
+            let node = check_and_unpack_node::<V>(&blk, self.ignore_non_fatal, true)
+                .map_err(|e| node_err(path, e))?;
+            let next = match node {
+                Node::Internal { values, .. } if first => values.first().cloned(),
+                Node::Internal { values, .. } => values.last().cloned(),
+                Node::Leaf { keys, .. } if first => return Ok(keys.first().cloned()),
+                Node::Leaf { keys, .. } => return Ok(keys.last().cloned()),
+            };
+            match next {
+                Some(n) => loc = n,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    // Returns the key span of a subtree, reading it from the disk the
+    // first time.  The node may not have been completely walked yet, as
+    // another thread can still be working on it.
+    fn get_span<V: Unpack>(&self, path: &[u64], b: u64) -> Result<Option<KeySpan>> {
+        if let Some(span) = self.spans.lock().unwrap().get(&b) {
+            return Ok(Some(*span));
+        }
+
+        let low = self.edge_key::<V>(path, b, true)?;
+        let high = self.edge_key::<V>(path, b, false)?;
+        match (low, high) {
+            (Some(low), Some(high)) => {
+                let span = (low, high);
+                self.spans.lock().unwrap().insert(b, span);
+                Ok(Some(span))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Checks a node visited before is within the key range of the new path.
+    fn check_shared<V: Unpack>(&self, path: &[u64], kr: &KeyRange, b: u64) -> Result<()> {
+        let mut path = path.to_vec();
+        if path.contains(&b) {
+            // the node is one of its own ancestors
+            path.push(b);
+            return Err(context_err(&path, "cycle in the tree"));
+        }
+        path.push(b);
+
+        if let Some(span) = self.get_span::<V>(&path, b)? {
+            check_key_span(&path, kr, &span, true)?;
+        }
+        Ok(())
+    }
+
+    // Revisits a node that has already been checked.
+    fn revisit<NV, V>(&self, path: &[u64], visitor: &NV, kr: &KeyRange, b: u64) -> Result<()>
+    where
+        NV: NodeVisitor<V>,
+        V: Unpack,
+    {
+        match self.failed(b) {
+            None => {
+                // ... it was clean, but might not fit in this path.
+                self.check_shared::<V>(path, kr, b)?;
+
+                // ... and the visitor might not be happy
+                visitor.visit_again(path, b)
+            }
+            Some(e) => {
+                // ... there was an error
+                // TODO: revisit the node if the key context is different
+                Err(e)
+            }
+        }
+    }
+
+    // Checks the keys of a newly read node are within the key range given
+    // by the parent.
+    fn check_keys(&self, path: &[u64], kr: &KeyRange, b: u64, keys: &[u64]) -> Result<()> {
+        if let (Some(first), Some(last)) = (keys.first(), keys.last()) {
+            if let Err(e) = check_key_span(path, kr, &(*first, *last), false) {
+                self.set_fail(b, e.clone());
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
     // Atomically increments the ref count, and returns the _old_ count.
     fn sm_inc(&self, b: u64) -> Result<u32> {
         let mut sm = self.sm.lock().unwrap();
@@ -120,6 +244,7 @@ impl BTreeWalker {
 
         let mut blocks = Vec::with_capacity(bs.len());
         let mut filtered_krs = Vec::with_capacity(krs.len());
+        let mut dups = Vec::new();
         for i in 0..bs.len() {
             let rc = match self.sm_inc(bs[i]) {
                 Ok(n) => n,
@@ -132,22 +257,13 @@ impl BTreeWalker {
                 // Node not yet seen
                 blocks.push(bs[i]);
                 filtered_krs.push(krs[i].clone());
-            } else {
-                // This node has already been checked ...
-                match self.failed(bs[i]) {
-                    None => {
-                        // ... it was clean.
-                        if let Err(e) = visitor.visit_again(path, bs[i]) {
-                            // ... but the visitor isn't happy
-                            errs.push(e.clone());
-                        }
-                    }
-                    Some(e) => {
-                        // ... there was an error
-                        // TODO: revisit the node if the key context is different
-                        errs.push(e.clone());
-                    }
-                }
+            } else if blocks.contains(&bs[i]) {
+                // A sibling refers to the same node, so it can only be
+                // revisited once that sibling has been walked.
+                dups.push(i);
+            } else if let Err(e) = self.revisit(path, visitor, &krs[i], bs[i]) {
+                // This node has already been checked, but there's a problem
+                errs.push(e);
             }
         }
 
@@ -180,6 +296,12 @@ impl BTreeWalker {
             }
         }
 
+        for i in dups {
+            if let Err(e) = self.revisit(path, visitor, &krs[i], bs[i]) {
+                errs.push(e);
+            }
+        }
+
         errs
     }
 
@@ -206,11 +328,13 @@ impl BTreeWalker {
             }
         };
 
+        self.check_keys(path, kr, b.loc, node.get_keys())?;
+
         match node {
             Internal { keys, values, .. } => {
                 let krs = split_key_ranges(path, kr, &keys)?;
                 let errs = self.walk_nodes(path, visitor, &krs, &values);
-                return self.build_aggregate(b.loc, errs); // implicitly calls set_fail()
+                self.build_aggregate(b.loc, errs)?; // implicitly calls set_fail()
             }
             Leaf {
                 header,
@@ -302,11 +426,13 @@ where
         }
     };
 
+    w.check_keys(path, kr, b.loc, node.get_keys())?;
+
     match node {
         Internal { keys, values, .. } => {
             let krs = split_key_ranges(path, kr, &keys)?;
             let errs = walk_nodes_threaded(w.clone(), path, pool, visitor, &krs, &values);
-            return w.build_aggregate(b.loc, errs); // implicitly calls set_fail()
+            w.build_aggregate(b.loc, errs)?; // implicitly calls set_fail()
         }
         Leaf {
             header,
@@ -356,6 +482,7 @@ where
 
     let mut blocks = Vec::with_capacity(bs.len());
     let mut filtered_krs = Vec::with_capacity(krs.len());
+    let mut dups = Vec::new();
     for i in 0..bs.len() {
         let rc = match w.sm_inc(bs[i]) {
             Ok(n) => n,
@@ -368,22 +495,13 @@ where
             // Node not yet seen
             blocks.push(bs[i]);
             filtered_krs.push(krs[i].clone());
-        } else {
-            // This node has already been checked ...
-            match w.failed(bs[i]) {
-                None => {
-                    // ... it was clean.
-                    if let Err(e) = visitor.visit_again(path, bs[i]) {
-                        // ... but the visitor isn't happy
-                        errs.push(e.clone());
-                    }
-                }
-                Some(e) => {
-                    // ... there was an error
-                    // TODO: revisit the node if the key context is different
-                    errs.push(e.clone());
-                }
-            }
+        } else if blocks.contains(&bs[i]) {
+            // A sibling refers to the same node, so it can only be
+            // revisited once that sibling has been walked.
+            dups.push(i);
+        } else if let Err(e) = w.revisit(path, visitor.as_ref(), &krs[i], bs[i]) {
+            // This node has already been checked, but there's a problem
+            errs.push(e);
         }
     }
 
@@ -431,6 +549,12 @@ where
         }
     }
 
+    for i in dups {
+        if let Err(e) = w.revisit(path, visitor.as_ref(), &krs[i], bs[i]) {
+            errs.push(e);
+        }
+    }
+
     errs
 }
 
@@ -4,6 +4,7 @@ use mockall::*;
 use rangemap::RangeSet;
 use std::ops::Range;
 
+use crate::checksum;
 use crate::io_engine::core::*;
 use crate::pdata::btree_builder::test_utils::*;
 use crate::write_batcher::WriteBatcher;
@@ -351,3 +352,107 @@ fn walk_tree_with_damaged_leaves_and_internals() {
 }
 
 //------------------------------------------
+// key range checks
+
+fn rewrite_node<V: Pack + Unpack>(engine: &dyn IoEngine, loc: u64, f: impl FnOnce(&mut Node<V>)) {
+    let b = engine.read(loc).unwrap();
+    let mut node = unpack_node::<V>(&[0], b.get_data(), true, false).unwrap();
+    f(&mut node);
+    pack_node(&node, &mut std::io::Cursor::new(b.get_data())).unwrap();
+    checksum::write_checksum(b.get_data(), checksum::BT::NODE).unwrap();
+    engine.write(&b).unwrap();
+}
+
+fn find_error(e: &BTreeError, pred: &dyn Fn(&BTreeError) -> bool) -> bool {
+    if pred(e) {
+        return true;
+    }
+
+    match e {
+        BTreeError::KeyContext(_, e) | BTreeError::Path(_, e) => find_error(e, pred),
+        BTreeError::Aggregate(errs) => errs.iter().any(|e| find_error(e, pred)),
+        _ => false,
+    }
+}
+
+fn build_test_tree(engine: Arc<dyn IoEngine + Send + Sync>) -> BTreeLayout {
+    let mut t = BTreeWalkerTests::<u32>::new(engine);
+    let mappings = (0..100000u64).zip(1234u32..).collect::<Vec<(u64, u32)>>();
+    t.build_btree(mappings);
+    t.layout.unwrap()
+}
+
+#[test]
+fn walk_tree_with_a_key_below_the_parent_key() {
+    let engine = Arc::new(CoreIoEngine::new(320));
+    let layout = build_test_tree(engine.clone());
+
+    let leaf = &layout.leaves()[1];
+    let start = leaf.key_range.start.unwrap();
+    rewrite_node::<u32>(engine.as_ref(), leaf.block, |n| {
+        if let Node::Leaf { keys, .. } = n {
+            keys[0] = start - 1;
+        }
+    });
+
+    let walker = BTreeWalker::new(engine, false);
+    let visitor = NoopVisitor::<u32>::new();
+    let e = walker
+        .walk(&mut Vec::new(), &visitor, layout.root().block)
+        .unwrap_err();
+    assert!(find_error(&e, &|e| matches!(
+        e,
+        BTreeError::KeyOutOfRange(k) if *k == start - 1
+    )));
+}
+
+#[test]
+fn walk_tree_with_a_shared_node_out_of_range() {
+    let engine = Arc::new(CoreIoEngine::new(320));
+    let layout = build_test_tree(engine.clone());
+
+    // Let the second child points to the first one, which then is visited
+    // again with a key range it doesn't fit in.
+    let parent = &layout.nodes(1)[0];
+    rewrite_node::<u64>(engine.as_ref(), parent.block, |n| {
+        if let Node::Internal { values, .. } = n {
+            values[1] = values[0];
+        }
+    });
+
+    let walker = Arc::new(BTreeWalker::new(engine, false));
+    let visitor = Arc::new(NoopVisitor::<u32>::new());
+    let pool = ThreadPool::new(4);
+    let e =
+        walk_threaded(&mut Vec::new(), walker, &pool, visitor, layout.root().block).unwrap_err();
+    assert!(find_error(&e, &|e| matches!(
+        e,
+        BTreeError::SharedKeyOutOfRange(_)
+    )));
+}
+
+#[test]
+fn walk_tree_with_a_cycle_to_the_root() {
+    let engine = Arc::new(CoreIoEngine::new(320));
+    let layout = build_test_tree(engine.clone());
+
+    // Let the last child of the first internal node point back to the root,
+    // which spans the keys of the whole tree.
+    let root = layout.root().block;
+    let parent = &layout.nodes(1)[0];
+    rewrite_node::<u64>(engine.as_ref(), parent.block, |n| {
+        if let Node::Internal { values, .. } = n {
+            *values.last_mut().unwrap() = root;
+        }
+    });
+
+    let walker = BTreeWalker::new(engine, false);
+    let visitor = NoopVisitor::<u32>::new();
+    let e = walker.walk(&mut Vec::new(), &visitor, root).unwrap_err();
+    assert!(find_error(&e, &|e| matches!(
+        e,
+        BTreeError::ContextError(msg) if msg.contains("cycle")
+    )));
+}
+
+//------------------------------------------
@@ -0,0 +1,230 @@
+use serde::Serialize;
+use std::io::Write;
+use std::sync::Mutex;
+
+use crate::pdata::btree_error::*;
+
+//------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    Checksum,
+    Io,
+    Unpack,
+    KeyOrder,
+    RefCount,
+    Leak,
+    MappedCount,
+    Value,
+    Other,
+}
+
+impl From<&NodeError> for IssueKind {
+    fn from(e: &NodeError) -> Self {
+        match e {
+            NodeError::IoError => IssueKind::Io,
+            NodeError::ChecksumError => IssueKind::Checksum,
+            NodeError::KeysOutOfOrder => IssueKind::KeyOrder,
+            _ => IssueKind::Unpack,
+        }
+    }
+}
+
+// A single problem found by a checker.  The block is the metadata block
+// the problem was found in, while thin_begin and thin_end give the range
+// of thin blocks affected, with the end being one-past-the-end.  Problems
+// in the data space map refer to a data block instead.
+#[derive(Clone, Debug, Serialize)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub fatal: bool,
+    pub block: Option<u64>,
+    pub path: Vec<u64>,
+    pub dev_id: Option<u64>,
+    pub thin_begin: Option<u64>,
+    pub thin_end: Option<u64>,
+    pub data_block: Option<u64>,
+    pub message: String,
+}
+
+impl Issue {
+    pub fn new(kind: IssueKind, fatal: bool, message: String) -> Issue {
+        Issue {
+            kind,
+            fatal,
+            block: None,
+            path: Vec::new(),
+            dev_id: None,
+            thin_begin: None,
+            thin_end: None,
+            data_block: None,
+            message,
+        }
+    }
+
+    // Sets the path and the block it leads to
+    pub fn at(mut self, path: &[u64]) -> Issue {
+        self.block = path.last().cloned();
+        self.path = path.to_vec();
+        self
+    }
+
+    pub fn block(mut self, b: u64) -> Issue {
+        self.block = Some(b);
+        self
+    }
+
+    pub fn keys(mut self, kr: &KeyRange) -> Issue {
+        self.thin_begin = kr.start;
+        self.thin_end = kr.end;
+        self
+    }
+
+    pub fn dev_id(mut self, dev_id: Option<u64>) -> Issue {
+        self.dev_id = dev_id;
+        self
+    }
+
+    pub fn data_block(mut self, b: u64) -> Issue {
+        self.data_block = Some(b);
+        self
+    }
+}
+
+//------------------------------------------
+
+fn flatten_btree_error(
+    e: &BTreeError,
+    path: &[u64],
+    kr: &KeyRange,
+    fatal: bool,
+    issues: &mut Vec<Issue>,
+) {
+    match e {
+        BTreeError::NodeError(ne) => {
+            issues.push(
+                Issue::new(IssueKind::from(ne), fatal, ne.to_string())
+                    .at(path)
+                    .keys(kr),
+            );
+        }
+        BTreeError::ValueError(msg) => {
+            issues.push(
+                Issue::new(IssueKind::Value, fatal, msg.clone())
+                    .at(path)
+                    .keys(kr),
+            );
+        }
+        BTreeError::KeyOutOfRange(_) | BTreeError::SharedKeyOutOfRange(_) => {
+            issues.push(
+                Issue::new(IssueKind::KeyOrder, fatal, e.to_string())
+                    .at(path)
+                    .keys(kr),
+            );
+        }
+        BTreeError::ContextError(msg) => {
+            issues.push(
+                Issue::new(IssueKind::Other, fatal, msg.clone())
+                    .at(path)
+                    .keys(kr),
+            );
+        }
+        BTreeError::KeyContext(kr, e) => flatten_btree_error(e, path, kr, fatal, issues),
+        BTreeError::Aggregate(errs) => {
+            for e in errs {
+                flatten_btree_error(e, path, kr, fatal, issues);
+            }
+        }
+        BTreeError::Path(path, e) => flatten_btree_error(e, path, kr, fatal, issues),
+    }
+}
+
+/// Breaks a btree error down into the individual node errors it carries,
+/// along with the paths and key ranges they were found at.
+pub fn issues_from_btree_error(e: &BTreeError, fatal: bool) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    flatten_btree_error(e, &[], &KeyRange::new(), fatal, &mut issues);
+    issues
+}
+
+//------------------------------------------
+
+/// Collects the issues found by the checkers, in the order found.
+#[derive(Default)]
+pub struct IssueLog {
+    issues: Mutex<Vec<Issue>>,
+}
+
+impl IssueLog {
+    pub fn new() -> IssueLog {
+        IssueLog::default()
+    }
+
+    pub fn add(&self, issue: Issue) {
+        self.issues.lock().unwrap().push(issue);
+    }
+
+    pub fn extend(&self, issues: Vec<Issue>) {
+        self.issues.lock().unwrap().extend(issues);
+    }
+
+    pub fn has_fatal(&self) -> bool {
+        self.issues.lock().unwrap().iter().any(|i| i.fatal)
+    }
+
+    pub fn issues(&self) -> Vec<Issue> {
+        self.issues.lock().unwrap().clone()
+    }
+
+    pub fn write_json(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        #[derive(Serialize)]
+        struct Doc<'a> {
+            issues: &'a [Issue],
+        }
+
+        let issues = self.issues.lock().unwrap();
+        serde_json::to_writer_pretty(&mut *w, &Doc { issues: &issues })
+            .map_err(std::io::Error::from)?;
+        writeln!(w)
+    }
+}
+
+//------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_issues_from_nested_btree_error() {
+        let kr = KeyRange {
+            start: Some(100),
+            end: Some(200),
+        };
+        let e = aggregate_error(vec![
+            node_err(&[0, 5, 9], NodeError::ChecksumError).keys_context(&kr),
+            io_err(&[0, 5, 10]),
+            value_err("bad value".to_string()),
+        ]);
+
+        let issues = issues_from_btree_error(&e, true);
+        assert_eq!(issues.len(), 3);
+
+        assert_eq!(issues[0].kind, IssueKind::Checksum);
+        assert_eq!(issues[0].block, Some(9));
+        assert_eq!(issues[0].path, vec![0, 5, 9]);
+        assert_eq!(issues[0].thin_begin, Some(100));
+        assert_eq!(issues[0].thin_end, Some(200));
+
+        assert_eq!(issues[1].kind, IssueKind::Io);
+        assert_eq!(issues[1].block, Some(10));
+        assert_eq!(issues[1].thin_begin, None);
+
+        assert_eq!(issues[2].kind, IssueKind::Value);
+        assert_eq!(issues[2].block, None);
+        assert!(issues.iter().all(|i| i.fatal));
+    }
+}
+
+//------------------------------------------
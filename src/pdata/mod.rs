@@ -9,5 +9,6 @@ pub mod btree_iterator;
 pub mod btree_leaf_walker;
 pub mod btree_merge;
 pub mod btree_walker;
+pub mod issues;
 pub mod space_map;
 pub mod unpack;
@@ -2,6 +2,7 @@ use anyhow::{anyhow, ensure, Result};
 use fixedbitset::FixedBitSet;
 use num_traits::Bounded;
 use std::boxed::Box;
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::sync::{Arc, Mutex};
 
@@ -181,6 +182,167 @@ pub fn core_sm_without_mutex(nr_entries: u64, max_count: u32) -> Box<dyn SpaceMa
 
 //------------------------------------------
 
+// This in core space map stores the ref counts in 4-bit counters, two
+// to a byte, and moves the counts that don't fit into a separate map.
+// Typically only a tiny portion of the data blocks reach a high ref
+// count, so this takes far less memory than a CoreSpaceMap for a large
+// pool.
+pub struct CompactSpaceMap {
+    nr_blocks: u64,
+    nr_allocated: u64,
+    alloc_begin: u64,
+    counters: Vec<u8>,
+    overflow: HashMap<u64, u32>,
+}
+
+const COUNTER_BITS: u64 = 4;
+const COUNTER_MASK: u8 = (1 << COUNTER_BITS) - 1;
+
+// The counter value indicating the count is held in the overflow map
+const OVERFLOW_COUNT: u8 = COUNTER_MASK;
+
+impl CompactSpaceMap {
+    pub fn new(nr_entries: u64) -> CompactSpaceMap {
+        CompactSpaceMap {
+            nr_blocks: nr_entries,
+            nr_allocated: 0,
+            alloc_begin: 0,
+            counters: vec![0; ((nr_entries + 1) >> 1) as usize],
+            overflow: HashMap::new(),
+        }
+    }
+
+    /// Returns the number of blocks with their counts in the overflow map
+    pub fn get_nr_overflowed(&self) -> u64 {
+        self.overflow.len() as u64
+    }
+
+    #[inline]
+    fn check_index_out_of_bounds(&self, b: u64) -> Result<()> {
+        if b >= self.nr_blocks {
+            return Err(anyhow!("block out of bounds"));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn get_counter(&self, b: u64) -> u8 {
+        let shift = (b & 1) * COUNTER_BITS;
+        (self.counters[(b >> 1) as usize] >> shift) & COUNTER_MASK
+    }
+
+    #[inline]
+    fn set_counter(&mut self, b: u64, c: u8) {
+        let shift = (b & 1) * COUNTER_BITS;
+        let byte = &mut self.counters[(b >> 1) as usize];
+        *byte = (*byte & !(COUNTER_MASK << shift)) | (c << shift);
+    }
+}
+
+impl SpaceMap for CompactSpaceMap {
+    fn get_nr_blocks(&self) -> Result<u64> {
+        Ok(self.nr_blocks)
+    }
+
+    fn get_nr_allocated(&self) -> Result<u64> {
+        Ok(self.nr_allocated)
+    }
+
+    fn get(&self, b: u64) -> Result<u32> {
+        self.check_index_out_of_bounds(b)?;
+
+        let c = self.get_counter(b);
+        if c == OVERFLOW_COUNT {
+            Ok(*self.overflow.get(&b).unwrap())
+        } else {
+            Ok(c as u32)
+        }
+    }
+
+    fn set(&mut self, b: u64, v: u32) -> Result<u32> {
+        let old = self.get(b)?;
+
+        if v >= OVERFLOW_COUNT as u32 {
+            self.set_counter(b, OVERFLOW_COUNT);
+            self.overflow.insert(b, v);
+        } else {
+            if old >= OVERFLOW_COUNT as u32 {
+                self.overflow.remove(&b);
+            }
+            self.set_counter(b, v as u8);
+        }
+
+        if old == 0 && v != 0 {
+            self.nr_allocated += 1;
+        } else if old != 0 && v == 0 {
+            self.nr_allocated -= 1;
+        }
+
+        Ok(old)
+    }
+
+    fn inc(&mut self, begin: u64, len: u64) -> Result<()> {
+        if begin + len > self.nr_blocks {
+            return Err(anyhow!("block out of bounds"));
+        }
+
+        for b in begin..(begin + len) {
+            match self.get_counter(b) {
+                0 => {
+                    self.nr_allocated += 1;
+                    self.set_counter(b, 1);
+                }
+                OVERFLOW_COUNT => {
+                    let v = self.overflow.get_mut(&b).unwrap();
+                    ensure!(*v < u32::MAX);
+                    *v += 1;
+                }
+                n if n == OVERFLOW_COUNT - 1 => {
+                    self.set_counter(b, OVERFLOW_COUNT);
+                    self.overflow.insert(b, OVERFLOW_COUNT as u32);
+                }
+                n => self.set_counter(b, n + 1),
+            }
+        }
+        Ok(())
+    }
+
+    fn alloc(&mut self) -> Result<Option<u64>> {
+        let mut b = self.find_free(self.alloc_begin, self.nr_blocks)?;
+        if b.is_none() {
+            b = self.find_free(0, self.alloc_begin)?;
+            if b.is_none() {
+                return Ok(None);
+            }
+        }
+
+        self.set_counter(b.unwrap(), 1);
+        self.nr_allocated += 1;
+        self.alloc_begin = b.unwrap() + 1;
+
+        Ok(b)
+    }
+
+    fn find_free(&mut self, begin: u64, end: u64) -> Result<Option<u64>> {
+        for b in begin..end {
+            if self.get_counter(b) == 0 {
+                return Ok(Some(b));
+            }
+        }
+        Ok(None)
+    }
+
+    fn get_alloc_begin(&self) -> Result<u64> {
+        Ok(self.alloc_begin)
+    }
+}
+
+pub fn compact_sm(nr_entries: u64) -> Arc<Mutex<dyn SpaceMap + Send + Sync>> {
+    Arc::new(Mutex::new(CompactSpaceMap::new(nr_entries)))
+}
+
+//------------------------------------------
+
 // This in core space map can only count to one, useful when walking
 // btrees when we want to avoid visiting a node more than once, but
 // aren't interested in counting how many times we've visited.
@@ -6,6 +6,7 @@ use crate::checksum;
 use crate::io_engine::IoEngine;
 use crate::pdata::btree::{self, *};
 use crate::pdata::btree_walker::*;
+use crate::pdata::issues::*;
 use crate::pdata::space_map::common::*;
 use crate::pdata::space_map::metadata::*;
 use crate::pdata::space_map::*;
@@ -21,14 +22,27 @@ pub struct BitmapLeak {
 
 //------------------------------------------
 
+// Data blocks are told apart from the metadata blocks in the issues
+fn block_issue(kind: &str, issue_kind: IssueKind, fatal: bool, b: u64, msg: String) -> Issue {
+    let issue = Issue::new(issue_kind, fatal, msg);
+    if kind == "data" {
+        issue.data_block(b)
+    } else {
+        issue.block(b)
+    }
+}
+
+//------------------------------------------
+
 struct OverflowChecker<'a> {
     kind: &'a str,
     sm: &'a dyn SpaceMap,
+    issues: &'a IssueLog,
 }
 
 impl<'a> OverflowChecker<'a> {
-    fn new(kind: &'a str, sm: &'a dyn SpaceMap) -> OverflowChecker<'a> {
-        OverflowChecker { kind, sm }
+    fn new(kind: &'a str, sm: &'a dyn SpaceMap, issues: &'a IssueLog) -> OverflowChecker<'a> {
+        OverflowChecker { kind, sm, issues }
     }
 }
 
@@ -46,10 +60,18 @@ impl<'a> NodeVisitor<u32> for OverflowChecker<'a> {
             let v = values[n];
             let expected = self.sm.get(k).unwrap();
             if expected != v {
-                return Err(value_err(format!(
+                let msg = format!(
                     "Bad reference count for {} block {}.  Expected {}, but space map contains {}.",
                     self.kind, k, expected, v
-                )));
+                );
+                self.issues.add(block_issue(
+                    self.kind,
+                    IssueKind::RefCount,
+                    true,
+                    k,
+                    msg.clone(),
+                ));
+                return Err(value_err(msg));
             }
         }
 
@@ -82,6 +104,7 @@ fn inc_entries(sm: &ASpaceMap, entries: &[IndexEntry]) -> Result<()> {
 fn check_low_ref_counts(
     engine: Arc<dyn IoEngine + Send + Sync>,
     report: Arc<Report>,
+    issues: &IssueLog,
     kind: &str,
     entries: Vec<IndexEntry>,
     sm: ASpaceMap,
@@ -110,10 +133,12 @@ fn check_low_ref_counts(
             }
             Ok(b) => {
                 if checksum::metadata_block_type(b.get_data()) != checksum::BT::BITMAP {
-                    report.fatal(&format!(
+                    let msg = format!(
                         "Index entry points to block ({}) that isn't a bitmap",
                         b.loc
-                    ));
+                    );
+                    report.fatal(&msg);
+                    issues.add(Issue::new(IssueKind::Checksum, true, msg).block(b.loc));
                     failed = true;
 
                     // FIXME: revert the ref-count at b.loc?
@@ -121,7 +146,7 @@ fn check_low_ref_counts(
 
                 let bitmap = unpack::<Bitmap>(b.get_data())?;
                 let first_blocknr = blocknr;
-                let mut contains_leak = false;
+                let mut nr_leaks = 0;
                 for e in bitmap.entries.iter() {
                     if blocknr >= nr_blocks {
                         break;
@@ -131,26 +156,53 @@ fn check_low_ref_counts(
                         BitmapEntry::Small(actual) => {
                             let expected = sm.get(blocknr)?;
                             if *actual == 1 && expected == 0 {
-                                leaks += 1;
-                                contains_leak = true;
+                                nr_leaks += 1;
                             } else if *actual != expected as u8 {
-                                report.fatal(&format!("Bad reference count for {} block {}.  Expected {}, but space map contains {}.",
-                                          kind, blocknr, expected, actual));
+                                let msg = format!("Bad reference count for {} block {}.  Expected {}, but space map contains {}.",
+                                          kind, blocknr, expected, actual);
+                                report.fatal(&msg);
+                                issues.add(block_issue(
+                                    kind,
+                                    IssueKind::RefCount,
+                                    true,
+                                    blocknr,
+                                    msg,
+                                ));
                                 failed = true;
                             }
                         }
                         BitmapEntry::Overflow => {
                             let expected = sm.get(blocknr)?;
                             if expected < 3 {
-                                report.fatal(&format!("Bad reference count for {} block {}.  Expected {}, but space map says it's >= 3.",
-                                                  kind, blocknr, expected));
+                                let msg = format!("Bad reference count for {} block {}.  Expected {}, but space map says it's >= 3.",
+                                                  kind, blocknr, expected);
+                                report.fatal(&msg);
+                                issues.add(block_issue(
+                                    kind,
+                                    IssueKind::RefCount,
+                                    true,
+                                    blocknr,
+                                    msg,
+                                ));
                                 failed = true;
                             }
                         }
                     }
                     blocknr += 1;
                 }
-                if contains_leak {
+                if nr_leaks > 0 {
+                    leaks += nr_leaks;
+                    issues.add(
+                        Issue::new(
+                            IssueKind::Leak,
+                            false,
+                            format!(
+                                "{} {} blocks have leaked in the bitmap for blocks {}..{}",
+                                nr_leaks, kind, first_blocknr, blocknr
+                            ),
+                        )
+                        .block(b.loc),
+                    );
                     bitmap_leaks.push(BitmapLeak {
                         blocknr: first_blocknr,
                         loc: b.loc,
@@ -214,6 +266,7 @@ fn gather_metadata_index_entries(
 pub fn check_disk_space_map(
     engine: Arc<dyn IoEngine + Send + Sync>,
     report: Arc<Report>,
+    issues: &IssueLog,
     root: SMRoot,
     disk_sm: ASpaceMap,
     metadata_sm: ASpaceMap,
@@ -229,13 +282,13 @@ pub fn check_disk_space_map(
     // check overflow ref-counts
     {
         let sm = disk_sm.lock().unwrap();
-        let v = OverflowChecker::new("data", &*sm);
+        let v = OverflowChecker::new("data", &*sm, issues);
         let w = BTreeWalker::new_with_sm(engine.clone(), metadata_sm.clone(), ignore_non_fatal)?;
         w.walk(&mut vec![0], &v, root.ref_count_root)?;
     }
 
     // check low ref-counts in bitmaps
-    check_low_ref_counts(engine, report, "data", entries, disk_sm)
+    check_low_ref_counts(engine, report, issues, "data", entries, disk_sm)
 }
 
 // This checks the space map and returns any leak blocks for auto-repair to process.
@@ -244,6 +297,7 @@ pub fn check_disk_space_map(
 pub fn check_metadata_space_map(
     engine: Arc<dyn IoEngine + Send + Sync>,
     report: Arc<Report>,
+    issues: &IssueLog,
     root: SMRoot,
     metadata_sm: ASpaceMap,
     ignore_non_fatal: bool,
@@ -266,13 +320,13 @@ pub fn check_metadata_space_map(
     // check overflow ref-counts
     {
         let sm = metadata_sm.lock().unwrap();
-        let v = OverflowChecker::new("metadata", &*sm);
+        let v = OverflowChecker::new("metadata", &*sm, issues);
         let w = BTreeWalker::new(engine.clone(), ignore_non_fatal);
         w.walk(&mut vec![0], &v, root.ref_count_root)?;
     }
 
     // check low ref-counts in bitmaps
-    check_low_ref_counts(engine, report, "metadata", entries, metadata_sm)
+    check_low_ref_counts(engine, report, issues, "metadata", entries, metadata_sm)
 }
 
 // This assumes the only errors in the space map are leaks.  Entries should just be
@@ -153,6 +153,97 @@ mod core_sm_u8 {
 
 //------------------------------------------
 
+mod compact_sm {
+    use super::*;
+    const NR_BLOCKS: u64 = 65536;
+
+    #[test]
+    fn get_nr_blocks() {
+        let sm = CompactSpaceMap::new(NR_BLOCKS);
+        tests::test_get_nr_blocks(&sm, NR_BLOCKS);
+    }
+
+    #[test]
+    fn get_nr_allocated() {
+        let mut sm = CompactSpaceMap::new(NR_BLOCKS);
+        tests::test_get_nr_allocated(&mut sm);
+    }
+
+    #[test]
+    fn runs_out_of_space() {
+        let mut sm = CompactSpaceMap::new(NR_BLOCKS);
+        tests::test_runs_out_of_space(&mut sm);
+    }
+
+    #[test]
+    fn inc_and_dec() {
+        let mut sm = CompactSpaceMap::new(NR_BLOCKS);
+        tests::test_inc_and_dec(&mut sm);
+    }
+
+    #[test]
+    fn not_allocated_twice() {
+        let mut sm = CompactSpaceMap::new(NR_BLOCKS);
+        tests::test_not_allocated_twice(&mut sm);
+    }
+
+    #[test]
+    fn set_affects_nr_allocated() {
+        let mut sm = CompactSpaceMap::new(NR_BLOCKS);
+        tests::test_set_affects_nr_allocated(&mut sm);
+    }
+
+    #[test]
+    fn wraparound_allocation() {
+        let mut sm = CompactSpaceMap::new(NR_BLOCKS);
+        tests::test_wraparound_allocation(&mut sm);
+    }
+
+    #[test]
+    fn counts_beyond_a_counter() {
+        let mut sm = CompactSpaceMap::new(NR_BLOCKS);
+        let b = rand::thread_rng().gen_range(0..NR_BLOCKS);
+
+        for i in 0..1000 {
+            assert_eq!(sm.get(b).unwrap(), i);
+            assert!(sm.inc(b, 1).is_ok());
+        }
+        assert_eq!(sm.get_nr_overflowed(), 1);
+        assert_eq!(sm.get_nr_allocated().unwrap(), 1);
+
+        for i in (15..=1000).rev() {
+            assert_eq!(sm.get(b).unwrap(), i);
+            assert!(!sm.dec(b).unwrap());
+        }
+        assert_eq!(sm.get(b).unwrap(), 14);
+        assert_eq!(sm.get_nr_overflowed(), 0);
+
+        assert_eq!(sm.set(b, 70000).unwrap(), 14);
+        assert_eq!(sm.get(b).unwrap(), 70000);
+        assert_eq!(sm.set(b, 0).unwrap(), 70000);
+        assert_eq!(sm.get_nr_overflowed(), 0);
+        assert_eq!(sm.get_nr_allocated().unwrap(), 0);
+    }
+
+    #[test]
+    fn neighbouring_counters_are_independent() {
+        // an odd number of blocks leaves half of the last byte unused
+        let mut sm = CompactSpaceMap::new(NR_BLOCKS - 1);
+
+        for b in 0..(NR_BLOCKS - 1) {
+            for _ in 0..(b % 20) {
+                assert!(sm.inc(b, 1).is_ok());
+            }
+        }
+        for b in 0..(NR_BLOCKS - 1) {
+            assert_eq!(sm.get(b).unwrap(), (b % 20) as u32);
+        }
+        assert!(sm.get(NR_BLOCKS - 1).is_err());
+    }
+}
+
+//------------------------------------------
+
 mod metadata_sm {
     use anyhow::{ensure, Result};
     use std::sync::Arc;
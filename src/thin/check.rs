@@ -1,17 +1,21 @@
 use anyhow::{anyhow, Result};
 use fixedbitset::FixedBitSet;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::mpsc::{self, SyncSender};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
+use crate::checksum;
 use crate::commands::engine::*;
 use crate::hashvec::HashVec;
 use crate::io_engine::*;
 use crate::pdata::btree::{self, *};
+use crate::pdata::btree_leaf_walker::*;
 use crate::pdata::btree_walker::*;
+use crate::pdata::issues::*;
 use crate::pdata::space_map::checker::*;
 use crate::pdata::space_map::common::*;
 use crate::pdata::space_map::*;
@@ -38,6 +42,24 @@ fn inc_superblock(sm: &ASpaceMap, metadata_snap: u64) -> Result<()> {
 
 //------------------------------------------
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CheckFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for CheckFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(CheckFormat::Text),
+            "json" => Ok(CheckFormat::Json),
+            _ => Err(anyhow!("Unknown format")),
+        }
+    }
+}
+
 pub struct ThinCheckOptions<'a> {
     pub input: &'a Path,
     pub engine_opts: EngineOptions,
@@ -48,12 +70,14 @@ pub struct ThinCheckOptions<'a> {
     pub clear_needs_check: bool,
     pub override_mapping_root: Option<u64>,
     pub override_details_root: Option<u64>,
+    pub format: CheckFormat,
     pub report: Arc<Report>,
 }
 
 struct Context {
     report: Arc<Report>,
     engine: Arc<dyn IoEngine + Send + Sync>,
+    issues: Arc<IssueLog>,
 }
 
 //----------------------------------------
@@ -380,10 +404,17 @@ fn read_internal_nodes(
 
     // FIXME: make get-depth more resilient
     let mut path = Vec::new();
-    let depth = if let Ok(d) = get_depth(ctx, &mut path, root as u64, true) {
-        d
-    } else {
-        return;
+    let depth = match get_depth(ctx, &mut path, root as u64, true) {
+        Ok(d) => d,
+        Err(e) => {
+            // Keep the error if it is the root that is broken
+            if let Some(BTreeError::Path(p, e)) = e.downcast_ref::<BTreeError>() {
+                if let (true, BTreeError::NodeError(ne)) = (p.is_empty(), e.as_ref()) {
+                    let _ = nodes.insert_error(root, ne.clone());
+                }
+            }
+            return;
+        }
     };
 
     if depth == 0 {
@@ -400,9 +431,34 @@ fn read_internal_nodes(
     }
 }
 
+// Issues found while summarizing the trees, tagged with the device owning
+// the tree being walked.  Each node is reported once per device, along with
+// the first path reaching it.
+#[derive(Default)]
+struct TreeIssues {
+    dev_id: Option<u64>,
+    issues: Vec<Issue>,
+    reported: BTreeSet<(Option<u64>, u64)>,
+}
+
+impl TreeIssues {
+    fn add(&mut self, path: &[u64], kr: &KeyRange, kind: IssueKind, fatal: bool, msg: String) {
+        let b = *path.last().unwrap();
+        if self.reported.insert((self.dev_id, b)) {
+            self.issues.push(
+                Issue::new(kind, fatal, msg)
+                    .at(path)
+                    .keys(kr)
+                    .dev_id(self.dev_id),
+            );
+        }
+    }
+}
+
 // Summarize a subtree rooted at the specified block.
 // Only a good internal node will have a summary stored.
 // TODO: check the tree is balanced by comparing the height of visited nodes
+#[allow(clippy::too_many_arguments)]
 fn summarize_tree(
     path: &mut Vec<u64>,
     kr: &KeyRange,
@@ -411,10 +467,30 @@ fn summarize_tree(
     nodes: &NodeMap,
     summaries: &mut HashVec<NodeSummary>,
     ignore_non_fatal: bool,
+    issues: &mut TreeIssues,
 ) -> NodeSummary {
+    // Reaching a node again from its own descendants means a cycle
+    if path[..path.len() - 1].contains(&(root as u64)) {
+        issues.add(
+            path,
+            kr,
+            IssueKind::Other,
+            true,
+            "cycle in the mapping tree".to_string(),
+        );
+        return NodeSummary::error();
+    }
+
     if let Some(sum) = summaries.get(root) {
         // Check underfull
         if !ignore_non_fatal && !is_root && sum.nr_entries < MIN_ENTRIES {
+            issues.add(
+                path,
+                kr,
+                IssueKind::Unpack,
+                false,
+                NodeError::NumEntriesTooSmall.to_string(),
+            );
             return NodeSummary::error();
         }
 
@@ -424,12 +500,26 @@ fn summarize_tree(
                 // The parent key could be less than or equal to,
                 // but not greater than the child's first key
                 if n > sum.key_low {
+                    issues.add(
+                        path,
+                        kr,
+                        IssueKind::KeyOrder,
+                        true,
+                        format!("first key {} is below the key range {}", sum.key_low, kr),
+                    );
                     return NodeSummary::error();
                 }
             }
             if let Some(n) = kr.end {
                 // note that KeyRange is a right-opened interval
                 if n < sum.key_high {
+                    issues.add(
+                        path,
+                        kr,
+                        IssueKind::KeyOrder,
+                        true,
+                        format!("last key {} is beyond the key range {}", sum.key_high, kr),
+                    );
                     return NodeSummary::error();
                 }
             }
@@ -443,6 +533,13 @@ fn summarize_tree(
             if let Some(info) = nodes.internal_info.get(root) {
                 // Check underfull
                 if !ignore_non_fatal && !is_root && info.keys.len() < MIN_ENTRIES as usize {
+                    issues.add(
+                        path,
+                        kr,
+                        IssueKind::Unpack,
+                        false,
+                        NodeError::NumEntriesTooSmall.to_string(),
+                    );
                     return NodeSummary::error();
                 }
 
@@ -450,7 +547,10 @@ fn summarize_tree(
                 // Return immediately if the keys don't match.
                 let child_keys = match split_key_ranges(path, kr, &info.keys) {
                     Ok(keys) => keys,
-                    Err(_) => return NodeSummary::error(),
+                    Err(e) => {
+                        issues.add(path, kr, IssueKind::KeyOrder, true, e.to_string());
+                        return NodeSummary::error();
+                    }
                 };
 
                 // Gather information from the children
@@ -465,6 +565,7 @@ fn summarize_tree(
                         nodes,
                         summaries,
                         ignore_non_fatal,
+                        issues,
                     );
                     let _ = sum.append(&child_sums);
                     path.pop();
@@ -475,12 +576,49 @@ fn summarize_tree(
                 sum
             } else {
                 // This is unexpected. However, we would like to skip that kind of error.
+                issues.add(
+                    path,
+                    kr,
+                    IssueKind::Other,
+                    true,
+                    "missing internal node info".to_string(),
+                );
                 NodeSummary::error()
             }
         }
 
-        // This is unexpected since a leaf should have been summarized
-        _ => NodeSummary::error(),
+        NodeType::Error => {
+            let (kind, msg) = match nodes.node_errors.get(root) {
+                Some(e) => (IssueKind::from(e), e.to_string()),
+                None => (IssueKind::Other, "unknown node error".to_string()),
+            };
+            issues.add(path, kr, kind, true, msg);
+            NodeSummary::error()
+        }
+
+        // A leaf without summary couldn't be read, while an unknown node
+        // is one that hasn't been reached due to errors in its parent.
+        NodeType::Leaf => {
+            issues.add(
+                path,
+                kr,
+                IssueKind::Io,
+                true,
+                NodeError::IoError.to_string(),
+            );
+            NodeSummary::error()
+        }
+
+        NodeType::None => {
+            issues.add(
+                path,
+                kr,
+                IssueKind::Other,
+                true,
+                "node was not read".to_string(),
+            );
+            NodeSummary::error()
+        }
     }
 }
 
@@ -490,6 +628,7 @@ fn check_mappings_bottom_level_(
     metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
     data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
     roots: &[u64],
+    root_devs: &BTreeMap<u64, u64>,
     ignore_non_fatal: bool,
 ) -> Result<HashVec<NodeSummary>> {
     let report = &ctx.report;
@@ -505,7 +644,8 @@ fn check_mappings_bottom_level_(
     report.debug(&format!("reading leaf nodes: {:?}", duration));
 
     let start = std::time::Instant::now();
-    count_mapped_blocks(roots, &nodes, &mut summaries, ignore_non_fatal);
+    let issues = count_mapped_blocks(roots, root_devs, &nodes, &mut summaries, ignore_non_fatal);
+    ctx.issues.extend(issues);
     let duration = start.elapsed();
     report.debug(&format!("counting mapped blocks: {:?}", duration));
 
@@ -739,15 +879,20 @@ fn read_leaf_nodes(
 // check this property after reading all the nodes. For example, given an
 // underfull node that is erroneously being used as a root and non-root in
 // different trees, only the second case should be treated as an error.
+//
+// Returns the issues found, tagged with the device owning the tree.
 fn count_mapped_blocks(
     roots: &[u64],
+    root_devs: &BTreeMap<u64, u64>,
     nodes: &NodeMap,
     summaries: &mut HashVec<NodeSummary>,
     ignore_non_fatal: bool,
-) {
+) -> Vec<Issue> {
+    let mut issues = TreeIssues::default();
     for root in roots.iter() {
         let mut path = vec![0, *root]; // the path is just for error reporting
         let kr = KeyRange::new();
+        issues.dev_id = root_devs.get(root).cloned();
         summarize_tree(
             &mut path,
             &kr,
@@ -756,8 +901,10 @@ fn count_mapped_blocks(
             nodes,
             summaries,
             ignore_non_fatal,
+            &mut issues,
         );
     }
+    issues.issues
 }
 
 //------------------------------------------
@@ -772,32 +919,39 @@ fn check_mapped_blocks(
     let start = std::time::Instant::now();
     let mut failed = false;
     for (thin_id, root, details) in devs {
-        if let Some(sum) = summaries.get(*root as u32) {
+        let msg = if let Some(sum) = summaries.get(*root as u32) {
             if sum.nr_errors > 0 {
-                failed = true;
                 let missed = details.mapped_blocks.saturating_sub(sum.nr_mappings);
                 let mut errors = sum.nr_errors.to_string();
                 if sum.nr_errors == 255 {
                     errors.push('+');
                 }
-                report.fatal(&format!(
+                format!(
                     "Thin device {} has {} errors and is missing {} mappings, while expected {}",
                     thin_id, errors, missed, details.mapped_blocks
-                ));
+                )
             } else if sum.nr_mappings != details.mapped_blocks {
-                failed = true;
-                report.fatal(&format!(
+                format!(
                     "Thin device {} has unexpected number of mappings, expected {}, actual {}",
                     thin_id, details.mapped_blocks, sum.nr_mappings
-                ));
+                )
+            } else {
+                continue;
             }
         } else {
-            failed = true;
-            report.fatal(&format!(
+            format!(
                 "Thin device {} is missing root with {} mappings",
                 thin_id, details.mapped_blocks
-            ));
-        }
+            )
+        };
+
+        failed = true;
+        report.fatal(&msg);
+        ctx.issues.add(
+            Issue::new(IssueKind::MappedCount, true, msg)
+                .at(&[0, *root])
+                .dev_id(Some(*thin_id)),
+        );
     }
     let duration = start.elapsed();
     report.debug(&format!("checking mapped blocks: {:?}", duration));
@@ -809,16 +963,160 @@ fn check_mapped_blocks(
     }
 }
 
-fn mk_context_(engine: Arc<dyn IoEngine + Send + Sync>, report: Arc<Report>) -> Result<Context> {
-    Ok(Context { report, engine })
+// Device details holding counters that don't agree with the metadata,
+// along with the corrected values.
+struct StaleDetail {
+    fixed: DeviceDetail,
+    msgs: Vec<(IssueKind, String)>,
+}
+
+// The number of mapped blocks can only be recomputed if the mappings were
+// read without errors, while the transaction id and snapshot time mustn't
+// be ahead of the pool.
+fn find_stale_details(
+    sb: &Superblock,
+    thins: &BTreeMap<u64, (u64, DeviceDetail)>,
+    summaries: &HashVec<NodeSummary>,
+) -> BTreeMap<u64, StaleDetail> {
+    let mut stale = BTreeMap::new();
+    for (thin_id, (root, details)) in thins {
+        let mut fixed = *details;
+        let mut msgs = Vec::new();
+
+        if let Some(sum) = summaries.get(*root as u32) {
+            if sum.nr_errors == 0 && sum.nr_mappings != details.mapped_blocks {
+                msgs.push((
+                    IssueKind::MappedCount,
+                    format!(
+                        "Thin device {} has unexpected number of mappings, expected {}, actual {}",
+                        thin_id, details.mapped_blocks, sum.nr_mappings
+                    ),
+                ));
+                fixed.mapped_blocks = sum.nr_mappings;
+            }
+        }
+
+        if details.transaction_id > sb.transaction_id {
+            msgs.push((
+                IssueKind::Value,
+                format!(
+                    "Thin device {} has stale transaction id {}, while the pool is at {}",
+                    thin_id, details.transaction_id, sb.transaction_id
+                ),
+            ));
+            fixed.transaction_id = sb.transaction_id;
+        }
+
+        if details.snapshotted_time > sb.time {
+            msgs.push((
+                IssueKind::Value,
+                format!(
+                    "Thin device {} has stale snapshot time {}, while the pool is at {}",
+                    thin_id, details.snapshotted_time, sb.time
+                ),
+            ));
+            fixed.snapshotted_time = sb.time;
+        }
+
+        if !msgs.is_empty() {
+            stale.insert(*thin_id, StaleDetail { fixed, msgs });
+        }
+    }
+    stale
+}
+
+// Rewrites the leaves of the details tree in place.  If any of the leaves
+// to change is shared with the metadata snapshot, nothing is written, to
+// keep the snapshot intact, and the devices those leaves hold are returned.
+fn repair_device_details(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    metadata_sm: &ASpaceMap,
+    details_root: u64,
+    fixes: &BTreeMap<u64, DeviceDetail>,
+) -> Result<Vec<u64>> {
+    struct LeafCollector {
+        leaves: Vec<u64>,
+    }
+
+    impl LeafVisitor<DeviceDetail> for LeafCollector {
+        fn visit(&mut self, _kr: &KeyRange, b: u64) -> btree::Result<()> {
+            self.leaves.push(b);
+            Ok(())
+        }
+
+        fn visit_again(&mut self, _b: u64) -> btree::Result<()> {
+            Ok(())
+        }
+
+        fn end_walk(&mut self) -> btree::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut sm = RestrictedSpaceMap::new(engine.get_nr_blocks());
+    let mut walker = LeafWalker::new(engine.clone(), &mut sm, false);
+    let mut collector = LeafCollector { leaves: Vec::new() };
+    walker.walk(&mut vec![0], &mut collector, details_root)?;
+
+    let mut dirty_leaves = Vec::new();
+    let mut shared = Vec::new();
+    for loc in collector.leaves {
+        let b = engine.read(loc)?;
+        let mut node = unpack_node::<DeviceDetail>(&[0], b.get_data(), false, loc == details_root)?;
+        let Node::Leaf { keys, values, .. } = &mut node else {
+            return Err(anyhow!("details tree leaf {} is not a leaf", loc));
+        };
+
+        let mut dirty = false;
+        for (k, v) in keys.iter().zip(values.iter_mut()) {
+            if let Some(fixed) = fixes.get(k) {
+                *v = *fixed;
+                dirty = true;
+            }
+        }
+        if !dirty {
+            continue;
+        }
+
+        if metadata_sm.lock().unwrap().get(loc)? > 1 {
+            shared.extend(keys.iter().filter(|k| fixes.contains_key(k)));
+            continue;
+        }
+
+        dirty_leaves.push((b, node));
+    }
+
+    if !shared.is_empty() {
+        return Ok(shared);
+    }
+
+    for (b, node) in dirty_leaves {
+        btree::pack_node(&node, &mut std::io::Cursor::new(b.get_data()))?;
+        checksum::write_checksum(b.get_data(), checksum::BT::NODE)?;
+        engine.write(&b)?;
+    }
+
+    Ok(Vec::new())
+}
+
+fn mk_context_(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    issues: Arc<IssueLog>,
+) -> Result<Context> {
+    Ok(Context {
+        report,
+        engine,
+        issues,
+    })
 }
 
-fn mk_context(opts: &ThinCheckOptions) -> Result<Context> {
+fn mk_context(opts: &ThinCheckOptions, issues: Arc<IssueLog>) -> Result<Context> {
     let engine = EngineBuilder::new(opts.input, &opts.engine_opts)
         .write(opts.auto_repair || opts.clear_needs_check)
         .exclusive(!opts.engine_opts.use_metadata_snap)
         .build()?;
-    mk_context_(engine, opts.report.clone())
+    mk_context_(engine, opts.report.clone(), issues)
 }
 
 fn print_info(sb: &Superblock, report: Arc<Report>) -> Result<()> {
@@ -850,6 +1148,28 @@ fn metadata_err(context: &str, err: anyhow::Error) -> MetadataError {
     }
 }
 
+// Converts an error that stopped the check into issues, breaking down the
+// btree errors into the nodes involved.
+fn issues_from_error(e: &anyhow::Error) -> Vec<Issue> {
+    let (context, be) = match e.downcast_ref::<MetadataError>() {
+        Some(me) => (Some(&me.context), me.err.downcast_ref::<BTreeError>()),
+        None => (None, e.downcast_ref::<BTreeError>()),
+    };
+
+    match be {
+        Some(be) => issues_from_btree_error(be, true)
+            .into_iter()
+            .map(|mut i| {
+                if let Some(ctx) = context {
+                    i.message = format!("{}: {}", ctx, i.message);
+                }
+                i
+            })
+            .collect(),
+        None => vec![Issue::new(IssueKind::Other, true, e.to_string())],
+    }
+}
+
 // We read the top-level tree once to get the number of thin devices, and hence the
 // maximum metadata ref count.  Then create metadata space map.
 fn create_metadata_sm(
@@ -1020,6 +1340,7 @@ fn check_mappings_bottom_level(
     metadata_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
     data_sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>,
     roots: &[u64],
+    root_devs: &BTreeMap<u64, u64>,
     ignore_non_fatal: bool,
 ) -> Result<HashVec<NodeSummary>> {
     let report = &ctx.report;
@@ -1038,21 +1359,35 @@ fn check_mappings_bottom_level(
         },
     );
 
-    let summaries =
-        check_mappings_bottom_level_(ctx, metadata_sm, data_sm, roots, ignore_non_fatal);
+    let summaries = check_mappings_bottom_level_(
+        ctx,
+        metadata_sm,
+        data_sm,
+        roots,
+        root_devs,
+        ignore_non_fatal,
+    );
 
     monitor.stop();
 
     summaries
 }
 
+// Pools with more data blocks than this have their ref counts held in
+// a CompactSpaceMap, as a byte per block would take over 256MB.
+const COMPACT_DATA_SM_THRESHOLD: u64 = 1 << 28;
+
 fn create_data_sm(sb: &Superblock, nr_devs: u32) -> Result<ASpaceMap> {
     let data_root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
 
+    // Counts that don't fit in a small counter are rare, so keep them
+    // aside rather than widening the counter of every data block.
     let data_sm = if nr_devs <= 1 {
         Arc::new(Mutex::new(RestrictedSpaceMap::new(data_root.nr_blocks)))
-    } else {
+    } else if nr_devs <= u8::MAX as u32 && data_root.nr_blocks < COMPACT_DATA_SM_THRESHOLD {
         core_sm(data_root.nr_blocks, nr_devs)
+    } else {
+        compact_sm(data_root.nr_blocks)
     };
 
     Ok(data_sm)
@@ -1067,8 +1402,23 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
         return Err(anyhow!("cannot perform repair outside the actual metadata"));
     }
 
-    let ctx = mk_context(&opts)?;
+    let issues = Arc::new(IssueLog::new());
+    let r = mk_context(&opts, issues.clone()).and_then(|ctx| check_(&ctx, &opts));
+
+    if opts.format == CheckFormat::Json {
+        // The issues logged already explain the error if there's any
+        if let Err(e) = &r {
+            if issues.issues().is_empty() {
+                issues.extend(issues_from_error(e));
+            }
+        }
+        issues.write_json(&mut std::io::stdout().lock())?;
+    }
+
+    r
+}
 
+fn check_(ctx: &Context, opts: &ThinCheckOptions) -> Result<()> {
     // FIXME: temporarily get these out
     let report = &ctx.report;
     let engine = &ctx.engine;
@@ -1186,20 +1536,55 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
         create_data_sm(&sb, all_roots.len() as u32)?
     };
 
+    // Devices owning the roots, for issue reporting.  Roots shared with
+    // the metadata snapshot are attributed to the devices in use.
+    let mut root_devs: BTreeMap<u64, u64> = BTreeMap::new();
+    for (id, (root, _)) in thins.iter() {
+        root_devs.entry(*root).or_insert(*id);
+    }
+    if let Ok(thins_snap) = &thins_snap {
+        for (id, (root, _)) in thins_snap.iter() {
+            root_devs.entry(*root).or_insert(*id);
+        }
+    }
+
     let summaries = check_mappings_bottom_level(
-        &ctx,
+        ctx,
         &sb,
         &metadata_sm,
         &data_sm,
         &all_roots,
+        &root_devs,
         opts.ignore_non_fatal,
     )?;
 
+    // Stale device details are checked against the corrected values if
+    // they're going to be repaired.
+    let repairing = opts.auto_repair || opts.clear_needs_check;
+    let stale = find_stale_details(&sb, &thins, &summaries);
+    for (thin_id, sd) in stale.iter() {
+        for (kind, msg) in sd.msgs.iter() {
+            // A wrong number of mappings is fatal unless it's repaired
+            if *kind == IssueKind::MappedCount && !repairing {
+                continue;
+            }
+            report.warning(msg);
+            ctx.issues.add(
+                Issue::new(*kind, false, msg.clone())
+                    .at(&[0, thins[thin_id].0])
+                    .dev_id(Some(*thin_id)),
+            );
+        }
+    }
+
     // Check the number of mapped blocks
     let mut iter = thins
         .iter()
-        .map(|(id, (root, details))| (id, root, details));
-    check_mapped_blocks(&ctx, &mut iter, &summaries)?;
+        .map(|(id, (root, details))| match stale.get(id) {
+            Some(sd) if repairing => (id, root, &sd.fixed),
+            _ => (id, root, details),
+        });
+    check_mapped_blocks(ctx, &mut iter, &summaries)?;
 
     match thins_snap {
         Err(e) => {
@@ -1209,7 +1594,7 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
             let mut iter = thins_snap
                 .iter()
                 .map(|(id, (root, details))| (id, root, details));
-            check_mapped_blocks(&ctx, &mut iter, &summaries)?;
+            check_mapped_blocks(ctx, &mut iter, &summaries)?;
         }
     }
 
@@ -1226,6 +1611,7 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
     let data_leaks = check_disk_space_map(
         engine.clone(),
         report.clone(),
+        &ctx.issues,
         root,
         data_sm.clone(),
         metadata_sm.clone(),
@@ -1246,6 +1632,7 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
     let metadata_leaks = check_metadata_space_map(
         engine.clone(),
         report.clone(),
+        &ctx.issues,
         root,
         metadata_sm.clone(),
         opts.ignore_non_fatal,
@@ -1257,6 +1644,41 @@ pub fn check(opts: ThinCheckOptions) -> Result<()> {
     //-----------------------------------------
     // Fix minor issues found in the metadata
 
+    if !stale.is_empty() {
+        if repairing {
+            report.warning("Repairing device details.");
+            let fixes = stale
+                .iter()
+                .map(|(id, sd)| (*id, sd.fixed))
+                .collect::<BTreeMap<u64, DeviceDetail>>();
+            let shared =
+                repair_device_details(engine.clone(), &metadata_sm, sb.details_root, &fixes)?;
+            if !shared.is_empty() {
+                let msg = format!(
+                    "details of thin devices {:?} are shared with the metadata snapshot, left unrepaired",
+                    shared
+                );
+
+                // Only a wrong number of mappings is fatal, and nothing has
+                // been written yet.
+                let miscounted = shared.iter().any(|id| {
+                    stale[id]
+                        .msgs
+                        .iter()
+                        .any(|(kind, _)| *kind == IssueKind::MappedCount)
+                });
+                if miscounted {
+                    return Err(anyhow!(msg));
+                }
+                report.warning(&msg);
+            }
+        } else {
+            // The kernel copes with stale counters, so they're only warned about
+            report
+                .warning("device details contain stale values, run with --auto-repair to fix them");
+        }
+    }
+
     if !data_leaks.is_empty() {
         if opts.auto_repair || opts.clear_needs_check {
             report.warning("Repairing data leaks.");
@@ -1312,7 +1734,7 @@ pub fn check_with_maps(
     engine: Arc<dyn IoEngine + Send + Sync>,
     report: Arc<Report>,
 ) -> Result<CheckMaps> {
-    let ctx = mk_context_(engine.clone(), report.clone())?;
+    let ctx = mk_context_(engine.clone(), report.clone(), Arc::new(IssueLog::new()))?;
     report.set_title("Checking thin metadata");
 
     let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
@@ -1331,7 +1753,9 @@ pub fn check_with_maps(
     report.set_sub_title("mapping tree");
 
     let data_sm = create_data_sm(&sb, all_roots.len() as u32)?;
-    let summaries = check_mappings_bottom_level_(&ctx, &metadata_sm, &data_sm, &all_roots, false)?;
+    let root_devs = thins.iter().map(|(id, (root, _))| (*root, *id)).collect();
+    let summaries =
+        check_mappings_bottom_level_(&ctx, &metadata_sm, &data_sm, &all_roots, &root_devs, false)?;
 
     // Check the number of mapped blocks
     let mut iter = thins
@@ -1346,6 +1770,7 @@ pub fn check_with_maps(
     let _data_leaks = check_disk_space_map(
         engine.clone(),
         report.clone(),
+        &ctx.issues,
         root,
         data_sm.clone(),
         metadata_sm.clone(),
@@ -1358,8 +1783,14 @@ pub fn check_with_maps(
     let root = unpack::<SMRoot>(&sb.metadata_sm_root[0..])?;
 
     // Now the counts should be correct and we can check it.
-    let _metadata_leaks =
-        check_metadata_space_map(engine.clone(), report, root, metadata_sm.clone(), false)?;
+    let _metadata_leaks = check_metadata_space_map(
+        engine.clone(),
+        report,
+        &ctx.issues,
+        root,
+        metadata_sm.clone(),
+        false,
+    )?;
 
     //-----------------------------------------
 
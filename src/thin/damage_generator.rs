@@ -1,15 +1,23 @@
-use anyhow::Result;
-
+use anyhow::{anyhow, Result};
+use rand::prelude::SliceRandom;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::BTreeSet;
+use std::io::Cursor;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
 
+use crate::checksum;
 use crate::commands::engine::*;
 use crate::devtools::damage_generator::*;
-use crate::io_engine::IoEngine;
-
+use crate::io_engine::{Block, IoEngine};
+use crate::pdata::btree::*;
+use crate::pdata::btree_walker::btree_to_map;
 use crate::pdata::space_map::common::*;
-
-use crate::pdata::unpack::unpack;
+use crate::pdata::space_map::metadata::*;
+use crate::pdata::unpack::{unpack, Pack, Unpack};
+use crate::thin::block_time::*;
 use crate::thin::superblock::*;
 
 //------------------------------------------
@@ -39,6 +47,168 @@ pub fn override_superblock(
 
 //------------------------------------------
 
+fn read_node<V: Unpack>(engine: &dyn IoEngine, loc: u64) -> Result<Node<V>> {
+    let b = engine.read(loc)?;
+    Ok(unpack_node::<V>(&[0], b.get_data(), true, false)?)
+}
+
+fn write_node<V: Pack + Unpack>(engine: &dyn IoEngine, loc: u64, node: &Node<V>) -> Result<()> {
+    let b = Block::zeroed(loc);
+    pack_node(node, &mut Cursor::new(b.get_data()))?;
+    checksum::write_checksum(b.get_data(), checksum::BT::NODE)?;
+    engine.write(&b)?;
+    Ok(())
+}
+
+// Returns the leaf holding the key, and the index of the key within it
+fn find_leaf(engine: &dyn IoEngine, root: u64, key: u64) -> Result<Option<(u64, usize)>> {
+    let mut loc = root;
+    loop {
+        match read_node::<u64>(engine, loc)? {
+            Node::Internal { keys, values, .. } => match keys.iter().rposition(|k| *k <= key) {
+                Some(i) => loc = values[i],
+                None => return Ok(None),
+            },
+            Node::Leaf { keys, .. } => {
+                return Ok(keys.iter().position(|k| *k == key).map(|i| (loc, i)));
+            }
+        }
+    }
+}
+
+fn device_root(engine: &dyn IoEngine, sb: &Superblock, dev_id: u64) -> Result<u64> {
+    let (leaf, i) = find_leaf(engine, sb.mapping_root, dev_id)?
+        .ok_or_else(|| anyhow!("device {} not found", dev_id))?;
+    match read_node::<u64>(engine, leaf)? {
+        Node::Leaf { values, .. } => Ok(values[i]),
+        Node::Internal { .. } => Err(anyhow!("block {} is not a leaf", leaf)),
+    }
+}
+
+fn internal_nodes(engine: &dyn IoEngine, root: u64) -> Result<Vec<u64>> {
+    let mut nodes = Vec::new();
+    let mut seen = BTreeSet::new();
+    let mut stack = vec![root];
+    while let Some(loc) = stack.pop() {
+        if !seen.insert(loc) {
+            continue;
+        }
+        if let Node::Internal { values, .. } = read_node::<BlockTime>(engine, loc)? {
+            nodes.push(loc);
+            stack.extend(values);
+        }
+    }
+    Ok(nodes)
+}
+
+// Picks one of the internal nodes of a device's mapping tree at random
+fn pick_internal_node(
+    engine: &dyn IoEngine,
+    sb: &Superblock,
+    dev_id: u64,
+    rng: &mut StdRng,
+) -> Result<u64> {
+    let root = device_root(engine, sb, dev_id)?;
+    let nodes = internal_nodes(engine, root)?;
+    nodes.choose(rng).copied().ok_or_else(|| {
+        anyhow!(
+            "the mapping tree of device {} has no internal nodes",
+            dev_id
+        )
+    })
+}
+
+fn corrupt_node_checksum(engine: &dyn IoEngine, loc: u64) -> Result<()> {
+    let b = engine.read(loc)?;
+    if checksum::metadata_block_type(b.get_data()) != checksum::BT::NODE {
+        return Err(anyhow!("block {} is not a btree node", loc));
+    }
+    corrupt_checksum(engine, loc)
+}
+
+// Points a child of an internal node back at the root of the tree
+fn create_cycle(
+    engine: &dyn IoEngine,
+    sb: &Superblock,
+    dev_id: u64,
+    rng: &mut StdRng,
+) -> Result<()> {
+    let root = device_root(engine, sb, dev_id)?;
+    let loc = pick_internal_node(engine, sb, dev_id, rng)?;
+    let mut node = read_node::<BlockTime>(engine, loc)?;
+    if let Node::Internal { values, .. } = &mut node {
+        *values.last_mut().unwrap() = root;
+    }
+    write_node(engine, loc, &node)
+}
+
+// Makes a device share the mapping tree of another, without adjusting
+// the reference counts or the device details.
+fn cross_link(engine: &dyn IoEngine, sb: &Superblock, dev_id: u64, other: u64) -> Result<()> {
+    if dev_id == other {
+        return Err(anyhow!("can't cross link device {} to itself", dev_id));
+    }
+
+    let other_root = device_root(engine, sb, other)?;
+    let (leaf, i) = find_leaf(engine, sb.mapping_root, dev_id)?
+        .ok_or_else(|| anyhow!("device {} not found", dev_id))?;
+    let mut node = read_node::<u64>(engine, leaf)?;
+    if let Node::Leaf { values, .. } = &mut node {
+        values[i] = other_root;
+    }
+    write_node(engine, leaf, &node)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpaceMapType {
+    Data,
+    Metadata,
+}
+
+impl FromStr for SpaceMapType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "data" => Ok(SpaceMapType::Data),
+            "metadata" => Ok(SpaceMapType::Metadata),
+            _ => Err(anyhow!("unknown space map '{}'", s)),
+        }
+    }
+}
+
+fn drop_bitmap(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &Superblock,
+    sm: SpaceMapType,
+    index: usize,
+) -> Result<()> {
+    let entries = match sm {
+        SpaceMapType::Data => {
+            let root = unpack::<SMRoot>(&sb.data_sm_root)?;
+            btree_to_map::<IndexEntry>(&mut vec![], engine.clone(), false, root.bitmap_root)?
+                .into_values()
+                .collect::<Vec<IndexEntry>>()
+        }
+        SpaceMapType::Metadata => {
+            let root = unpack::<SMRoot>(&sb.metadata_sm_root)?;
+            let b = engine.read(root.bitmap_root)?;
+            load_metadata_index(&b, root.nr_blocks)?.indexes
+        }
+    };
+
+    let ie = entries.get(index).ok_or_else(|| {
+        anyhow!(
+            "bitmap index {} out of range, the space map has {} bitmaps",
+            index,
+            entries.len()
+        )
+    })?;
+    zero_block(engine.as_ref(), ie.blocknr)
+}
+
+//------------------------------------------
+
 pub enum DamageOp {
     CreateMetadataLeaks {
         nr_blocks: usize,
@@ -46,12 +216,34 @@ pub enum DamageOp {
         actual_rc: u32,
     },
     OverrideSuperblock(SuperblockOverrides),
+    CorruptNodeChecksum {
+        block: u64,
+    },
+    ZeroInternalNode {
+        dev_id: u64,
+    },
+    ScrambleInternalNode {
+        dev_id: u64,
+    },
+    BreakDetailsTree,
+    CreateCycle {
+        dev_id: u64,
+    },
+    CrossLink {
+        dev_id: u64,
+        other_dev_id: u64,
+    },
+    DropBitmap {
+        sm: SpaceMapType,
+        index: usize,
+    },
 }
 
 pub struct ThinDamageOpts<'a> {
     pub engine_opts: EngineOptions,
     pub op: DamageOp,
     pub output: &'a Path,
+    pub seed: u64,
 }
 
 pub fn damage_metadata(opts: ThinDamageOpts) -> Result<()> {
@@ -60,14 +252,37 @@ pub fn damage_metadata(opts: ThinDamageOpts) -> Result<()> {
         .build()?;
     let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
     let sm_root = unpack::<SMRoot>(&sb.metadata_sm_root)?;
+    let mut rng = StdRng::seed_from_u64(opts.seed);
 
     match opts.op {
         DamageOp::CreateMetadataLeaks {
             nr_blocks,
             expected_rc,
             actual_rc,
-        } => create_metadata_leaks(engine, sm_root, nr_blocks, expected_rc, actual_rc),
+        } => create_metadata_leaks(engine, sm_root, nr_blocks, expected_rc, actual_rc, &mut rng),
         DamageOp::OverrideSuperblock(opts) => override_superblock(engine, &opts),
+        DamageOp::CorruptNodeChecksum { block } => corrupt_node_checksum(engine.as_ref(), block),
+        DamageOp::ZeroInternalNode { dev_id } => {
+            let loc = pick_internal_node(engine.as_ref(), &sb, dev_id, &mut rng)?;
+            zero_block(engine.as_ref(), loc)
+        }
+        DamageOp::ScrambleInternalNode { dev_id } => {
+            let loc = pick_internal_node(engine.as_ref(), &sb, dev_id, &mut rng)?;
+            scramble_block(
+                engine.as_ref(),
+                loc,
+                NODE_HEADER_SIZE,
+                checksum::BT::NODE,
+                &mut rng,
+            )
+        }
+        DamageOp::BreakDetailsTree => zero_block(engine.as_ref(), sb.details_root),
+        DamageOp::CreateCycle { dev_id } => create_cycle(engine.as_ref(), &sb, dev_id, &mut rng),
+        DamageOp::CrossLink {
+            dev_id,
+            other_dev_id,
+        } => cross_link(engine.as_ref(), &sb, dev_id, other_dev_id),
+        DamageOp::DropBitmap { sm, index } => drop_bitmap(engine, &sb, sm, index),
     }
 }
 
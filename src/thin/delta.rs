@@ -1,16 +1,20 @@
 use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::BufWriter;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use threadpool::ThreadPool;
 
 use crate::commands::engine::*;
 use crate::io_engine::*;
-use crate::pdata::btree::{self, KeyRange, NodeHeader};
-use crate::pdata::btree_walker::{btree_to_map, BTreeWalker, NodeVisitor};
+use crate::pdata::btree::{self, unpack_node, KeyRange, Node, NodeHeader};
+use crate::pdata::btree_walker::{btree_to_map, walk_threaded, BTreeWalker, NodeVisitor};
 use crate::pdata::space_map::common::SMRoot;
 use crate::pdata::unpack::unpack;
 use crate::report::Report;
 use crate::thin::block_time::BlockTime;
+use crate::thin::delta_binary::*;
 use crate::thin::delta_visitor::*;
 use crate::thin::ir;
 use crate::thin::metadata_repair::is_superblock_consistent;
@@ -50,55 +54,58 @@ impl RunBuilder {
     }
 }
 
-struct RecorderInner {
-    mappings: Vec<DataMapping>,
-    builder: RunBuilder,
+type LeafRuns = Arc<Vec<DataMapping>>;
+
+fn build_leaf_runs(keys: &[u64], values: &[BlockTime]) -> LeafRuns {
+    let mut builder = RunBuilder::new();
+    let mut runs = Vec::new();
+    for (k, v) in keys.iter().zip(values) {
+        if let Some(m) = builder.next(*k, v.block) {
+            runs.push(m);
+        }
+    }
+    if let Some(m) = builder.complete() {
+        runs.push(m);
+    }
+    Arc::new(runs)
 }
 
+// Records the runs of each visited leaf into a cache shared by all the walks,
+// and the roots of the subtrees visited in earlier walks.  Leaves are visited
+// in arbitrary order by the threaded walker, so the runs are put in order
+// afterwards.
 struct MappingRecorder {
-    inner: Mutex<RecorderInner>,
+    leaves: Arc<Mutex<BTreeMap<u64, LeafRuns>>>,
+    nodes: Mutex<Vec<u64>>,
 }
 
 impl MappingRecorder {
-    fn new() -> MappingRecorder {
+    fn new(leaves: Arc<Mutex<BTreeMap<u64, LeafRuns>>>) -> MappingRecorder {
         MappingRecorder {
-            inner: Mutex::new(RecorderInner {
-                mappings: Vec::new(),
-                builder: RunBuilder::new(),
-            }),
-        }
-    }
-
-    fn complete(self) -> Vec<DataMapping> {
-        let mut inner = self.inner.lock().unwrap();
-        if let Some(m) = inner.builder.complete() {
-            inner.mappings.push(m);
+            leaves,
+            nodes: Mutex::new(Vec::new()),
         }
-        let mut mappings = Vec::new();
-        std::mem::swap(&mut mappings, &mut inner.mappings);
-        mappings
     }
 }
 
 impl NodeVisitor<BlockTime> for MappingRecorder {
     fn visit(
         &self,
-        _path: &[u64],
+        path: &[u64],
         _kr: &KeyRange,
         _h: &NodeHeader,
         keys: &[u64],
         values: &[BlockTime],
     ) -> btree::Result<()> {
-        let mut inner = self.inner.lock().unwrap();
-        for (k, v) in keys.iter().zip(values) {
-            if let Some(m) = inner.builder.next(*k, v.block) {
-                inner.mappings.push(m);
-            }
-        }
+        let runs = build_leaf_runs(keys, values);
+        let b = *path.last().unwrap();
+        self.leaves.lock().unwrap().insert(b, runs);
+        self.nodes.lock().unwrap().push(b);
         Ok(())
     }
 
-    fn visit_again(&self, _path: &[u64], _b: u64) -> btree::Result<()> {
+    fn visit_again(&self, _path: &[u64], b: u64) -> btree::Result<()> {
+        self.nodes.lock().unwrap().push(b);
         Ok(())
     }
 
@@ -107,18 +114,102 @@ impl NodeVisitor<BlockTime> for MappingRecorder {
     }
 }
 
-// In order to compare snapshots that are not derived from the same origin,
-// thin_delta compares mappings based on the data block addresses, thus the
-// mapping timestamps are not extracted.
+// Collects the mappings of multiple devices.  Subtrees shared between the
+// devices are walked only once.  The devices are compared in a chain, and
+// neighbours share the most, so only the leaves of the last device collected
+// are cached.  Leaves evicted but shared with later devices are read again.
+struct MappingCollector {
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    walker: Arc<BTreeWalker>,
+    pool: ThreadPool,
+    leaves: Arc<Mutex<BTreeMap<u64, LeafRuns>>>,
+}
+
+impl MappingCollector {
+    fn new(engine: Arc<dyn IoEngine + Send + Sync>) -> MappingCollector {
+        let nr_threads = std::cmp::max(1, engine.suggest_nr_threads());
+        MappingCollector {
+            engine: engine.clone(),
+            walker: Arc::new(BTreeWalker::new(engine, false)),
+            pool: ThreadPool::new(nr_threads),
+            leaves: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    // Gathers the runs of the leaves under node b, along with the leaf
+    // blocks.  The node was either visited in this walk, or in earlier
+    // walks, in which case its leaves might have been evicted.
+    fn gather_leaves(&self, b: u64, runs: &mut Vec<(u64, LeafRuns)>) -> Result<()> {
+        if let Some(r) = self.leaves.lock().unwrap().get(&b) {
+            runs.push((b, r.clone()));
+            return Ok(());
+        }
+
+        let blk = self.engine.read(b)?;
+        match unpack_node::<u64>(&[b], blk.get_data(), true, true)? {
+            Node::Internal { values, .. } => {
+                for child in values {
+                    self.gather_leaves(child, runs)?;
+                }
+                Ok(())
+            }
+            Node::Leaf { .. } => {
+                if let Node::Leaf { keys, values, .. } =
+                    unpack_node::<BlockTime>(&[b], blk.get_data(), true, true)?
+                {
+                    let r = build_leaf_runs(&keys, &values);
+                    self.leaves.lock().unwrap().insert(b, r.clone());
+                    runs.push((b, r));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // In order to compare snapshots that are not derived from the same origin,
+    // thin_delta compares mappings based on the data block addresses, thus the
+    // mapping timestamps are not extracted.
+    fn get_mappings(&self, root: u64) -> Result<Vec<DataMapping>> {
+        let mr = Arc::new(MappingRecorder::new(self.leaves.clone()));
+        let mut path = Vec::new();
+        walk_threaded(&mut path, self.walker.clone(), &self.pool, mr.clone(), root)?;
+
+        let nodes = std::mem::take(&mut *mr.nodes.lock().unwrap());
+        let mut runs = Vec::new();
+        for b in nodes {
+            self.gather_leaves(b, &mut runs)?;
+        }
+
+        // evict the leaves not shared with this device
+        let used: BTreeSet<u64> = runs.iter().map(|(b, _)| *b).collect();
+        self.leaves.lock().unwrap().retain(|b, _| used.contains(b));
+
+        let mut runs: Vec<LeafRuns> = runs.into_iter().map(|(_, r)| r).collect();
+        runs.retain(|r| !r.is_empty());
+        runs.sort_unstable_by_key(|r| r[0].thin_begin);
+
+        // join the runs that span leaves
+        let mut mappings: Vec<DataMapping> = Vec::new();
+        for m in runs.iter().flat_map(|r| r.iter()) {
+            match mappings.last_mut() {
+                Some(last)
+                    if last.thin_begin + last.len == m.thin_begin
+                        && last.data_begin + last.len == m.data_begin =>
+                {
+                    last.len += m.len;
+                }
+                _ => mappings.push(m.clone()),
+            }
+        }
+        Ok(mappings)
+    }
+}
+
 pub fn get_mappings(
     engine: Arc<dyn IoEngine + Send + Sync>,
     root: u64,
 ) -> Result<Vec<DataMapping>> {
-    let mr = MappingRecorder::new();
-    let w = Arc::new(BTreeWalker::new(engine.clone(), false));
-    let mut path = Vec::new();
-    w.walk(&mut path, &mr, root)?;
-    Ok(mr.complete())
+    MappingCollector::new(engine).get_mappings(root)
 }
 
 //------------------------------------------
@@ -239,28 +330,11 @@ fn dump_diff(
     engine: Arc<dyn IoEngine + Send + Sync>,
     visitor: &mut dyn DeltaVisitor,
     sb: &Superblock,
-    snap1: Snap,
-    snap2: Snap,
+    snaps: &[Snap],
 ) -> Result<()> {
     let mut path = Vec::new();
     let roots = btree_to_map::<u64>(&mut path, engine.clone(), false, sb.mapping_root)?;
 
-    let root1 = match snap1 {
-        Snap::DeviceId(dev_id) => *roots
-            .get(&dev_id)
-            .ok_or_else(|| anyhow!("Unable to find mapping tree for snap1 ({})", dev_id))?,
-        Snap::RootBlock(b) => b,
-    };
-    let mappings1 = get_mappings(engine.clone(), root1)?;
-
-    let root2 = match snap2 {
-        Snap::DeviceId(dev_id) => *roots
-            .get(&dev_id)
-            .ok_or_else(|| anyhow!("Unable to find mapping tree for snap2 ({})", dev_id))?,
-        Snap::RootBlock(b) => b,
-    };
-    let mappings2 = get_mappings(engine.clone(), root2)?;
-
     let data_root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
     let out_sb = ir::Superblock {
         uuid: "".to_string(),
@@ -273,10 +347,30 @@ fn dump_diff(
         metadata_snap: None,
     };
 
+    let snap_roots = snaps
+        .iter()
+        .enumerate()
+        .map(|(i, snap)| match snap {
+            Snap::DeviceId(dev_id) => roots.get(dev_id).copied().ok_or_else(|| {
+                anyhow!("Unable to find mapping tree for snap{} ({})", i + 1, dev_id)
+            }),
+            Snap::RootBlock(b) => Ok(*b),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let collector = MappingCollector::new(engine);
+    let mut left = collector.get_mappings(snap_roots[0])?;
+
     visitor.superblock_b(&out_sb)?;
-    visitor.diff_b(snap1, snap2)?;
-    dump_delta_mappings(&mappings1, &mappings2, visitor)?;
-    visitor.diff_e()?;
+    for i in 1..snaps.len() {
+        let right = collector.get_mappings(snap_roots[i])?;
+
+        visitor.diff_b(snaps[i - 1], snaps[i])?;
+        dump_delta_mappings(&left, &right, visitor)?;
+        visitor.diff_e()?;
+
+        left = right;
+    }
     visitor.superblock_e()?;
 
     Ok(())
@@ -284,13 +378,36 @@ fn dump_diff(
 
 //------------------------------------------
 
+#[derive(Clone)]
+pub enum OutputFormat {
+    XML,
+    Binary,
+    QemuBitmap,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "xml" => Ok(OutputFormat::XML),
+            "binary" => Ok(OutputFormat::Binary),
+            "qemu_bitmap" => Ok(OutputFormat::QemuBitmap),
+            _ => Err(anyhow!("unknown format")),
+        }
+    }
+}
+
 pub struct ThinDeltaOptions<'a> {
     pub input: &'a Path,
     pub engine_opts: EngineOptions,
     pub report: Arc<Report>,
-    pub snap1: Snap,
-    pub snap2: Snap,
+    // Deltas are emitted for each consecutive pair of snaps
+    pub snaps: Vec<Snap>,
     pub verbose: bool,
+    pub format: OutputFormat,
+    // Size of the thin devices in sectors, for the qemu bitmap
+    pub thin_size: Option<u64>,
 }
 
 struct Context {
@@ -310,6 +427,22 @@ fn mk_context(opts: &ThinDeltaOptions) -> Result<Context> {
 }
 
 pub fn delta(opts: ThinDeltaOptions) -> Result<()> {
+    if opts.snaps.len() < 2 {
+        return Err(anyhow!("at least two thin devices are required"));
+    }
+
+    if matches!(opts.format, OutputFormat::QemuBitmap) {
+        // a bitmap doesn't tell where one diff ends and the next begins
+        if opts.snaps.len() > 2 {
+            return Err(anyhow!(
+                "the qemu_bitmap format supports only a single diff"
+            ));
+        }
+        if opts.thin_size.is_none() {
+            return Err(anyhow!("the qemu_bitmap format requires --thin-size"));
+        }
+    }
+
     let ctx = mk_context(&opts)?;
 
     let sb = if opts.engine_opts.use_metadata_snap {
@@ -322,13 +455,14 @@ pub fn delta(opts: ThinDeltaOptions) -> Result<()> {
     is_superblock_consistent(sb.clone(), ctx.engine.clone(), false)?;
 
     let w = BufWriter::new(std::io::stdout());
-    let mut writer: Box<dyn DeltaVisitor> = if opts.verbose {
-        Box::new(VerboseXmlWriter::new(w))
-    } else {
-        Box::new(SimpleXmlWriter::new(w))
+    let mut writer: Box<dyn DeltaVisitor> = match opts.format {
+        OutputFormat::XML if opts.verbose => Box::new(VerboseXmlWriter::new(w)),
+        OutputFormat::XML => Box::new(SimpleXmlWriter::new(w)),
+        OutputFormat::Binary => Box::new(BinaryWriter::new(w)),
+        OutputFormat::QemuBitmap => Box::new(QemuBitmapWriter::new(w, opts.thin_size.unwrap())),
     };
 
-    dump_diff(ctx.engine, writer.as_mut(), &sb, opts.snap1, opts.snap2)
+    dump_diff(ctx.engine, writer.as_mut(), &sb, &opts.snaps)
 }
 
 //------------------------------------------
@@ -1,6 +1,12 @@
 use super::*;
 
+use std::sync::Mutex;
+
+use crate::io_engine::core::CoreIoEngine;
+use crate::pdata::btree_builder::test_utils::build_btree_from_mappings;
+use crate::pdata::space_map::*;
 use crate::thin::ir::{self, Visit};
+use crate::write_batcher::WriteBatcher;
 
 //------------------------------------------
 
@@ -160,3 +166,86 @@ fn test_delta_ends_by_left() -> Result<()> {
 }
 
 //------------------------------------------
+
+#[test]
+fn test_get_mappings_across_leaves() -> Result<()> {
+    let nr_blocks = 1024;
+    let engine: Arc<dyn IoEngine + Send + Sync> = Arc::new(CoreIoEngine::new(nr_blocks));
+    let sm: Arc<Mutex<dyn SpaceMap>> = Arc::new(Mutex::new(CoreSpaceMap::<u32>::new(nr_blocks)));
+
+    // Long runs spanning many leaves, with gaps and discontinuities in
+    // the data blocks every now and then.
+    let mut mappings = Vec::new();
+    let mut expected: Vec<DataMapping> = Vec::new();
+    for i in 0..40u64 {
+        let thin_begin = i * 1000;
+        let data_begin = i * 5000 + 7;
+        let len = 500 + i * 13;
+        for j in 0..len {
+            let bt = BlockTime {
+                block: data_begin + j,
+                time: 0,
+            };
+            mappings.push((thin_begin + j, bt));
+        }
+        expected.push(DataMapping {
+            thin_begin,
+            data_begin,
+            len,
+        });
+    }
+
+    let mut w = WriteBatcher::new(engine.clone(), sm, 16);
+    let root = build_btree_from_mappings(&mut w, &mappings).root().block;
+
+    let actual = get_mappings(engine, root)?;
+    assert_eq!(actual, expected);
+    Ok(())
+}
+
+#[test]
+fn test_get_mappings_evicts_leaves_of_earlier_devices() -> Result<()> {
+    let nr_blocks = 1024;
+    let engine: Arc<dyn IoEngine + Send + Sync> = Arc::new(CoreIoEngine::new(nr_blocks));
+    let sm: Arc<Mutex<dyn SpaceMap>> = Arc::new(Mutex::new(CoreSpaceMap::<u32>::new(nr_blocks)));
+    let mut w = WriteBatcher::new(engine.clone(), sm, 16);
+
+    let mk_mappings = |data_begin: u64| {
+        (0..10000u64)
+            .map(|i| {
+                let bt = BlockTime {
+                    block: data_begin + i,
+                    time: 0,
+                };
+                (i, bt)
+            })
+            .collect::<Vec<_>>()
+    };
+    let left = build_btree_from_mappings(&mut w, &mk_mappings(0));
+    let right = build_btree_from_mappings(&mut w, &mk_mappings(20000));
+    let expected = |data_begin| {
+        vec![DataMapping {
+            thin_begin: 0,
+            data_begin,
+            len: 10000,
+        }]
+    };
+
+    let collector = MappingCollector::new(engine);
+    assert_eq!(collector.get_mappings(left.root().block)?, expected(0));
+    assert_eq!(collector.get_mappings(right.root().block)?, expected(20000));
+    assert_eq!(
+        collector.leaves.lock().unwrap().len() as u64,
+        right.nr_leaves()
+    );
+
+    // the leaves of the left tree have to be read again
+    assert_eq!(collector.get_mappings(left.root().block)?, expected(0));
+    assert_eq!(
+        collector.leaves.lock().unwrap().len() as u64,
+        left.nr_leaves()
+    );
+    Ok(())
+}
+
+//------------------------------------------
@@ -0,0 +1,260 @@
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::Write;
+
+use crate::thin::delta_visitor::*;
+use crate::thin::ir::{self, Visit};
+
+//------------------------------------------
+
+// The binary changed-block list is a header followed by a sequence of
+// fixed sized records, all in little endian:
+//
+//   header:  magic (8 bytes), version (u32), data block size in sectors (u32)
+//   record:  type (u32), flags (u32), begin (u64), length (u64)
+//
+// Each diff starts with a DIFF_BEGIN record, with the left and right devices
+// stored in begin and length, and flags telling whether they are root blocks
+// rather than device ids.  The extents follow in sectors, and a DIFF_END
+// record carries the number of extents in the diff.
+pub const BINARY_MAGIC: &[u8; 8] = b"THINDLTA";
+pub const BINARY_VERSION: u32 = 1;
+
+pub const RECORD_DIFF_BEGIN: u32 = 1;
+pub const RECORD_WRITE: u32 = 2;
+pub const RECORD_DISCARD: u32 = 3;
+pub const RECORD_DIFF_END: u32 = 4;
+
+pub const FLAG_LEFT_ROOT: u32 = 1;
+pub const FLAG_RIGHT_ROOT: u32 = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+enum ExtentType {
+    Write,
+    Discard,
+}
+
+struct Extent {
+    typ: ExtentType,
+    begin: u64,
+    len: u64,
+}
+
+// Blocks that have been remapped or newly mapped are written, and those
+// unmapped in the right device are discarded.  Unchanged blocks are skipped.
+fn to_extent(d: &Delta) -> Option<Extent> {
+    match d {
+        Delta::LeftOnly(m) => Some(Extent {
+            typ: ExtentType::Discard,
+            begin: m.thin_begin,
+            len: m.len,
+        }),
+        Delta::RightOnly(m) => Some(Extent {
+            typ: ExtentType::Write,
+            begin: m.thin_begin,
+            len: m.len,
+        }),
+        Delta::Differ(m) => Some(Extent {
+            typ: ExtentType::Write,
+            begin: m.thin_begin,
+            len: m.len,
+        }),
+        Delta::Same(_) => None,
+    }
+}
+
+struct ExtentBuilder {
+    run: Option<Extent>,
+}
+
+impl ExtentBuilder {
+    fn new() -> ExtentBuilder {
+        ExtentBuilder { run: None }
+    }
+
+    fn next(&mut self, e: Extent) -> Option<Extent> {
+        if let Some(ref mut cur) = self.run {
+            if cur.typ == e.typ && cur.begin + cur.len == e.begin {
+                cur.len += e.len;
+                return None;
+            }
+        }
+        self.run.replace(e)
+    }
+
+    fn complete(&mut self) -> Option<Extent> {
+        self.run.take()
+    }
+}
+
+//------------------------------------------
+
+pub struct BinaryWriter<W: Write> {
+    w: W,
+    data_block_size: u64,
+    builder: ExtentBuilder,
+    nr_extents: u64,
+}
+
+impl<W: Write> BinaryWriter<W> {
+    pub fn new(w: W) -> BinaryWriter<W> {
+        BinaryWriter {
+            w,
+            data_block_size: 0,
+            builder: ExtentBuilder::new(),
+            nr_extents: 0,
+        }
+    }
+
+    fn write_record(&mut self, typ: u32, flags: u32, begin: u64, len: u64) -> Result<()> {
+        self.w.write_u32::<LittleEndian>(typ)?;
+        self.w.write_u32::<LittleEndian>(flags)?;
+        self.w.write_u64::<LittleEndian>(begin)?;
+        self.w.write_u64::<LittleEndian>(len)?;
+        Ok(())
+    }
+
+    fn write_extent(&mut self, e: &Extent) -> Result<()> {
+        let typ = match e.typ {
+            ExtentType::Write => RECORD_WRITE,
+            ExtentType::Discard => RECORD_DISCARD,
+        };
+        let begin = e.begin * self.data_block_size;
+        let len = e.len * self.data_block_size;
+        self.write_record(typ, 0, begin, len)?;
+        self.nr_extents += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write> DeltaVisitor for BinaryWriter<W> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.data_block_size = sb.data_block_size as u64;
+        self.w.write_all(BINARY_MAGIC)?;
+        self.w.write_u32::<LittleEndian>(BINARY_VERSION)?;
+        self.w.write_u32::<LittleEndian>(sb.data_block_size)?;
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.w.flush()?;
+        Ok(Visit::Continue)
+    }
+
+    fn diff_b(&mut self, snap1: Snap, snap2: Snap) -> Result<Visit> {
+        let mut flags = 0;
+        let left = match snap1 {
+            Snap::DeviceId(dev_id) => dev_id,
+            Snap::RootBlock(b) => {
+                flags |= FLAG_LEFT_ROOT;
+                b
+            }
+        };
+        let right = match snap2 {
+            Snap::DeviceId(dev_id) => dev_id,
+            Snap::RootBlock(b) => {
+                flags |= FLAG_RIGHT_ROOT;
+                b
+            }
+        };
+        self.nr_extents = 0;
+        self.write_record(RECORD_DIFF_BEGIN, flags, left, right)?;
+        Ok(Visit::Continue)
+    }
+
+    fn diff_e(&mut self) -> Result<Visit> {
+        if let Some(e) = self.builder.complete() {
+            self.write_extent(&e)?;
+        }
+        self.write_record(RECORD_DIFF_END, 0, 0, self.nr_extents)?;
+        Ok(Visit::Continue)
+    }
+
+    fn delta(&mut self, d: &Delta) -> Result<Visit> {
+        if let Some(run) = to_extent(d).and_then(|e| self.builder.next(e)) {
+            self.write_extent(&run)?;
+        }
+        Ok(Visit::Continue)
+    }
+}
+
+//------------------------------------------
+
+// Writes the changed blocks as a qemu dirty bitmap, i.e., one bit per data
+// block, least significant bit first, padded to 64-bit words.  Both the
+// written and the discarded blocks are marked dirty.  The bitmap covers the
+// whole thin device, whose size in sectors isn't held by the metadata and
+// has to be given.
+pub struct QemuBitmapWriter<W: Write> {
+    w: W,
+    bits: Vec<u64>,
+    thin_size: u64,
+    nr_blocks: u64,
+}
+
+impl<W: Write> QemuBitmapWriter<W> {
+    pub fn new(w: W, thin_size: u64) -> QemuBitmapWriter<W> {
+        QemuBitmapWriter {
+            w,
+            bits: Vec::new(),
+            thin_size,
+            nr_blocks: 0,
+        }
+    }
+
+    fn set_range(&mut self, begin: u64, len: u64) {
+        let end = begin + len;
+        let mut b = begin;
+        while b < end {
+            let word = (b / 64) as usize;
+            let bit = b % 64;
+            let n = std::cmp::min(64 - bit, end - b);
+            let mask = if n == 64 {
+                u64::MAX
+            } else {
+                ((1u64 << n) - 1) << bit
+            };
+            self.bits[word] |= mask;
+            b += n;
+        }
+    }
+}
+
+impl<W: Write> DeltaVisitor for QemuBitmapWriter<W> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.nr_blocks = self.thin_size.div_ceil(sb.data_block_size as u64);
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.w.flush()?;
+        Ok(Visit::Continue)
+    }
+
+    fn diff_b(&mut self, _snap1: Snap, _snap2: Snap) -> Result<Visit> {
+        self.bits = vec![0; self.nr_blocks.div_ceil(64) as usize];
+        Ok(Visit::Continue)
+    }
+
+    fn diff_e(&mut self) -> Result<Visit> {
+        for word in &self.bits {
+            self.w.write_u64::<LittleEndian>(*word)?;
+        }
+        Ok(Visit::Continue)
+    }
+
+    fn delta(&mut self, d: &Delta) -> Result<Visit> {
+        if let Some(e) = to_extent(d) {
+            if e.begin + e.len > self.nr_blocks {
+                return Err(anyhow!(
+                    "block {} is mapped beyond the end of the thin device",
+                    e.begin + e.len - 1
+                ));
+            }
+            self.set_range(e.begin, e.len);
+        }
+        Ok(Visit::Continue)
+    }
+}
+
+//------------------------------------------
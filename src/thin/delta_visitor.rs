@@ -36,6 +36,7 @@ pub enum Delta {
     Same(DataMapping),
 }
 
+#[derive(Clone, Copy)]
 pub enum Snap {
     DeviceId(u64),
     RootBlock(u64),
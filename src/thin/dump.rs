@@ -15,8 +15,10 @@ use crate::pdata::space_map::common::*;
 use crate::pdata::unpack::*;
 use crate::report::*;
 use crate::thin::block_time::*;
+use crate::thin::graft::*;
 use crate::thin::human_readable_format::HumanReadableWriter;
 use crate::thin::ir::{self, MetadataVisitor};
+use crate::thin::json;
 use crate::thin::metadata::*;
 use crate::thin::metadata_repair::*;
 use crate::thin::superblock::*;
@@ -187,6 +189,8 @@ impl<'a> MetadataVisitor for OutputVisitor<'a> {
 pub enum OutputFormat {
     XML,
     HumanReadable,
+    JSON,
+    JSONLines,
 }
 
 impl FromStr for OutputFormat {
@@ -196,6 +200,8 @@ impl FromStr for OutputFormat {
         match s {
             "xml" => Ok(OutputFormat::XML),
             "human_readable" => Ok(OutputFormat::HumanReadable),
+            "json" => Ok(OutputFormat::JSON),
+            "json_lines" => Ok(OutputFormat::JSONLines),
             _ => Err(anyhow!("unknown format")),
         }
     }
@@ -285,7 +291,7 @@ fn emit_leaves(
     v.end_walk()
 }
 
-fn emit_entries(
+pub fn emit_entries(
     engine: Arc<dyn IoEngine>,
     out: &mut dyn MetadataVisitor,
     entries: &[Entry],
@@ -305,6 +311,7 @@ fn emit_entries(
                 let str = format!("{}", id);
                 out.ref_shared(&str)?;
             }
+            Entry::Hole => {}
         }
     }
 
@@ -315,7 +322,7 @@ fn emit_entries(
     Ok(())
 }
 
-fn to_superblock_ir(sb: &ThinSuperblock) -> Result<ir::Superblock> {
+pub fn to_superblock_ir(sb: &ThinSuperblock) -> Result<ir::Superblock> {
     match sb {
         ThinSuperblock::OnDisk(sb) => {
             let data_root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
@@ -398,14 +405,26 @@ pub fn dump_with_formatter(opts: ThinDumpOptions, out: &mut dyn MetadataVisitor)
         )
     };
 
-    let md = if opts.skip_mappings {
-        build_metadata_without_mappings(ctx.engine.clone(), &sb)?
-    } else {
-        let m = build_metadata_with_dev(ctx.engine.clone(), &sb, opts.selected_devs)?;
-        optimise_metadata(m)?
-    };
+    if opts.skip_mappings {
+        let md = build_metadata_without_mappings(ctx.engine.clone(), &sb)?;
+        return dump_metadata(ctx.engine, out, &sb, &md);
+    }
+
+    if !opts.repair {
+        let md = build_metadata_with_dev(ctx.engine.clone(), &sb, opts.selected_devs)?;
+        let md = optimise_metadata(md)?;
+        return dump_metadata(ctx.engine, out, &sb, &md);
+    }
 
-    dump_metadata(ctx.engine, out, &sb, &md)
+    let (md, grafts, _) = build_metadata_with_grafts(
+        ctx.engine.clone(),
+        &sb,
+        opts.selected_devs,
+        ctx.report.as_ref(),
+    )?;
+    let md = optimise_metadata(md)?;
+    let mut graft = SnapshotGraft::new(out, grafts);
+    dump_metadata(ctx.engine, &mut graft, &sb, &md)
 }
 
 pub fn dump(opts: ThinDumpOptions) -> Result<()> {
@@ -419,6 +438,8 @@ pub fn dump(opts: ThinDumpOptions) -> Result<()> {
     let mut out: Box<dyn MetadataVisitor> = match opts.format {
         OutputFormat::XML => Box::new(xml::XmlWriter::new(writer)),
         OutputFormat::HumanReadable => Box::new(HumanReadableWriter::new(writer)),
+        OutputFormat::JSON => Box::new(json::JsonWriter::new(writer)),
+        OutputFormat::JSONLines => Box::new(json::JsonLinesWriter::new(writer)),
     };
 
     dump_with_formatter(opts, out.as_mut())
@@ -0,0 +1,276 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::io_engine::*;
+use crate::pdata::btree::{self, *};
+use crate::pdata::btree_walker::*;
+use crate::report::*;
+use crate::thin::block_time::*;
+use crate::thin::dump::{to_superblock_ir, RunBuilder};
+use crate::thin::ir::{self, MetadataVisitor, Visit};
+use crate::thin::metadata::*;
+use crate::thin::superblock::*;
+
+//------------------------------------------
+
+struct HoleCollector<'a> {
+    holes: &'a [(u64, u64)],
+    mappings: Mutex<BTreeMap<u64, BlockTime>>,
+}
+
+impl<'a> HoleCollector<'a> {
+    fn in_holes(&self, k: u64) -> bool {
+        self.holes.iter().any(|(b, e)| *b <= k && k < *e)
+    }
+}
+
+impl<'a> NodeVisitor<BlockTime> for HoleCollector<'a> {
+    fn visit(
+        &self,
+        _path: &[u64],
+        kr: &KeyRange,
+        _h: &NodeHeader,
+        keys: &[u64],
+        values: &[BlockTime],
+    ) -> btree::Result<()> {
+        let begin = kr.start.unwrap_or(0);
+        let end = kr.end.unwrap_or(u64::MAX);
+        if !self.holes.iter().any(|(b, e)| *b < end && begin < *e) {
+            return Ok(());
+        }
+
+        let mut mappings = self.mappings.lock().unwrap();
+        for (k, v) in keys.iter().zip(values) {
+            if self.in_holes(*k) {
+                mappings.insert(*k, *v);
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_again(&self, _path: &[u64], _b: u64) -> btree::Result<()> {
+        Ok(())
+    }
+
+    fn end_walk(&self) -> btree::Result<()> {
+        Ok(())
+    }
+}
+
+/// The device trees of a metadata snapshot, used to fill the holes left
+/// by damage in the live trees.  The snapshot shares any subtrees that
+/// hadn't changed since it was taken, so the mappings found are possibly
+/// older than those lost.
+pub struct GraftSource {
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    roots: BTreeMap<u64, u64>,
+}
+
+impl GraftSource {
+    /// Returns None if the superblock has no readable metadata snapshot.
+    pub fn new(engine: Arc<dyn IoEngine + Send + Sync>, sb: &ThinSuperblock) -> Option<Self> {
+        let snap = match sb {
+            ThinSuperblock::OnDisk(sb) if sb.metadata_snap > 0 => {
+                read_superblock(engine.as_ref(), sb.metadata_snap).ok()?
+            }
+            _ => return None,
+        };
+
+        let roots =
+            btree_to_map::<u64>(&mut vec![0], engine.clone(), true, snap.mapping_root).ok()?;
+        Some(GraftSource { engine, roots })
+    }
+
+    /// Reads the mappings of a device that fall into the holes.  Damaged
+    /// parts of the snapshot are skipped.
+    pub fn read_mappings(&self, dev_id: u64, holes: &[(u64, u64)]) -> BTreeMap<u64, BlockTime> {
+        let root = match self.roots.get(&dev_id) {
+            Some(root) => *root,
+            None => return BTreeMap::new(),
+        };
+
+        let walker = BTreeWalker::new(self.engine.clone(), true);
+        let visitor = HoleCollector {
+            holes,
+            mappings: Mutex::new(BTreeMap::new()),
+        };
+        let _ = walker.walk(&mut vec![0], &visitor, root);
+        visitor.mappings.into_inner().unwrap()
+    }
+}
+
+fn fmt_hole(b: u64, e: u64) -> String {
+    if e == u64::MAX {
+        format!("{}..", b)
+    } else {
+        format!("{}..{}", b, e)
+    }
+}
+
+/// Reads the mappings to fill the holes of each device, leaving out those
+/// to data blocks beyond the pool.  Every hole is reported, along with the
+/// number of mappings grafted into it.
+pub fn read_grafts(
+    src: &GraftSource,
+    holes: &Holes,
+    nr_data_blocks: u64,
+    time: u32,
+    report: &Report,
+) -> BTreeMap<u32, Vec<ir::Map>> {
+    let mut grafts = BTreeMap::new();
+
+    for (dev_id, dev_holes) in holes {
+        let mappings = src.read_mappings(*dev_id as u64, dev_holes);
+
+        for (b, e) in dev_holes {
+            let nr_grafted = mappings
+                .range(b..e)
+                .filter(|(_, bt)| bt.block < nr_data_blocks)
+                .count();
+            report.warning(&format!(
+                "device {}: thin blocks {} damaged, {} mappings grafted from the metadata snapshot",
+                dev_id,
+                fmt_hole(*b, *e),
+                nr_grafted
+            ));
+        }
+
+        let mut runs = Vec::new();
+        let mut builder = RunBuilder::new();
+        for (k, bt) in mappings {
+            if bt.block >= nr_data_blocks {
+                continue;
+            }
+            if let Some(run) = builder.next(k, bt.block, std::cmp::min(bt.time, time)) {
+                runs.push(run);
+            }
+        }
+        runs.extend(builder.complete());
+
+        if !runs.is_empty() {
+            grafts.insert(*dev_id, runs);
+        }
+    }
+
+    grafts
+}
+
+/// Builds the metadata for a device dump.  If there's a metadata snapshot
+/// to graft from, the damaged parts of the mapping trees are filled from it
+/// rather than failing the dump, and the mappings grafted are returned for
+/// each device, along with the holes they were grafted into.
+pub fn build_metadata_with_grafts(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &ThinSuperblock,
+    selected_dev: Option<Vec<u64>>,
+    report: &Report,
+) -> Result<(Metadata, BTreeMap<u32, Vec<ir::Map>>, Holes)> {
+    let src = match GraftSource::new(engine.clone(), sb) {
+        Some(src) => src,
+        None => {
+            let md = build_metadata_with_dev(engine, sb, selected_dev)?;
+            return Ok((md, BTreeMap::new(), Holes::new()));
+        }
+    };
+
+    let (md, holes) = build_metadata_with_holes(engine, sb, selected_dev)?;
+    let out_sb = to_superblock_ir(sb)?;
+    let grafts = read_grafts(&src, &holes, out_sb.nr_data_blocks, out_sb.time, report);
+    Ok((md, grafts, holes))
+}
+
+//------------------------------------------
+
+/// Merges the grafted mappings into the devices passed through, keeping
+/// the mappings of each device in order.
+pub struct SnapshotGraft<'a> {
+    inner: &'a mut dyn MetadataVisitor,
+    grafts: BTreeMap<u32, Vec<ir::Map>>,
+    pending: VecDeque<ir::Map>,
+
+    // The first thin block of each shared def, so the grafts can be put
+    // ahead of the references
+    def_begins: BTreeMap<String, u64>,
+    current_def: Option<String>,
+}
+
+impl<'a> SnapshotGraft<'a> {
+    pub fn new(
+        inner: &'a mut dyn MetadataVisitor,
+        grafts: BTreeMap<u32, Vec<ir::Map>>,
+    ) -> SnapshotGraft<'a> {
+        SnapshotGraft {
+            inner,
+            grafts,
+            pending: VecDeque::new(),
+            def_begins: BTreeMap::new(),
+            current_def: None,
+        }
+    }
+
+    fn flush_before(&mut self, thin_block: u64) -> Result<()> {
+        while let Some(m) = self.pending.front() {
+            if m.thin_begin >= thin_block {
+                break;
+            }
+            let m = self.pending.pop_front().unwrap();
+            self.inner.map(&m)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> MetadataVisitor for SnapshotGraft<'a> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.inner.superblock_b(sb)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.inner.superblock_e()
+    }
+
+    fn def_shared_b(&mut self, name: &str) -> Result<Visit> {
+        self.current_def = Some(name.to_string());
+        self.inner.def_shared_b(name)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        self.current_def = None;
+        self.inner.def_shared_e()
+    }
+
+    fn device_b(&mut self, d: &ir::Device) -> Result<Visit> {
+        self.pending = self.grafts.remove(&d.dev_id).unwrap_or_default().into();
+        self.inner.device_b(d)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        self.flush_before(u64::MAX)?;
+        self.inner.device_e()
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        if let Some(name) = &self.current_def {
+            if !self.def_begins.contains_key(name) {
+                self.def_begins.insert(name.clone(), m.thin_begin);
+            }
+        } else {
+            self.flush_before(m.thin_begin)?;
+        }
+        self.inner.map(m)
+    }
+
+    fn ref_shared(&mut self, name: &str) -> Result<Visit> {
+        if let Some(begin) = self.def_begins.get(name).cloned() {
+            self.flush_before(begin)?;
+        }
+        self.inner.ref_shared(name)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        self.inner.eof()
+    }
+}
+
+//------------------------------------------
@@ -1,20 +1,26 @@
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 
 //------------------------------------------
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Superblock {
     pub uuid: String,
     pub time: u32,
     pub transaction: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub flags: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub version: Option<u32>,
     pub data_block_size: u32,
     pub nr_data_blocks: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub metadata_snap: Option<u64>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Device {
     pub dev_id: u32,
     pub mapped_blocks: u64,
@@ -23,11 +29,15 @@ pub struct Device {
     pub snap_time: u32,
 }
 
-#[derive(Clone)]
+// Field names follow the attributes of the xml range_mapping tag.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Map {
+    #[serde(rename = "origin_begin")]
     pub thin_begin: u64,
     pub data_begin: u64,
     pub time: u32,
+    #[serde(rename = "length")]
     pub len: u64,
 }
 
@@ -0,0 +1,501 @@
+use anyhow::{anyhow, Result};
+use serde::de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{BufReader, Read, Write};
+
+use crate::thin::ir::*;
+
+//---------------------------------------
+
+// The JSON document mirrors the structure of the xml format:
+//
+// {
+//   "superblock": {"uuid": "", "time": 0, ...},
+//   "defs": [
+//     {"def": "name", "mappings": [
+//       {"origin_begin": 0, "data_begin": 0, "time": 0, "length": 16}
+//     ]}
+//   ],
+//   "devices": [
+//     {"device": {"dev_id": 0, ...}, "mappings": [
+//       {"ref": "name"},
+//       {"origin_begin": 16, "data_begin": 16, "time": 0, "length": 8}
+//     ]}
+//   ]
+// }
+//
+// The JSON lines format is a flat stream of the visitor events, one per
+// line, that could be processed without holding the whole document.
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Mapping<'a> {
+    Ref {
+        #[serde(rename = "ref")]
+        name: Cow<'a, str>,
+    },
+    Map(Cow<'a, Map>),
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Section {
+    Superblock,
+    Def,
+    Device,
+}
+
+impl Section {
+    fn list_name(&self) -> &'static str {
+        match self {
+            Section::Superblock => "superblock",
+            Section::Def => "defs",
+            Section::Device => "devices",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum Event<'a> {
+    Superblock(Cow<'a, Superblock>),
+    Def(Cow<'a, str>),
+    Device(Cow<'a, Device>),
+    Map(Cow<'a, Map>),
+    Ref(Cow<'a, str>),
+    End(Section),
+}
+
+//---------------------------------------
+
+// serde_json wraps io errors in its own error type, which hides broken
+// pipes from the callers.  Unwrap them.
+fn write_json<W: Write, T: Serialize + ?Sized>(w: &mut W, v: &T) -> std::io::Result<()> {
+    serde_json::to_writer(w, v).map_err(std::io::Error::from)
+}
+
+pub struct JsonWriter<W: Write> {
+    w: W,
+    section: Section,
+    first_mapping: bool,
+}
+
+impl<W: Write> JsonWriter<W> {
+    pub fn new(w: W) -> JsonWriter<W> {
+        JsonWriter {
+            w,
+            section: Section::Superblock,
+            first_mapping: true,
+        }
+    }
+
+    // Opens the "defs" or "devices" list if needed, then starts a new entry
+    fn begin_entry(&mut self, section: Section) -> Result<()> {
+        if self.section == section {
+            self.w.write_all(b",\n")?;
+        } else {
+            if self.section == Section::Device {
+                return Err(anyhow!("shared definitions must precede the devices"));
+            }
+            self.close_list()?;
+            write!(self.w, ",\n  \"{}\": [\n", section.list_name())?;
+            self.section = section;
+        }
+
+        self.first_mapping = true;
+        Ok(())
+    }
+
+    fn end_entry(&mut self) -> Result<()> {
+        if !self.first_mapping {
+            self.w.write_all(b"\n    ")?;
+        }
+        self.w.write_all(b"]}")?;
+        Ok(())
+    }
+
+    fn close_list(&mut self) -> Result<()> {
+        if self.section != Section::Superblock {
+            self.w.write_all(b"\n  ]")?;
+        }
+        Ok(())
+    }
+
+    fn mapping(&mut self, m: &Mapping) -> Result<()> {
+        if self.first_mapping {
+            self.w.write_all(b"\n      ")?;
+            self.first_mapping = false;
+        } else {
+            self.w.write_all(b",\n      ")?;
+        }
+        write_json(&mut self.w, m)?;
+        Ok(())
+    }
+}
+
+impl<W: Write> MetadataVisitor for JsonWriter<W> {
+    fn superblock_b(&mut self, sb: &Superblock) -> Result<Visit> {
+        self.w.write_all(b"{\n  \"superblock\": ")?;
+        write_json(&mut self.w, sb)?;
+        self.section = Section::Superblock;
+        Ok(Visit::Continue)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.close_list()?;
+        self.w.write_all(b"\n}\n")?;
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_b(&mut self, name: &str) -> Result<Visit> {
+        self.begin_entry(Section::Def)?;
+        self.w.write_all(b"    {\"def\": ")?;
+        write_json(&mut self.w, name)?;
+        self.w.write_all(b", \"mappings\": [")?;
+        Ok(Visit::Continue)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        self.end_entry()?;
+        Ok(Visit::Continue)
+    }
+
+    fn device_b(&mut self, d: &Device) -> Result<Visit> {
+        self.begin_entry(Section::Device)?;
+        self.w.write_all(b"    {\"device\": ")?;
+        write_json(&mut self.w, d)?;
+        self.w.write_all(b", \"mappings\": [")?;
+        Ok(Visit::Continue)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        self.end_entry()?;
+        Ok(Visit::Continue)
+    }
+
+    fn map(&mut self, m: &Map) -> Result<Visit> {
+        self.mapping(&Mapping::Map(Cow::Borrowed(m)))?;
+        Ok(Visit::Continue)
+    }
+
+    fn ref_shared(&mut self, name: &str) -> Result<Visit> {
+        self.mapping(&Mapping::Ref {
+            name: Cow::Borrowed(name),
+        })?;
+        Ok(Visit::Continue)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        self.w.flush()?;
+        Ok(Visit::Continue)
+    }
+}
+
+//---------------------------------------
+
+pub struct JsonLinesWriter<W: Write> {
+    w: W,
+}
+
+impl<W: Write> JsonLinesWriter<W> {
+    pub fn new(w: W) -> JsonLinesWriter<W> {
+        JsonLinesWriter { w }
+    }
+
+    fn event(&mut self, e: &Event) -> Result<Visit> {
+        write_json(&mut self.w, e)?;
+        self.w.write_all(b"\n")?;
+        Ok(Visit::Continue)
+    }
+}
+
+impl<W: Write> MetadataVisitor for JsonLinesWriter<W> {
+    fn superblock_b(&mut self, sb: &Superblock) -> Result<Visit> {
+        self.event(&Event::Superblock(Cow::Borrowed(sb)))
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.event(&Event::End(Section::Superblock))
+    }
+
+    fn def_shared_b(&mut self, name: &str) -> Result<Visit> {
+        self.event(&Event::Def(Cow::Borrowed(name)))
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        self.event(&Event::End(Section::Def))
+    }
+
+    fn device_b(&mut self, d: &Device) -> Result<Visit> {
+        self.event(&Event::Device(Cow::Borrowed(d)))
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        self.event(&Event::End(Section::Device))
+    }
+
+    fn map(&mut self, m: &Map) -> Result<Visit> {
+        self.event(&Event::Map(Cow::Borrowed(m)))
+    }
+
+    fn ref_shared(&mut self, name: &str) -> Result<Visit> {
+        self.event(&Event::Ref(Cow::Borrowed(name)))
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        self.w.flush()?;
+        Ok(Visit::Continue)
+    }
+}
+
+//---------------------------------------
+
+// Tracks the visitor while the document is being deserialized.  Errors
+// returned by the visitor are stashed here so the caller gets them back
+// intact rather than flattened into a serde error message.
+struct ReadState<'a> {
+    visitor: &'a mut dyn MetadataVisitor,
+    err: Option<anyhow::Error>,
+    stopped: bool,
+}
+
+impl<'a> ReadState<'a> {
+    fn call<E, F>(&mut self, f: F) -> std::result::Result<(), E>
+    where
+        E: de::Error,
+        F: FnOnce(&mut dyn MetadataVisitor) -> Result<Visit>,
+    {
+        match f(self.visitor) {
+            Ok(Visit::Continue) => Ok(()),
+            Ok(Visit::Stop) => {
+                self.stopped = true;
+                Err(E::custom("stopped by visitor"))
+            }
+            Err(e) => {
+                let msg = e.to_string();
+                self.err = Some(e);
+                Err(E::custom(msg))
+            }
+        }
+    }
+}
+
+struct DocumentSeed<'s, 'a>(&'s mut ReadState<'a>);
+
+impl<'de> DeserializeSeed<'de> for DocumentSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, d: D) -> std::result::Result<(), D::Error> {
+        d.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for DocumentSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a thin metadata document")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<(), A::Error> {
+        const FIELDS: &[&str] = &["superblock", "defs", "devices"];
+
+        let mut has_superblock = false;
+        while let Some(key) = map.next_key::<String>()? {
+            match (key.as_str(), has_superblock) {
+                ("superblock", false) => {
+                    let sb: Superblock = map.next_value()?;
+                    self.0.call(|v| v.superblock_b(&sb))?;
+                    has_superblock = true;
+                }
+                ("superblock", true) => return Err(de::Error::duplicate_field("superblock")),
+                ("defs", true) => map.next_value_seed(ListSeed(&mut *self.0, Section::Def))?,
+                ("devices", true) => {
+                    map.next_value_seed(ListSeed(&mut *self.0, Section::Device))?
+                }
+                ("defs", false) | ("devices", false) => {
+                    return Err(de::Error::missing_field("superblock"))
+                }
+                _ => return Err(de::Error::unknown_field(&key, FIELDS)),
+            }
+        }
+
+        if !has_superblock {
+            return Err(de::Error::missing_field("superblock"));
+        }
+        self.0.call(|v| v.superblock_e())
+    }
+}
+
+struct ListSeed<'s, 'a>(&'s mut ReadState<'a>, Section);
+
+impl<'de> DeserializeSeed<'de> for ListSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, d: D) -> std::result::Result<(), D::Error> {
+        d.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ListSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a list of {}", self.1.list_name())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<(), A::Error> {
+        while seq
+            .next_element_seed(EntrySeed(&mut *self.0, self.1))?
+            .is_some()
+        {}
+        Ok(())
+    }
+}
+
+// A def or device entry: the header must precede the mappings
+struct EntrySeed<'s, 'a>(&'s mut ReadState<'a>, Section);
+
+impl<'de> DeserializeSeed<'de> for EntrySeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, d: D) -> std::result::Result<(), D::Error> {
+        d.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for EntrySeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a def or device entry")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<(), A::Error> {
+        let (header, fields): (&str, &'static [&'static str]) = match self.1 {
+            Section::Def => ("def", &["def", "mappings"]),
+            _ => ("device", &["device", "mappings"]),
+        };
+
+        let mut begun = false;
+        while let Some(key) = map.next_key::<String>()? {
+            match (key.as_str(), begun) {
+                ("def", false) if self.1 == Section::Def => {
+                    let name: String = map.next_value()?;
+                    self.0.call(|v| v.def_shared_b(&name))?;
+                    begun = true;
+                }
+                ("device", false) if self.1 == Section::Device => {
+                    let d: Device = map.next_value()?;
+                    self.0.call(|v| v.device_b(&d))?;
+                    begun = true;
+                }
+                ("mappings", true) => map.next_value_seed(MappingsSeed(&mut *self.0))?,
+                ("mappings", false) => return Err(de::Error::missing_field(header)),
+                (k, true) if k == header => return Err(de::Error::duplicate_field(header)),
+                _ => return Err(de::Error::unknown_field(&key, fields)),
+            }
+        }
+
+        if !begun {
+            return Err(de::Error::missing_field(header));
+        }
+
+        match self.1 {
+            Section::Def => self.0.call(|v| v.def_shared_e()),
+            _ => self.0.call(|v| v.device_e()),
+        }
+    }
+}
+
+struct MappingsSeed<'s, 'a>(&'s mut ReadState<'a>);
+
+impl<'de> DeserializeSeed<'de> for MappingsSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, d: D) -> std::result::Result<(), D::Error> {
+        d.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for MappingsSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of mappings")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<(), A::Error> {
+        while let Some(m) = seq.next_element::<Mapping>()? {
+            match m {
+                Mapping::Map(m) => self.0.call(|v| v.map(&m))?,
+                Mapping::Ref { name } => self.0.call(|v| v.ref_shared(&name))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+//---------------------------------------
+
+pub fn read<R, M>(input: R, visitor: &mut M) -> Result<()>
+where
+    R: Read,
+    M: MetadataVisitor,
+{
+    let mut de = serde_json::Deserializer::from_reader(BufReader::new(input));
+    let mut state = ReadState {
+        visitor,
+        err: None,
+        stopped: false,
+    };
+
+    let r = DocumentSeed(&mut state)
+        .deserialize(&mut de)
+        .and_then(|_| de.end());
+
+    match r {
+        Ok(()) => {
+            state.visitor.eof()?;
+            Ok(())
+        }
+        Err(_) if state.stopped => Ok(()),
+        Err(e) => match state.err.take() {
+            Some(err) => Err(err),
+            None => Err(anyhow!("parse error: {}", e)),
+        },
+    }
+}
+
+pub fn read_lines<R, M>(input: R, visitor: &mut M) -> Result<()>
+where
+    R: Read,
+    M: MetadataVisitor,
+{
+    let events = serde_json::Deserializer::from_reader(BufReader::new(input)).into_iter::<Event>();
+
+    for e in events {
+        let e = e.map_err(|e| anyhow!("parse error: {}", e))?;
+        let r = match e {
+            Event::Superblock(sb) => visitor.superblock_b(&sb)?,
+            Event::Def(name) => visitor.def_shared_b(&name)?,
+            Event::Device(d) => visitor.device_b(&d)?,
+            Event::Map(m) => visitor.map(&m)?,
+            Event::Ref(name) => visitor.ref_shared(&name)?,
+            Event::End(Section::Superblock) => visitor.superblock_e()?,
+            Event::End(Section::Def) => visitor.def_shared_e()?,
+            Event::End(Section::Device) => visitor.device_e()?,
+        };
+
+        if let Visit::Stop = r {
+            return Ok(());
+        }
+    }
+
+    visitor.eof()?;
+    Ok(())
+}
+
+//---------------------------------------
@@ -0,0 +1,233 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::io_engine::*;
+use crate::pdata::btree_walker::*;
+use crate::thin::block_time::*;
+use crate::thin::dump::RunBuilder;
+use crate::thin::ir::{self, MetadataVisitor, Visit};
+use crate::thin::metadata::{Holes, Metadata};
+use crate::thin::metadata_repair::OrphanTree;
+
+//------------------------------------------
+
+/// Picks out the orphans that could have been lost from the damaged
+/// devices.  A subtree is kept if its keys fall within a hole of a
+/// device, and it has mappings no older than the device's last
+/// snapshot, as a leaf private to a device was written since.  Other
+/// unreferenced subtrees are most likely left over from copy-on-write.
+pub fn select_orphans(orphans: Vec<OrphanTree>, holes: &Holes, md: &Metadata) -> Vec<OrphanTree> {
+    let snap_times: BTreeMap<u32, u32> = md
+        .devs
+        .iter()
+        .map(|d| (d.thin_id, d.detail.snapshotted_time))
+        .collect();
+
+    orphans
+        .into_iter()
+        .filter(|o| {
+            holes.iter().any(|(thin_id, dev_holes)| {
+                snap_times.get(thin_id).is_some_and(|t| o.age >= *t)
+                    && dev_holes
+                        .iter()
+                        .any(|(b, e)| *b <= o.key_low && o.key_high < *e)
+            })
+        })
+        .collect()
+}
+
+//------------------------------------------
+
+// Where an orphaned subtree is placed in the lost+found device.  The
+// subtrees are laid out back to back in the order given, keeping the
+// relative offsets of the original keys, so the device is the same for
+// every run over the same metadata.
+struct Placement {
+    orphan: OrphanTree,
+    thin_begin: u64,
+    nr_emitted: u64,
+}
+
+impl Placement {
+    fn thin_end(&self) -> u64 {
+        self.thin_begin + (self.orphan.key_high - self.orphan.key_low + 1)
+    }
+}
+
+/// Appends a synthetic device holding the orphaned mapping subtrees to
+/// the metadata passed through.  The device takes the id following the
+/// highest one seen.
+pub struct LostFound<'a> {
+    inner: &'a mut dyn MetadataVisitor,
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    placements: Vec<Placement>,
+    sb: Option<ir::Superblock>,
+    next_dev_id: u32,
+    dev_id: Option<u32>,
+}
+
+impl<'a> LostFound<'a> {
+    pub fn new(
+        inner: &'a mut dyn MetadataVisitor,
+        engine: Arc<dyn IoEngine + Send + Sync>,
+        orphans: Vec<OrphanTree>,
+    ) -> LostFound<'a> {
+        let mut thin_begin = 0;
+        let placements = orphans
+            .into_iter()
+            .map(|orphan| {
+                let p = Placement {
+                    orphan,
+                    thin_begin,
+                    nr_emitted: 0,
+                };
+                thin_begin = p.thin_end();
+                p
+            })
+            .collect();
+
+        LostFound {
+            inner,
+            engine,
+            placements,
+            sb: None,
+            next_dev_id: 0,
+            dev_id: None,
+        }
+    }
+
+    /// The id of the lost+found device, if there was anything to recover
+    pub fn dev_id(&self) -> Option<u32> {
+        self.dev_id
+    }
+
+    // Mappings to data blocks beyond the pool can't be restored
+    fn read_runs(&mut self, nr_data_blocks: u64, time: u32) -> Result<Vec<ir::Map>> {
+        let mut runs = Vec::new();
+        for p in self.placements.iter_mut() {
+            let mappings =
+                btree_to_map::<BlockTime>(&mut vec![0], self.engine.clone(), true, p.orphan.root)?;
+
+            let mut builder = RunBuilder::new();
+            for (k, bt) in mappings {
+                if bt.block >= nr_data_blocks {
+                    continue;
+                }
+                let thin_block = p.thin_begin + (k - p.orphan.key_low);
+                if let Some(run) = builder.next(thin_block, bt.block, std::cmp::min(bt.time, time))
+                {
+                    runs.push(run);
+                }
+                p.nr_emitted += 1;
+            }
+            runs.extend(builder.complete());
+        }
+        Ok(runs)
+    }
+
+    fn emit_device(&mut self) -> Result<()> {
+        let (nr_data_blocks, time, transaction) = match &self.sb {
+            Some(sb) => (sb.nr_data_blocks, sb.time, sb.transaction),
+            None => return Ok(()),
+        };
+
+        let runs = self.read_runs(nr_data_blocks, time)?;
+        if runs.is_empty() {
+            return Ok(());
+        }
+
+        // The device is a snapshot taken at the current time, so writes
+        // break the sharing of the older mappings, rather than overwrite
+        // data that might still be in use.
+        let dev = ir::Device {
+            dev_id: self.next_dev_id,
+            mapped_blocks: runs.iter().map(|m| m.len).sum(),
+            transaction: transaction.saturating_sub(1),
+            creation_time: time,
+            snap_time: time,
+        };
+        self.inner.device_b(&dev)?;
+        for m in runs.iter() {
+            self.inner.map(m)?;
+        }
+        self.inner.device_e()?;
+        self.dev_id = Some(dev.dev_id);
+
+        Ok(())
+    }
+
+    /// Writes out where the subtrees were placed in the lost+found
+    /// device, with the keys they had originally.  The ends are exclusive.
+    pub fn write_report(&self, w: &mut dyn Write) -> Result<()> {
+        match self.dev_id {
+            Some(dev_id) => writeln!(w, "# lost+found device {}", dev_id)?,
+            None => writeln!(w, "# no lost+found device")?,
+        }
+        writeln!(
+            w,
+            "# thin_begin thin_end orig_begin orig_end subtree nr_mappings"
+        )?;
+        if self.dev_id.is_none() {
+            return Ok(());
+        }
+
+        for p in self.placements.iter().filter(|p| p.nr_emitted > 0) {
+            writeln!(
+                w,
+                "{} {} {} {} {} {}",
+                p.thin_begin,
+                p.thin_end(),
+                p.orphan.key_low,
+                p.orphan.key_high + 1,
+                p.orphan.root,
+                p.nr_emitted
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> MetadataVisitor for LostFound<'a> {
+    fn superblock_b(&mut self, sb: &ir::Superblock) -> Result<Visit> {
+        self.sb = Some(sb.clone());
+        self.inner.superblock_b(sb)
+    }
+
+    fn superblock_e(&mut self) -> Result<Visit> {
+        self.emit_device()?;
+        self.inner.superblock_e()
+    }
+
+    fn def_shared_b(&mut self, name: &str) -> Result<Visit> {
+        self.inner.def_shared_b(name)
+    }
+
+    fn def_shared_e(&mut self) -> Result<Visit> {
+        self.inner.def_shared_e()
+    }
+
+    fn device_b(&mut self, d: &ir::Device) -> Result<Visit> {
+        self.next_dev_id = std::cmp::max(self.next_dev_id, d.dev_id + 1);
+        self.inner.device_b(d)
+    }
+
+    fn device_e(&mut self) -> Result<Visit> {
+        self.inner.device_e()
+    }
+
+    fn map(&mut self, m: &ir::Map) -> Result<Visit> {
+        self.inner.map(m)
+    }
+
+    fn ref_shared(&mut self, name: &str) -> Result<Visit> {
+        self.inner.ref_shared(name)
+    }
+
+    fn eof(&mut self) -> Result<Visit> {
+        self.inner.eof()
+    }
+}
+
+//------------------------------------------
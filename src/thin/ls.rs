@@ -134,6 +134,178 @@ impl std::fmt::Display for OutputField {
 
 //------------------------------------------
 
+fn is_pretty_printed(field: &OutputField) -> bool {
+    use OutputField::*;
+    matches!(field, Mapped | Exclusive | Shared | HighestMapped)
+}
+
+fn is_counting_field(field: &OutputField) -> bool {
+    use OutputField::*;
+    !matches!(
+        field,
+        DeviceId | TransactionId | CreationTime | SnapshottedTime
+    )
+}
+
+//------------------------------------------
+
+// Sorts by the field in ascending order, or descending if prefixed by '-'
+#[derive(Clone)]
+pub struct SortKey {
+    pub field: OutputField,
+    pub descending: bool,
+}
+
+impl FromStr for SortKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, descending) = match s.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (s.strip_prefix('+').unwrap_or(s), false),
+        };
+
+        Ok(SortKey {
+            field: name.parse::<OutputField>()?,
+            descending,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+enum FilterOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+// An expression in the form of FIELD<op>VALUE, e.g., EXCLUSIVE_BYTES>10G.
+// Values with a unit suffix are converted to bytes.
+#[derive(Clone)]
+pub struct Filter {
+    field: OutputField,
+    op: FilterOp,
+    value: u64,
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const OPS: [(&str, FilterOp); 6] = [
+            (">=", FilterOp::Ge),
+            ("<=", FilterOp::Le),
+            ("!=", FilterOp::Ne),
+            ("=", FilterOp::Eq),
+            (">", FilterOp::Gt),
+            ("<", FilterOp::Lt),
+        ];
+
+        let pos = s
+            .find(['<', '>', '=', '!'])
+            .ok_or_else(|| anyhow!("Missing comparison operator"))?;
+        let (name, rest) = s.split_at(pos);
+        let (op_str, op) = OPS
+            .iter()
+            .find(|(op_str, _)| rest.starts_with(op_str))
+            .ok_or_else(|| anyhow!("Unknown comparison operator"))?;
+        let value_str = &rest[op_str.len()..];
+
+        let value = if value_str.chars().all(|c| c.is_ascii_digit()) {
+            value_str.parse::<u64>()?
+        } else {
+            value_str.parse::<StorageSize>()?.size_bytes()
+        };
+
+        Ok(Filter {
+            field: name.parse::<OutputField>()?,
+            op: *op,
+            value,
+        })
+    }
+}
+
+impl Filter {
+    fn matches(&self, row: &DeviceRow, data_block_size: u64) -> bool {
+        let val = row.value(&self.field, data_block_size);
+        match self.op {
+            FilterOp::Eq => val == self.value,
+            FilterOp::Ne => val != self.value,
+            FilterOp::Lt => val < self.value,
+            FilterOp::Le => val <= self.value,
+            FilterOp::Gt => val > self.value,
+            FilterOp::Ge => val >= self.value,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum OutputMode {
+    Table,
+    Json,
+    Csv,
+}
+
+impl FromStr for OutputMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputMode::Table),
+            "json" => Ok(OutputMode::Json),
+            "csv" => Ok(OutputMode::Csv),
+            _ => Err(anyhow!("Unknown output mode")),
+        }
+    }
+}
+
+//------------------------------------------
+
+struct DeviceRow {
+    dev_id: u64,
+    detail: DeviceDetail,
+    mapped_blocks: u64,
+    shared_blocks: u64,
+    highest_mapped_block: u64,
+}
+
+impl DeviceRow {
+    fn value(&self, field: &OutputField, bs: u64) -> u64 {
+        use OutputField::*;
+
+        let mapped_blocks = self.mapped_blocks;
+        let shared_blocks = self.shared_blocks;
+        let ex_blocks = mapped_blocks - shared_blocks;
+        let highest_mapped_block = self.highest_mapped_block;
+
+        match field {
+            DeviceId => self.dev_id,
+            TransactionId => self.detail.transaction_id,
+            CreationTime => self.detail.creation_time as u64,
+            SnapshottedTime => self.detail.snapshotted_time as u64,
+            MappedBlocks => mapped_blocks,
+            MappedSectors => mapped_blocks * bs,
+            MappedBytes | Mapped => (mapped_blocks * bs) << SECTOR_SHIFT as u64,
+            ExclusiveBlocks => ex_blocks,
+            ExclusiveSectors => ex_blocks * bs,
+            ExclusiveBytes | Exclusive => (ex_blocks * bs) << SECTOR_SHIFT as u64,
+            SharedBlocks => shared_blocks,
+            SharedSectors => shared_blocks * bs,
+            SharedBytes | Shared => (shared_blocks * bs) << SECTOR_SHIFT as u64,
+            HighestMappedBlock => highest_mapped_block,
+            HighestMappedSector => (highest_mapped_block + 1) * bs - 1,
+            HighestMappedByte | HighestMapped => {
+                (((highest_mapped_block + 1) * bs) << SECTOR_SHIFT) - 1
+            }
+        }
+    }
+}
+
+//------------------------------------------
+
 pub struct LsTable<'a> {
     fields: &'a [OutputField],
     grid: GridLayout,
@@ -162,53 +334,21 @@ impl<'a> LsTable<'a> {
         self.grid.new_row();
     }
 
-    fn push_row(
-        &mut self,
-        dev_id: u64,
-        detail: &DeviceDetail,
-        mapped_blocks: u64,
-        shared_blocks: u64,
-        highest_mapped_block: u64,
-    ) {
-        use OutputField::*;
-
+    fn push_row(&mut self, row: &DeviceRow) {
         if self.fields.is_empty() {
             return;
         }
 
-        let bs = self.data_block_size;
-        let ex_blocks = mapped_blocks - shared_blocks;
-
         for field in self.fields {
-            let val: u64 = match field {
-                DeviceId => dev_id,
-                TransactionId => detail.transaction_id,
-                CreationTime => detail.creation_time as u64,
-                SnapshottedTime => detail.snapshotted_time as u64,
-                MappedBlocks => mapped_blocks,
-                MappedSectors => mapped_blocks * bs,
-                MappedBytes | Mapped => (mapped_blocks * bs) << SECTOR_SHIFT as u64,
-                ExclusiveBlocks => ex_blocks,
-                ExclusiveSectors => ex_blocks * bs,
-                ExclusiveBytes | Exclusive => (ex_blocks * bs) << SECTOR_SHIFT as u64,
-                SharedBlocks => shared_blocks,
-                SharedSectors => shared_blocks * bs,
-                SharedBytes | Shared => (shared_blocks * bs) << SECTOR_SHIFT as u64,
-                HighestMappedBlock => highest_mapped_block,
-                HighestMappedSector => (highest_mapped_block + 1) * bs - 1,
-                HighestMappedByte | HighestMapped => {
-                    (((highest_mapped_block + 1) * bs) << SECTOR_SHIFT) - 1
-                }
-            };
+            let val = row.value(field, self.data_block_size);
 
-            let cell = match field {
-                Mapped | Exclusive | Shared | HighestMapped => {
-                    let (val, unit) = to_pretty_print_size(val);
-                    let mut s = val.to_string();
-                    s.push_str(&unit.to_string_short());
-                    s
-                }
-                _ => val.to_string(),
+            let cell = if is_pretty_printed(field) {
+                let (val, unit) = to_pretty_print_size(val);
+                let mut s = val.to_string();
+                s.push_str(&unit.to_string_short());
+                s
+            } else {
+                val.to_string()
             };
 
             self.grid.field(cell);
@@ -222,6 +362,51 @@ impl<'a> LsTable<'a> {
     }
 }
 
+// The machine readable outputs always show the raw values
+fn write_json(
+    w: &mut dyn Write,
+    fields: &[OutputField],
+    rows: &[DeviceRow],
+    bs: u64,
+) -> Result<()> {
+    let devices: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| {
+            let obj = fields
+                .iter()
+                .map(|f| (f.to_string(), serde_json::Value::from(row.value(f, bs))))
+                .collect::<serde_json::Map<_, _>>();
+            serde_json::Value::Object(obj)
+        })
+        .collect();
+
+    serde_json::to_writer_pretty(&mut *w, &devices)?;
+    writeln!(w)?;
+    Ok(())
+}
+
+fn write_csv(
+    w: &mut dyn Write,
+    fields: &[OutputField],
+    rows: &[DeviceRow],
+    bs: u64,
+    no_headers: bool,
+) -> Result<()> {
+    if !no_headers {
+        let headers: Vec<String> = fields.iter().map(|f| f.to_string()).collect();
+        writeln!(w, "{}", headers.join(","))?;
+    }
+
+    for row in rows {
+        let values: Vec<String> = fields
+            .iter()
+            .map(|f| row.value(f, bs).to_string())
+            .collect();
+        writeln!(w, "{}", values.join(","))?;
+    }
+    Ok(())
+}
+
 //------------------------------------------
 
 #[derive(Debug, Clone)]
@@ -1142,6 +1327,10 @@ pub struct ThinLsOptions<'a> {
     pub engine_opts: EngineOptions,
     pub fields: Vec<OutputField>,
     pub no_headers: bool,
+    pub sort_keys: Vec<SortKey>,
+    pub filters: Vec<Filter>,
+    pub dev_ids: Option<Vec<u64>>,
+    pub mode: OutputMode,
     pub report: Arc<Report>,
 }
 
@@ -1161,21 +1350,25 @@ fn mk_context(opts: &ThinLsOptions) -> Result<Context> {
     })
 }
 
-fn some_counting_fields(fields: &[OutputField]) -> bool {
-    use OutputField::*;
+fn some_counting_fields(opts: &ThinLsOptions) -> bool {
+    opts.fields
+        .iter()
+        .chain(opts.sort_keys.iter().map(|k| &k.field))
+        .chain(opts.filters.iter().map(|f| &f.field))
+        .any(is_counting_field)
+}
 
-    for field in fields.iter() {
-        match field {
-            DeviceId | TransactionId | CreationTime | SnapshottedTime => {
-                continue;
-            }
-            _ => {
-                return true;
+fn sort_rows(rows: &mut [DeviceRow], keys: &[SortKey], bs: u64) {
+    rows.sort_by(|lhs, rhs| {
+        for k in keys {
+            let ord = lhs.value(&k.field, bs).cmp(&rhs.value(&k.field, bs));
+            let ord = if k.descending { ord.reverse() } else { ord };
+            if ord.is_ne() {
+                return ord;
             }
         }
-    }
-
-    false
+        std::cmp::Ordering::Equal
+    });
 }
 
 pub fn ls(opts: ThinLsOptions) -> Result<()> {
@@ -1194,30 +1387,55 @@ pub fn ls(opts: ThinLsOptions) -> Result<()> {
     let details =
         btree_to_map::<DeviceDetail>(&mut path, ctx.engine.clone(), false, sb.details_root)?;
 
-    let mut table = LsTable::new(&opts.fields, details.len(), sb.data_block_size);
-    if !opts.no_headers {
-        table.push_headers();
-    }
-
-    if some_counting_fields(&opts.fields) {
+    let mut rows: Vec<DeviceRow> = if some_counting_fields(&opts) {
         let actual_sb = read_superblock(ctx.engine.as_ref(), SUPERBLOCK_LOCATION)?;
         let mapped = count_data_mappings(&ctx, &actual_sb, sb.mapping_root, false)?;
-        for ((dev_id, detail), summary) in details.iter().zip(mapped) {
-            table.push_row(
-                *dev_id,
-                detail,
-                summary.nr_mappings,
-                summary.nr_shared,
-                summary.key_high,
-            );
-        }
+        details
+            .iter()
+            .zip(mapped)
+            .map(|((dev_id, detail), summary)| DeviceRow {
+                dev_id: *dev_id,
+                detail: *detail,
+                mapped_blocks: summary.nr_mappings,
+                shared_blocks: summary.nr_shared,
+                highest_mapped_block: summary.key_high,
+            })
+            .collect()
     } else {
-        for (dev_id, detail) in details.iter() {
-            table.push_row(*dev_id, detail, 0, 0, 0);
+        details
+            .iter()
+            .map(|(dev_id, detail)| DeviceRow {
+                dev_id: *dev_id,
+                detail: *detail,
+                mapped_blocks: 0,
+                shared_blocks: 0,
+                highest_mapped_block: 0,
+            })
+            .collect()
+    };
+
+    let bs = sb.data_block_size as u64;
+    if let Some(ids) = &opts.dev_ids {
+        rows.retain(|row| ids.contains(&row.dev_id));
+    }
+    rows.retain(|row| opts.filters.iter().all(|f| f.matches(row, bs)));
+    sort_rows(&mut rows, &opts.sort_keys, bs);
+
+    let mut w = std::io::stdout();
+    match opts.mode {
+        OutputMode::Table => {
+            let mut table = LsTable::new(&opts.fields, rows.len(), sb.data_block_size);
+            if !opts.no_headers {
+                table.push_headers();
+            }
+            for row in &rows {
+                table.push_row(row);
+            }
+            table.render(&mut w)
         }
+        OutputMode::Json => write_json(&mut w, &opts.fields, &rows, bs),
+        OutputMode::Csv => write_csv(&mut w, &opts.fields, &rows, bs, opts.no_headers),
     }
-
-    table.render(&mut std::io::stdout())
 }
 
 //------------------------------------------
@@ -0,0 +1,248 @@
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::commands::engine::*;
+use crate::io_engine::*;
+use crate::pdata::btree_iterator::BTreeIterator;
+use crate::pdata::btree_walker::btree_to_map;
+use crate::pdata::space_map::metadata::*;
+use crate::report::*;
+use crate::thin::block_time::*;
+use crate::thin::device_detail::DeviceDetail;
+use crate::thin::dump::*;
+use crate::thin::ir::{self, MetadataVisitor};
+use crate::thin::metadata::*;
+use crate::thin::restore::*;
+use crate::thin::superblock::*;
+use crate::write_batcher::*;
+
+//------------------------------------------
+
+pub struct ThinMergeOptions<'a> {
+    pub input: &'a Path,
+    pub output: &'a Path,
+    pub engine_opts: EngineOptions,
+    pub report: Arc<Report>,
+    pub origin: u64,
+    pub snapshot: u64,
+    pub overlay: bool,
+}
+
+struct Context {
+    report: Arc<Report>,
+    engine_in: Arc<dyn IoEngine + Send + Sync>,
+    engine_out: Arc<dyn IoEngine + Send + Sync>,
+}
+
+fn new_context(opts: &ThinMergeOptions) -> Result<Context> {
+    let engine_in = EngineBuilder::new(opts.input, &opts.engine_opts).build()?;
+    let engine_out = EngineBuilder::new(opts.output, &opts.engine_opts)
+        .write(true)
+        .build()?;
+
+    Ok(Context {
+        report: opts.report.clone(),
+        engine_in,
+        engine_out,
+    })
+}
+
+//------------------------------------------
+
+// Visits the mappings of both devices in key order.  Where both devices
+// map a block, the snapshot's mapping is used.
+fn overlay_mappings<F>(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    origin_root: u64,
+    snap_root: u64,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(u64, &BlockTime) -> Result<()>,
+{
+    let mut origin = BTreeIterator::<BlockTime>::new(engine.clone(), origin_root)?;
+    let mut snap = BTreeIterator::<BlockTime>::new(engine, snap_root)?;
+
+    loop {
+        let o = origin.get().map(|(k, v)| (k, *v));
+        let s = snap.get().map(|(k, v)| (k, *v));
+
+        match (o, s) {
+            (None, None) => break,
+            (Some((ok, ov)), None) => {
+                f(ok, &ov)?;
+                origin.step()?;
+            }
+            (None, Some((sk, sv))) => {
+                f(sk, &sv)?;
+                snap.step()?;
+            }
+            (Some((ok, ov)), Some((sk, sv))) => {
+                if ok < sk {
+                    f(ok, &ov)?;
+                    origin.step()?;
+                } else {
+                    if ok == sk {
+                        origin.step()?;
+                    }
+                    f(sk, &sv)?;
+                    snap.step()?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn emit_overlay(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    out: &mut dyn MetadataVisitor,
+    origin_root: u64,
+    snap_root: u64,
+) -> Result<()> {
+    let mut builder = RunBuilder::new();
+
+    overlay_mappings(engine, origin_root, snap_root, |k, bt| {
+        if let Some(run) = builder.next(k, bt.block, bt.time) {
+            out.map(&run)?;
+        }
+        Ok(())
+    })?;
+
+    if let Some(run) = builder.complete() {
+        out.map(&run)?;
+    }
+
+    Ok(())
+}
+
+fn to_device_ir(thin_id: u32, detail: &DeviceDetail) -> ir::Device {
+    ir::Device {
+        dev_id: thin_id,
+        mapped_blocks: detail.mapped_blocks,
+        transaction: detail.transaction_id,
+        creation_time: detail.creation_time,
+        snap_time: detail.snapshotted_time,
+    }
+}
+
+struct MergedDevice<'a> {
+    origin: &'a Device,
+    snap: &'a Device,
+    origin_root: u64,
+    snap_root: u64,
+    overlay: bool,
+}
+
+fn merge_metadata(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    out: &mut dyn MetadataVisitor,
+    sb: &ThinSuperblock,
+    md: &Metadata,
+    merged: &MergedDevice,
+) -> Result<()> {
+    let out_sb = to_superblock_ir(sb)?;
+    out.superblock_b(&out_sb)?;
+
+    // Defs that were only referenced by the snapshot are released by
+    // the restorer, along with their data blocks.
+    for d in &md.defs {
+        out.def_shared_b(&format!("{}", d.def_id))?;
+        emit_entries(engine.clone(), out, &d.map.entries)?;
+        out.def_shared_e()?;
+    }
+
+    for dev in &md.devs {
+        if dev.thin_id == merged.snap.thin_id {
+            continue;
+        }
+
+        if dev.thin_id != merged.origin.thin_id {
+            out.device_b(&to_device_ir(dev.thin_id, &dev.detail))?;
+            emit_entries(engine.clone(), out, &dev.map.entries)?;
+            out.device_e()?;
+            continue;
+        }
+
+        // The origin keeps its identity, but takes on the contents of
+        // the snapshot.
+        let mut detail = merged.origin.detail;
+        detail.snapshotted_time = std::cmp::max(
+            merged.origin.detail.snapshotted_time,
+            merged.snap.detail.snapshotted_time,
+        );
+
+        if merged.overlay {
+            let mut nr_mapped = 0;
+            overlay_mappings(
+                engine.clone(),
+                merged.origin_root,
+                merged.snap_root,
+                |_, _| {
+                    nr_mapped += 1;
+                    Ok(())
+                },
+            )?;
+            detail.mapped_blocks = nr_mapped;
+
+            out.device_b(&to_device_ir(dev.thin_id, &detail))?;
+            emit_overlay(engine.clone(), out, merged.origin_root, merged.snap_root)?;
+        } else {
+            detail.mapped_blocks = merged.snap.detail.mapped_blocks;
+
+            out.device_b(&to_device_ir(dev.thin_id, &detail))?;
+            emit_entries(engine.clone(), out, &merged.snap.map.entries)?;
+        }
+        out.device_e()?;
+    }
+
+    out.superblock_e()?;
+    out.eof()?;
+
+    Ok(())
+}
+
+fn find_device(md: &Metadata, thin_id: u64) -> Result<&Device> {
+    md.devs
+        .iter()
+        .find(|d| d.thin_id as u64 == thin_id)
+        .ok_or_else(|| anyhow!("couldn't find device {}", thin_id))
+}
+
+//------------------------------------------
+
+pub fn merge(opts: ThinMergeOptions) -> Result<()> {
+    if opts.origin == opts.snapshot {
+        return Err(anyhow!("the origin and snapshot must be different devices"));
+    }
+
+    let ctx = new_context(&opts)?;
+
+    let sb = read_superblock(ctx.engine_in.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![0], ctx.engine_in.clone(), false, sb.mapping_root)?;
+    let sb = ThinSuperblock::OnDisk(sb);
+
+    let md = build_metadata(ctx.engine_in.clone(), &sb)?;
+    let md = optimise_metadata(md)?;
+
+    let origin = find_device(&md, opts.origin)?;
+    let snap = find_device(&md, opts.snapshot)?;
+    let merged = MergedDevice {
+        origin,
+        snap,
+        origin_root: roots[&opts.origin],
+        snap_root: roots[&opts.snapshot],
+        overlay: opts.overlay,
+    };
+
+    let sm = core_metadata_sm(ctx.engine_out.get_nr_blocks(), u32::MAX);
+    let batch_size = ctx.engine_out.get_batch_size();
+    let mut w = WriteBatcher::new(ctx.engine_out, sm.clone(), batch_size);
+    let mut restorer = Restorer::new(&mut w, ctx.report);
+
+    merge_metadata(ctx.engine_in, &mut restorer, &sb, &md, &merged)
+}
+
+//------------------------------------------
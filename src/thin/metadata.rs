@@ -2,10 +2,12 @@ use anyhow::Result;
 use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
 
+use crate::checksum;
 use crate::io_engine::IoEngine;
 use crate::pdata::btree::{self, *};
 use crate::pdata::btree_leaf_walker::*;
 use crate::pdata::btree_walker::*;
+use crate::pdata::issues::issues_from_btree_error;
 use crate::pdata::space_map::*;
 use crate::thin::block_time::*;
 use crate::thin::device_detail::*;
@@ -21,6 +23,10 @@ type ThinId = u32;
 pub enum Entry {
     Leaf(u64),
     Ref(DefId),
+
+    // Marks where damaged leaves were left out, so the leaves either
+    // side never end up in the same shared def.
+    Hole,
 }
 
 #[derive(Clone)]
@@ -152,6 +158,36 @@ fn build_metadata_with_dev_(
     })
 }
 
+// The devices paired up by device id, along with the ids found in only one
+// of the mapping and details trees.
+pub struct JoinedDevices {
+    pub devs: BTreeMap<u64, (u64, DeviceDetail)>,
+    pub roots_only: Vec<u64>,
+    pub details_only: Vec<u64>,
+}
+
+pub fn join_devices(
+    roots: BTreeMap<u64, u64>,
+    mut details: BTreeMap<u64, DeviceDetail>,
+) -> JoinedDevices {
+    let mut devs = BTreeMap::new();
+    let mut roots_only = Vec::new();
+    for (thin_id, root) in roots {
+        match details.remove(&thin_id) {
+            Some(detail) => {
+                devs.insert(thin_id, (root, detail));
+            }
+            None => roots_only.push(thin_id),
+        }
+    }
+
+    JoinedDevices {
+        devs,
+        roots_only,
+        details_only: details.into_keys().collect(),
+    }
+}
+
 fn devices_iter(
     engine: Arc<dyn IoEngine + Send + Sync>,
     sb: &Superblock,
@@ -160,20 +196,17 @@ fn devices_iter(
     let details =
         btree_to_map::<DeviceDetail>(&mut vec![0], engine.clone(), true, sb.details_root)?;
     let roots = btree_to_map::<u64>(&mut vec![0], engine.clone(), true, sb.mapping_root)?;
-    Ok(roots
-        .into_iter()
-        .zip(details.into_values())
-        .map(|((thin_id, root), detail)| (thin_id, (root, detail))))
+    Ok(join_devices(roots, details).devs.into_iter())
 }
 
-pub fn build_metadata_with_dev(
+fn select_devices(
     engine: Arc<dyn IoEngine + Send + Sync>,
     sb: &ThinSuperblock,
     selected_dev: Option<Vec<u64>>,
-) -> Result<Metadata> {
+) -> Result<BTreeMap<u64, (u64, DeviceDetail)>> {
     let devs: BTreeMap<u64, (u64, DeviceDetail)> = match sb {
         ThinSuperblock::OnDisk(sb) => {
-            let iter = devices_iter(engine.clone(), sb)?;
+            let iter = devices_iter(engine, sb)?;
             if let Some(mut devs) = selected_dev {
                 devs.sort_unstable();
                 iter.filter(|(k, _)| devs.binary_search(k).is_ok())
@@ -195,9 +228,180 @@ pub fn build_metadata_with_dev(
         }
     };
 
+    Ok(devs)
+}
+
+pub fn build_metadata_with_dev(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &ThinSuperblock,
+    selected_dev: Option<Vec<u64>>,
+) -> Result<Metadata> {
+    let devs = select_devices(engine.clone(), sb, selected_dev)?;
     build_metadata_with_dev_(engine, &devs)
 }
 
+//------------------------------------------
+
+/// Ranges of thin blocks that couldn't be read from each device, with
+/// the ends being one-past-the-end.
+pub type Holes = BTreeMap<u32, Vec<(u64, u64)>>;
+
+struct CollectRanges {
+    leaves: Vec<(KeyRange, u64)>,
+}
+
+impl LeafVisitor<BlockTime> for CollectRanges {
+    fn visit(&mut self, kr: &KeyRange, b: u64) -> btree::Result<()> {
+        self.leaves.push((kr.clone(), b));
+        Ok(())
+    }
+
+    fn visit_again(&mut self, _b: u64) -> btree::Result<()> {
+        Ok(())
+    }
+
+    fn end_walk(&mut self) -> btree::Result<()> {
+        Ok(())
+    }
+}
+
+fn to_hole(begin: Option<u64>, end: Option<u64>) -> (u64, u64) {
+    (begin.unwrap_or(0), end.unwrap_or(u64::MAX))
+}
+
+fn merge_holes(mut holes: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    holes.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(holes.len());
+    for (b, e) in holes {
+        match merged.last_mut() {
+            Some(last) if b <= last.1 => last.1 = std::cmp::max(last.1, e),
+            _ => merged.push((b, e)),
+        }
+    }
+    merged
+}
+
+// The leaves aren't read until they're dumped, so any broken ones are
+// picked out beforehand.
+fn find_bad_leaves(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    leaves: &BTreeSet<u64>,
+) -> Result<BTreeSet<u64>> {
+    let mut bad = BTreeSet::new();
+    let leaves: Vec<u64> = leaves.iter().cloned().collect();
+
+    for bs in leaves.chunks(engine.get_batch_size()) {
+        let rblocks = engine.read_many(bs)?;
+        for (b, rb) in bs.iter().zip(rblocks) {
+            let ok = rb.is_ok_and(|blk| {
+                checksum::metadata_block_type(blk.get_data()) == checksum::BT::NODE
+                    && matches!(
+                        unpack_node::<BlockTime>(&[], blk.get_data(), true, false),
+                        Ok(Node::Leaf { .. })
+                    )
+            });
+            if !ok {
+                bad.insert(*b);
+            }
+        }
+    }
+
+    Ok(bad)
+}
+
+/// As build_metadata_with_dev(), but the damaged parts of the mapping trees
+/// are left out rather than failing the whole run.  The thin blocks they
+/// covered are returned for each device.
+pub fn build_metadata_with_holes(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &ThinSuperblock,
+    selected_dev: Option<Vec<u64>>,
+) -> Result<(Metadata, Holes)> {
+    let devices = select_devices(engine.clone(), sb, selected_dev)?;
+    let mut sm = RestrictedSpaceMap::new(engine.get_nr_blocks());
+
+    let mut collected = Vec::with_capacity(devices.len());
+    let mut holes = Holes::new();
+    for (&thin_id, &(root, _)) in devices.iter() {
+        let mut w = LeafWalker::new(engine.clone(), &mut sm, false);
+        let mut v = CollectRanges { leaves: Vec::new() };
+        let mut path = vec![0];
+        if let Err(e) = w.walk::<CollectRanges, BlockTime>(&mut path, &mut v, root) {
+            let dev_holes = holes.entry(thin_id as u32).or_default();
+            for issue in issues_from_btree_error(&e, true) {
+                dev_holes.push(to_hole(issue.thin_begin, issue.thin_end));
+            }
+        }
+        collected.push((thin_id, v.leaves));
+    }
+
+    let leaves: BTreeSet<u64> = collected
+        .iter()
+        .flat_map(|(_, ls)| ls.iter().map(|(_, b)| *b))
+        .collect();
+    let bad = find_bad_leaves(engine.clone(), &leaves)?;
+
+    for (thin_id, leaves) in &collected {
+        for (kr, b) in leaves {
+            if bad.contains(b) {
+                holes
+                    .entry(*thin_id as u32)
+                    .or_default()
+                    .push(to_hole(kr.start, kr.end));
+            }
+        }
+    }
+
+    let holes: Holes = holes
+        .into_iter()
+        .map(|(thin_id, hs)| (thin_id, merge_holes(hs)))
+        .collect();
+
+    let devs: Vec<Device> = collected
+        .into_iter()
+        .map(|(thin_id, leaves)| {
+            let dev_holes = holes.get(&(thin_id as u32)).map_or(&[][..], |hs| &hs[..]);
+            let mut next_hole = 0;
+            let mut entries = Vec::with_capacity(leaves.len());
+            for (kr, b) in leaves {
+                if bad.contains(&b) {
+                    continue;
+                }
+
+                let begin = kr.start.unwrap_or(0);
+                if next_hole < dev_holes.len() && dev_holes[next_hole].0 < begin {
+                    entries.push(Entry::Hole);
+                    while next_hole < dev_holes.len() && dev_holes[next_hole].0 < begin {
+                        next_hole += 1;
+                    }
+                }
+                entries.push(Entry::Leaf(b));
+            }
+
+            Device {
+                thin_id: thin_id as u32,
+                detail: devices[&thin_id].1,
+                map: Mapping {
+                    kr: KeyRange::new(),
+                    entries,
+                },
+            }
+        })
+        .collect();
+
+    Ok((
+        Metadata {
+            defs: Vec::new(),
+            devs,
+            nr_blocks: engine.get_nr_blocks(),
+        },
+        holes,
+    ))
+}
+
+//------------------------------------------
+
 fn build_metadata_without_mappings_(
     engine: Arc<dyn IoEngine + Send + Sync>,
     details: &mut dyn Iterator<Item = (&u64, &DeviceDetail)>,
@@ -249,7 +453,7 @@ fn gather_entries(g: &mut Gatherer, es: &[Entry]) {
             Entry::Leaf(b) => {
                 g.next(*b);
             }
-            Entry::Ref(_id) => {
+            Entry::Ref(_) | Entry::Hole => {
                 g.new_seq();
             }
         }
@@ -283,6 +487,10 @@ fn entries_to_runs(runs: &BTreeMap<u64, (Vec<u64>, bool)>, es: &[Entry]) -> Vec<
                 result.push(Ref(id));
                 entry_index += 1;
             }
+            Hole => {
+                result.push(Hole);
+                entry_index += 1;
+            }
             Leaf(b) => {
                 if let Some((run, shared)) = runs.get(&b) {
                     if *shared {
@@ -1,4 +1,7 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
 use std::sync::Arc;
 
@@ -6,7 +9,7 @@ use crate::commands::engine::*;
 use crate::io_engine::*;
 use crate::pdata::space_map::metadata::core_metadata_sm;
 use crate::report::mk_quiet_report;
-use crate::thin::ir::MetadataVisitor;
+use crate::thin::ir::{self, MetadataVisitor};
 use crate::thin::restore::Restorer;
 use crate::write_batcher::WriteBatcher;
 
@@ -16,17 +19,436 @@ pub trait MetadataGenerator {
     fn generate_metadata(&self, v: &mut dyn MetadataVisitor) -> Result<()>;
 }
 
-struct ThinGenerator;
+//------------------------------------------
+
+// Data blocks are never released by the generator, since every
+// overwritten block is still held by an older snapshot.  So the
+// allocator only needs to track the free extents.
+struct DataAllocator {
+    free: BTreeMap<u64, u64>, // begin -> end
+    cursor: u64,
+}
+
+impl DataAllocator {
+    fn new(nr_blocks: u64) -> Self {
+        let mut free = BTreeMap::new();
+        if nr_blocks > 0 {
+            free.insert(0, nr_blocks);
+        }
+        DataAllocator { free, cursor: 0 }
+    }
+
+    // Allocates up to 'len' blocks from the first free extent at or after
+    // the hint, wrapping around if necessary.
+    fn alloc_extent(&mut self, hint: u64, len: u64) -> Result<(u64, u64)> {
+        let extent = self
+            .free
+            .range(..=hint)
+            .next_back()
+            .filter(|(_, &e)| e > hint)
+            .or_else(|| self.free.range(hint..).next())
+            .or_else(|| self.free.iter().next())
+            .map(|(&b, &e)| (b, e));
+
+        let (b, e) = extent.ok_or_else(|| anyhow!("out of data space"))?;
+        let begin = if (b..e).contains(&hint) { hint } else { b };
+        let end = std::cmp::min(e, begin + len);
+
+        self.free.remove(&b);
+        if b < begin {
+            self.free.insert(b, begin);
+        }
+        if end < e {
+            self.free.insert(end, e);
+        }
+        self.cursor = end;
+
+        Ok((begin, end - begin))
+    }
+}
+
+//------------------------------------------
+
+// Sharing between devices is tracked in regions of this many thin blocks.
+// A fully provisioned region spans a handful of leaves, which will be
+// emitted as a shared subtree if more than one device references it.
+const REGION_SIZE: u64 = 1024;
+
+// Mappings within a region, ordered by thin block.  Regions are
+// shared by devices until they are written.
+struct Region {
+    id: u64,
+    maps: Vec<ir::Map>,
+}
+
+#[derive(Clone)]
+struct DevState {
+    regions: Vec<Arc<Region>>,
+}
+
+impl DevState {
+    fn mapped_blocks(&self) -> u64 {
+        self.regions
+            .iter()
+            .map(|r| r.maps.iter().map(|m| m.len).sum::<u64>())
+            .sum()
+    }
+}
+
+// Replaces the range covered by 'm' in a sorted list of runs
+fn overwrite(maps: &[ir::Map], m: &ir::Map) -> Vec<ir::Map> {
+    let b = m.thin_begin;
+    let e = m.thin_begin + m.len;
+
+    let mut out = Vec::with_capacity(maps.len() + 2);
+    let mut inserted = false;
+    for r in maps {
+        let rb = r.thin_begin;
+        let re = r.thin_begin + r.len;
+
+        if re <= b {
+            out.push(r.clone());
+            continue;
+        }
+
+        if rb >= e {
+            if !inserted {
+                out.push(m.clone());
+                inserted = true;
+            }
+            out.push(r.clone());
+            continue;
+        }
+
+        if rb < b {
+            out.push(ir::Map {
+                len: b - rb,
+                ..r.clone()
+            });
+        }
+
+        if !inserted {
+            out.push(m.clone());
+            inserted = true;
+        }
+
+        if re > e {
+            out.push(ir::Map {
+                thin_begin: e,
+                data_begin: r.data_begin + (e - rb),
+                time: r.time,
+                len: re - e,
+            });
+        }
+    }
+
+    if !inserted {
+        out.push(m.clone());
+    }
+
+    out
+}
+
+//------------------------------------------
+
+/// Generates a number of thin devices, each followed by a chain of
+/// snapshots.  Snapshot i holds the state of its origin at time i, while
+/// the origin holds the latest state.
+pub struct ThinGenerator {
+    pub data_block_size: u32,
+    pub nr_data_blocks: u64,
+    pub nr_thins: u32,
+    pub nr_snapshots: u32,
+
+    /// Virtual size of each thin device, in data blocks
+    pub thin_size: u64,
+
+    /// Percentage of the thin blocks mapped in the origin
+    pub percent_provisioned: u8,
+
+    /// Percentage of the mappings left untouched between two snapshots
+    pub percent_shared: u8,
+
+    /// Average length of the mapped runs, in blocks
+    pub run_length: u64,
+
+    /// Chance of a run being allocated at a random position on the data
+    /// device, rather than right after the previous one
+    pub percent_fragmented: u8,
+
+    pub seed: Option<u64>,
+}
+
+struct GenContext {
+    rng: StdRng,
+    allocator: DataAllocator,
+    next_region_id: u64,
+}
+
+impl ThinGenerator {
+    fn check_params(&self) -> Result<()> {
+        if self.percent_provisioned > 100
+            || self.percent_shared > 100
+            || self.percent_fragmented > 100
+        {
+            return Err(anyhow!("percentage out of range"));
+        }
+
+        if self.run_length == 0 {
+            return Err(anyhow!("run length must be greater than zero"));
+        }
+
+        let nr_devs = self.nr_thins as u64 * (self.nr_snapshots as u64 + 1);
+        if nr_devs > u32::MAX as u64 {
+            return Err(anyhow!("too many devices"));
+        }
+
+        Ok(())
+    }
+
+    fn nr_regions(&self) -> usize {
+        self.thin_size.div_ceil(REGION_SIZE) as usize
+    }
+
+    fn gen_run_length(&self, ctx: &mut GenContext, remaining: u64) -> u64 {
+        let len = ctx.rng.gen_range(1..=(self.run_length * 2 - 1));
+        std::cmp::min(len, remaining)
+    }
+
+    // Allocates data blocks for the thin range, splitting it into
+    // several mappings if the free space is fragmented.
+    fn alloc_run(
+        &self,
+        ctx: &mut GenContext,
+        thin_begin: u64,
+        len: u64,
+        time: u32,
+    ) -> Result<Vec<ir::Map>> {
+        let mut hint = if ctx.rng.gen_range(0..100) < self.percent_fragmented {
+            ctx.rng.gen_range(0..self.nr_data_blocks.max(1))
+        } else {
+            ctx.allocator.cursor
+        };
+
+        let mut maps = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let (data_begin, n) = ctx.allocator.alloc_extent(hint, len - done)?;
+            maps.push(ir::Map {
+                thin_begin: thin_begin + done,
+                data_begin,
+                time,
+                len: n,
+            });
+            done += n;
+            hint = ctx.allocator.cursor;
+        }
+
+        Ok(maps)
+    }
+
+    // Writes the runs to the device, unsharing the regions touched
+    fn apply_maps(&self, ctx: &mut GenContext, dev: &mut DevState, maps: &[ir::Map]) {
+        for m in maps {
+            let mut b = m.thin_begin;
+            let e = m.thin_begin + m.len;
+            while b < e {
+                let region = (b / REGION_SIZE) as usize;
+                let region_end = std::cmp::min(e, (region as u64 + 1) * REGION_SIZE);
+                let piece = ir::Map {
+                    thin_begin: b,
+                    data_begin: m.data_begin + (b - m.thin_begin),
+                    time: m.time,
+                    len: region_end - b,
+                };
+
+                let new_region = Region {
+                    id: ctx.next_region_id,
+                    maps: overwrite(&dev.regions[region].maps, &piece),
+                };
+                ctx.next_region_id += 1;
+                dev.regions[region] = Arc::new(new_region);
+
+                b = region_end;
+            }
+        }
+    }
+
+    fn gen_origin(&self, ctx: &mut GenContext) -> Result<DevState> {
+        let mut dev = DevState {
+            regions: Vec::with_capacity(self.nr_regions()),
+        };
+        for _ in 0..self.nr_regions() {
+            dev.regions.push(Arc::new(Region {
+                id: ctx.next_region_id,
+                maps: Vec::new(),
+            }));
+            ctx.next_region_id += 1;
+        }
+
+        if self.percent_provisioned == 0 {
+            return Ok(dev);
+        }
+
+        // Runs are separated by gaps sized to meet the provisioning ratio
+        let max_gap = self.run_length * 2 * (100 - self.percent_provisioned as u64)
+            / self.percent_provisioned as u64;
+
+        let mut b = 0;
+        while b < self.thin_size {
+            b += ctx.rng.gen_range(0..=max_gap);
+            if b >= self.thin_size {
+                break;
+            }
+
+            let len = self.gen_run_length(ctx, self.thin_size - b);
+            let maps = self.alloc_run(ctx, b, len, 0)?;
+            self.apply_maps(ctx, &mut dev, &maps);
+            b += len;
+        }
+
+        Ok(dev)
+    }
+
+    // Overwrites a portion of the device at the given time
+    fn diverge(&self, ctx: &mut GenContext, dev: &mut DevState, time: u32) -> Result<()> {
+        let nr_writes = dev.mapped_blocks() * (100 - self.percent_shared as u64) / 100;
+
+        let mut written = 0;
+        while written < nr_writes {
+            let b = ctx.rng.gen_range(0..self.thin_size);
+            let len = self.gen_run_length(ctx, self.thin_size - b);
+            let maps = self.alloc_run(ctx, b, len, time)?;
+            self.apply_maps(ctx, dev, &maps);
+            written += len;
+        }
+
+        Ok(())
+    }
+
+    fn emit_device(
+        &self,
+        v: &mut dyn MetadataVisitor,
+        d: &ir::Device,
+        dev: &DevState,
+        shared: &HashMap<u64, u32>,
+    ) -> Result<()> {
+        v.device_b(d)?;
+        for r in &dev.regions {
+            if r.maps.is_empty() {
+                continue;
+            }
+
+            if shared.get(&r.id).is_some_and(|&count| count > 1) {
+                v.ref_shared(&format!("{}", r.id))?;
+            } else {
+                for m in &r.maps {
+                    v.map(m)?;
+                }
+            }
+        }
+        v.device_e()?;
+        Ok(())
+    }
+}
 
 impl MetadataGenerator for ThinGenerator {
-    fn generate_metadata(&self, _v: &mut dyn MetadataVisitor) -> Result<()> {
-        Ok(()) // TODO
+    fn generate_metadata(&self, v: &mut dyn MetadataVisitor) -> Result<()> {
+        self.check_params()?;
+
+        let mut ctx = GenContext {
+            rng: match self.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+            allocator: DataAllocator::new(self.nr_data_blocks),
+            next_region_id: 0,
+        };
+
+        // Build the snapshot chains.  The last state of each chain
+        // belongs to the origin.
+        let mut chains = Vec::with_capacity(self.nr_thins as usize);
+        for _ in 0..self.nr_thins {
+            let mut states = Vec::with_capacity(self.nr_snapshots as usize + 1);
+            let mut dev = self.gen_origin(&mut ctx)?;
+            for time in 1..=self.nr_snapshots {
+                states.push(dev.clone());
+                self.diverge(&mut ctx, &mut dev, time)?;
+            }
+            states.push(dev);
+            chains.push(states);
+        }
+
+        // Count the references to each region
+        let mut shared: HashMap<u64, u32> = HashMap::new();
+        for r in chains.iter().flatten().flat_map(|dev| dev.regions.iter()) {
+            *shared.entry(r.id).or_insert(0) += 1;
+        }
+
+        let sb = ir::Superblock {
+            uuid: String::new(),
+            time: self.nr_snapshots,
+            transaction: self.nr_thins as u64 * (self.nr_snapshots as u64 + 1),
+            flags: None,
+            version: Some(2),
+            data_block_size: self.data_block_size,
+            nr_data_blocks: self.nr_data_blocks,
+            metadata_snap: None,
+        };
+        v.superblock_b(&sb)?;
+
+        let mut emitted = std::collections::HashSet::new();
+        for r in chains.iter().flatten().flat_map(|dev| dev.regions.iter()) {
+            if r.maps.is_empty() || shared[&r.id] < 2 || !emitted.insert(r.id) {
+                continue;
+            }
+
+            v.def_shared_b(&format!("{}", r.id))?;
+            for m in &r.maps {
+                v.map(m)?;
+            }
+            v.def_shared_e()?;
+        }
+
+        let mut dev_id = 0;
+        for states in &chains {
+            let origin_id = dev_id;
+            dev_id += 1;
+
+            for (i, dev) in states[..states.len() - 1].iter().enumerate() {
+                let time = i as u32 + 1;
+                let d = ir::Device {
+                    dev_id,
+                    mapped_blocks: dev.mapped_blocks(),
+                    transaction: 0,
+                    creation_time: time,
+                    snap_time: time,
+                };
+                self.emit_device(v, &d, dev, &shared)?;
+                dev_id += 1;
+            }
+
+            let origin = states.last().unwrap();
+            let d = ir::Device {
+                dev_id: origin_id,
+                mapped_blocks: origin.mapped_blocks(),
+                transaction: 0,
+                creation_time: 0,
+                snap_time: self.nr_snapshots,
+            };
+            self.emit_device(v, &d, origin, &shared)?;
+        }
+
+        v.superblock_e()?;
+        v.eof()?;
+
+        Ok(())
     }
 }
 
 //------------------------------------------
 
-fn format(engine: Arc<dyn IoEngine + Send + Sync>, gen: ThinGenerator) -> Result<()> {
+fn format(engine: Arc<dyn IoEngine + Send + Sync>, gen: &ThinGenerator) -> Result<()> {
     let sm = core_metadata_sm(engine.get_nr_blocks(), u32::MAX);
     let batch_size = engine.get_batch_size();
     let mut w = WriteBatcher::new(engine, sm, batch_size);
@@ -48,6 +470,14 @@ fn set_needs_check(engine: Arc<dyn IoEngine + Send + Sync>, flag: bool) -> Resul
 pub struct ThinFormatOpts {
     pub data_block_size: u32,
     pub nr_data_blocks: u64,
+    pub nr_thins: u32,
+    pub nr_snapshots: u32,
+    pub thin_size: Option<u64>,
+    pub percent_provisioned: u8,
+    pub percent_shared: u8,
+    pub run_length: u64,
+    pub percent_fragmented: u8,
+    pub seed: Option<u64>,
 }
 
 pub enum MetadataOp {
@@ -66,8 +496,21 @@ pub fn generate_metadata(opts: ThinGenerateOpts) -> Result<()> {
         .write(true)
         .build()?;
     match opts.op {
-        // FIXME: parameterize ThinGenerator
-        MetadataOp::Format(_op) => format(engine, ThinGenerator),
+        MetadataOp::Format(op) => {
+            let thin_gen = ThinGenerator {
+                data_block_size: op.data_block_size,
+                nr_data_blocks: op.nr_data_blocks,
+                nr_thins: op.nr_thins,
+                nr_snapshots: op.nr_snapshots,
+                thin_size: op.thin_size.unwrap_or(op.nr_data_blocks),
+                percent_provisioned: op.percent_provisioned,
+                percent_shared: op.percent_shared,
+                run_length: op.run_length,
+                percent_fragmented: op.percent_fragmented,
+                seed: op.seed,
+            };
+            format(engine, &thin_gen)
+        }
         MetadataOp::SetNeedsCheck(flag) => set_needs_check(engine, flag),
     }
 }
@@ -3,10 +3,12 @@ use fixedbitset::FixedBitSet;
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::fmt;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 
 use crate::checksum;
-use crate::io_engine::IoEngine;
+use crate::io_engine::{Block, IoEngine};
 use crate::pdata::btree::*;
 use crate::pdata::btree_walker::*;
 use crate::pdata::space_map::common::*;
@@ -29,6 +31,19 @@ pub struct SuperblockOverrides {
     pub nr_data_blocks: Option<u64>,
 }
 
+/// A bottom-level mapping subtree that couldn't be attached to any device.
+/// The keys are the thin blocks it mapped in the original device, with
+/// the key_high being inclusive.  The age is the newest time among the
+/// mappings.
+#[derive(Clone, Debug)]
+pub struct OrphanTree {
+    pub root: u64,
+    pub key_low: u64,
+    pub key_high: u64,
+    pub nr_mappings: u64,
+    pub age: u32,
+}
+
 struct RootPair {
     mapping_root: u64,
     details_root: u64,
@@ -36,7 +51,10 @@ struct RootPair {
 
 enum TreeRoots {
     OnDisk(RootPair),
-    InCore(BTreeMap<u64, (u64, u64)>), // maps dev_id to (root, mapped_blocks)
+    InCore {
+        mapping_root: u64,
+        devices: BTreeMap<u64, (u64, u64)>, // maps dev_id to (root, mapped_blocks)
+    },
 }
 
 struct FoundRoots {
@@ -48,6 +66,15 @@ struct FoundRoots {
     nr_data_blocks: u64,
 }
 
+impl FoundRoots {
+    fn mapping_root(&self) -> u64 {
+        match &self.devices {
+            TreeRoots::OnDisk(r) => r.mapping_root,
+            TreeRoots::InCore { mapping_root, .. } => *mapping_root,
+        }
+    }
+}
+
 fn devices_identical(
     engine: Arc<dyn IoEngine + Send + Sync>,
     dev_root: u64,
@@ -259,12 +286,224 @@ enum NodeInfo {
     Details(DetailsInfo),
 }
 
+// Results of examining a single block in the first pass.  Leaves of the
+// bottom level trees are summarised straight away, while nodes that
+// depend on their children are held until all the blocks have been read.
+enum ScannedNode {
+    Info(NodeInfo),
+    Pending(Node<u64>),
+}
+
+fn maybe_top_level(nr_blocks: u64, values: &[u64]) -> bool {
+    if values.is_empty() {
+        return false;
+    }
+
+    for v in values {
+        if *v > nr_blocks {
+            return false;
+        }
+    }
+
+    true
+}
+
+fn gather_mapping_leaf_info(
+    header: &NodeHeader,
+    keys: &[u64],
+    values: &[BlockTime],
+) -> Result<NodeInfo> {
+    let mut info = MappingsInfo::new(header.block);
+    info.nr_mappings = header.nr_entries as u64;
+
+    // min & max logical block address
+    if header.nr_entries > 0 {
+        info.key_low = keys[0];
+        info.key_high = keys[keys.len() - 1];
+    }
+
+    for bt in values {
+        info.highest_mapped_data_block = std::cmp::max(bt.block, info.highest_mapped_data_block);
+        *info.time_counts.entry(bt.time).or_insert(0) += 1;
+        info.age = std::cmp::max(info.age, bt.time);
+    }
+
+    Ok(NodeInfo::Mappings(info))
+}
+
+fn gather_details_leaf_info(
+    header: &NodeHeader,
+    keys: &[u64],
+    values: &[DeviceDetail],
+) -> Result<NodeInfo> {
+    let mut info = DetailsInfo::new(header.block);
+    info.nr_devices = header.nr_entries as u64;
+
+    // min & max device id
+    if header.nr_entries > 0 {
+        info.key_low = keys[0];
+        info.key_high = keys[keys.len() - 1];
+    }
+
+    for details in values {
+        info.nr_mappings += details.mapped_blocks;
+        info.max_tid = std::cmp::max(info.max_tid, details.transaction_id);
+        info.age = std::cmp::max(info.age, details.creation_time);
+        info.age = std::cmp::max(info.age, details.snapshotted_time);
+    }
+
+    Ok(NodeInfo::Details(info))
+}
+
+fn to_dev_leaf_info(data: &[u8]) -> Result<NodeInfo> {
+    let node = unpack_node::<BlockTime>(&[0], data, true, true)?;
+    if let Node::Leaf {
+        ref header,
+        ref keys,
+        ref values,
+    } = node
+    {
+        gather_mapping_leaf_info(header, keys, values)
+    } else {
+        Err(anyhow!("unexpected internal node"))
+    }
+}
+
+fn to_details_leaf_info(data: &[u8]) -> Result<NodeInfo> {
+    let node = unpack_node::<DeviceDetail>(&[0], data, true, true)?;
+    if let Node::Leaf {
+        ref header,
+        ref keys,
+        ref values,
+    } = node
+    {
+        gather_details_leaf_info(header, keys, values)
+    } else {
+        Err(anyhow!("unexpected value size within an internal node"))
+    }
+}
+
+fn scan_node(nr_blocks: u64, data: &[u8]) -> Result<ScannedNode> {
+    let bt = checksum::metadata_block_type(data);
+    if bt != checksum::BT::NODE {
+        return Err(anyhow!("not a btree node"));
+    }
+
+    let (_, hdr) = NodeHeader::unpack(data).map_err(|_| anyhow!("couldn't unpack node header"))?;
+    if hdr.value_size as usize == std::mem::size_of::<u64>() {
+        let node = unpack_node::<u64>(&[0], data, true, true)?;
+        match node {
+            Node::Internal { .. } => Ok(ScannedNode::Pending(node)),
+            Node::Leaf { ref values, .. } => {
+                if maybe_top_level(nr_blocks, values) {
+                    Ok(ScannedNode::Pending(node))
+                } else {
+                    // FIXME: convert the values only, to avoid unpacking the node twice
+                    Ok(ScannedNode::Info(to_dev_leaf_info(data)?))
+                }
+            }
+        }
+    } else if hdr.value_size == DeviceDetail::disk_size() {
+        Ok(ScannedNode::Info(to_details_leaf_info(data)?))
+    } else {
+        Err(anyhow!("not the value size of interest"))
+    }
+}
+
+#[derive(Default)]
+struct ScanResults {
+    infos: BTreeMap<u64, NodeInfo>,
+    pending: BTreeMap<u64, Node<u64>>,
+}
+
+fn scanner(
+    blocks_rx: &Arc<Mutex<mpsc::Receiver<Vec<Block>>>>,
+    results: &Mutex<ScanResults>,
+    nr_blocks: u64,
+) {
+    loop {
+        let blocks = {
+            let rx = blocks_rx.lock().unwrap();
+            if let Ok(blocks) = rx.recv() {
+                blocks
+            } else {
+                break;
+            }
+        };
+
+        let mut scanned = Vec::with_capacity(blocks.len());
+        for b in blocks {
+            if let Ok(s) = scan_node(nr_blocks, b.get_data()) {
+                scanned.push((b.loc, s));
+            }
+        }
+
+        let mut results = results.lock().unwrap();
+        for (loc, s) in scanned {
+            match s {
+                ScannedNode::Info(info) => {
+                    results.infos.insert(loc, info);
+                }
+                ScannedNode::Pending(node) => {
+                    results.pending.insert(loc, node);
+                }
+            }
+        }
+    }
+}
+
+// Reads and examines every metadata block.  The IO is done in the
+// calling thread, while the unpacking is spread across the number of
+// threads suggested by the io engine.
+fn scan_blocks(engine: Arc<dyn IoEngine + Send + Sync>) -> ScanResults {
+    const QUEUE_DEPTH: usize = 4;
+    const CHUNK_SIZE: u64 = 1024;
+
+    let nr_blocks = engine.get_nr_blocks();
+    let nr_threads = std::cmp::max(1, engine.suggest_nr_threads());
+
+    let (blocks_tx, blocks_rx) = mpsc::sync_channel::<Vec<Block>>(QUEUE_DEPTH);
+    let blocks_rx = Arc::new(Mutex::new(blocks_rx));
+    let results = Arc::new(Mutex::new(ScanResults::default()));
+
+    let mut scanners = Vec::with_capacity(nr_threads);
+    for _i in 0..nr_threads {
+        let blocks_rx = blocks_rx.clone();
+        let results = results.clone();
+        scanners.push(thread::spawn(move || {
+            scanner(&blocks_rx, &results, nr_blocks)
+        }));
+    }
+    drop(blocks_rx);
+
+    let mut begin = 0;
+    while begin < nr_blocks {
+        let end = std::cmp::min(begin + CHUNK_SIZE, nr_blocks);
+        let locs: Vec<u64> = (begin..end).collect();
+
+        // Unreadable blocks are simply skipped, the same as invalid nodes
+        if let Ok(blocks) = engine.read_many(&locs) {
+            let bs = blocks.into_iter().filter_map(|b| b.ok()).collect();
+            blocks_tx.send(bs).expect("couldn't send blocks to scanner");
+        }
+
+        begin = end;
+    }
+    drop(blocks_tx);
+
+    for tid in scanners {
+        tid.join().expect("couldn't join scanner");
+    }
+
+    Arc::try_unwrap(results).ok().unwrap().into_inner().unwrap()
+}
+
 struct NodeCollector {
     engine: Arc<dyn IoEngine + Send + Sync>,
     nr_blocks: u64,
-    examined: FixedBitSet,
     referenced: FixedBitSet,
     infos: BTreeMap<u64, NodeInfo>,
+    pending: BTreeMap<u64, Node<u64>>,
     _report: Arc<Report>,
 }
 
@@ -274,9 +513,9 @@ impl NodeCollector {
         NodeCollector {
             engine,
             nr_blocks,
-            examined: FixedBitSet::with_capacity(nr_blocks as usize),
             referenced: FixedBitSet::with_capacity(nr_blocks as usize),
             infos: BTreeMap::<u64, NodeInfo>::new(),
+            pending: BTreeMap::<u64, Node<u64>>::new(),
             _report: report,
         }
     }
@@ -397,135 +636,28 @@ impl NodeCollector {
             }
         }
 
-        Ok(NodeInfo::Dev(info))
-    }
-
-    fn gather_mapping_leaf_info(
-        &self,
-        header: &NodeHeader,
-        keys: &[u64],
-        values: &[BlockTime],
-    ) -> Result<NodeInfo> {
-        let mut info = MappingsInfo::new(header.block);
-        info.nr_mappings = header.nr_entries as u64;
-
-        // min & max logical block address
-        if header.nr_entries > 0 {
-            info.key_low = keys[0];
-            info.key_high = keys[keys.len() - 1];
-        }
-
-        for bt in values {
-            info.highest_mapped_data_block =
-                std::cmp::max(bt.block, info.highest_mapped_data_block);
-            *info.time_counts.entry(bt.time).or_insert(0) += 1;
-            info.age = std::cmp::max(info.age, bt.time);
-        }
-
-        Ok(NodeInfo::Mappings(info))
-    }
-
-    fn gather_details_leaf_info(
-        &self,
-        header: &NodeHeader,
-        keys: &[u64],
-        values: &[DeviceDetail],
-    ) -> Result<NodeInfo> {
-        let mut info = DetailsInfo::new(header.block);
-        info.nr_devices = header.nr_entries as u64;
-
-        // min & max device id
-        if header.nr_entries > 0 {
-            info.key_low = keys[0];
-            info.key_high = keys[keys.len() - 1];
-        }
-
-        for details in values {
-            info.nr_mappings += details.mapped_blocks;
-            info.max_tid = std::cmp::max(info.max_tid, details.transaction_id);
-            info.age = std::cmp::max(info.age, details.creation_time);
-            info.age = std::cmp::max(info.age, details.snapshotted_time);
-        }
-
-        Ok(NodeInfo::Details(info))
-    }
-
-    fn maybe_top_level(&self, values: &[u64]) -> bool {
-        if values.is_empty() {
-            return false;
-        }
-
-        for v in values {
-            if *v > self.nr_blocks {
-                return false;
-            }
+        // the subtrees are attached to a device, so they're not orphans
+        for b in values {
+            self.referenced.set(*b as usize, true);
         }
 
-        true
-    }
-
-    fn to_dev_leaf_info(&self, data: &[u8]) -> Result<NodeInfo> {
-        let node = unpack_node::<BlockTime>(&[0], data, true, true)?;
-        if let Node::Leaf {
-            ref header,
-            ref keys,
-            ref values,
-        } = node
-        {
-            self.gather_mapping_leaf_info(header, keys, values)
-        } else {
-            Err(anyhow!("unexpected internal node"))
-        }
+        Ok(NodeInfo::Dev(info))
     }
 
-    fn to_details_leaf_info(&self, data: &[u8]) -> Result<NodeInfo> {
-        let node = unpack_node::<DeviceDetail>(&[0], data, true, true)?;
-        if let Node::Leaf {
-            ref header,
-            ref keys,
-            ref values,
-        } = node
-        {
-            self.gather_details_leaf_info(header, keys, values)
-        } else {
-            Err(anyhow!("unexpected value size within an internal node"))
-        }
-    }
-
-    fn gather_info(&mut self, b: u64) -> Result<NodeInfo> {
-        let blk = self.engine.read(b)?;
-
-        let bt = checksum::metadata_block_type(blk.get_data());
-        if bt != checksum::BT::NODE {
-            return Err(anyhow!("not a btree node"));
-        }
-
-        let (_, hdr) = NodeHeader::unpack(blk.get_data())?;
-        if hdr.value_size as usize == std::mem::size_of::<u64>() {
-            let node = unpack_node::<u64>(&[0], blk.get_data(), true, true)?;
-            match node {
-                Node::Internal {
-                    ref header,
-                    ref keys,
-                    ref values,
-                } => self.gather_subtree_info(header, keys, values),
-                Node::Leaf {
-                    ref header,
-                    ref keys,
-                    ref values,
-                } => {
-                    if self.maybe_top_level(values) {
-                        self.gather_dev_leaf_info(header, keys, values)
-                    } else {
-                        // FIXME: convert the values only, to avoid unpacking the node twice
-                        self.to_dev_leaf_info(blk.get_data())
-                    }
-                }
-            }
-        } else if hdr.value_size == DeviceDetail::disk_size() {
-            self.to_details_leaf_info(blk.get_data())
-        } else {
-            Err(anyhow!("not the value size of interest"))
+    // Resolves a node held over from the first pass, given the infos
+    // of its children.
+    fn gather_info(&mut self, node: Node<u64>) -> Result<NodeInfo> {
+        match node {
+            Node::Internal {
+                ref header,
+                ref keys,
+                ref values,
+            } => self.gather_subtree_info(header, keys, values),
+            Node::Leaf {
+                ref header,
+                ref keys,
+                ref values,
+            } => self.gather_dev_leaf_info(header, keys, values),
         }
     }
 
@@ -536,20 +668,29 @@ impl NodeCollector {
     }
 
     fn get_info(&mut self, b: u64) -> Result<&NodeInfo> {
-        if self.examined.contains(b as usize) {
-            // TODO: use an extra 'valid' bitset for faster lookup?
+        if b >= self.nr_blocks {
+            return Err(anyhow!("block {} is out of bounds", b));
+        }
+
+        // Pending nodes are removed before gathering, so any cycles are
+        // detected as an invalid child.
+        if let Some(node) = self.pending.remove(&b) {
+            let info = self.gather_info(node)?;
+            Ok(self.infos.entry(b).or_insert(info))
+        } else {
             self.infos
                 .get(&b)
                 .ok_or_else(|| anyhow!("block {} was examined as invalid", b))
-        } else {
-            self.examined.set(b as usize, true);
-            let info = self.gather_info(b)?;
-            Ok(self.infos.entry(b).or_insert(info))
         }
     }
 
     fn collect_infos(&mut self) -> Result<()> {
-        for b in 0..self.nr_blocks {
+        let results = scan_blocks(self.engine.clone());
+        self.infos = results.infos;
+        self.pending = results.pending;
+
+        let pending: Vec<u64> = self.pending.keys().cloned().collect();
+        for b in pending {
             let _ret = self.get_info(b);
         }
         Ok(())
@@ -574,6 +715,28 @@ impl NodeCollector {
 
         Ok((dev_roots, details_roots))
     }
+
+    // Bottom-level subtrees that aren't attached to any device, ordered
+    // by their lowest key.
+    fn gather_orphans(&self) -> Vec<OrphanTree> {
+        let mut orphans: Vec<OrphanTree> = self
+            .infos
+            .iter()
+            .filter(|(b, _)| !self.referenced.contains(**b as usize))
+            .filter_map(|(b, info)| match info {
+                NodeInfo::Mappings(m) if m.nr_mappings > 0 => Some(OrphanTree {
+                    root: *b,
+                    key_low: m.key_low,
+                    key_high: m.key_high,
+                    nr_mappings: m.nr_mappings,
+                    age: m.age,
+                }),
+                _ => None,
+            })
+            .collect();
+        orphans.sort_unstable_by_key(|o| (o.key_low, o.root));
+        orphans
+    }
 }
 
 //------------------------------------------
@@ -631,6 +794,45 @@ fn filter_details_roots<'a>(
     Ok(filtered)
 }
 
+// Each check walks both trees, so the candidates are spread across
+// the number of threads suggested by the io engine.  The results are
+// returned in the order of the candidates.
+fn check_root_pairs(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    candidates: Vec<(u64, u64)>,
+) -> Vec<bool> {
+    let nr_candidates = candidates.len();
+    let nr_threads = std::cmp::min(std::cmp::max(1, engine.suggest_nr_threads()), nr_candidates);
+
+    let candidates = Arc::new(candidates);
+    let next = Arc::new(AtomicUsize::new(0));
+    let results = Arc::new(Mutex::new(vec![false; nr_candidates]));
+
+    let mut checkers = Vec::with_capacity(nr_threads);
+    for _i in 0..nr_threads {
+        let engine = engine.clone();
+        let candidates = candidates.clone();
+        let next = next.clone();
+        let results = results.clone();
+        checkers.push(thread::spawn(move || loop {
+            let i = next.fetch_add(1, AtomicOrdering::Relaxed);
+            if i >= candidates.len() {
+                break;
+            }
+
+            let (dev_root, details_root) = candidates[i];
+            let identical = devices_identical(engine.clone(), dev_root, details_root, true);
+            results.lock().unwrap()[i] = identical;
+        }));
+    }
+
+    for tid in checkers {
+        tid.join().expect("couldn't join checker");
+    }
+
+    Arc::try_unwrap(results).unwrap().into_inner().unwrap()
+}
+
 fn find_root_pairs<'a>(
     engine: Arc<dyn IoEngine + Send + Sync>,
     dev_roots: &mut [&'a DevInfo],
@@ -649,20 +851,25 @@ fn find_root_pairs<'a>(
     let mut details_infos = filter_details_roots(details_roots, &nr_devices)?;
     details_infos.sort_unstable_by_key(|i| i.nr_mappings);
 
-    let mut pairs = Vec::new();
+    let mut candidates = Vec::new();
     for dev in dev_roots {
         // use lowerbound search in case there are duplicated numbers of mappings
         let lower = lower_bound(&details_infos, dev.nr_mappings);
         let upper = upper_bound(&details_infos, dev.nr_mappings);
 
         for details in &details_infos[lower..upper] {
-            if devices_identical(engine.clone(), dev.b, details._b, true) {
-                pairs.push((*dev, *details));
-            }
+            candidates.push((*dev, *details));
         }
     }
 
-    Ok(pairs)
+    let locs: Vec<(u64, u64)> = candidates.iter().map(|(d, t)| (d.b, t._b)).collect();
+    let identical = check_root_pairs(engine, locs);
+
+    Ok(candidates
+        .into_iter()
+        .zip(identical)
+        .filter_map(|(pair, ok)| if ok { Some(pair) } else { None })
+        .collect())
 }
 
 fn to_found_roots(dev_root: &DevInfo, details_root: &DetailsInfo) -> Result<FoundRoots> {
@@ -706,7 +913,10 @@ fn to_partial_found_roots(
     //    metadata is at transaction#1. This suggested value then will be
     //    compared with that in the on-disk superblock if available.
     Ok(FoundRoots {
-        devices: TreeRoots::InCore(devices),
+        devices: TreeRoots::InCore {
+            mapping_root: dev_root.b,
+            devices,
+        },
         time: dev_root.age + 1,
         transaction_id: 1,
         nr_data_blocks: dev_root.highest_mapped_data_block + 1,
@@ -749,7 +959,7 @@ fn log_results(
 fn find_roots(
     engine: Arc<dyn IoEngine + Send + Sync>,
     report: Arc<Report>,
-) -> Result<Vec<FoundRoots>> {
+) -> Result<(Vec<FoundRoots>, Vec<OrphanTree>)> {
     let mut c = NodeCollector::new(engine.clone(), report.clone());
     c.collect_infos()?;
 
@@ -758,26 +968,41 @@ fn find_roots(
     let pairs = find_root_pairs(engine.clone(), &mut dev_roots, &details_roots)?;
     log_results(report.clone(), &dev_roots, &details_roots, &pairs);
 
-    if pairs.is_empty() {
-        if dev_roots.is_empty() {
-            return Err(anyhow!("no compatible roots found"));
-        }
+    let mut found_roots = pairs
+        .iter()
+        .map(|(dev, details)| to_found_roots(dev, details))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Mapping trees without a matching details tree are kept as fallbacks,
+    // with the device details regenerated from the mappings.
+    let unpaired: Vec<&DevInfo> = dev_roots
+        .iter()
+        .filter(|dev| !pairs.iter().any(|(d, _)| d.b == dev.b))
+        .copied()
+        .collect();
 
+    if pairs.is_empty() && !unpaired.is_empty() {
         report.warning(&format!(
             "found {} mapping trees without device details",
-            dev_roots.len()
+            unpaired.len()
         ));
+    }
 
-        return dev_roots
-            .iter()
-            .map(|dev| to_partial_found_roots(engine.clone(), dev, &c))
-            .collect();
+    for dev in unpaired {
+        match to_partial_found_roots(engine.clone(), dev, &c) {
+            Ok(roots) => found_roots.push(roots),
+            Err(e) => report.warning(&format!(
+                "skipping the mapping root {} without device details: {}",
+                dev.b, e
+            )),
+        }
     }
 
-    pairs
-        .iter()
-        .map(|(dev, details)| to_found_roots(dev, details))
-        .collect()
+    if found_roots.is_empty() {
+        return Err(anyhow!("no compatible roots found"));
+    }
+
+    Ok((found_roots, c.gather_orphans()))
 }
 
 fn check_data_block_size(bs: u32) -> Result<u32> {
@@ -916,7 +1141,7 @@ fn rebuild_superblock(
             data_block_size,
             nr_metadata_blocks: 0,
         })),
-        TreeRoots::InCore(devs) => {
+        TreeRoots::InCore { devices: devs, .. } => {
             // the maximal tid among devices should be less than that in superblock
             let devices = build_devices(devs, transaction_id.saturating_sub(1), roots.time);
             Ok(ThinSuperblock::InCore(CoreSuperblock {
@@ -958,26 +1183,314 @@ impl Override for Superblock {
     }
 }
 
+// Prefers the roots sharing the mapping tree with the on-disk superblock,
+// since that is the last committed state even if its details tree is lost.
+fn select_roots<'a>(found_roots: &'a [FoundRoots], ref_sb: Option<&Superblock>) -> &'a FoundRoots {
+    ref_sb
+        .and_then(|sb| {
+            found_roots
+                .iter()
+                .find(|roots| roots.mapping_root() == sb.mapping_root)
+        })
+        .unwrap_or(&found_roots[0])
+}
+
 pub fn read_or_rebuild_superblock(
     engine: Arc<dyn IoEngine + Send + Sync>,
     report: Arc<Report>,
     loc: u64,
     opts: &SuperblockOverrides,
 ) -> Result<ThinSuperblock> {
-    let found_roots = find_roots(engine.clone(), report)?;
+    read_or_rebuild_superblock_with_orphans(engine, report, loc, opts).map(|(sb, _)| sb)
+}
 
-    read_superblock(engine.as_ref(), loc)
+/// As read_or_rebuild_superblock(), but also returns the mapping subtrees
+/// left behind by the superblock.
+pub fn read_or_rebuild_superblock_with_orphans(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    loc: u64,
+    opts: &SuperblockOverrides,
+) -> Result<(ThinSuperblock, Vec<OrphanTree>)> {
+    let (found_roots, orphans) = match find_roots(engine.clone(), report.clone()) {
+        Ok(r) => r,
+        Err(e) => {
+            let sb = read_graftable_superblock(engine, loc, report.as_ref()).map_err(|_| e)?;
+            return Ok((ThinSuperblock::OnDisk(sb.overrides(opts)?), Vec::new()));
+        }
+    };
+
+    let sb = read_superblock(engine.as_ref(), loc)
         .and_then(|sb| is_superblock_consistent_(sb, &found_roots))
         .and_then(|sb| sb.overrides(opts))
         .map_or_else(
             |e| {
+                if let Ok(sb) = read_graftable_superblock(engine.clone(), loc, report.as_ref()) {
+                    return Ok(ThinSuperblock::OnDisk(sb.overrides(opts)?));
+                }
+
                 let ref_sb = e
                     .downcast_ref::<SuperblockError>()
                     .and_then(|err| err.failed_sb.clone());
-                rebuild_superblock(&found_roots[0], ref_sb, opts)
+                let roots = select_roots(&found_roots, ref_sb.as_ref());
+                rebuild_superblock(roots, ref_sb, opts)
             },
             |sb| Ok(ThinSuperblock::OnDisk(sb)),
-        )
+        )?;
+
+    Ok((sb, orphans))
+}
+
+// The superblock is kept despite damaged mapping subtrees if the metadata
+// snapshot could fill the holes, provided the top-level trees are intact.
+// This is preferred over rebuilding from older roots, which would lose
+// every change made since.
+fn read_graftable_superblock(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    loc: u64,
+    report: &Report,
+) -> Result<Superblock> {
+    let sb = read_superblock(engine.as_ref(), loc)?;
+    if sb.metadata_snap == 0 {
+        return Err(anyhow!("no metadata snapshot"));
+    }
+    read_superblock(engine.as_ref(), sb.metadata_snap)?;
+
+    let sb = is_superblock_consistent(sb, engine, true)?;
+    report.warning("mapping trees are damaged, repairing them from the metadata snapshot");
+    Ok(sb)
+}
+
+//------------------------------------------
+
+/// A consistent pair of mapping and details roots that a superblock could
+/// point to.  Roots other than the current ones are usually left behind by
+/// earlier transactions, in blocks that were freed but not yet reused.
+/// The transaction id is estimated from the device details, so it's the
+/// earliest transaction the roots could belong to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RollbackCandidate {
+    pub mapping_root: u64,
+    pub details_root: u64,
+    pub transaction_id: u64,
+    pub time: u32,
+}
+
+// Newest first.  Roots with the same transaction id are kept in the order
+// found, apart from the current ones which come first.
+fn rollback_candidates(
+    found_roots: &[FoundRoots],
+    ref_sb: Option<&Superblock>,
+) -> Vec<RollbackCandidate> {
+    let mut candidates: Vec<RollbackCandidate> = found_roots
+        .iter()
+        .filter_map(|roots| match &roots.devices {
+            TreeRoots::OnDisk(r) => Some(RollbackCandidate {
+                mapping_root: r.mapping_root,
+                details_root: r.details_root,
+                transaction_id: roots.transaction_id,
+                time: roots.time,
+            }),
+            TreeRoots::InCore { .. } => None,
+        })
+        .collect();
+
+    let is_current = |c: &RollbackCandidate| {
+        ref_sb.is_some_and(|sb| {
+            sb.mapping_root == c.mapping_root && sb.details_root == c.details_root
+        })
+    };
+    candidates.sort_by_key(|c| (std::cmp::Reverse(c.transaction_id), !is_current(c)));
+    candidates
+}
+
+struct MappingCounter {
+    nr_mappings: AtomicU64,
+}
+
+impl NodeVisitor<BlockTime> for MappingCounter {
+    fn visit(
+        &self,
+        _path: &[u64],
+        _kr: &KeyRange,
+        _h: &NodeHeader,
+        keys: &[u64],
+        _values: &[BlockTime],
+    ) -> crate::pdata::btree::Result<()> {
+        self.nr_mappings
+            .fetch_add(keys.len() as u64, AtomicOrdering::Relaxed);
+        Ok(())
+    }
+
+    fn visit_again(&self, _path: &[u64], _b: u64) -> crate::pdata::btree::Result<()> {
+        Ok(())
+    }
+
+    fn end_walk(&self) -> crate::pdata::btree::Result<()> {
+        Ok(())
+    }
+}
+
+// The roots are paired by the device ids and the total number of
+// mappings only, so older roots are checked in full before being rolled
+// back to: the blocks of their trees may have been reused since.
+fn check_rollback_candidate(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    c: &RollbackCandidate,
+) -> Result<()> {
+    let roots = btree_to_map::<u64>(&mut vec![0], engine.clone(), false, c.mapping_root)?;
+    let details =
+        btree_to_map::<DeviceDetail>(&mut vec![0], engine.clone(), false, c.details_root)?;
+    if !roots.keys().eq(details.keys()) {
+        return Err(anyhow!(
+            "the mapping and details trees hold different devices"
+        ));
+    }
+
+    for (dev_id, root) in roots {
+        let walker = BTreeWalker::new(engine.clone(), false);
+        let counter = MappingCounter {
+            nr_mappings: AtomicU64::new(0),
+        };
+        walker
+            .walk(&mut vec![0, dev_id], &counter, root)
+            .map_err(|e| anyhow!("device {}: {}", dev_id, e))?;
+
+        let nr_mappings = counter.nr_mappings.into_inner();
+        let mapped_blocks = details[&dev_id].mapped_blocks;
+        if nr_mappings != mapped_blocks {
+            return Err(anyhow!(
+                "device {}: {} mappings found, but {} in the details",
+                dev_id,
+                nr_mappings,
+                mapped_blocks
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Takes the newest consistent roots that could belong to the given
+// transaction or an earlier one, or without a transaction id, the newest
+// consistent roots other than those of the current superblock.  Any roots
+// skipped are reported.
+fn select_rollback_roots<'a>(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: &Report,
+    candidates: &'a [RollbackCandidate],
+    ref_sb: Option<&Superblock>,
+    transaction_id: Option<u64>,
+) -> Result<&'a RollbackCandidate> {
+    let is_current = |c: &RollbackCandidate| {
+        ref_sb.is_some_and(|sb| {
+            sb.mapping_root == c.mapping_root && sb.details_root == c.details_root
+        })
+    };
+
+    let eligible: Vec<&RollbackCandidate> = match transaction_id {
+        Some(tid) => candidates
+            .iter()
+            .filter(|c| c.transaction_id <= tid)
+            .filter(|c| !is_current(c) || ref_sb.is_some_and(|sb| sb.transaction_id <= tid))
+            .collect(),
+        None => candidates
+            .iter()
+            .filter(|c| ref_sb.is_none_or(|sb| sb.mapping_root != c.mapping_root))
+            .collect(),
+    };
+
+    let mut nr_skipped = 0;
+    for c in eligible {
+        match check_rollback_candidate(engine.clone(), c) {
+            Ok(()) => return Ok(c),
+            Err(e) => {
+                report.warning(&format!(
+                    "skipping the mapping root {} and the details root {}: {}",
+                    c.mapping_root, c.details_root, e
+                ));
+                nr_skipped += 1;
+            }
+        }
+    }
+
+    let which = if nr_skipped > 0 {
+        "no consistent roots found"
+    } else {
+        "no roots found"
+    };
+    match transaction_id {
+        Some(tid) => Err(anyhow!("{} for transaction {} or earlier", which, tid)),
+        None => Err(anyhow!("{} from an earlier transaction", which)),
+    }
+}
+
+pub fn rollback_superblock(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    loc: u64,
+    opts: &SuperblockOverrides,
+) -> Result<ThinSuperblock> {
+    rollback_superblock_with_orphans(engine, report, loc, opts).map(|(sb, _)| sb)
+}
+
+/// Rebuilds the superblock from the roots of an earlier transaction.  The
+/// transaction id in the overrides picks the transaction to roll back to,
+/// and is written to the superblock, while the other overrides apply as
+/// usual.  Without one, the newest roots other than the current ones are
+/// taken, along with the transaction id they suggest.
+pub fn rollback_superblock_with_orphans(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: Arc<Report>,
+    loc: u64,
+    opts: &SuperblockOverrides,
+) -> Result<(ThinSuperblock, Vec<OrphanTree>)> {
+    let (found_roots, orphans) = find_roots(engine.clone(), report.clone())?;
+
+    let ref_sb = read_superblock(engine.as_ref(), loc).ok();
+
+    let candidates = rollback_candidates(&found_roots, ref_sb.as_ref());
+    report.info(&format!("\nrollback candidates ({}):", candidates.len()));
+    for c in candidates.iter() {
+        report.info(&format!(
+            "transaction={}, mapping_root={}, details_root={}, time={}",
+            c.transaction_id, c.mapping_root, c.details_root, c.time
+        ));
+    }
+
+    let selected = select_rollback_roots(
+        engine.clone(),
+        report.as_ref(),
+        &candidates,
+        ref_sb.as_ref(),
+        opts.transaction_id,
+    )?;
+    report.info(&format!(
+        "rolling back to the mapping root {} and the details root {}",
+        selected.mapping_root, selected.details_root
+    ));
+
+    let roots = found_roots
+        .iter()
+        .find(|roots| {
+            matches!(&roots.devices, TreeRoots::OnDisk(r)
+                if r.mapping_root == selected.mapping_root && r.details_root == selected.details_root)
+        })
+        .ok_or_else(|| {
+            anyhow!(
+                "the mapping root {} and the details root {} are no longer found",
+                selected.mapping_root,
+                selected.details_root
+            )
+        })?;
+
+    let opts = SuperblockOverrides {
+        transaction_id: Some(opts.transaction_id.unwrap_or(selected.transaction_id)),
+        ..*opts
+    };
+    let sb = rebuild_superblock(roots, ref_sb, &opts)?;
+
+    Ok((sb, orphans))
 }
 
 //------------------------------------------
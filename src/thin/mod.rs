@@ -1,12 +1,17 @@
 pub mod block_time;
 pub mod check;
 pub mod delta;
+pub mod delta_binary;
 pub mod delta_visitor;
 pub mod device_detail;
 pub mod dump;
+pub mod graft;
 pub mod human_readable_format;
 pub mod ir;
+pub mod json;
+pub mod lost_found;
 pub mod ls;
+pub mod merge;
 pub mod metadata;
 pub mod metadata_repair;
 pub mod metadata_size;
@@ -15,6 +20,7 @@ pub mod restore;
 pub mod rmap;
 pub mod runs;
 pub mod shrink;
+pub mod subset;
 pub mod superblock;
 pub mod trim;
 pub mod xml;
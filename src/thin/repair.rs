@@ -1,12 +1,22 @@
 use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use crate::commands::engine::*;
 use crate::io_engine::*;
+use crate::pdata::btree::{self, *};
+use crate::pdata::btree_walker::*;
+use crate::pdata::issues::*;
 use crate::pdata::space_map::metadata::*;
+use crate::pdata::unpack::*;
 use crate::report::*;
+use crate::thin::block_time::*;
+use crate::thin::device_detail::*;
 use crate::thin::dump::*;
+use crate::thin::graft::*;
+use crate::thin::lost_found::*;
 use crate::thin::metadata::*;
 use crate::thin::metadata_repair::*;
 use crate::thin::restore::*;
@@ -21,6 +31,16 @@ pub struct ThinRepairOptions<'a> {
     pub engine_opts: EngineOptions,
     pub report: Arc<Report>,
     pub overrides: SuperblockOverrides,
+    pub rollback: bool,
+    pub lost_found: Option<&'a Path>,
+}
+
+pub struct ThinRepairDryRunOptions<'a> {
+    pub input: &'a Path,
+    pub engine_opts: EngineOptions,
+    pub report: Arc<Report>,
+    pub overrides: SuperblockOverrides,
+    pub rollback: bool,
 }
 
 struct Context {
@@ -47,21 +67,424 @@ fn new_context(opts: &ThinRepairOptions) -> Result<Context> {
 pub fn repair(opts: ThinRepairOptions) -> Result<()> {
     let ctx = new_context(&opts)?;
 
-    let sb = read_or_rebuild_superblock(
+    let read_sb = if opts.rollback {
+        rollback_superblock_with_orphans
+    } else {
+        read_or_rebuild_superblock_with_orphans
+    };
+    let (sb, orphans) = read_sb(
         ctx.engine_in.clone(),
         ctx.report.clone(),
         SUPERBLOCK_LOCATION,
         &opts.overrides,
     )?;
-    let md = build_metadata(ctx.engine_in.clone(), &sb)?;
+    let (md, grafts, holes) =
+        build_metadata_with_grafts(ctx.engine_in.clone(), &sb, None, ctx.report.as_ref())?;
     let md = optimise_metadata(md)?;
 
     let sm = core_metadata_sm(ctx.engine_out.get_nr_blocks(), u32::MAX);
     let batch_size = ctx.engine_out.get_batch_size();
     let mut w = WriteBatcher::new(ctx.engine_out, sm.clone(), batch_size);
-    let mut restorer = Restorer::new(&mut w, ctx.report);
+    let mut restorer = Restorer::new(&mut w, ctx.report.clone());
+
+    let path = match opts.lost_found {
+        Some(path) => path,
+        None => {
+            let mut graft = SnapshotGraft::new(&mut restorer, grafts);
+            return dump_metadata(ctx.engine_in, &mut graft, &sb, &md);
+        }
+    };
+
+    // Orphaned subtrees are gathered into a lost+found device
+    let nr_orphans = orphans.len();
+    let orphans = select_orphans(orphans, &holes, &md);
+    if orphans.len() < nr_orphans {
+        ctx.report.info(&format!(
+            "{} orphaned subtrees left out as stale",
+            nr_orphans - orphans.len()
+        ));
+    }
+
+    let mut lost_found = LostFound::new(&mut restorer, ctx.engine_in.clone(), orphans);
+    let mut graft = SnapshotGraft::new(&mut lost_found, grafts);
+    dump_metadata(ctx.engine_in, &mut graft, &sb, &md)?;
+
+    match lost_found.dev_id() {
+        Some(dev_id) => ctx.report.warning(&format!(
+            "orphaned mappings recovered into device {}",
+            dev_id
+        )),
+        None => ctx.report.info("no orphaned mappings found"),
+    }
+
+    let mut f = std::io::BufWriter::new(std::fs::File::create(path)?);
+    lost_found.write_report(&mut f)?;
+    f.flush()?;
+    Ok(())
+}
+
+//------------------------------------------
+
+// Ranges of thin blocks, with the ends being one-past-the-end
+type Runs = Vec<(u64, u64)>;
+
+fn merge_runs(mut runs: Runs) -> Runs {
+    runs.sort_unstable();
+
+    let mut merged: Runs = Vec::with_capacity(runs.len());
+    for (b, e) in runs {
+        match merged.last_mut() {
+            Some(last) if b <= last.1 => last.1 = std::cmp::max(last.1, e),
+            _ => merged.push((b, e)),
+        }
+    }
+    merged
+}
+
+// Returns the parts of lhs not covered by rhs.  Both are merged runs.
+fn subtract_runs(lhs: &[(u64, u64)], rhs: &[(u64, u64)]) -> Runs {
+    let mut result = Vec::new();
+    let mut rhs = rhs.iter().peekable();
+
+    for &(mut b, e) in lhs {
+        while let Some(&&(rb, re)) = rhs.peek() {
+            if re <= b {
+                rhs.next();
+                continue;
+            }
+            if rb >= e {
+                break;
+            }
+            if rb > b {
+                result.push((b, rb));
+            }
+            b = re;
+            if re >= e {
+                break;
+            }
+            rhs.next();
+        }
+        if b < e {
+            result.push((b, e));
+        }
+    }
+    result
+}
+
+fn nr_run_blocks(runs: &[(u64, u64)]) -> u64 {
+    runs.iter().map(|(b, e)| e - b).sum()
+}
+
+struct RunCollector {
+    runs: Mutex<Runs>,
+}
+
+impl RunCollector {
+    fn new() -> RunCollector {
+        RunCollector {
+            runs: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl NodeVisitor<BlockTime> for RunCollector {
+    fn visit(
+        &self,
+        _path: &[u64],
+        _kr: &KeyRange,
+        _h: &NodeHeader,
+        keys: &[u64],
+        _values: &[BlockTime],
+    ) -> btree::Result<()> {
+        let mut runs = self.runs.lock().unwrap();
+        for k in keys {
+            match runs.last_mut() {
+                Some(last) if last.1 == *k => last.1 += 1,
+                _ => runs.push((*k, *k + 1)),
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_again(&self, _path: &[u64], _b: u64) -> btree::Result<()> {
+        Ok(())
+    }
+
+    fn end_walk(&self) -> btree::Result<()> {
+        Ok(())
+    }
+}
+
+struct EntryCollector<V> {
+    entries: Mutex<BTreeMap<u64, V>>,
+}
+
+impl<V: Unpack + Copy> NodeVisitor<V> for EntryCollector<V> {
+    fn visit(
+        &self,
+        _path: &[u64],
+        _kr: &KeyRange,
+        _h: &NodeHeader,
+        keys: &[u64],
+        values: &[V],
+    ) -> btree::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.extend(keys.iter().cloned().zip(values.iter().cloned()));
+        Ok(())
+    }
+
+    fn visit_again(&self, _path: &[u64], _b: u64) -> btree::Result<()> {
+        Ok(())
+    }
+
+    fn end_walk(&self) -> btree::Result<()> {
+        Ok(())
+    }
+}
+
+// Key ranges of the nodes that couldn't be read
+fn damaged_runs(e: &BTreeError) -> Runs {
+    let runs = issues_from_btree_error(e, true)
+        .iter()
+        .map(|i| (i.thin_begin.unwrap_or(0), i.thin_end.unwrap_or(u64::MAX)))
+        .collect();
+    merge_runs(runs)
+}
+
+// Reads whatever is readable from a possibly damaged tree
+fn read_entries<V: Unpack + Copy>(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    root: u64,
+) -> (BTreeMap<u64, V>, Runs) {
+    let walker = BTreeWalker::new(engine, true);
+    let visitor = EntryCollector {
+        entries: Mutex::new(BTreeMap::new()),
+    };
+    let damaged = match walker.walk(&mut vec![0], &visitor, root) {
+        Ok(()) => Vec::new(),
+        Err(e) => damaged_runs(&e),
+    };
+    (visitor.entries.into_inner().unwrap(), damaged)
+}
+
+fn read_mappings(engine: Arc<dyn IoEngine + Send + Sync>, root: u64) -> (Runs, Runs) {
+    let walker = BTreeWalker::new(engine, true);
+    let visitor = RunCollector::new();
+    let damaged = match walker.walk(&mut vec![0], &visitor, root) {
+        Ok(()) => Vec::new(),
+        Err(e) => damaged_runs(&e),
+    };
+    (merge_runs(visitor.runs.into_inner().unwrap()), damaged)
+}
+
+// The devices as they were before the damage, as far as they're readable
+// from the on-disk superblock.
+#[derive(Default)]
+struct ReferenceDevices {
+    roots: BTreeMap<u64, u64>,
+    details: BTreeMap<u64, DeviceDetail>,
+    damaged_ids: Runs,
+}
+
+fn read_reference_devices(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    report: &Report,
+) -> ReferenceDevices {
+    let sb = match read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION) {
+        Ok(sb) => sb,
+        Err(_) => {
+            report.warning(
+                "superblock is unreadable, estimating against the recovered device details",
+            );
+            return ReferenceDevices::default();
+        }
+    };
+
+    let (roots, mut damaged_ids) = read_entries::<u64>(engine.clone(), sb.mapping_root);
+    let (details, damaged_details) = read_entries::<DeviceDetail>(engine, sb.details_root);
+    damaged_ids.extend(damaged_details);
+
+    ReferenceDevices {
+        roots,
+        details,
+        damaged_ids: merge_runs(damaged_ids),
+    }
+}
+
+// Devices found in only one of the trees are left out by the repair, so
+// they're reported rather than recovered.
+fn recovered_devices(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    sb: &ThinSuperblock,
+    report: &Report,
+) -> Result<BTreeMap<u64, (u64, DeviceDetail)>> {
+    match sb {
+        ThinSuperblock::OnDisk(sb) => {
+            let roots = btree_to_map::<u64>(&mut vec![0], engine.clone(), true, sb.mapping_root)?;
+            let details =
+                btree_to_map::<DeviceDetail>(&mut vec![0], engine, true, sb.details_root)?;
+            let joined = join_devices(roots, details);
+            for dev_id in joined.roots_only {
+                report.to_stdout(&format!(
+                    "device {}: no device details found, left out",
+                    dev_id
+                ));
+            }
+            for dev_id in joined.details_only {
+                report.to_stdout(&format!(
+                    "device {}: no mapping tree found, left out",
+                    dev_id
+                ));
+            }
+            Ok(joined.devs)
+        }
+        ThinSuperblock::InCore(sb) => Ok(sb.devices.clone()),
+    }
+}
+
+fn fmt_run(b: u64, e: u64) -> String {
+    if e == u64::MAX {
+        format!("{}..", b)
+    } else {
+        format!("{}..{}", b, e)
+    }
+}
+
+fn fmt_size(nr_blocks: u64, data_block_size: u32) -> String {
+    let bytes = nr_blocks.saturating_mul(data_block_size as u64 * 512);
+    let (size, unit) = crate::units::to_pretty_print_size(bytes);
+    format!("{}{}", size, unit.to_string_short())
+}
+
+/// Reports what thin_repair would recover without writing anything.  The
+/// recovered devices are compared against the devices readable from the
+/// on-disk superblock, to estimate the mappings that would be lost.
+pub fn dry_run(opts: ThinRepairDryRunOptions) -> Result<()> {
+    let engine = EngineBuilder::new(opts.input, &opts.engine_opts).build()?;
+    let report = opts.report;
+
+    let read_sb = if opts.rollback {
+        rollback_superblock
+    } else {
+        read_or_rebuild_superblock
+    };
+    let sb = read_sb(
+        engine.clone(),
+        report.clone(),
+        SUPERBLOCK_LOCATION,
+        &opts.overrides,
+    )?;
+    let data_block_size = match &sb {
+        ThinSuperblock::OnDisk(sb) => {
+            report.to_stdout(&format!(
+                "using the mapping root {} and the details root {}",
+                sb.mapping_root, sb.details_root
+            ));
+            sb.data_block_size
+        }
+        ThinSuperblock::InCore(sb) => {
+            report.to_stdout("rebuilding the device details from the mappings");
+            sb.data_block_size
+        }
+    };
+
+    let graft_src = GraftSource::new(engine.clone(), &sb);
+    let nr_data_blocks = to_superblock_ir(&sb)?.nr_data_blocks;
+    let recovered = recovered_devices(engine.clone(), &sb, &report)?;
+    let reference = read_reference_devices(engine.clone(), &report);
+    for (b, e) in reference.damaged_ids.iter() {
+        report.to_stdout(&format!("unreadable device ids {}", fmt_run(*b, *e)));
+    }
+
+    let dev_ids: BTreeSet<u64> = recovered
+        .keys()
+        .chain(reference.roots.keys())
+        .chain(reference.details.keys())
+        .cloned()
+        .collect();
+
+    let mut total_lost = 0;
+    for dev_id in dev_ids {
+        let (read_runs, holes) = match recovered.get(&dev_id) {
+            Some((root, _)) => read_mappings(engine.clone(), *root),
+            None => (Vec::new(), Vec::new()),
+        };
+
+        // Holes in the recovered trees are filled from the metadata snapshot
+        let mut grafted: Runs = Vec::new();
+        if let Some(src) = graft_src.as_ref().filter(|_| !holes.is_empty()) {
+            for (k, bt) in src.read_mappings(dev_id, &holes) {
+                if bt.block >= nr_data_blocks {
+                    continue;
+                }
+                match grafted.last_mut() {
+                    Some(last) if last.1 == k => last.1 += 1,
+                    _ => grafted.push((k, k + 1)),
+                }
+            }
+        }
+        let rec_runs = merge_runs(read_runs.iter().chain(grafted.iter()).cloned().collect());
+        let nr_recovered = nr_run_blocks(&rec_runs);
+
+        // Roots shared with the recovered devices needn't be read again
+        let (ref_runs, damaged) = match (reference.roots.get(&dev_id), recovered.get(&dev_id)) {
+            (Some(root), Some((rec_root, _))) if root == rec_root => (read_runs, Vec::new()),
+            (Some(root), _) => read_mappings(engine.clone(), *root),
+            (None, _) => (Vec::new(), Vec::new()),
+        };
+
+        let expected = reference
+            .details
+            .get(&dev_id)
+            .or_else(|| recovered.get(&dev_id).map(|(_, d)| d))
+            .map_or(nr_run_blocks(&ref_runs), |d| d.mapped_blocks);
+
+        let lost_runs = subtract_runs(&ref_runs, &rec_runs);
+        let nr_lost = std::cmp::max(
+            nr_run_blocks(&lost_runs),
+            expected.saturating_sub(nr_recovered),
+        );
+        total_lost += nr_lost;
+
+        // Stale trees might bring back deleted devices
+        let stale = !reference.details.is_empty()
+            && !reference.details.contains_key(&dev_id)
+            && !reference
+                .damaged_ids
+                .iter()
+                .any(|(b, e)| *b <= dev_id && dev_id < *e);
+
+        report.to_stdout(&format!(
+            "device {}: {} of {} mapped blocks recovered, {} lost ({}){}",
+            dev_id,
+            nr_recovered,
+            expected,
+            nr_lost,
+            fmt_size(nr_lost, data_block_size),
+            if stale {
+                ", not in the on-disk device details"
+            } else {
+                ""
+            }
+        ));
+        for (b, e) in grafted {
+            report.to_stdout(&format!("  grafted thin blocks {}", fmt_run(b, e)));
+        }
+        for (b, e) in lost_runs {
+            report.to_stdout(&format!("  lost thin blocks {}", fmt_run(b, e)));
+        }
+        for (b, e) in subtract_runs(&damaged, &rec_runs) {
+            report.to_stdout(&format!("  unreadable thin blocks {}", fmt_run(b, e)));
+        }
+    }
+
+    report.to_stdout(&format!(
+        "total: {} blocks lost ({})",
+        total_lost,
+        fmt_size(total_lost, data_block_size)
+    ));
 
-    dump_metadata(ctx.engine_in, &mut restorer, &sb, &md)
+    Ok(())
 }
 
 //------------------------------------------
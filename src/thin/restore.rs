@@ -4,6 +4,7 @@ use std::collections::BTreeMap;
 use std::fs::OpenOptions;
 use std::ops::Deref;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 
 use crate::commands::engine::*;
@@ -17,6 +18,7 @@ use crate::report::*;
 use crate::thin::block_time::*;
 use crate::thin::device_detail::*;
 use crate::thin::ir::{self, MetadataVisitor, Visit};
+use crate::thin::json;
 use crate::thin::metadata_repair::{Override, SuperblockOverrides};
 use crate::thin::superblock::{self, *};
 use crate::thin::xml;
@@ -235,7 +237,7 @@ impl<'a> MetadataVisitor for Restorer<'a> {
         }
 
         self.sb = Some(sb.clone());
-        self.data_sm = Some(core_sm(sb.nr_data_blocks, u32::MAX));
+        self.data_sm = Some(compact_sm(sb.nr_data_blocks));
         let b = self.w.alloc()?;
         if b.loc != SUPERBLOCK_LOCATION {
             return Err(anyhow!("superblock was occupied"));
@@ -285,8 +287,10 @@ impl<'a> MetadataVisitor for Restorer<'a> {
     }
 
     fn device_e(&mut self) -> Result<Visit> {
-        if let Some(detail) = self.current_dev.take() {
+        if let Some(mut detail) = self.current_dev.take() {
             if let (MappedSection::Dev(thin_id), nodes) = self.end_section()? {
+                // Recount the mappings rather than trusting the input
+                detail.mapped_blocks = nodes.iter().map(|n| n.nr_entries() as u64).sum();
                 let root = build_btree(self.w, nodes)?;
                 self.devices.insert(thin_id, (detail, root));
                 self.in_section = Section::Superblock;
@@ -361,12 +365,33 @@ fn build_data_sm(w: &mut WriteBatcher, sm: &dyn SpaceMap) -> Result<Vec<u8>> {
 
 //------------------------------------------
 
+#[derive(Clone)]
+pub enum InputFormat {
+    XML,
+    JSON,
+    JSONLines,
+}
+
+impl FromStr for InputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xml" => Ok(InputFormat::XML),
+            "json" => Ok(InputFormat::JSON),
+            "json_lines" => Ok(InputFormat::JSONLines),
+            _ => Err(anyhow!("unknown format")),
+        }
+    }
+}
+
 pub struct ThinRestoreOptions<'a> {
     pub input: &'a Path,
     pub output: &'a Path,
     pub engine_opts: EngineOptions,
     pub report: Arc<Report>,
     pub overrides: SuperblockOverrides,
+    pub format: InputFormat,
 }
 
 struct Context {
@@ -399,7 +424,11 @@ pub fn restore(opts: ThinRestoreOptions) -> Result<()> {
     let sm = core_metadata_sm(ctx.engine.get_nr_blocks(), max_count);
     let mut w = WriteBatcher::new(ctx.engine.clone(), sm.clone(), ctx.engine.get_batch_size());
     let mut restorer = Restorer::new_with(&mut w, &opts.overrides, ctx.report);
-    xml::read(input, &mut restorer)?;
+    match opts.format {
+        InputFormat::XML => xml::read(input, &mut restorer)?,
+        InputFormat::JSON => json::read(input, &mut restorer)?,
+        InputFormat::JSONLines => json::read_lines(input, &mut restorer)?,
+    }
 
     Ok(())
 }
@@ -0,0 +1,171 @@
+use anyhow::{anyhow, Result};
+use fixedbitset::FixedBitSet;
+use std::collections::BTreeMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+use crate::io_engine::*;
+use crate::pdata::btree::{self, *};
+use crate::pdata::btree_builder::*;
+use crate::pdata::btree_walker::*;
+use crate::pdata::space_map::common::*;
+use crate::pdata::space_map::disk::*;
+use crate::pdata::space_map::metadata::*;
+use crate::pdata::space_map::*;
+use crate::pdata::unpack::unpack;
+use crate::thin::block_time::*;
+use crate::thin::device_detail::*;
+use crate::thin::superblock::*;
+use crate::write_batcher::*;
+
+//------------------------------------------
+
+// Counts the references to data blocks.  Shared leaves are only visited
+// once, so each leaf holds a single reference to the blocks it maps.
+struct DataCounter {
+    sm: Arc<Mutex<dyn SpaceMap + Send + Sync>>,
+}
+
+impl NodeVisitor<BlockTime> for DataCounter {
+    fn visit(
+        &self,
+        _path: &[u64],
+        _kr: &KeyRange,
+        _h: &NodeHeader,
+        _keys: &[u64],
+        values: &[BlockTime],
+    ) -> btree::Result<()> {
+        let mut sm = self.sm.lock().unwrap();
+        for bt in values {
+            if bt.block >= sm.get_nr_blocks().map_err(|e| value_err(e.to_string()))? {
+                return Err(value_err(format!(
+                    "data block {} is beyond the end of the pool",
+                    bt.block
+                )));
+            }
+            sm.inc(bt.block, 1).map_err(|e| value_err(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn visit_again(&self, _path: &[u64], _b: u64) -> btree::Result<()> {
+        Ok(())
+    }
+
+    fn end_walk(&self) -> btree::Result<()> {
+        Ok(())
+    }
+}
+
+//------------------------------------------
+
+/// The blocks of thin metadata needed to hold a subset of the devices.
+/// The device details and top level mapping trees are rebuilt to hold
+/// just those devices, and the space maps rebuilt to count just the
+/// blocks in use by them.  The mapping trees of the devices are kept as
+/// they are.
+pub struct Subset {
+    blocks: FixedBitSet,
+    rewritten: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Subset {
+    pub fn contains(&self, b: u64) -> bool {
+        self.blocks.contains(b as usize)
+    }
+
+    /// Returns the new contents of a block, if it was rewritten.
+    pub fn get_rewritten(&self, b: u64) -> Option<&[u8]> {
+        self.rewritten.get(&b).map(|data| data.as_slice())
+    }
+}
+
+fn blocks_in_use(sm: &Arc<Mutex<dyn SpaceMap + Send + Sync>>) -> Result<FixedBitSet> {
+    let sm = sm.lock().unwrap();
+    let nr_blocks = sm.get_nr_blocks()?;
+    let mut blocks = FixedBitSet::with_capacity(nr_blocks as usize);
+    for b in 0..nr_blocks {
+        if sm.get(b)? > 0 {
+            blocks.insert(b as usize);
+        }
+    }
+    Ok(blocks)
+}
+
+pub fn build_subset(
+    engine: Arc<dyn IoEngine + Send + Sync>,
+    selected_devs: &[u64],
+) -> Result<Subset> {
+    let sb = read_superblock(engine.as_ref(), SUPERBLOCK_LOCATION)?;
+    let roots = btree_to_map::<u64>(&mut vec![], engine.clone(), false, sb.mapping_root)?;
+    let details =
+        btree_to_map::<DeviceDetail>(&mut vec![], engine.clone(), false, sb.details_root)?;
+    let data_root = unpack::<SMRoot>(&sb.data_sm_root[0..])?;
+
+    let nr_blocks = engine.get_nr_blocks();
+    let metadata_sm = core_metadata_sm(nr_blocks, u32::MAX);
+    metadata_sm.lock().unwrap().inc(SUPERBLOCK_LOCATION, 1)?;
+    let data_sm = core_sm(data_root.nr_blocks, u32::MAX);
+
+    // Count the blocks used by the selected devices
+    let walker = BTreeWalker::new_with_sm(engine.clone(), metadata_sm.clone(), false)?;
+    let counter = DataCounter {
+        sm: data_sm.clone(),
+    };
+    let mut devs = BTreeMap::new();
+    for dev_id in selected_devs {
+        let (root, detail) = match (roots.get(dev_id), details.get(dev_id)) {
+            (Some(root), Some(detail)) => (*root, *detail),
+            _ => return Err(anyhow!("device {} not found", dev_id)),
+        };
+        walker
+            .walk(&mut vec![0, *dev_id], &counter, root)
+            .map_err(|e| anyhow!("unable to read the mappings of device {}: {}", dev_id, e))?;
+        devs.insert(*dev_id, (root, detail));
+    }
+
+    // Build the new trees and space maps in the free blocks
+    let overlay = Arc::new(OverlayIoEngine::new(engine.clone()));
+    let mut w = WriteBatcher::new(
+        overlay.clone(),
+        metadata_sm.clone(),
+        overlay.get_batch_size(),
+    );
+    for b in blocks_in_use(&metadata_sm)?.ones() {
+        w.add_allocations(b as u64..b as u64 + 1);
+    }
+
+    let mut details_builder: BTreeBuilder<DeviceDetail> = BTreeBuilder::new(Box::new(NoopRC {}));
+    let mut dev_builder: BTreeBuilder<u64> = BTreeBuilder::new(Box::new(NoopRC {}));
+    for (dev_id, (root, detail)) in &devs {
+        details_builder.push_value(&mut w, *dev_id, *detail)?;
+        dev_builder.push_value(&mut w, *dev_id, *root)?;
+    }
+    let details_root = details_builder.complete(&mut w)?;
+    let mapping_root = dev_builder.complete(&mut w)?;
+
+    let data_sm_root = write_disk_sm(&mut w, data_sm.lock().unwrap().deref())?;
+    let metadata_sm_root = write_metadata_sm(&mut w)?;
+
+    let new_sb = Superblock {
+        metadata_snap: 0,
+        data_sm_root: pack_root(&data_sm_root, SPACE_MAP_ROOT_SIZE)?,
+        metadata_sm_root: pack_root(&metadata_sm_root, SPACE_MAP_ROOT_SIZE)?,
+        mapping_root,
+        details_root,
+        nr_metadata_blocks: metadata_sm_root.nr_blocks,
+        ..sb
+    };
+    write_superblock(overlay.as_ref(), SUPERBLOCK_LOCATION, &new_sb)?;
+
+    let blocks = blocks_in_use(&metadata_sm)?;
+
+    drop(w);
+    let rewritten = Arc::try_unwrap(overlay)
+        .map_err(|_| anyhow!("metadata overlay still in use"))?
+        .into_blocks();
+
+    Ok(Subset { blocks, rewritten })
+}
+
+//------------------------------------------
@@ -139,8 +139,8 @@ fn pack_superblock<W: WriteBytesExt>(sb: &Superblock, w: &mut W) -> Result<()> {
     Ok(())
 }
 
-pub fn write_superblock(engine: &dyn IoEngine, _loc: u64, sb: &Superblock) -> Result<()> {
-    let b = Block::zeroed(SUPERBLOCK_LOCATION);
+pub fn write_superblock(engine: &dyn IoEngine, loc: u64, sb: &Superblock) -> Result<()> {
+    let b = Block::zeroed(loc);
 
     // pack the superblock
     {
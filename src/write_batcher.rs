@@ -73,6 +73,13 @@ impl WriteBatcher {
         Ok(Block::zeroed(loc))
     }
 
+    // Records blocks that are already in use in the space map, eg. those
+    // kept from existing metadata, so that they're written out along with
+    // the allocations.
+    pub fn add_allocations(&mut self, blocks: std::ops::Range<u64>) {
+        self.allocations.insert(blocks);
+    }
+
     pub fn clear_allocations(&mut self) -> RangeSet<u64> {
         let mut tmp = RangeSet::<u64>::new();
         std::mem::swap(&mut tmp, &mut self.allocations);
@@ -115,7 +122,10 @@ impl WriteBatcher {
     }
 
     fn flush_(&mut self, queue: Vec<Block>) -> Result<()> {
-        self.engine.write_many(&queue)?;
+        let results = self.engine.write_many(&queue)?;
+        for (b, r) in queue.iter().zip(results) {
+            r.map_err(|e| anyhow!("write block {} error: {}", b.loc, e))?;
+        }
         Ok(())
     }
 
@@ -4,6 +4,7 @@ mod common;
 
 use common::cache::*;
 use common::common_args::*;
+use common::damage::*;
 use common::fixture::*;
 use common::input_arg::*;
 use common::process::*;
@@ -82,6 +83,16 @@ impl<'a> InputProgram<'a> for CacheCheck {
 
 impl<'a> MetadataReader<'a> for CacheCheck {}
 
+impl<'a> DamageTarget<'a> for CacheCheck {
+    fn generate_damage_cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        cache_generate_damage_cmd(args)
+    }
+}
+
 //------------------------------------------
 
 test_accepts_help!(CacheCheck);
@@ -444,3 +455,43 @@ fn metadata_v2_without_slow_dev_size_info() -> Result<()> {
 }
 
 //------------------------------------------
+// test generated damage
+
+#[test]
+fn detects_unsupported_superblock_version() -> Result<()> {
+    test_damage_detected::<CacheCheck>(
+        &["--override", "--superblock-version", "3"],
+        "unsupported metadata version",
+    )
+}
+
+#[test]
+fn detects_missing_dirty_bitset() -> Result<()> {
+    test_damage_detected::<CacheCheck>(
+        &["--override", "--dirty-root", "0"],
+        "dirty bitset not found",
+    )
+}
+
+#[test]
+fn detects_corrupted_mapping_array_checksum() -> Result<()> {
+    test_damage_detected::<CacheCheck>(
+        &["--damage-array", "checksum", "--array", "mapping"],
+        "checksum",
+    )
+}
+
+#[test]
+fn detects_zeroed_hint_array() -> Result<()> {
+    test_damage_detected::<CacheCheck>(&["--damage-array", "zero", "--array", "hint"], "checksum")
+}
+
+#[test]
+fn detects_corrupted_dirty_bitset_checksum() -> Result<()> {
+    test_damage_detected::<CacheCheck>(
+        &["--damage-array", "checksum", "--array", "dirty"],
+        "checksum",
+    )
+}
+
+//------------------------------------------
@@ -0,0 +1,40 @@
+use anyhow::Result;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use crate::args;
+use crate::common::process::*;
+use crate::common::program::*;
+use crate::common::test_dir::TestDir;
+
+//------------------------------------------
+
+// Checkers that come with a damage generator for their metadata
+pub trait DamageTarget<'a>: InputProgram<'a> {
+    fn generate_damage_cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<OsString>;
+
+    fn mk_damageable_input(td: &mut TestDir) -> Result<PathBuf> {
+        Self::mk_valid_input(td)
+    }
+}
+
+pub fn generate_damage<'a, P: DamageTarget<'a>>(md: &Path, damage: &[&str]) -> Result<()> {
+    let mut args: Vec<OsString> = vec!["-o".into(), md.into()];
+    args.extend(damage.iter().map(OsString::from));
+    run_ok(P::generate_damage_cmd(args))?;
+    Ok(())
+}
+
+pub fn test_damage_detected<'a, P: DamageTarget<'a>>(damage: &[&str], pattern: &str) -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = P::mk_damageable_input(&mut td)?;
+    generate_damage::<P>(&md, damage)?;
+    let stderr = run_fail(P::cmd(args![&md]))?;
+    assert!(stderr.contains(pattern));
+    Ok(())
+}
+
+//------------------------------------------
@@ -5,6 +5,7 @@
 pub mod cache;
 pub mod cache_xml_generator;
 pub mod common_args;
+pub mod damage;
 pub mod era;
 pub mod era_xml_generator;
 pub mod fixture;
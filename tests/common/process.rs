@@ -92,13 +92,18 @@ fn run_raw(command: Command) -> std::io::Result<std::process::Output> {
 }
 
 fn log_output(output: &process::Output) {
-    use std::str::from_utf8;
-
+    // stdout might be binary
     if !output.stdout.is_empty() {
-        eprintln!("stdout: \n{}<<END>>", from_utf8(&output.stdout).unwrap());
+        eprintln!(
+            "stdout: \n{}<<END>>",
+            String::from_utf8_lossy(&output.stdout)
+        );
     }
     if !output.stderr.is_empty() {
-        eprintln!("stderr: \n{}<<END>>", from_utf8(&output.stderr).unwrap());
+        eprintln!(
+            "stderr: \n{}<<END>>",
+            String::from_utf8_lossy(&output.stderr)
+        );
     }
 }
 
@@ -156,4 +161,14 @@ pub fn run_fail_raw(command: Command) -> Result<std::process::Output> {
     Ok(output)
 }
 
+// Returns the entire output, whatever the status.
+pub fn run_unchecked_raw(command: Command) -> Result<std::process::Output> {
+    eprintln!("run_unchecked_raw: {}", command);
+
+    let output = run_raw(command)?;
+    log_output(&output);
+
+    Ok(output)
+}
+
 //------------------------------------------
@@ -68,6 +68,14 @@ where
     rust_devel_cmd("thin_generate_damage", args)
 }
 
+pub fn thin_journal_check_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    rust_devel_cmd("thin_journal_check", args)
+}
+
 pub fn thin_restore_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
@@ -108,6 +116,14 @@ where
     rust_cmd("thin_ls", args)
 }
 
+pub fn thin_merge_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    rust_cmd("thin_merge", args)
+}
+
 pub fn thin_metadata_pack_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
@@ -220,6 +236,14 @@ where
     rust_cmd("era_dump", args)
 }
 
+pub fn era_generate_damage_cmd<I>(args: I) -> Command
+where
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    rust_devel_cmd("era_generate_damage", args)
+}
+
 pub fn era_invalidate_cmd<I>(args: I) -> Command
 where
     I: IntoIterator,
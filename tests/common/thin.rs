@@ -6,6 +6,7 @@ use std::sync::Arc;
 use thinp::file_utils;
 use thinp::io_engine::*;
 use thinp::pdata::btree_walker::btree_to_map;
+use thinp::pdata::unpack::{Pack, Unpack};
 use thinp::thin::device_detail::DeviceDetail;
 
 use crate::args;
@@ -36,6 +37,35 @@ pub fn mk_valid_md(td: &mut TestDir) -> Result<PathBuf> {
     Ok(md)
 }
 
+// Generates metadata with snapshot chains sharing most of their mappings
+pub fn mk_generated_md(td: &mut TestDir, nr_thins: u32, nr_snapshots: u32) -> Result<PathBuf> {
+    let md = td.mk_path("meta.bin");
+    let _file = file_utils::create_sized_file(&md, 4096 * 4096);
+
+    let nr_thins = nr_thins.to_string();
+    let nr_snapshots = nr_snapshots.to_string();
+    let args = args![
+        "-o",
+        &md,
+        "--format",
+        "--nr-data-blocks",
+        "102400",
+        "--nr-thins",
+        &nr_thins,
+        "--nr-snapshots",
+        &nr_snapshots,
+        "--thin-size",
+        "16384",
+        "--shared",
+        "98",
+        "--seed",
+        "1"
+    ];
+    run_ok(thin_generate_metadata_cmd(args))?;
+
+    Ok(md)
+}
+
 //-----------------------------------------------
 
 pub enum TestData {
@@ -185,4 +215,275 @@ pub fn get_thins(md: &Path) -> Result<BTreeMap<u64, (u64, DeviceDetail)>> {
         .collect();
     Ok(thins)
 }
+
+// The mappings of every device, as (thin block, data block, time)
+pub type DeviceMappings = BTreeMap<u64, Vec<(u64, u64, u32)>>;
+
+pub fn get_mappings(md: &Path) -> Result<DeviceMappings> {
+    use thinp::thin::block_time::BlockTime;
+
+    let engine: Arc<dyn IoEngine + Send + Sync> = Arc::new(SyncIoEngine::new(md, false)?);
+    let mut mappings = BTreeMap::new();
+    for (dev_id, (root, _)) in get_thins(md)? {
+        let m = btree_to_map::<BlockTime>(&mut Vec::new(), engine.clone(), false, root)?;
+        mappings.insert(
+            dev_id,
+            m.into_iter()
+                .map(|(k, bt)| (k, bt.block, bt.time))
+                .collect(),
+        );
+    }
+    Ok(mappings)
+}
+
+// Modifies the details of every device, assuming the details tree is
+// just a leaf.
+pub fn update_device_details<F>(md: &Path, f: F) -> Result<()>
+where
+    F: Fn(&mut DeviceDetail),
+{
+    use thinp::thin::superblock::*;
+
+    let sb = read_superblock(&SyncIoEngine::new(md, false)?, SUPERBLOCK_LOCATION)?;
+    update_details_leaf(md, sb.details_root, |values| values.iter_mut().for_each(f))
+}
+
+// Modifies the values of a details leaf at the given block
+pub fn update_details_leaf<F>(md: &Path, loc: u64, f: F) -> Result<()>
+where
+    F: FnOnce(&mut [DeviceDetail]),
+{
+    use thinp::checksum;
+    use thinp::pdata::btree::*;
+
+    let engine = SyncIoEngine::new(md, true)?;
+    let b = engine.read(loc)?;
+    let mut node = unpack_node::<DeviceDetail>(&[0], b.get_data(), false, true)?;
+    if let Node::Leaf { values, .. } = &mut node {
+        f(values);
+    }
+    pack_node(&node, &mut std::io::Cursor::new(b.get_data()))?;
+    checksum::write_checksum(b.get_data(), checksum::BT::NODE)?;
+    engine.write(&b)?;
+    Ok(())
+}
+
+fn remove_leaf_entry<V: Pack + Unpack>(engine: &dyn IoEngine, loc: u64, key: u64) -> Result<()> {
+    use thinp::checksum;
+    use thinp::pdata::btree::*;
+
+    let b = engine.read(loc)?;
+    let mut node = unpack_node::<V>(&[0], b.get_data(), false, true)?;
+    if let Node::Leaf {
+        header,
+        keys,
+        values,
+    } = &mut node
+    {
+        if let Some(i) = keys.iter().position(|k| *k == key) {
+            keys.remove(i);
+            values.remove(i);
+            header.nr_entries -= 1;
+        }
+    }
+    pack_node(&node, &mut std::io::Cursor::new(b.get_data()))?;
+    checksum::write_checksum(b.get_data(), checksum::BT::NODE)?;
+    engine.write(&b)?;
+    Ok(())
+}
+
+// Removes a device from the top-level trees, leaving its mappings
+// orphaned.  Both trees are assumed to be just a leaf.
+pub fn detach_device(md: &Path, dev_id: u64) -> Result<()> {
+    use thinp::thin::superblock::*;
+
+    let engine = SyncIoEngine::new(md, true)?;
+    let sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    remove_leaf_entry::<u64>(&engine, sb.mapping_root, dev_id)?;
+    remove_leaf_entry::<DeviceDetail>(&engine, sb.details_root, dev_id)?;
+    Ok(())
+}
+// Removes a device from the details tree only, which is assumed to be just
+// a leaf.
+pub fn remove_device_details(md: &Path, dev_id: u64) -> Result<()> {
+    use thinp::thin::superblock::*;
+
+    let engine = SyncIoEngine::new(md, true)?;
+    let sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    remove_leaf_entry::<DeviceDetail>(&engine, sb.details_root, dev_id)
+}
 //-----------------------------------------------
+
+// Copies a node to another block, letting the caller modify the copy
+fn copy_node<V: Pack + Unpack, F>(engine: &dyn IoEngine, from: u64, to: u64, f: F) -> Result<()>
+where
+    F: FnOnce(&mut thinp::pdata::btree::Node<V>),
+{
+    use thinp::checksum;
+    use thinp::pdata::btree::*;
+
+    let b = engine.read(from)?;
+    let mut node = unpack_node::<V>(&[0], b.get_data(), false, true)?;
+    match &mut node {
+        Node::Internal { header, .. } | Node::Leaf { header, .. } => header.block = to,
+    }
+    f(&mut node);
+
+    let copy = Block::zeroed(to);
+    pack_node(&node, &mut std::io::Cursor::new(copy.get_data()))?;
+    checksum::write_checksum(copy.get_data(), checksum::BT::NODE)?;
+    engine.write(&copy)?;
+    Ok(())
+}
+
+// Returns the root of a device tree, and one of the leaves under it.  The
+// top-level mapping tree is assumed to be just a leaf, and the device tree
+// to be an internal node over leaves.
+fn device_leaf(
+    engine: &dyn IoEngine,
+    mapping_root: u64,
+    dev_id: u64,
+    index: usize,
+) -> Result<(u64, u64)> {
+    use thinp::pdata::btree::*;
+
+    let b = engine.read(mapping_root)?;
+    let dev_root = match unpack_node::<u64>(&[0], b.get_data(), false, true)? {
+        Node::Leaf { keys, values, .. } => {
+            let i = keys.iter().position(|k| *k == dev_id).unwrap();
+            values[i]
+        }
+        _ => return Err(anyhow::anyhow!("top-level mapping tree isn't a leaf")),
+    };
+
+    let b = engine.read(dev_root)?;
+    match unpack_node::<u64>(&[0], b.get_data(), false, true)? {
+        Node::Internal { values, .. } => Ok((dev_root, values[index])),
+        _ => Err(anyhow::anyhow!("device tree isn't an internal node")),
+    }
+}
+
+// Returns one of the leaves of a device.  The top-level mapping tree is
+// assumed to be just a leaf, and the device tree to be an internal node
+// over leaves.
+pub fn get_device_leaf(md: &Path, dev_id: u64, index: usize) -> Result<u64> {
+    use thinp::thin::superblock::*;
+
+    let engine = SyncIoEngine::new(md, false)?;
+    let sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    let (_, leaf) = device_leaf(&engine, sb.mapping_root, dev_id, index)?;
+    Ok(leaf)
+}
+
+// Moves as many mappings as fit from the first leaf of a device to the
+// second one, leaving the first leaf underfull with the mappings intact.
+// The device tree is assumed to be an internal node over private leaves.
+// Returns the first leaf.
+pub fn make_underfull_leaf(md: &Path, dev_id: u64) -> Result<u64> {
+    use thinp::pdata::btree::*;
+    use thinp::thin::block_time::BlockTime;
+    use thinp::thin::superblock::*;
+
+    let engine = SyncIoEngine::new(md, true)?;
+    let sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    let (dev_root, lhs) = device_leaf(&engine, sb.mapping_root, dev_id, 0)?;
+    let (_, rhs) = device_leaf(&engine, sb.mapping_root, dev_id, 1)?;
+
+    let b = engine.read(rhs)?;
+    let hdr = *unpack_node::<BlockTime>(&[0], b.get_data(), false, true)?.get_header();
+    let nr_free = (hdr.max_entries - hdr.nr_entries) as usize;
+
+    let mut moved = (Vec::new(), Vec::new());
+    copy_node::<BlockTime, _>(&engine, lhs, lhs, |n| {
+        if let Node::Leaf {
+            header,
+            keys,
+            values,
+        } = n
+        {
+            let at = keys.len() - nr_free;
+            moved = (keys.split_off(at), values.split_off(at));
+            header.nr_entries = keys.len() as u32;
+        }
+    })?;
+    let first_key = moved.0[0];
+    copy_node::<BlockTime, _>(&engine, rhs, rhs, |n| {
+        if let Node::Leaf {
+            header,
+            keys,
+            values,
+        } = n
+        {
+            keys.splice(0..0, moved.0);
+            values.splice(0..0, moved.1);
+            header.nr_entries = keys.len() as u32;
+        }
+    })?;
+    copy_node::<u64, _>(&engine, dev_root, dev_root, |n| {
+        if let Node::Internal { keys, .. } = n {
+            keys[1] = first_key;
+        }
+    })?;
+
+    Ok(lhs)
+}
+
+// Copies a leaf of a device to the last block of the metadata, as
+// copy-on-write leaves the old version of a node behind.  Returns the
+// copy.
+pub fn leave_stale_leaf(md: &Path, dev_id: u64, index: usize) -> Result<u64> {
+    use thinp::thin::block_time::BlockTime;
+    use thinp::thin::superblock::*;
+
+    let engine = SyncIoEngine::new(md, true)?;
+    let sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    let (_, leaf) = device_leaf(&engine, sb.mapping_root, dev_id, index)?;
+    let stale = engine.get_nr_blocks() - 1;
+    copy_node::<BlockTime, _>(&engine, leaf, stale, |_| {})?;
+    Ok(stale)
+}
+
+// Takes a metadata snapshot that shares everything with the live metadata
+// but the path to the given leaf of a device, so the live leaf can be
+// damaged with the snapshot left intact.  The copies go to the last blocks
+// of the metadata, which are assumed to be unused.  The top-level mapping
+// tree is assumed to be just a leaf, and the device tree to be an internal
+// node over leaves.  Returns the live leaf.
+pub fn take_metadata_snap_with_private_leaf(md: &Path, dev_id: u64, index: usize) -> Result<u64> {
+    use thinp::pdata::btree::*;
+    use thinp::thin::block_time::BlockTime;
+    use thinp::thin::superblock::*;
+
+    let engine = SyncIoEngine::new(md, true)?;
+    let mut sb = read_superblock(&engine, SUPERBLOCK_LOCATION)?;
+    let nr_blocks = engine.get_nr_blocks();
+    let (snap_loc, top, internal, leaf) =
+        (nr_blocks - 1, nr_blocks - 2, nr_blocks - 3, nr_blocks - 4);
+
+    let (dev_root, live_leaf) = device_leaf(&engine, sb.mapping_root, dev_id, index)?;
+
+    copy_node::<BlockTime, _>(&engine, live_leaf, leaf, |_| {})?;
+    copy_node::<u64, _>(&engine, dev_root, internal, |n| {
+        if let Node::Internal { values, .. } = n {
+            values[index] = leaf;
+        }
+    })?;
+    copy_node::<u64, _>(&engine, sb.mapping_root, top, |n| {
+        if let Node::Leaf { keys, values, .. } = n {
+            let i = keys.iter().position(|k| *k == dev_id).unwrap();
+            values[i] = internal;
+        }
+    })?;
+
+    let snap = Superblock {
+        block: snap_loc,
+        metadata_snap: 0,
+        mapping_root: top,
+        ..sb.clone()
+    };
+    write_superblock(&engine, snap_loc, &snap)?;
+    sb.metadata_snap = snap_loc;
+    write_superblock(&engine, SUPERBLOCK_LOCATION, &sb)?;
+
+    Ok(live_leaf)
+}
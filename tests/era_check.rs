@@ -3,6 +3,7 @@ use anyhow::Result;
 mod common;
 
 use common::common_args::*;
+use common::damage::*;
 use common::era::*;
 use common::fixture::*;
 use common::input_arg::*;
@@ -77,6 +78,16 @@ impl<'a> InputProgram<'a> for EraCheck {
 
 impl<'a> MetadataReader<'a> for EraCheck {}
 
+impl<'a> DamageTarget<'a> for EraCheck {
+    fn generate_damage_cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        era_generate_damage_cmd(args)
+    }
+}
+
 //------------------------------------------
 
 test_accepts_help!(EraCheck);
@@ -137,3 +148,39 @@ fn failing_quiet() -> Result<()> {
 }
 
 //------------------------------------------
+// test generated damage
+
+#[test]
+fn detects_unknown_superblock_version() -> Result<()> {
+    test_damage_detected::<EraCheck>(
+        &["--override", "--superblock-version", "2"],
+        "unknown superblock version",
+    )
+}
+
+#[test]
+fn detects_corrupted_writeset_checksum() -> Result<()> {
+    test_damage_detected::<EraCheck>(&["--damage-writeset", "checksum"], "checksum")
+}
+
+#[test]
+fn detects_zeroed_writeset() -> Result<()> {
+    test_damage_detected::<EraCheck>(&["--damage-writeset", "zero"], "checksum")
+}
+
+#[test]
+fn detects_corrupted_era_array_checksum() -> Result<()> {
+    test_damage_detected::<EraCheck>(&["--damage-era-array", "checksum"], "checksum")
+}
+
+#[test]
+fn detects_scrambled_era_array() -> Result<()> {
+    test_damage_detected::<EraCheck>(&["--damage-era-array", "scramble"], "invalid era value")
+}
+
+#[test]
+fn detects_eras_beyond_the_current_era() -> Result<()> {
+    test_damage_detected::<EraCheck>(&["--override", "--current-era", "0"], "invalid era value")
+}
+
+//------------------------------------------
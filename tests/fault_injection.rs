@@ -0,0 +1,208 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+mod common;
+
+use common::fixture::*;
+use common::process::*;
+use common::target::*;
+use common::test_dir::*;
+
+//------------------------------------------
+
+// The faults each checker must report, whichever blocks they hit
+const READ_FAULTS: &[&str] = &[
+    "read 0 eio",
+    "read 0 bitflip",
+    "read 0 checksum",
+    "read 1..1000000 eio",
+    "read 1..1000000 bitflip",
+    "read 1..1000000 checksum",
+];
+
+const NR_SEEDS: u64 = 8;
+
+fn mk_rules(td: &mut TestDir, rules: &str) -> Result<PathBuf> {
+    let path = td.mk_path("faults.txt");
+    std::fs::write(&path, rules)?;
+    Ok(path)
+}
+
+fn stderr_of(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+fn assert_no_panic(output: &std::process::Output) {
+    assert_ne!(output.status.code(), Some(101));
+    assert!(!stderr_of(output).contains("panicked"));
+}
+
+// The tools record the faults injected in the log given
+fn injected(log: &Path) -> bool {
+    std::fs::read_to_string(log).is_ok_and(|faults| !faults.is_empty())
+}
+
+//------------------------------------------
+
+fn check_reports_faults<F>(mk_md: F, check: fn(&Path, &[&str]) -> Command) -> Result<()>
+where
+    F: Fn(&mut TestDir) -> Result<PathBuf>,
+{
+    let mut td = TestDir::new()?;
+    let md = mk_md(&mut td)?;
+
+    for rule in READ_FAULTS {
+        let rules = mk_rules(&mut td, rule)?;
+        let log = td.mk_path("faults.log");
+        let output = run_fail_raw(check(
+            &md,
+            &[
+                "--fault-rules",
+                rules.to_str().unwrap(),
+                "--fault-log",
+                log.to_str().unwrap(),
+            ],
+        ))?;
+        assert_no_panic(&output);
+        assert!(injected(&log), "{}", rule);
+    }
+
+    for seed in 0..NR_SEEDS {
+        let seed = seed.to_string();
+        let log = td.mk_path("faults.log");
+        let output = run_unchecked_raw(check(
+            &md,
+            &[
+                "--fault-seed",
+                &seed,
+                "--fault-rate",
+                "0.05",
+                "--fault-log",
+                log.to_str().unwrap(),
+            ],
+        ))?;
+        assert_no_panic(&output);
+        assert_eq!(injected(&log), !output.status.success(), "seed {}", seed);
+    }
+
+    Ok(())
+}
+
+fn thin_check(md: &Path, faults: &[&str]) -> Command {
+    let mut args = vec![md.to_str().unwrap()];
+    args.extend_from_slice(faults);
+    thin_check_cmd(args)
+}
+
+fn cache_check(md: &Path, faults: &[&str]) -> Command {
+    let mut args = vec![md.to_str().unwrap()];
+    args.extend_from_slice(faults);
+    cache_check_cmd(args)
+}
+
+fn era_check(md: &Path, faults: &[&str]) -> Command {
+    let mut args = vec![md.to_str().unwrap()];
+    args.extend_from_slice(faults);
+    era_check_cmd(args)
+}
+
+#[test]
+fn thin_check_reports_faults() -> Result<()> {
+    check_reports_faults(common::thin::mk_valid_md, thin_check)
+}
+
+#[test]
+fn cache_check_reports_faults() -> Result<()> {
+    check_reports_faults(common::cache::mk_valid_md, cache_check)
+}
+
+#[test]
+fn era_check_reports_faults() -> Result<()> {
+    check_reports_faults(common::era::mk_valid_md, era_check)
+}
+
+//------------------------------------------
+
+fn thin_repair(td: &mut TestDir, md: &Path, faults: &[&str]) -> Result<(PathBuf, Command)> {
+    let out = mk_zeroed_md(td)?;
+    let mut args = vec![
+        "-i",
+        md.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "--data-block-size=128",
+        "--nr-data-blocks=20480",
+    ];
+    args.extend_from_slice(faults);
+    let cmd = thin_repair_cmd(args);
+    Ok((out, cmd))
+}
+
+#[test]
+fn thin_repair_reports_write_faults() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = common::thin::mk_valid_md(&mut td)?;
+
+    for rule in ["write * eio", "write * torn", "write 0 eio", "write 0 torn"] {
+        let rules = mk_rules(&mut td, rule)?;
+        let log = td.mk_path("faults.log");
+        let (_, cmd) = thin_repair(
+            &mut td,
+            &md,
+            &[
+                "--fault-rules",
+                rules.to_str().unwrap(),
+                "--fault-log",
+                log.to_str().unwrap(),
+            ],
+        )?;
+        let output = run_fail_raw(cmd)?;
+        assert_no_panic(&output);
+        assert!(injected(&log), "{}", rule);
+    }
+    Ok(())
+}
+
+// Repair may work around damage to its input, but whatever it writes
+// must then be valid.
+#[test]
+fn thin_repair_survives_faults() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = common::thin::mk_valid_md(&mut td)?;
+
+    let mut schedules: Vec<Vec<String>> = READ_FAULTS
+        .iter()
+        .map(|rule| -> Result<Vec<String>> {
+            let rules = mk_rules(&mut td, rule)?;
+            Ok(vec![
+                "--fault-rules".to_string(),
+                rules.to_str().unwrap().to_string(),
+            ])
+        })
+        .collect::<Result<_>>()?;
+    for seed in 0..NR_SEEDS {
+        schedules.push(vec![
+            "--fault-seed".to_string(),
+            seed.to_string(),
+            "--fault-rate".to_string(),
+            "0.05".to_string(),
+        ]);
+    }
+
+    for schedule in &schedules {
+        let log = td.mk_path("faults.log");
+        let mut faults: Vec<&str> = schedule.iter().map(|s| s.as_str()).collect();
+        faults.extend_from_slice(&["--fault-log", log.to_str().unwrap()]);
+        let (out, cmd) = thin_repair(&mut td, &md, &faults)?;
+        let output = run_unchecked_raw(cmd)?;
+        assert_no_panic(&output);
+        if output.status.success() {
+            run_ok(thin_check_cmd(args![&out]))?;
+        } else {
+            assert!(injected(&log), "{:?}", schedule);
+        }
+    }
+    Ok(())
+}
+
+//------------------------------------------
@@ -3,6 +3,7 @@ use anyhow::Result;
 mod common;
 
 use common::common_args::*;
+use common::damage::*;
 use common::fixture::*;
 use common::input_arg::*;
 use common::process::*;
@@ -23,6 +24,7 @@ Arguments:
 Options:
       --auto-repair                      Auto repair trivial issues.
       --clear-needs-check-flag           Clears the 'needs_check' flag in the superblock
+      --format <FORMAT>                  Choose the report format, text or json
   -h, --help                             Print help
       --ignore-non-fatal-errors          Only return a non-zero exit code if a fatal error is found.
   -m, --metadata-snap                    Check the metadata snapshot on a live pool
@@ -83,6 +85,20 @@ impl<'a> InputProgram<'a> for ThinCheck {
 
 impl MetadataReader<'_> for ThinCheck {}
 
+impl<'a> DamageTarget<'a> for ThinCheck {
+    fn generate_damage_cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        thin_generate_damage_cmd(args)
+    }
+
+    fn mk_damageable_input(td: &mut TestDir) -> Result<std::path::PathBuf> {
+        prep_metadata(td)
+    }
+}
+
 //------------------------------------------
 
 test_accepts_help!(ThinCheck);
@@ -496,6 +512,136 @@ fn fatal_errors_cant_be_ignored() -> Result<()> {
     })
 }
 
+//------------------------------------------
+// test json report
+
+fn json_issues(stdout: &[u8]) -> Result<Vec<serde_json::Value>> {
+    let report: serde_json::Value = serde_json::from_slice(stdout)?;
+    Ok(report["issues"].as_array().unwrap().clone())
+}
+
+#[test]
+fn json_report_is_empty_for_valid_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let output = run_ok_raw(thin_check_cmd(args!["--format", "json", &md]))?;
+    assert!(json_issues(&output.stdout)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn json_report_lists_leaks_as_non_fatal() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    generate_metadata_leaks(&md, 16, 0, 1)?;
+
+    let output = run_fail_raw(thin_check_cmd(args!["--format", "json", &md]))?;
+    let issues = json_issues(&output.stdout)?;
+    assert!(!issues.is_empty());
+    for i in issues {
+        assert_eq!(i["kind"], "leak");
+        assert_eq!(i["fatal"], false);
+        assert!(i["block"].is_u64());
+    }
+    Ok(())
+}
+
+#[test]
+fn json_report_lists_ref_count_mismatches() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    generate_metadata_leaks(&md, 16, 1, 0)?;
+
+    let output = run_fail_raw(thin_check_cmd(args!["--format", "json", &md]))?;
+    let issues = json_issues(&output.stdout)?;
+    assert!(issues
+        .iter()
+        .any(|i| i["kind"] == "ref_count" && i["fatal"] == true && i["block"].is_u64()));
+    Ok(())
+}
+
+#[test]
+fn json_report_lists_damaged_mapping_trees() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let (dev_id, (root, _)) = get_thins(&md)?.into_iter().next().unwrap();
+
+    let file = std::fs::OpenOptions::new().write(true).open(&md)?;
+    file.write_all_at(&[0; 8], root * 4096)?;
+    drop(file);
+
+    let output = run_fail_raw(thin_check_cmd(args!["--format", "json", &md]))?;
+    let issues = json_issues(&output.stdout)?;
+    let damaged = issues
+        .iter()
+        .find(|i| i["block"] == root && i["kind"] == "checksum")
+        .unwrap();
+    assert_eq!(damaged["dev_id"], dev_id);
+    assert_eq!(damaged["path"], serde_json::json!([0, root]));
+    assert_eq!(damaged["fatal"], true);
+    assert!(issues
+        .iter()
+        .any(|i| i["kind"] == "mapped_count" && i["dev_id"] == dev_id));
+    Ok(())
+}
+
+#[test]
+fn json_report_lists_damaged_shared_leaves_per_device() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+
+    // the fifth leaf of thin#1 is shared with thin#15 and thin#16
+    let leaf = get_device_leaf(&md, 1, 4)?;
+    let file = std::fs::OpenOptions::new().write(true).open(&md)?;
+    file.write_all_at(&[0; 8], leaf * 4096)?;
+    drop(file);
+
+    let output = run_fail_raw(thin_check_cmd(args!["--format", "json", &md]))?;
+    let issues = json_issues(&output.stdout)?;
+    for dev_id in [1, 15, 16] {
+        assert!(issues
+            .iter()
+            .any(|i| i["block"] == leaf && i["kind"] == "checksum" && i["dev_id"] == dev_id));
+    }
+    Ok(())
+}
+
+fn mk_underfull_md(td: &mut TestDir) -> Result<(std::path::PathBuf, u64)> {
+    let xml = td.mk_path("meta.xml");
+    let md = mk_zeroed_md(td)?;
+    write_file(
+        &xml,
+        b"<superblock uuid=\"\" time=\"0\" transaction=\"1\" data_block_size=\"128\" nr_data_blocks=\"1024\">
+  <device dev_id=\"1\" mapped_blocks=\"300\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"0\" length=\"300\" time=\"0\"/>
+  </device>
+</superblock>",
+    )?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    let leaf = make_underfull_leaf(&md, 1)?;
+    Ok((md, leaf))
+}
+
+#[test]
+fn underfull_leaves_are_non_fatal() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let (md, leaf) = mk_underfull_md(&mut td)?;
+
+    let output = run_fail_raw(thin_check_cmd(args!["--format", "json", &md]))?;
+    let issues = json_issues(&output.stdout)?;
+    assert!(issues
+        .iter()
+        .any(|i| i["block"] == leaf && i["dev_id"] == 1 && i["fatal"] == false));
+
+    run_fail(thin_check_cmd(args![&md]))?;
+    run_ok(thin_check_cmd(args!["--ignore-non-fatal-errors", &md]))?;
+    Ok(())
+}
+
 //------------------------------------------
 // test auto-repair
 
@@ -585,6 +731,92 @@ fn auto_repair_clears_needs_check() -> Result<()> {
     test_option_clears_needs_check("--auto-repair")
 }
 
+#[test]
+fn auto_repair_fixes_mapped_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let thins = get_thins(&md)?;
+
+    update_device_details(&md, |d| d.mapped_blocks += 1)?;
+    run_fail(thin_check_cmd(args![&md]))?;
+    run_ok(thin_check_cmd(args!["--auto-repair", &md]))?;
+    run_ok(thin_check_cmd(args![&md]))?; // ensure metadata is repaired
+
+    for (id, (_, details)) in get_thins(&md)? {
+        assert_eq!(details.mapped_blocks, thins[&id].1.mapped_blocks);
+    }
+    Ok(())
+}
+
+#[test]
+fn auto_repair_fixes_stale_transaction_id_and_snap_time() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let sb = get_superblock(&md)?;
+
+    update_device_details(&md, |d| {
+        d.transaction_id = sb.transaction_id + 1;
+        d.snapshotted_time = sb.time + 1;
+    })?;
+
+    // stale values are warned about, but don't fail the check
+    let output = run_ok_raw(thin_check_cmd(args![&md]))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("has stale transaction id"));
+    assert!(stderr.contains("run with --auto-repair"));
+
+    run_ok(thin_check_cmd(args!["--auto-repair", &md]))?;
+
+    // ensure metadata is repaired
+    let output = run_ok_raw(thin_check_cmd(args![&md]))?;
+    assert!(!std::str::from_utf8(&output.stderr)?.contains("stale"));
+
+    for (_, (_, details)) in get_thins(&md)? {
+        assert_eq!(details.transaction_id, sb.transaction_id);
+        assert_eq!(details.snapshotted_time, sb.time);
+    }
+    Ok(())
+}
+
+#[test]
+fn auto_repair_leaves_details_shared_with_metadata_snap() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata_with_metadata_snap(&mut td)?;
+    let sb = get_superblock(&md)?;
+
+    update_device_details(&md, |d| d.snapshotted_time = sb.time + 1)?;
+    set_needs_check(&md)?;
+
+    // the snapshot keeps the stale details, but the rest is repaired
+    let output = run_ok_raw(thin_check_cmd(args!["--auto-repair", &md]))?;
+    assert!(std::str::from_utf8(&output.stderr)?.contains("shared with the metadata snapshot"));
+    assert!(!get_needs_check(&md)?);
+
+    for (_, (_, details)) in get_thins(&md)? {
+        assert_eq!(details.snapshotted_time, sb.time + 1);
+    }
+    Ok(())
+}
+
+#[test]
+fn auto_repair_fails_on_miscounted_details_shared_with_metadata_snap() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata_with_metadata_snap(&mut td)?;
+    let thins = get_thins(&md)?;
+
+    update_device_details(&md, |d| d.mapped_blocks += 1)?;
+    set_needs_check(&md)?;
+
+    // nothing is written, as the wrong counts can't be repaired
+    run_fail(thin_check_cmd(args!["--auto-repair", &md]))?;
+    assert!(get_needs_check(&md)?);
+
+    for (id, (_, details)) in get_thins(&md)? {
+        assert_eq!(details.mapped_blocks, thins[&id].1.mapped_blocks + 1);
+    }
+    Ok(())
+}
+
 //------------------------------------------
 // test metadata snapshot
 
@@ -660,3 +892,110 @@ fn offline_check_metadata_with_deleted_snapshot_should_success() -> Result<()> {
 }
 
 //------------------------------------------
+// test generated metadata
+
+#[test]
+fn accepts_generated_metadata_with_snapshots() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_generated_md(&mut td, 2, 3)?;
+    run_ok(thin_check_cmd(args![&md]))?;
+
+    // the snapshots should share subtrees
+    let stdout = run_ok(thin_dump_cmd(args![&md]))?;
+    assert!(stdout.contains("<ref"));
+    assert_eq!(get_thins(&md)?.len(), 8);
+    Ok(())
+}
+
+//------------------------------------------
+// test packed metadata
+
+#[test]
+fn accepts_packed_metadata() -> Result<()> {
+    let md = path_to(TestData::PackedMetadata)?;
+    run_ok(thin_check_cmd(args![&md]))?;
+    Ok(())
+}
+
+#[test]
+fn refuses_to_write_packed_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = td.mk_path("meta.pack");
+    std::fs::copy(path_to(TestData::PackedMetadata)?, &md)?;
+    let before = std::fs::read(&md)?;
+
+    let stderr = run_fail(thin_check_cmd(args!["--clear-needs-check-flag", &md]))?;
+    assert!(stderr.contains("Can't write to a pack file"));
+    assert_eq!(std::fs::read(&md)?, before);
+    Ok(())
+}
+
+//------------------------------------------
+// test generated damage
+
+#[test]
+fn detects_corrupted_node_checksum() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let root = get_superblock(&md)?.mapping_root.to_string();
+    generate_damage::<ThinCheck>(&md, &["--corrupt-checksum", "--block", &root])?;
+    let stderr = run_fail(thin_check_cmd(args![&md]))?;
+    assert!(stderr.contains("checksum error"));
+    Ok(())
+}
+
+#[test]
+fn detects_zeroed_internal_node() -> Result<()> {
+    test_damage_detected::<ThinCheck>(&["--zero-node", "--dev-id", "1"], "Thin device 1")
+}
+
+#[test]
+fn detects_scrambled_internal_node() -> Result<()> {
+    test_damage_detected::<ThinCheck>(&["--scramble-node", "--dev-id", "2"], "Thin device 2")
+}
+
+#[test]
+fn detects_broken_details_tree() -> Result<()> {
+    test_damage_detected::<ThinCheck>(&["--break-details-tree"], "device details tree")
+}
+
+#[test]
+fn detects_cycle_in_mapping_tree() -> Result<()> {
+    test_damage_detected::<ThinCheck>(&["--create-cycle", "--dev-id", "3"], "Thin device 3")
+}
+
+#[test]
+fn detects_cross_linked_devices() -> Result<()> {
+    test_damage_detected::<ThinCheck>(
+        &["--cross-link", "--dev-id", "1", "--other-dev-id", "2"],
+        "Thin device 1",
+    )
+}
+
+#[test]
+fn detects_dropped_data_bitmap() -> Result<()> {
+    test_damage_detected::<ThinCheck>(&["--drop-bitmap", "--space-map", "data"], "data space map")
+}
+
+#[test]
+fn detects_dropped_metadata_bitmap() -> Result<()> {
+    test_damage_detected::<ThinCheck>(
+        &["--drop-bitmap", "--space-map", "metadata"],
+        "metadata space map",
+    )
+}
+
+#[test]
+fn generated_damage_is_reproducible() -> Result<()> {
+    let mut damaged = Vec::new();
+    for _ in 0..2 {
+        let mut td = TestDir::new()?;
+        let md = prep_metadata(&mut td)?;
+        generate_damage::<ThinCheck>(&md, &["--scramble-node", "--dev-id", "2", "--seed", "7"])?;
+        damaged.push(std::fs::read(&md)?);
+    }
+    assert!(damaged[0] == damaged[1]);
+    Ok(())
+}
+
+//------------------------------------------
@@ -3,6 +3,7 @@ use anyhow::Result;
 mod common;
 
 use common::common_args::*;
+use common::fixture::*;
 use common::process::*;
 use common::program::*;
 use common::target::*;
@@ -19,14 +20,17 @@ Arguments:
   <INPUT>  Specify the input device
 
 Options:
-  -h, --help             Print help
-  -m, --metadata-snap    Use metadata snapshot
-      --root1 <BLOCKNR>  The root block for the first thin volume to diff
-      --root2 <BLOCKNR>  The root block for the second thin volume to diff
-      --thin1 <DEV_ID>   The numeric identifier for the first thin volume to diff [aliases: snap1]
-      --thin2 <DEV_ID>   The numeric identifier for the second thin volume to diff [aliases: snap2]
-  -V, --version          Print version
-      --verbose          Provide extra information on the mappings";
+      --chain <DEV_IDS>            Diff each consecutive pair in a comma separated list of thin volumes
+  -f, --format <TYPE>              Choose the output format
+  -h, --help                       Print help
+  -m, --metadata-snap              Use metadata snapshot
+      --root1 <BLOCKNR>            The root block for the first thin volume to diff
+      --root2 <BLOCKNR>            The root block for the second thin volume to diff
+      --thin-size <SIZE[bskmgtp]>  Specify the size of the thin volumes, for the qemu_bitmap format
+      --thin1 <DEV_ID>             The numeric identifier for the first thin volume to diff [aliases: snap1]
+      --thin2 <DEV_ID>             The numeric identifier for the second thin volume to diff [aliases: snap2]
+  -V, --version                    Print version
+      --verbose                    Provide extra information on the mappings";
 
 //------------------------------------------
 
@@ -129,4 +133,197 @@ fn test_same_root() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn chain_needs_two_devices() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let stderr = run_fail(thin_delta_cmd(args!["--chain", "0", &md]))?;
+    assert!(stderr.contains("--chain requires at least two thin devices"));
+    Ok(())
+}
+
+#[test]
+fn chain_conflicts_with_thin_args() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_fail(thin_delta_cmd(args!["--chain", "0,1", "--thin1", "0", &md]))?;
+    Ok(())
+}
+
+#[test]
+fn chain_emits_consecutive_diffs() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_generated_md(&mut td, 1, 3)?;
+
+    let chain = run_ok(thin_delta_cmd(args!["--chain", "0,1,2,3", &md]))?;
+
+    // the chain output is the concatenation of the pairwise diffs
+    let mut expected = Vec::new();
+    for (left, right) in [("0", "1"), ("1", "2"), ("2", "3")] {
+        let stdout = run_ok(thin_delta_cmd(args![
+            "--thin1", left, "--thin2", right, &md
+        ]))?;
+        let lines: Vec<&str> = stdout.lines().collect();
+        expected.extend(lines[1..lines.len() - 1].iter().map(|l| l.to_string()));
+    }
+
+    let lines: Vec<&str> = chain.lines().collect();
+    assert!(lines[0].starts_with("<superblock"));
+    assert_eq!(lines[lines.len() - 1], "</superblock>");
+    assert_eq!(lines[1..lines.len() - 1], expected);
+    assert_eq!(
+        expected
+            .iter()
+            .filter(|l| l.starts_with("  <diff "))
+            .count(),
+        3
+    );
+    Ok(())
+}
+
+// thin#1 -> thin#2: same 0..50, different 50..100, right_only 100..150,
+// left_only 200..300
+const TWO_THINS: &[u8] = b"<superblock uuid=\"\" time=\"1\" transaction=\"2\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"1024\">
+  <device dev_id=\"1\" mapped_blocks=\"200\" transaction=\"0\" creation_time=\"0\" snap_time=\"1\">
+    <range_mapping origin_begin=\"0\" data_begin=\"0\" length=\"50\" time=\"0\"/>
+    <range_mapping origin_begin=\"50\" data_begin=\"400\" length=\"50\" time=\"1\"/>
+    <range_mapping origin_begin=\"200\" data_begin=\"500\" length=\"100\" time=\"1\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"150\" transaction=\"0\" creation_time=\"1\" snap_time=\"1\">
+    <range_mapping origin_begin=\"0\" data_begin=\"0\" length=\"50\" time=\"0\"/>
+    <range_mapping origin_begin=\"50\" data_begin=\"300\" length=\"50\" time=\"1\"/>
+    <range_mapping origin_begin=\"100\" data_begin=\"600\" length=\"50\" time=\"1\"/>
+  </device>
+</superblock>";
+
+fn mk_two_thins(td: &mut TestDir) -> Result<std::path::PathBuf> {
+    let xml = td.mk_path("meta.xml");
+    let md = mk_zeroed_md(td)?;
+    write_file(&xml, TWO_THINS)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    Ok(md)
+}
+
+fn to_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes(b.try_into().unwrap())
+}
+
+fn to_u64(b: &[u8]) -> u64 {
+    u64::from_le_bytes(b.try_into().unwrap())
+}
+
+#[test]
+fn binary_format() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_two_thins(&mut td)?;
+    let output = run_ok_raw(thin_delta_cmd(args![
+        "--thin1", "1", "--thin2", "2", "-f", "binary", &md
+    ]))?;
+    let out = output.stdout;
+
+    assert_eq!(out.len(), 16 + 24 * 4);
+    assert_eq!(&out[0..8], b"THINDLTA");
+    assert_eq!(to_u32(&out[8..12]), 1);
+    assert_eq!(to_u32(&out[12..16]), 128);
+
+    let records: Vec<(u32, u32, u64, u64)> = out[16..]
+        .chunks(24)
+        .map(|r| {
+            (
+                to_u32(&r[0..4]),
+                to_u32(&r[4..8]),
+                to_u64(&r[8..16]),
+                to_u64(&r[16..24]),
+            )
+        })
+        .collect();
+    assert_eq!(
+        records,
+        vec![
+            (1, 0, 1, 2),
+            (2, 0, 50 * 128, 100 * 128),
+            (3, 0, 200 * 128, 100 * 128),
+            (4, 0, 0, 2),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn qemu_bitmap_format() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_two_thins(&mut td)?;
+    let output = run_ok_raw(thin_delta_cmd(args![
+        "--thin1",
+        "1",
+        "--thin2",
+        "2",
+        "-f",
+        "qemu_bitmap",
+        "--thin-size",
+        "51200",
+        &md
+    ]))?;
+
+    // the whole 400 blocks of the devices, rounded up to 64-bit words
+    let out = output.stdout;
+    assert_eq!(out.len(), 56);
+    for b in 0..448 {
+        let dirty = out[b / 8] & (1 << (b % 8)) != 0;
+        assert_eq!(dirty, (50..150).contains(&b) || (200..300).contains(&b));
+    }
+    Ok(())
+}
+
+#[test]
+fn qemu_bitmap_requires_thin_size() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_two_thins(&mut td)?;
+    let stderr = run_fail(thin_delta_cmd(args![
+        "--thin1",
+        "1",
+        "--thin2",
+        "2",
+        "-f",
+        "qemu_bitmap",
+        &md
+    ]))?;
+    assert!(stderr.contains("requires --thin-size"));
+    Ok(())
+}
+
+#[test]
+fn qemu_bitmap_rejects_mappings_beyond_thin_size() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_two_thins(&mut td)?;
+    let stderr = run_fail(thin_delta_cmd(args![
+        "--thin1",
+        "1",
+        "--thin2",
+        "2",
+        "-f",
+        "qemu_bitmap",
+        "--thin-size",
+        "250",
+        &md
+    ]))?;
+    assert!(stderr.contains("beyond the end of the thin device"));
+    Ok(())
+}
+
+#[test]
+fn qemu_bitmap_rejects_chain() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_generated_md(&mut td, 1, 2)?;
+    let stderr = run_fail(thin_delta_cmd(args![
+        "--chain",
+        "0,1,2",
+        "-f",
+        "qemu_bitmap",
+        &md
+    ]))?;
+    assert!(stderr.contains("supports only a single diff"));
+    Ok(())
+}
+
 //------------------------------------------
@@ -117,6 +117,40 @@ fn dump_restore_cycle() -> Result<()> {
     Ok(())
 }
 
+fn dump_restore_cycle_with_format(format: &str) -> Result<()> {
+    let mut td = TestDir::new()?;
+
+    let md = prep_rebuilt_metadata(&mut td)?;
+    let xml_before = run_ok_raw(thin_dump_cmd(args![&md]))?;
+    let output = run_ok_raw(thin_dump_cmd(args![&md, "--format", format]))?;
+
+    let dumped = td.mk_path("meta.json");
+    write_file(&dumped, &output.stdout)?;
+
+    let md2 = mk_zeroed_md(&mut td)?;
+    run_ok(thin_restore_cmd(args![
+        "--format", format, "-i", &dumped, "-o", &md2
+    ]))?;
+
+    let output2 = run_ok_raw(thin_dump_cmd(args![&md2, "--format", format]))?;
+    assert_eq!(output.stdout, output2.stdout);
+
+    let xml_after = run_ok_raw(thin_dump_cmd(args![&md2]))?;
+    assert_eq!(xml_before.stdout, xml_after.stdout);
+
+    Ok(())
+}
+
+#[test]
+fn dump_restore_cycle_json() -> Result<()> {
+    dump_restore_cycle_with_format("json")
+}
+
+#[test]
+fn dump_restore_cycle_json_lines() -> Result<()> {
+    dump_restore_cycle_with_format("json_lines")
+}
+
 //------------------------------------------
 // test no stderr with a normal dump
 
@@ -147,6 +181,14 @@ fn no_stderr_on_broken_pipe_humanreadable() -> Result<()> {
     )
 }
 
+#[test]
+fn no_stderr_on_broken_pipe_json() -> Result<()> {
+    common::piping::test_no_stderr_on_broken_pipe::<ThinDump>(
+        prep_metadata,
+        &args!["--format", "json"],
+    )
+}
+
 #[test]
 fn no_stderr_on_broken_fifo_xml() -> Result<()> {
     common::piping::test_no_stderr_on_broken_fifo::<ThinDump>(prep_metadata, &[])
@@ -160,6 +202,14 @@ fn no_stderr_on_broken_fifo_humanreadable() -> Result<()> {
     )
 }
 
+#[test]
+fn no_stderr_on_broken_fifo_json() -> Result<()> {
+    common::piping::test_no_stderr_on_broken_fifo::<ThinDump>(
+        prep_metadata,
+        &args!["--format", "json"],
+    )
+}
+
 //------------------------------------------
 // test dump metadata snapshot from a live metadata
 // here we use a corrupted metadata to ensure that "thin_dump -m" reads the
@@ -175,6 +225,29 @@ fn dump_metadata_snapshot() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn repair_grafts_from_metadata_snapshot() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let before = get_mappings(&md)?;
+
+    let leaf = take_metadata_snap_with_private_leaf(&md, 1, 0)?;
+    let file = std::fs::OpenOptions::new().write(true).open(&md)?;
+    file.write_all_at(&[0; 8], leaf * 4096)?;
+    drop(file);
+
+    run_fail(thin_dump_cmd(args![&md]))?;
+    let xml = td.mk_path("repaired.xml");
+    run_ok(thin_dump_cmd(args!["--repair", &md, "-o", &xml]))?;
+
+    let restored = mk_zeroed_md(&mut td)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &restored]))?;
+    assert_eq!(get_mappings(&restored)?, before);
+    Ok(())
+}
+
 //------------------------------------------
 // test superblock overriding & repair
 // TODO: share with thin_repair
@@ -432,4 +505,54 @@ fn repair_device_details_tree() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn dump_pairs_devices_by_id() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let thins = get_thins(&md)?;
+    remove_device_details(&md, 1)?;
+
+    let stdout = run_ok(thin_dump_cmd(args![&md]))?;
+    let mut dev_ids = Vec::new();
+    for line in stdout
+        .lines()
+        .filter(|l| l.trim_start().starts_with("<device "))
+    {
+        let attr = |name: &str| -> u64 {
+            let v = line.split(&format!(" {}=\"", name)).nth(1).unwrap();
+            v[..v.find('"').unwrap()].parse().unwrap()
+        };
+        let dev_id = attr("dev_id");
+        assert_eq!(attr("mapped_blocks"), thins[&dev_id].1.mapped_blocks);
+        dev_ids.push(dev_id);
+    }
+    let expected: Vec<u64> = thins.keys().cloned().filter(|id| *id != 1).collect();
+    assert_eq!(dev_ids, expected);
+    Ok(())
+}
+
+//------------------------------------------
+
+#[test]
+fn dump_packed_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let expected = run_ok(thin_dump_cmd(args![&md]))?;
+
+    let packed = path_to(TestData::PackedMetadata)?;
+    assert_eq!(run_ok(thin_dump_cmd(args![&packed]))?, expected);
+
+    let zstd_packed = td.mk_path("meta.zstd.pack");
+    run_ok(thin_metadata_pack_cmd(args![
+        "-i",
+        &md,
+        "-o",
+        &zstd_packed,
+        "-z",
+        "zstd"
+    ]))?;
+    assert_eq!(run_ok(thin_dump_cmd(args![&zstd_packed]))?, expected);
+    Ok(())
+}
+
 //------------------------------------------
@@ -0,0 +1,132 @@
+use anyhow::Result;
+use std::path::Path;
+use std::sync::Arc;
+
+use thinp::cache;
+use thinp::io_engine::*;
+use thinp::thin::superblock::*;
+
+mod common;
+
+use common::cache::mk_valid_md as mk_valid_cache_md;
+use common::process::*;
+use common::target::*;
+use common::test_dir::*;
+use common::thin::*;
+
+//------------------------------------------
+
+// Runs the writes through a journalling engine, leaving the metadata as
+// it was before in a copy named base.bin.
+fn journal_writes<F>(td: &mut TestDir, md: &Path, f: F) -> Result<(String, String)>
+where
+    F: FnOnce(&dyn IoEngine) -> Result<()>,
+{
+    let base = td.mk_path("base.bin");
+    let journal = td.mk_path("journal.bin");
+    std::fs::copy(md, &base)?;
+
+    let inner = Arc::new(SyncIoEngine::new(md, true)?);
+    let engine = JournalIoEngine::new(inner, &journal)?;
+    f(&engine)?;
+    drop(engine);
+
+    Ok((
+        base.to_str().unwrap().to_string(),
+        journal.to_str().unwrap().to_string(),
+    ))
+}
+
+//------------------------------------------
+
+#[test]
+fn accepts_consistent_transactions() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+
+    let (base, journal) = journal_writes(&mut td, &md, |engine| {
+        let mut sb = read_superblock(engine, SUPERBLOCK_LOCATION)?;
+        for _ in 0..2 {
+            sb.transaction_id += 1;
+            write_superblock(engine, SUPERBLOCK_LOCATION, &sb)?;
+        }
+        Ok(())
+    })?;
+
+    let stdout = run_ok(thin_journal_check_cmd(args!["-j", &journal, &base]))?;
+    assert!(stdout.contains("transaction 2 is consistent"));
+    assert!(stdout.contains("2 transactions checked"));
+    Ok(())
+}
+
+#[test]
+fn detects_writes_damaging_the_previous_transaction() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+
+    let (base, journal) = journal_writes(&mut td, &md, |engine| {
+        // Overwrites a live block rather than shadowing it
+        let sb = read_superblock(engine, SUPERBLOCK_LOCATION)?;
+        engine.write(&Block::zeroed(sb.mapping_root))?;
+        write_superblock(engine, SUPERBLOCK_LOCATION, &sb)?;
+        Ok(())
+    })?;
+
+    let stderr = run_fail(thin_journal_check_cmd(args!["-j", &journal, &base]))?;
+    assert!(stderr.contains("transaction 1 is not crash-consistent"));
+    assert!(stderr.contains("previous transaction"));
+    Ok(())
+}
+
+#[test]
+fn detects_damaged_commit() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+
+    let (base, journal) = journal_writes(&mut td, &md, |engine| {
+        let mut sb = read_superblock(engine, SUPERBLOCK_LOCATION)?;
+        sb.transaction_id += 1;
+        write_superblock(engine, SUPERBLOCK_LOCATION, &sb)?;
+        sb.transaction_id += 1;
+        sb.details_root = sb.mapping_root;
+        write_superblock(engine, SUPERBLOCK_LOCATION, &sb)?;
+        Ok(())
+    })?;
+
+    let stderr = run_fail(thin_journal_check_cmd(args!["-j", &journal, &base]))?;
+    assert!(stderr.contains("transaction 2 is not crash-consistent"));
+    assert!(stderr.contains("committed metadata is damaged"));
+    Ok(())
+}
+
+#[test]
+fn rejects_journal_of_other_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let (_, journal) = journal_writes(&mut td, &md, |_| Ok(()))?;
+    let other = mk_valid_md(&mut td)?;
+
+    let stderr = run_fail(thin_journal_check_cmd(args!["-j", &journal, &other]))?;
+    assert!(stderr.contains("journal is for metadata of"));
+    Ok(())
+}
+
+#[test]
+fn checks_cache_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_cache_md(&mut td)?;
+
+    let (base, journal) = journal_writes(&mut td, &md, |engine| {
+        let sb =
+            cache::superblock::read_superblock(engine, cache::superblock::SUPERBLOCK_LOCATION)?;
+        cache::superblock::write_superblock(engine, cache::superblock::SUPERBLOCK_LOCATION, &sb)?;
+        Ok(())
+    })?;
+
+    run_ok(thin_journal_check_cmd(args![
+        "--cache", "-j", &journal, &base
+    ]))?;
+    Ok(())
+}
+
+//------------------------------------------
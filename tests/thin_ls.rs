@@ -20,11 +20,15 @@ Arguments:
   <INPUT>  Specify the input device
 
 Options:
-  -h, --help             Print help
-  -m, --metadata-snap    Use metadata snapshot
-      --no-headers       Don't output headers
-  -o, --format <FIELDS>  Give a comma separated list of fields to be output
-  -V, --version          Print version";
+      --dev-id <DEV_IDS>    Only list the devices in a comma separated list of device ids
+      --filter <EXPR>       Filter the devices by an expression, e.g., EXCLUSIVE_BYTES>10G
+  -h, --help                Print help
+  -m, --metadata-snap       Use metadata snapshot
+      --no-headers          Don't output headers
+  -o, --format <FIELDS>     Give a comma separated list of fields to be output
+      --output-mode <MODE>  Choose the output mode
+      --sort <FIELDS>       Sort by a comma separated list of fields, descending if prefixed by '-'
+  -V, --version             Print version";
 
 //-----------------------------------------
 
@@ -100,4 +104,143 @@ fn read_metadata_snapshot() -> Result<()> {
     Ok(())
 }
 
+// sorting, filtering and output modes
+
+fn parse_csv(output: &str) -> Vec<Vec<u64>> {
+    output
+        .lines()
+        .map(|l| l.split(',').map(|v| v.parse::<u64>().unwrap()).collect())
+        .collect()
+}
+
+#[test]
+fn sort_by_descending_field() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_generated_md(&mut td, 2, 2)?;
+    let thins = get_thins(&md)?;
+
+    let stdout = run_ok(thin_ls_cmd(args![
+        &md,
+        "-o",
+        "DEV,MAPPED_BLOCKS",
+        "--sort=-MAPPED_BLOCKS,DEV",
+        "--output-mode",
+        "csv",
+        "--no-headers"
+    ]))?;
+
+    let mut expected: Vec<Vec<u64>> = thins
+        .iter()
+        .map(|(id, (_, d))| vec![*id, d.mapped_blocks])
+        .collect();
+    expected.sort_by(|a, b| b[1].cmp(&a[1]).then(a[0].cmp(&b[0])));
+    assert_eq!(parse_csv(&stdout), expected);
+    Ok(())
+}
+
+#[test]
+fn filter_by_dev_ids_and_expression() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_generated_md(&mut td, 2, 2)?;
+    let thins = get_thins(&md)?;
+    let threshold = thins[&1].1.mapped_blocks;
+    let filter = format!("MAPPED_BLOCKS>={}", threshold);
+
+    let stdout = run_ok(thin_ls_cmd(args![
+        &md,
+        "-o",
+        "DEV",
+        "--dev-id",
+        "0,1,2,3",
+        "--filter",
+        &filter,
+        "--output-mode",
+        "csv",
+        "--no-headers"
+    ]))?;
+
+    let expected: Vec<Vec<u64>> = thins
+        .iter()
+        .filter(|(id, (_, d))| **id <= 3 && d.mapped_blocks >= threshold)
+        .map(|(id, _)| vec![*id])
+        .collect();
+    assert!(expected.contains(&vec![1]));
+    assert_eq!(parse_csv(&stdout), expected);
+    Ok(())
+}
+
+#[test]
+fn filter_with_size_units() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+
+    // the only device maps 1024 blocks of 64KiB
+    let stdout = run_ok(thin_ls_cmd(args![
+        &md,
+        "-o",
+        "DEV",
+        "--filter",
+        "MAPPED_BYTES=64MiB",
+        "--no-headers"
+    ]))?;
+    assert_eq!(stdout.trim(), "0");
+
+    let stdout = run_ok(thin_ls_cmd(args![
+        &md,
+        "-o",
+        "DEV",
+        "--filter",
+        "MAPPED_BYTES>64MiB",
+        "--no-headers"
+    ]))?;
+    assert!(stdout.is_empty());
+    Ok(())
+}
+
+#[test]
+fn rejects_bad_filter() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    run_fail(thin_ls_cmd(args![&md, "--filter", "MAPPED_BYTES"]))?;
+    run_fail(thin_ls_cmd(args![&md, "--filter", "UNKNOWN>1"]))?;
+    Ok(())
+}
+
+#[test]
+fn json_output() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+
+    let stdout = run_ok(thin_ls_cmd(args![
+        &md,
+        "-o",
+        "DEV,MAPPED,CREATE_TIME",
+        "--output-mode",
+        "json"
+    ]))?;
+
+    let devices: serde_json::Value = serde_json::from_str(&stdout)?;
+    assert_eq!(
+        devices,
+        serde_json::json!([{"DEV": 0, "MAPPED": 1024 * 65536, "CREATE_TIME": 0}])
+    );
+    Ok(())
+}
+
+#[test]
+fn csv_output_with_headers() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+
+    let stdout = run_ok(thin_ls_cmd(args![
+        &md,
+        "-o",
+        "DEV,MAPPED_BLOCKS",
+        "--output-mode",
+        "csv"
+    ]))?;
+    assert_eq!(stdout, "DEV,MAPPED_BLOCKS\n0,1024");
+    Ok(())
+}
+
 //------------------------------------------
@@ -0,0 +1,231 @@
+use anyhow::Result;
+
+mod common;
+
+use common::common_args::*;
+use common::fixture::*;
+use common::input_arg::*;
+use common::output_option::*;
+use common::process::*;
+use common::program::*;
+use common::target::*;
+use common::test_dir::*;
+use common::thin::*;
+
+//------------------------------------------
+
+const USAGE: &str =
+    "Merge a thin snapshot into its origin, and write the metadata to different device or file
+
+Usage: thin_merge [OPTIONS] --input <FILE> --origin <DEV_ID> --output <FILE> --snapshot <DEV_ID>
+
+Options:
+  -h, --help               Print help
+  -i, --input <FILE>       Specify the input device
+  -o, --output <FILE>      Specify the output device
+      --origin <DEV_ID>    Specify the device id of the origin
+      --overlay            Keep the origin's mappings where the snapshot has none
+  -q, --quiet              Suppress output messages, return only exit code.
+      --snapshot <DEV_ID>  Specify the device id of the snapshot to merge
+  -V, --version            Print version";
+
+//-----------------------------------------
+
+struct ThinMerge;
+
+impl<'a> Program<'a> for ThinMerge {
+    fn name() -> &'a str {
+        "thin_merge"
+    }
+
+    fn cmd<I>(args: I) -> Command
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString>,
+    {
+        thin_merge_cmd(args)
+    }
+
+    fn usage() -> &'a str {
+        USAGE
+    }
+
+    fn arg_type() -> ArgType {
+        ArgType::IoOptions
+    }
+
+    fn required_args() -> &'a [&'a str] {
+        &["--origin", "0", "--snapshot", "1"]
+    }
+
+    fn bad_option_hint(option: &str) -> String {
+        msg::bad_option_hint(option)
+    }
+}
+
+impl<'a> InputProgram<'a> for ThinMerge {
+    fn mk_valid_input(td: &mut TestDir) -> Result<std::path::PathBuf> {
+        mk_generated_md(td, 1, 1)
+    }
+
+    fn file_not_found() -> &'a str {
+        msg::FILE_NOT_FOUND
+    }
+
+    fn missing_input_arg() -> &'a str {
+        msg::MISSING_INPUT_ARG
+    }
+
+    fn corrupted_input() -> &'a str {
+        msg::BAD_SUPERBLOCK
+    }
+}
+
+impl<'a> OutputProgram<'a> for ThinMerge {
+    fn missing_output_arg() -> &'a str {
+        msg::MISSING_OUTPUT_ARG
+    }
+}
+
+impl<'a> MetadataWriter<'a> for ThinMerge {
+    fn file_not_found() -> &'a str {
+        msg::FILE_NOT_FOUND
+    }
+}
+
+//-----------------------------------------
+
+test_accepts_help!(ThinMerge);
+test_accepts_version!(ThinMerge);
+test_rejects_bad_option!(ThinMerge);
+
+test_input_file_not_found!(ThinMerge);
+test_input_cannot_be_a_directory!(ThinMerge);
+test_corrupted_input_data!(ThinMerge);
+
+test_readonly_input_file!(ThinMerge);
+
+test_missing_output_option!(ThinMerge);
+
+//-----------------------------------------
+
+// The origin has had blocks 50..100 and 200..300 written since the
+// snapshot was taken, and the snapshot has had 50..100 rewritten.
+const ORIGIN_AND_SNAP: &[u8] = b"<superblock uuid=\"\" time=\"1\" transaction=\"2\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"1024\">
+  <device dev_id=\"1\" mapped_blocks=\"200\" transaction=\"0\" creation_time=\"0\" snap_time=\"1\">
+    <range_mapping origin_begin=\"0\" data_begin=\"0\" length=\"50\" time=\"0\"/>
+    <range_mapping origin_begin=\"50\" data_begin=\"400\" length=\"50\" time=\"1\"/>
+    <range_mapping origin_begin=\"200\" data_begin=\"500\" length=\"100\" time=\"1\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"100\" transaction=\"0\" creation_time=\"1\" snap_time=\"1\">
+    <range_mapping origin_begin=\"0\" data_begin=\"0\" length=\"50\" time=\"0\"/>
+    <range_mapping origin_begin=\"50\" data_begin=\"300\" length=\"50\" time=\"1\"/>
+  </device>
+</superblock>";
+
+fn mk_origin_and_snap(td: &mut TestDir) -> Result<std::path::PathBuf> {
+    let xml = td.mk_path("meta.xml");
+    let md = mk_zeroed_md(td)?;
+    write_file(&xml, ORIGIN_AND_SNAP)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    Ok(md)
+}
+
+fn merge(td: &mut TestDir, extra_args: &[&str]) -> Result<std::path::PathBuf> {
+    let input = mk_origin_and_snap(td)?;
+    let output = mk_zeroed_md(td)?;
+
+    let mut args = args![
+        "-i",
+        &input,
+        "-o",
+        &output,
+        "--origin",
+        "1",
+        "--snapshot",
+        "2"
+    ]
+    .to_vec();
+    args.extend(extra_args.iter().map(std::ffi::OsStr::new));
+    run_ok(thin_merge_cmd(args))?;
+    run_ok(thin_check_cmd(args![&output]))?;
+
+    Ok(output)
+}
+
+#[test]
+fn merge_replaces_origin_mappings() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let output = merge(&mut td, &[])?;
+    let dump = run_ok(thin_dump_cmd(args![&output]))?;
+
+    assert!(!dump.contains("dev_id=\"2\""));
+    assert!(dump.contains("<device dev_id=\"1\" mapped_blocks=\"100\""));
+    assert!(dump.contains("origin_begin=\"50\" data_begin=\"300\" length=\"50\""));
+    assert!(!dump.contains("data_begin=\"400\""));
+    assert!(!dump.contains("data_begin=\"500\""));
+    assert_eq!(get_data_usage(&output)?.1, 100);
+    Ok(())
+}
+
+#[test]
+fn merge_overlays_origin_mappings() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let output = merge(&mut td, &["--overlay"])?;
+    let dump = run_ok(thin_dump_cmd(args![&output]))?;
+
+    assert!(!dump.contains("dev_id=\"2\""));
+    assert!(dump.contains("<device dev_id=\"1\" mapped_blocks=\"200\""));
+    assert!(dump.contains("origin_begin=\"50\" data_begin=\"300\" length=\"50\""));
+    assert!(dump.contains("origin_begin=\"200\" data_begin=\"500\" length=\"100\""));
+    assert!(!dump.contains("data_begin=\"400\""));
+    assert_eq!(get_data_usage(&output)?.1, 200);
+    Ok(())
+}
+
+#[test]
+fn merge_shared_snapshot_chain() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let input = mk_generated_md(&mut td, 1, 3)?;
+    let output = mk_zeroed_md(&mut td)?;
+    let thins = get_thins(&input)?;
+
+    run_ok(thin_merge_cmd(args![
+        "-i",
+        &input,
+        "-o",
+        &output,
+        "--origin",
+        "0",
+        "--snapshot",
+        "2"
+    ]))?;
+    run_ok(thin_check_cmd(args![&output]))?;
+
+    let merged = get_thins(&output)?;
+    assert_eq!(merged.len(), thins.len() - 1);
+    assert!(!merged.contains_key(&2));
+    assert_eq!(merged[&0].1.mapped_blocks, thins[&2].1.mapped_blocks);
+    Ok(())
+}
+
+#[test]
+fn rejects_missing_snapshot() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let input = mk_origin_and_snap(&mut td)?;
+    let output = mk_zeroed_md(&mut td)?;
+    let stderr = run_fail(thin_merge_cmd(args![
+        "-i",
+        &input,
+        "-o",
+        &output,
+        "--origin",
+        "1",
+        "--snapshot",
+        "3"
+    ]))?;
+    assert!(stderr.contains("couldn't find device 3"));
+    Ok(())
+}
+
+//-----------------------------------------
@@ -1,10 +1,15 @@
 use anyhow::Result;
+use std::fs::OpenOptions;
+use std::os::unix::fs::FileExt;
+
+use thinp::checksum::*;
 
 mod common;
 
 use common::common_args::*;
 use common::input_arg::*;
 use common::output_option::*;
+use common::process::*;
 use common::program::*;
 use common::target::*;
 use common::test_dir::*;
@@ -17,11 +22,14 @@ const USAGE: &str = "Produces a compressed file of thin metadata.  Only packs me
 Usage: thin_metadata_pack [OPTIONS] --input <DEV> --output <FILE>
 
 Options:
-  -f, --force          Force overwrite the output file
-  -h, --help           Print help
-  -i, --input <DEV>    Specify thinp metadata binary device/file
-  -o, --output <FILE>  Specify packed output file
-  -V, --version        Print version";
+      --anonymise           Scrub the uuid from the superblocks
+      --dev-id <THIN_ID>    Pack only the blocks used by the specified thin device
+  -f, --force               Force overwrite the output file
+  -h, --help                Print help
+  -i, --input <DEV>         Specify thinp metadata binary device/file
+  -o, --output <FILE>       Specify packed output file
+  -V, --version             Print version
+  -z, --compression <TYPE>  Choose the compression of the packed blocks [default: zlib]";
 
 //------------------------------------------
 
@@ -88,3 +96,98 @@ test_missing_output_option!(ThinMetadataPack);
 test_input_file_not_found!(ThinMetadataPack);
 
 //-----------------------------------------
+
+//------------------------------------------
+
+const UUID: std::ops::Range<usize> = 16..32;
+
+fn read_superblock_block(md: &std::path::Path) -> Result<Vec<u8>> {
+    let file = OpenOptions::new().read(true).open(md)?;
+    let mut buf = vec![0; 4096];
+    file.read_exact_at(&mut buf, 0)?;
+    Ok(buf)
+}
+
+#[test]
+fn anonymise_scrubs_uuid() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+
+    // set a uuid, since the tools leave it blank
+    let mut sb = read_superblock_block(&md)?;
+    sb[UUID].copy_from_slice(b"0123456789abcdef");
+    write_checksum(&mut sb, BT::THIN_SUPERBLOCK)?;
+    OpenOptions::new()
+        .write(true)
+        .open(&md)?
+        .write_all_at(&sb, 0)?;
+
+    let packed = td.mk_path("meta.pack");
+    let unpacked = td.mk_path("meta.out");
+    run_ok(thin_metadata_pack_cmd(args![
+        "-i",
+        &md,
+        "-o",
+        &packed,
+        "--anonymise"
+    ]))?;
+    run_ok(thin_metadata_unpack_cmd(args![
+        "-i", &packed, "-o", &unpacked
+    ]))?;
+
+    let sb = read_superblock_block(&unpacked)?;
+    assert_eq!(&sb[UUID], &[0; 16]);
+    assert_eq!(metadata_block_type(&sb), BT::THIN_SUPERBLOCK);
+    run_ok(thin_check_cmd(args![&unpacked]))?;
+    Ok(())
+}
+
+#[test]
+fn pack_selected_devices() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let packed = td.mk_path("meta.pack");
+    let unpacked = td.mk_path("meta.out");
+    run_ok(thin_metadata_pack_cmd(args![
+        "-i", &md, "-o", &packed, "--dev-id", "1", "--dev-id", "3"
+    ]))?;
+    run_ok(thin_metadata_unpack_cmd(args![
+        "-i", &packed, "-o", &unpacked
+    ]))?;
+
+    run_ok(thin_check_cmd(args![&unpacked]))?;
+    let expected = run_ok(thin_dump_cmd(args![&md, "--dev-id", "1", "--dev-id", "3"]))?;
+    assert_eq!(run_ok(thin_dump_cmd(args![&unpacked]))?, expected);
+
+    // the blocks of the other devices are left out
+    let full = td.mk_path("full.pack");
+    run_ok(thin_metadata_pack_cmd(args!["-i", &md, "-o", &full]))?;
+    assert!(std::fs::metadata(&packed)?.len() < std::fs::metadata(&full)?.len());
+    Ok(())
+}
+
+#[test]
+fn pack_selected_devices_with_metadata_snap() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata_with_metadata_snap(&mut td)?;
+    let packed = td.mk_path("meta.pack");
+    run_ok(thin_metadata_pack_cmd(args![
+        "-i", &md, "-o", &packed, "--dev-id", "1"
+    ]))?;
+    run_ok(thin_check_cmd(args![&packed]))?;
+    Ok(())
+}
+
+#[test]
+fn pack_unknown_device_fails() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let packed = td.mk_path("meta.pack");
+    let stderr = run_fail(thin_metadata_pack_cmd(args![
+        "-i", &md, "-o", &packed, "--dev-id", "999"
+    ]))?;
+    assert!(stderr.contains("device 999 not found"));
+    Ok(())
+}
+
+//------------------------------------------
@@ -1,4 +1,8 @@
 use anyhow::Result;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use thinp::pack::toplevel::PackReader;
 
 mod common;
 
@@ -114,3 +118,129 @@ fn end_to_end() -> Result<()> {
 }
 
 //------------------------------------------
+
+#[test]
+fn end_to_end_zstd() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_valid_md(&mut td)?;
+    let md_packed = td.mk_path("meta.pack");
+    let md_out = td.mk_path("meta.out");
+    run_ok(thin_metadata_pack_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_packed,
+        "--compression",
+        "zstd"
+    ]))?;
+    run_ok(thin_metadata_unpack_cmd(args![
+        "-i", &md_packed, "-o", &md_out
+    ]))?;
+
+    assert_eq!(std::fs::read(&md_in)?, std::fs::read(&md_out)?);
+    Ok(())
+}
+
+#[test]
+fn rejects_unknown_compression() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md_in = mk_valid_md(&mut td)?;
+    let md_packed = td.mk_path("meta.pack");
+    run_fail(thin_metadata_pack_cmd(args![
+        "-i",
+        &md_in,
+        "-o",
+        &md_packed,
+        "--compression",
+        "lz4"
+    ]))?;
+    Ok(())
+}
+
+// Reads the chunk offsets from the index of a version 4 pack file.
+fn read_index_offsets(pack: &std::path::Path) -> Result<Vec<u64>> {
+    let data = std::fs::read(pack)?;
+    let word = |off: usize| u64::from_le_bytes(data[off..off + 8].try_into().unwrap());
+    let footer = data.len() - 24;
+    let index = word(footer) as usize;
+    let nr_entries = word(footer + 8) as usize;
+    Ok((0..nr_entries).map(|i| word(index + i * 24 + 16)).collect())
+}
+
+#[test]
+fn chunks_span_multiple_ranges() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let packed = td.mk_path("meta.pack");
+    run_ok(thin_metadata_pack_cmd(args!["-i", &md, "-o", &packed]))?;
+
+    let offsets = read_index_offsets(&packed)?;
+    let mut chunks = offsets.clone();
+    chunks.sort_unstable();
+    chunks.dedup();
+    assert!(chunks.len() < offsets.len());
+
+    check_read_blocks(&packed, &md)
+}
+
+#[test]
+fn rejects_bad_chunk_length() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    let packed = td.mk_path("meta.pack");
+    let md_out = td.mk_path("meta.out");
+    run_ok(thin_metadata_pack_cmd(args!["-i", &md, "-o", &packed]))?;
+
+    let offset = read_index_offsets(&packed)?[0];
+    let file = std::fs::OpenOptions::new().write(true).open(&packed)?;
+    file.write_all_at(&(u64::MAX / 2).to_le_bytes(), offset)?;
+
+    let stderr = run_fail(thin_metadata_unpack_cmd(args![
+        "-i", &packed, "-o", &md_out
+    ]))?;
+    assert!(stderr.contains("runs past the end of the pack file"));
+    assert!(PackReader::open(&packed)?.read_block(0).is_err());
+    Ok(())
+}
+
+fn check_read_blocks(pack: &std::path::Path, md: &std::path::Path) -> Result<()> {
+    let reader = PackReader::open(pack)?;
+    let file = File::open(md)?;
+    assert_eq!(reader.nr_blocks() * 4096, file.metadata()?.len());
+
+    let mut expected = vec![0; 4096];
+    for b in 0..reader.nr_blocks() {
+        file.read_exact_at(&mut expected, b * 4096)?;
+        assert_eq!(reader.read_block(b)?, expected, "block {}", b);
+    }
+    assert!(reader.read_block(reader.nr_blocks()).is_err());
+    Ok(())
+}
+
+#[test]
+fn read_blocks_from_legacy_pack() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    check_read_blocks(&path_to(TestData::PackedMetadata)?, &md)
+}
+
+#[test]
+fn read_blocks_from_indexed_pack() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = mk_valid_md(&mut td)?;
+    for compression in ["zlib", "zstd"] {
+        let packed = td.mk_path(&format!("meta.{}.pack", compression));
+        run_ok(thin_metadata_pack_cmd(args![
+            "-i",
+            &md,
+            "-o",
+            &packed,
+            "-z",
+            compression
+        ]))?;
+        check_read_blocks(&packed, &md)?;
+    }
+    Ok(())
+}
+
+//------------------------------------------
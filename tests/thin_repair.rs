@@ -16,16 +16,19 @@ use common::thin::*;
 
 const USAGE: &str = "Repair thin-provisioning metadata, and write it to different device or file
 
-Usage: thin_repair [OPTIONS] --input <FILE> --output <FILE>
+Usage: thin_repair [OPTIONS] --input <FILE> <--output <FILE> | --dry-run>
 
 Options:
       --data-block-size <SECTORS>  Provide the data block size for repairing
+      --dry-run                    Report what would be recovered without writing the output
   -h, --help                       Print help
   -i, --input <FILE>               Specify the input device
+      --lost-found <FILE>          Recover orphaned mappings into a new device, listed in a file
       --nr-data-blocks <NUM>       Override the number of data blocks if needed
   -o, --output <FILE>              Specify the output device
   -q, --quiet                      Suppress output messages, return only exit code.
-      --transaction-id <NUM>       Override the transaction id if needed
+      --rollback                   Roll back to the roots of an earlier transaction
+      --transaction-id <NUM>       Override the transaction id if needed, or pick one to roll back to
   -V, --version                    Print version";
 
 //-----------------------------------------
@@ -366,4 +369,535 @@ fn repair_device_details_tree() -> Result<()> {
 
     Ok(())
 }
+
+fn repair_lost_details_tree(damaged_blocks: &[u64]) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut td = TestDir::new()?;
+    let orig = prep_metadata(&mut td)?;
+    let orig_sb = get_superblock(&orig)?;
+    let orig_thins = get_thins(&orig)?;
+    let orig_data_usage = get_data_usage(&orig)?;
+
+    let file = std::fs::OpenOptions::new().write(true).open(&orig)?;
+    for b in damaged_blocks {
+        file.write_all_at(&[0; 8], b * 4096)?;
+    }
+    drop(file);
+
+    let repaired = mk_zeroed_md(&mut td)?;
+    run_ok(thin_repair_cmd(args!["-i", &orig, "-o", &repaired]))?;
+
+    // verify the number of recovered data blocks
+    assert_eq!(get_data_usage(&repaired)?.1, orig_data_usage.1);
+
+    // verify the recovered devices
+    let repaired_thins = get_thins(&repaired)?;
+    assert!(repaired_thins
+        .iter()
+        .map(|(k, (_, d))| (k, d.mapped_blocks))
+        .eq(orig_thins.iter().map(|(k, (_, d))| (k, d.mapped_blocks))));
+
+    // verify the recovered timestamp
+    let orig_ts = orig_thins
+        .values()
+        .map(|(_, detail)| detail.snapshotted_time)
+        .max()
+        .unwrap_or(0);
+    let expected_ts = std::cmp::max(orig_sb.time, orig_ts + 1);
+    let repaired_sb = get_superblock(&repaired)?;
+    assert_eq!(repaired_sb.time, expected_ts);
+    assert!(repaired_thins
+        .values()
+        .map(|(_, d)| d.snapshotted_time)
+        .all(|ts| ts == expected_ts));
+
+    // verify the devices are behind the recovered transaction
+    assert_eq!(repaired_sb.transaction_id, orig_sb.transaction_id);
+    assert!(repaired_thins
+        .values()
+        .all(|(_, d)| d.transaction_id < repaired_sb.transaction_id));
+
+    Ok(())
+}
+
+#[test]
+fn repair_device_details_tree_recovers_transaction_id() -> Result<()> {
+    // damage the details trees located at block#1 and #2, and the mapping tree
+    // at block#20, leaving the mapping tree at block#5 alone
+    repair_lost_details_tree(&[1, 2, 20])
+}
+
+#[test]
+fn repair_device_details_tree_with_stale_mapping_trees() -> Result<()> {
+    // damage the details trees located at block#1 and #2 only. The mapping
+    // tree referenced by the superblock should be preferred over the stale
+    // ones.
+    repair_lost_details_tree(&[1, 2])
+}
+
+#[test]
+fn repair_superblock_of_many_devices() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let src = mk_generated_md(&mut td, 4, 4)?;
+    let orig_thins = get_thins(&src)?;
+    let orig_data_usage = get_data_usage(&src)?;
+    damage_superblock(&src)?;
+
+    let dest = mk_zeroed_md(&mut td)?;
+    run_ok(thin_repair_cmd(args![
+        "--data-block-size=128",
+        "--nr-data-blocks=102400",
+        "-i",
+        &src,
+        "-o",
+        &dest
+    ]))?;
+
+    assert_eq!(get_data_usage(&dest)?, orig_data_usage);
+    let repaired_thins = get_thins(&dest)?;
+    assert!(repaired_thins
+        .iter()
+        .map(|(k, (_, d))| (k, d.mapped_blocks))
+        .eq(orig_thins.iter().map(|(k, (_, d))| (k, d.mapped_blocks))));
+
+    Ok(())
+}
+
+//-----------------------------------------
+// test lost+found
+
+fn mk_detached_md(td: &mut TestDir) -> Result<std::path::PathBuf> {
+    let md = mk_zeroed_md(td)?;
+    let xml = td.mk_path("meta.xml");
+    let input = b"<superblock uuid=\"\" time=\"1\" transaction=\"2\" version=\"2\" data_block_size=\"128\" nr_data_blocks=\"1024\">
+  <device dev_id=\"1\" mapped_blocks=\"100\" transaction=\"0\" creation_time=\"0\" snap_time=\"0\">
+    <range_mapping origin_begin=\"0\" data_begin=\"0\" length=\"100\" time=\"0\"/>
+  </device>
+  <device dev_id=\"2\" mapped_blocks=\"200\" transaction=\"1\" creation_time=\"1\" snap_time=\"1\">
+    <range_mapping origin_begin=\"1000\" data_begin=\"200\" length=\"200\" time=\"1\"/>
+  </device>
+</superblock>";
+    write_file(&xml, input)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+
+    detach_device(&md, 2)?;
+    assert_eq!(get_thins(&md)?.len(), 1);
+    Ok(md)
+}
+
+// Damages the tree of device#1 after taking a metadata snapshot, which
+// leaves the first leaf of the device orphaned.  The other leaves are
+// still referenced by the snapshot.
+fn mk_orphaned_md(td: &mut TestDir) -> Result<std::path::PathBuf> {
+    let md = prep_metadata(td)?;
+    take_metadata_snap_with_private_leaf(&md, 1, 0)?;
+    let dev_root = get_thins(&md)?[&1].0;
+    damage_block(&md, dev_root)?;
+    Ok(md)
+}
+
+fn repair_with_lost_found(
+    td: &mut TestDir,
+    src: &std::path::Path,
+) -> Result<(std::path::PathBuf, Vec<String>)> {
+    let dest = mk_zeroed_md(td)?;
+    let report = td.mk_path("lost+found.txt");
+    run_ok(thin_repair_cmd(args![
+        "-i",
+        src,
+        "-o",
+        &dest,
+        "--lost-found",
+        &report
+    ]))?;
+    run_ok(thin_check_cmd(args![&dest]))?;
+
+    let report = std::fs::read_to_string(&report)?;
+    let lines = report
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .map(|l| l.to_string())
+        .collect();
+    Ok((dest, lines))
+}
+
+#[test]
+fn lost_found_recovers_orphaned_mappings() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let src = mk_orphaned_md(&mut td)?;
+    let (dest, lines) = repair_with_lost_found(&mut td, &src)?;
+
+    // the orphaned mappings are placed at the start of a new device
+    assert_eq!(lines.len(), 1);
+    let fields: Vec<&str> = lines[0].split(' ').collect();
+    assert_eq!(fields[0], "0");
+    assert_eq!(fields[5], "240");
+    let dump = run_ok(thin_dump_cmd(args![&dest]))?;
+    assert!(dump.contains("<device dev_id=\"17\" mapped_blocks=\"240\""));
+    Ok(())
+}
+
+#[test]
+fn orphaned_mappings_are_dropped_by_default() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let src = mk_orphaned_md(&mut td)?;
+    let dest = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_repair_cmd(args!["-i", &src, "-o", &dest]))?;
+    assert!(!get_thins(&dest)?.contains_key(&17));
+    Ok(())
+}
+
+#[test]
+fn lost_found_leaves_out_deleted_devices() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let src = mk_detached_md(&mut td)?;
+    let (dest, lines) = repair_with_lost_found(&mut td, &src)?;
+    assert!(lines.is_empty());
+    assert_eq!(get_thins(&dest)?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn lost_found_leaves_out_stale_leaves() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let src = prep_metadata(&mut td)?;
+    let nr_thins = get_thins(&src)?.len();
+    leave_stale_leaf(&src, 1, 0)?;
+
+    let (dest, lines) = repair_with_lost_found(&mut td, &src)?;
+    assert!(lines.is_empty());
+    assert_eq!(get_thins(&dest)?.len(), nr_thins);
+    Ok(())
+}
+
+//-----------------------------------------
+// test rollback
+
+// The test metadata holds the roots of an earlier transaction at block#20
+// and #2, where device#14 is not yet deleted.
+#[test]
+fn rollback_to_earlier_transaction() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let dest = mk_zeroed_md(&mut td)?;
+    assert!(!get_thins(&md)?.contains_key(&14));
+
+    run_ok(thin_repair_cmd(args!["-i", &md, "-o", &dest, "--rollback"]))?;
+    let sb = get_superblock(&dest)?;
+    assert_eq!(sb.transaction_id, 16);
+    let thins = get_thins(&dest)?;
+    assert_eq!(thins.len(), 12);
+    assert!(thins.contains_key(&14));
+    run_ok(thin_check_cmd(args![&dest]))?;
+    Ok(())
+}
+
+#[test]
+fn rollback_to_given_transaction() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let dest = mk_zeroed_md(&mut td)?;
+
+    run_ok(thin_repair_cmd(args![
+        "-i",
+        &md,
+        "-o",
+        &dest,
+        "--rollback",
+        "--transaction-id",
+        "18"
+    ]))?;
+    assert_eq!(get_superblock(&dest)?.transaction_id, 18);
+    assert!(get_thins(&dest)?.contains_key(&14));
+
+    // the current transaction is kept if it's not newer than the one given
+    run_ok(thin_repair_cmd(args![
+        "-i",
+        &md,
+        "-o",
+        &dest,
+        "--rollback",
+        "--transaction-id",
+        "30"
+    ]))?;
+    assert_eq!(get_superblock(&dest)?.transaction_id, 30);
+    assert_eq!(get_thins(&dest)?.len(), 11);
+    Ok(())
+}
+
+#[test]
+fn rollback_fails_without_earlier_transaction() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let dest = mk_zeroed_md(&mut td)?;
+
+    let stderr = run_fail(thin_repair_cmd(args![
+        "-i",
+        &md,
+        "-o",
+        &dest,
+        "--rollback",
+        "--transaction-id",
+        "10"
+    ]))?;
+    assert!(stderr.contains("no roots found for transaction 10 or earlier"));
+    Ok(())
+}
+
+#[test]
+fn rollback_skips_inconsistent_roots() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let dest = mk_zeroed_md(&mut td)?;
+
+    // The earlier roots are still paired up as the total number of
+    // mappings is kept, but the details of two devices are wrong.
+    update_details_leaf(&md, 2, |details| {
+        assert_ne!(details[0].mapped_blocks, details[1].mapped_blocks);
+        let mapped_blocks = details[0].mapped_blocks;
+        details[0].mapped_blocks = details[1].mapped_blocks;
+        details[1].mapped_blocks = mapped_blocks;
+    })?;
+
+    let stderr = run_fail(thin_repair_cmd(args!["-i", &md, "-o", &dest, "--rollback"]))?;
+    assert!(stderr.contains("skipping the mapping root 20 and the details root 2"));
+    assert!(stderr.contains("no consistent roots found from an earlier transaction"));
+    Ok(())
+}
+
+#[test]
+fn rollback_to_given_transaction_skips_inconsistent_roots() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let dest = mk_zeroed_md(&mut td)?;
+
+    // The current roots are taken for a transaction not older than the
+    // current one, unless they're inconsistent.
+    let sb = get_superblock(&md)?;
+    update_details_leaf(&md, sb.details_root, |details| {
+        assert_ne!(details[0].mapped_blocks, details[1].mapped_blocks);
+        let mapped_blocks = details[0].mapped_blocks;
+        details[0].mapped_blocks = details[1].mapped_blocks;
+        details[1].mapped_blocks = mapped_blocks;
+    })?;
+
+    let output = run_ok_raw(thin_repair_cmd(args![
+        "-i",
+        &md,
+        "-o",
+        &dest,
+        "--rollback",
+        "--transaction-id",
+        "30"
+    ]))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains(&format!(
+        "skipping the mapping root {} and the details root {}",
+        sb.mapping_root, sb.details_root
+    )));
+    assert_eq!(get_superblock(&dest)?.transaction_id, 30);
+    assert!(get_thins(&dest)?.contains_key(&14));
+
+    // no older roots are left to fall back to
+    update_details_leaf(&md, 2, |details| {
+        let mapped_blocks = details[0].mapped_blocks;
+        details[0].mapped_blocks = details[1].mapped_blocks;
+        details[1].mapped_blocks = mapped_blocks;
+    })?;
+    let stderr = run_fail(thin_repair_cmd(args![
+        "-i",
+        &md,
+        "-o",
+        &dest,
+        "--rollback",
+        "--transaction-id",
+        "30"
+    ]))?;
+    assert!(stderr.contains("skipping the mapping root 20 and the details root 2"));
+    assert!(stderr.contains("no consistent roots found for transaction 30 or earlier"));
+    Ok(())
+}
+
+#[test]
+fn rollback_with_damaged_superblock() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let dest = mk_zeroed_md(&mut td)?;
+    damage_superblock(&md)?;
+
+    // without the superblock, the newest roots are taken
+    run_ok(thin_repair_cmd(args![
+        "-i",
+        &md,
+        "-o",
+        &dest,
+        "--rollback",
+        "--data-block-size",
+        "128"
+    ]))?;
+    assert_eq!(get_superblock(&dest)?.transaction_id, 16);
+    run_ok(thin_check_cmd(args![&dest]))?;
+    Ok(())
+}
+
+//-----------------------------------------
+// test grafting from the metadata snapshot
+
+fn damage_block(md: &std::path::Path, b: u64) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let file = std::fs::OpenOptions::new().write(true).open(md)?;
+    file.write_all_at(&[0; 8], b * 4096)?;
+    Ok(())
+}
+
+#[test]
+fn graft_damaged_leaf_from_metadata_snap() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let dest = mk_zeroed_md(&mut td)?;
+    let before = get_mappings(&md)?;
+
+    let leaf = take_metadata_snap_with_private_leaf(&md, 1, 0)?;
+    damage_block(&md, leaf)?;
+
+    let output = run_ok_raw(thin_repair_cmd(args!["-i", &md, "-o", &dest]))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains("repairing them from the metadata snapshot"));
+    assert!(stderr.contains(
+        "device 1: thin blocks 0..364 damaged, 240 mappings grafted from the metadata snapshot"
+    ));
+
+    assert_eq!(get_mappings(&dest)?, before);
+    Ok(())
+}
+
+#[test]
+fn mappings_shared_with_metadata_snap_are_lost() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let dest = mk_zeroed_md(&mut td)?;
+    let thins = get_thins(&md)?;
+    take_metadata_snap_with_private_leaf(&md, 1, 0)?;
+
+    // the second leaf of device#1 is shared with the snapshot
+    damage_block(&md, 280)?;
+
+    let output = run_ok_raw(thin_repair_cmd(args!["-i", &md, "-o", &dest]))?;
+    let stderr = std::str::from_utf8(&output.stderr)?;
+    assert!(stderr.contains(
+        "device 1: thin blocks 364..710 damaged, 0 mappings grafted from the metadata snapshot"
+    ));
+
+    // the leaf is shared by other devices too
+    let repaired = get_thins(&dest)?;
+    assert_eq!(
+        repaired[&1].1.mapped_blocks,
+        thins[&1].1.mapped_blocks - 238
+    );
+    assert_eq!(repaired[&2].1.mapped_blocks, thins[&2].1.mapped_blocks);
+    run_ok(thin_check_cmd(args![&dest]))?;
+    Ok(())
+}
+
+#[test]
+fn graft_damaged_leaf_within_shared_run() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let dest = mk_zeroed_md(&mut td)?;
+    let before = get_mappings(&md)?;
+
+    // the fifth leaf of device#1 sits in a run of leaves shared with
+    // devices #15 and #16
+    let leaf = take_metadata_snap_with_private_leaf(&md, 1, 4)?;
+    damage_block(&md, leaf)?;
+
+    run_ok(thin_repair_cmd(args!["-i", &md, "-o", &dest]))?;
+    run_ok(thin_check_cmd(args![&dest]))?;
+    assert_eq!(get_mappings(&dest)?[&1], before[&1]);
+    Ok(())
+}
+
+#[test]
+fn dry_run_counts_grafted_mappings() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let thins = get_thins(&md)?;
+    let leaf = take_metadata_snap_with_private_leaf(&md, 1, 0)?;
+    damage_block(&md, leaf)?;
+
+    let stdout = run_ok(thin_repair_cmd(args!["-i", &md, "--dry-run"]))?;
+    let nr_mapped = thins[&1].1.mapped_blocks;
+    assert!(stdout.contains(&format!(
+        "device 1: {} of {} mapped blocks recovered, 0 lost",
+        nr_mapped, nr_mapped
+    )));
+    assert!(stdout.contains("  grafted thin blocks "));
+    assert!(stdout.contains("total: 0 blocks lost"));
+    Ok(())
+}
+
+//-----------------------------------------
+// test dry-run
+
+#[test]
+fn dry_run_reports_no_loss_for_valid_metadata() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let nr_thins = get_thins(&md)?.len();
+
+    ensure_untouched(&md, || {
+        let stdout = run_ok(thin_repair_cmd(args!["-i", &md, "--dry-run"]))?;
+        assert_eq!(stdout.matches(" 0 lost (0b)").count(), nr_thins);
+        assert!(stdout.contains("total: 0 blocks lost"));
+        Ok(())
+    })
+}
+
+#[test]
+fn dry_run_measures_missing_mappings() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let thins = get_thins(&md)?;
+
+    // the device details expect more mappings than found in the trees
+    update_device_details(&md, |d| d.mapped_blocks += 5)?;
+
+    let stdout = run_ok(thin_repair_cmd(args!["-i", &md, "--dry-run"]))?;
+    for (id, (_, d)) in thins {
+        assert!(stdout.contains(&format!(
+            "device {}: {} of {} mapped blocks recovered, 5 lost",
+            id,
+            d.mapped_blocks,
+            d.mapped_blocks + 5
+        )));
+    }
+    let total = 5 * get_thins(&md)?.len();
+    assert!(stdout.contains(&format!("total: {} blocks lost", total)));
+    Ok(())
+}
+
+#[test]
+fn dry_run_doesnt_require_output() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+    let stderr = run_fail(thin_repair_cmd(args!["-i", &md]))?;
+    assert!(stderr.contains(msg::MISSING_OUTPUT_ARG));
+    run_ok(thin_repair_cmd(args!["-i", &md, "--dry-run"]))?;
+    Ok(())
+}
+
+#[test]
+fn dry_run_with_rollback() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let md = prep_metadata(&mut td)?;
+
+    let stdout = run_ok(thin_repair_cmd(args!["-i", &md, "--dry-run", "--rollback"]))?;
+    assert!(stdout.contains("using the mapping root 20 and the details root 2"));
+    assert!(stdout.contains("not in the on-disk device details"));
+    Ok(())
+}
+
 //-----------------------------------------
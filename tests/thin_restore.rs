@@ -20,6 +20,7 @@ Usage: thin_restore [OPTIONS] --input <FILE> --output <FILE>
 
 Options:
       --data-block-size <SECTORS>  Override the data block size if needed
+  -f, --format <TYPE>              Choose the input format
   -h, --help                       Print help
   -i, --input <FILE>               Specify the input xml
       --nr-data-blocks <NUM>       Override the number of data blocks if needed
@@ -159,3 +160,34 @@ fn override_nr_data_blocks() -> Result<()> {
 }
 
 //-----------------------------------------
+
+#[test]
+fn recounts_mapped_blocks() -> Result<()> {
+    let mut td = TestDir::new()?;
+    let xml = mk_valid_xml(&mut td)?;
+    let md = mk_zeroed_md(&mut td)?;
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    let expected = get_thins(&md)?;
+    assert!(expected.values().any(|(_, d)| d.mapped_blocks > 0));
+
+    // Zero the mapped_blocks attributes of all the devices
+    let mut bad_xml = String::new();
+    let input = std::fs::read_to_string(&xml)?;
+    let mut parts = input.split("mapped_blocks=\"");
+    bad_xml.push_str(parts.next().unwrap());
+    for p in parts {
+        bad_xml.push_str("mapped_blocks=\"0");
+        bad_xml.push_str(&p[p.find('"').unwrap()..]);
+    }
+    std::fs::write(&xml, bad_xml)?;
+
+    run_ok(thin_restore_cmd(args!["-i", &xml, "-o", &md]))?;
+    let actual = get_thins(&md)?;
+    assert_eq!(actual.len(), expected.len());
+    for (id, (_, details)) in actual {
+        assert_eq!(details.mapped_blocks, expected[&id].1.mapped_blocks);
+    }
+    Ok(())
+}
+
+//-----------------------------------------